@@ -84,11 +84,16 @@ typedef enum mtpng_strategy_t {
 
 //
 // Compression levels for mtpng_encoder_options_set_compression_level().
+// Any zlib level from 1 to 9 may also be passed as a plain number.
+//
+// MTPNG_COMPRESSION_LEVEL_MAX compresses with Zopfli, which is much
+// slower and needs the library built with the "zopfli" feature.
 //
 typedef enum mtpng_compression_level_t {
     MTPNG_COMPRESSION_LEVEL_FAST = 1,
     MTPNG_COMPRESSION_LEVEL_DEFAULT = 6,
-    MTPNG_COMPRESSION_LEVEL_HIGH = 9
+    MTPNG_COMPRESSION_LEVEL_HIGH = 9,
+    MTPNG_COMPRESSION_LEVEL_MAX = 10
 } mtpng_compression_level;
 
 //
@@ -102,6 +107,32 @@ typedef enum mtpng_color_t {
     MTPNG_COLOR_TRUECOLOR_ALPHA = 6
 } mtpng_color;
 
+//
+// Compression methods for mtpng_header_set_compression_method().
+//
+// Only deflate is defined by the PNG spec.
+//
+typedef enum mtpng_compression_method_t {
+    MTPNG_COMPRESSION_METHOD_DEFLATE = 0
+} mtpng_compression_method;
+
+//
+// Filter methods for mtpng_header_set_filter_method().
+//
+// Only the standard set of five filter types is defined by the PNG spec.
+//
+typedef enum mtpng_filter_method_t {
+    MTPNG_FILTER_METHOD_STANDARD = 0
+} mtpng_filter_method;
+
+//
+// Interlace methods for mtpng_header_set_interlace_method().
+//
+typedef enum mtpng_interlace_method_t {
+    MTPNG_INTERLACE_METHOD_NONE = 0,
+    MTPNG_INTERLACE_METHOD_ADAM7 = 1
+} mtpng_interlace_method;
+
 #pragma mark Structs
 
 //
@@ -294,7 +325,8 @@ mtpng_encoder_options_set_strategy(mtpng_encoder_options* p_options,
 //
 // Override the default PNG compression level.
 //
-// Check the return value for errors.
+// Returns an error for MTPNG_COMPRESSION_LEVEL_MAX if Zopfli
+// support was not compiled in. Check the return value for errors.
 //
 extern mtpng_result
 mtpng_encoder_options_set_compression_level(mtpng_encoder_options* p_options,
@@ -317,12 +349,28 @@ mtpng_encoder_options_set_compression_level(mtpng_encoder_options* p_options,
 // chunk_size must be at least 32768 bytes, required for
 // maintaining compression across chunks.
 //
+// If not set, a chunk size is picked for each image to give about
+// two chunks per thread, from 32 KiB up to 4 MiB.
+//
 // Check the return value for errors.
 //
 extern mtpng_result
 mtpng_encoder_options_set_chunk_size(mtpng_encoder_options* p_options,
                                      size_t chunk_size);
 
+//
+// Set the number of rows in each chunk instead of a byte size,
+// so chunks start at known row multiples such as tile heights.
+// The last chunk takes whatever rows are left over.
+//
+// rows must be non-zero.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_options_set_chunk_rows(mtpng_encoder_options* p_options,
+                                     size_t rows);
+
 #pragma mark Header
 
 //
@@ -384,6 +432,43 @@ mtpng_header_set_color(mtpng_header* p_header,
                        mtpng_color color_type,
                        uint8_t depth);
 
+//
+// Set the compression method for the image.
+//
+// Only MTPNG_COMPRESSION_METHOD_DEFLATE is defined, and it is
+// the default.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_header_set_compression_method(mtpng_header* p_header,
+                                    mtpng_compression_method compression_method);
+
+//
+// Set the filter method for the image.
+//
+// Only MTPNG_FILTER_METHOD_STANDARD is defined, and it is
+// the default.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_header_set_filter_method(mtpng_header* p_header,
+                               mtpng_filter_method filter_method);
+
+//
+// Set the interlace method for the image.
+//
+// The default is MTPNG_INTERLACE_METHOD_NONE. With Adam7 interlacing
+// the encoder holds the whole image in memory until the last row
+// arrives, and only then starts compressing.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_header_set_interlace_method(mtpng_header* p_header,
+                                  mtpng_interlace_method interlace_method);
+
 #pragma mark Encoder
 
 //
@@ -480,11 +565,45 @@ mtpng_encoder_write_transparency(mtpng_encoder* p_encoder,
                                  const uint8_t* p_bytes,
                                  size_t len);
 
+//
+// Declare that input samples hold only the given number of significant
+// bits, such as 10-bit data in 16-bit samples, and write a matching
+// sBIT chunk. Image data is then scaled up to the full bit depth.
+//
+// Only 8- and 16-bit greyscale and truecolor images are supported.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_palette() or any image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_significant_bits(mtpng_encoder* p_encoder,
+                                     uint8_t bits);
+
+//
+// Like mtpng_encoder_write_significant_bits(), with a separate number
+// of significant bits for each channel in sBIT order, such as 5, 6, 5
+// for 5-6-5 color. Indexed-color images take three values for the
+// palette's red, green, and blue, and the palette is not scaled.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_significant_bits_per_channel(mtpng_encoder* p_encoder,
+                                                 const uint8_t* p_bits,
+                                                 size_t len);
+
 //
 // Write a custom ancillary chunk to the output stream.
 // The tag must be a 4-byte string. The data should be provided
 // in the appropriate format for the tag.
 //
+// Chunks must come after the header and before the image data;
+// critical chunks and tRNS are rejected, as are chunks out of the
+// order the spec requires relative to PLTE and repeats of chunks
+// that may only appear once.
+//
 // Check the return value for errors.
 //
 extern mtpng_result
@@ -511,6 +630,20 @@ mtpng_encoder_write_image_rows(mtpng_encoder* p_encoder,
                                const uint8_t* p_bytes,
                                size_t len);
 
+//
+// As mtpng_encoder_write_image_rows(), but rows start every stride
+// bytes, skipping any padding after each, as in GPU readbacks and
+// Windows DIBs. The stride must be at least the row length, and the
+// last row's padding may be left off.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_image_rows_with_stride(mtpng_encoder* p_encoder,
+                                           const uint8_t* p_bytes,
+                                           size_t len,
+                                           size_t stride);
+
 //
 // Wait for any outstanding work blocks, flush output,
 // release the encoder instance and clear the pointer.
@@ -0,0 +1,273 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// capi.rs - fuzz target driving the C API
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//
+// Interprets the fuzz input as a script of C API calls: each step
+// reads an opcode byte and whatever arguments it needs from the
+// rest of the input. Calls come in any order, with out-of-range
+// values, null pointers, and short buffers, as a careless C caller
+// might make them. Errors are expected; crashes and hangs are not.
+//
+// Run with: cargo fuzz run capi
+//
+
+#![no_main]
+
+#[macro_use] extern crate libfuzzer_sys;
+extern crate libc;
+extern crate mtpng;
+
+use std::ptr;
+
+use libc::{c_char, c_int, c_void, size_t};
+
+use mtpng::capi::*;
+
+// Pulls arguments off the front of the fuzz input, yielding
+// zeroes once it runs dry.
+struct Script<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Script<'a> {
+    fn is_done(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((&b, rest)) => {
+                self.data = rest;
+                b
+            },
+            None => 0,
+        }
+    }
+
+    fn int(&mut self) -> c_int {
+        self.byte() as i8 as c_int
+    }
+
+    fn bytes(&mut self) -> &'a [u8] {
+        let len = (self.byte() as usize).min(self.data.len());
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        bytes
+    }
+}
+
+unsafe extern "C" fn write_func(_user_data: *const c_void, _p_bytes: *const u8, len: size_t) -> size_t {
+    len
+}
+
+unsafe extern "C" fn flush_func(_user_data: *const c_void) -> bool {
+    true
+}
+
+// Hand out either the real pointer or null, as the script decides.
+fn maybe_null<T>(script: &mut Script, p: *mut T) -> *mut T {
+    if script.byte() & 0x80 != 0 {
+        ptr::null_mut()
+    } else {
+        p
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut script = Script { data };
+
+    let mut pool: PThreadPool = ptr::null_mut();
+    let mut options: PEncoderOptions = ptr::null_mut();
+    let mut header: PHeader = ptr::null_mut();
+    let mut encoder: PEncoder = ptr::null_mut();
+
+    unsafe {
+        while !script.is_done() {
+            match script.byte() % 23 {
+                0 => {
+                    let threads = (script.byte() % 4) as size_t;
+                    let pp = maybe_null(&mut script, &mut pool);
+                    // Replace a live pool rather than leak it, unless
+                    // options or an encoder may still be using it.
+                    if !pool.is_null() {
+                        if !options.is_null() || !encoder.is_null() {
+                            continue;
+                        }
+                        mtpng_threadpool_release(&mut pool);
+                    }
+                    mtpng_threadpool_new(pp, threads);
+                },
+                1 => {
+                    let pp = maybe_null(&mut script, &mut pool);
+                    // Releasing a pool still referenced by options or an
+                    // encoder would be a use-after-free in the caller,
+                    // not in the API.
+                    if options.is_null() && encoder.is_null() {
+                        mtpng_threadpool_release(pp);
+                    }
+                },
+                2 => {
+                    let pp = maybe_null(&mut script, &mut options);
+                    if !options.is_null() {
+                        mtpng_encoder_options_release(&mut options);
+                    }
+                    mtpng_encoder_options_new(pp);
+                },
+                3 => {
+                    let pp = maybe_null(&mut script, &mut options);
+                    mtpng_encoder_options_release(pp);
+                },
+                4 => {
+                    let p = maybe_null(&mut script, pool);
+                    mtpng_encoder_options_set_thread_pool(options, p);
+                },
+                5 => {
+                    let mode = script.int();
+                    mtpng_encoder_options_set_filter(options, mode);
+                },
+                6 => {
+                    let mode = script.int();
+                    mtpng_encoder_options_set_strategy(options, mode);
+                },
+                7 => {
+                    let level = script.int();
+                    mtpng_encoder_options_set_compression_level(options, level);
+                },
+                8 => {
+                    let size = (script.byte() as size_t) << 10;
+                    mtpng_encoder_options_set_chunk_size(options, size);
+                },
+                9 => {
+                    let pp = maybe_null(&mut script, &mut header);
+                    if !header.is_null() {
+                        mtpng_header_release(&mut header);
+                    }
+                    mtpng_header_new(pp);
+                },
+                10 => {
+                    let pp = maybe_null(&mut script, &mut header);
+                    mtpng_header_release(pp);
+                },
+                11 => {
+                    // Keep images small so runs stay fast.
+                    let width = script.byte() as u32;
+                    let height = script.byte() as u32;
+                    mtpng_header_set_size(header, width, height);
+                },
+                12 => {
+                    let color_type = script.int();
+                    let depth = script.byte();
+                    mtpng_header_set_color(header, color_type, depth);
+                },
+                13 => {
+                    let pp = maybe_null(&mut script, &mut encoder);
+                    let p_options = maybe_null(&mut script, options);
+                    let flags = script.byte();
+                    let wf = if flags & 1 != 0 { None } else { Some(write_func as CWriteFunc) };
+                    let ff = if flags & 2 != 0 { None } else { Some(flush_func as CFlushFunc) };
+                    if !encoder.is_null() {
+                        mtpng_encoder_release(&mut encoder);
+                    }
+                    mtpng_encoder_new(pp, wf, ff, ptr::null_mut(), p_options);
+                },
+                14 => {
+                    let p_header = maybe_null(&mut script, header);
+                    mtpng_encoder_write_header(encoder, p_header);
+                },
+                15 => {
+                    let which = script.byte();
+                    let bytes = script.bytes();
+                    let p_bytes = if which & 0x80 != 0 { ptr::null() } else { bytes.as_ptr() };
+                    match which % 3 {
+                        0 => mtpng_encoder_write_palette(encoder, p_bytes, bytes.len()),
+                        1 => mtpng_encoder_write_transparency(encoder, p_bytes, bytes.len()),
+                        _ => mtpng_encoder_write_image_rows(encoder, p_bytes, bytes.len()),
+                    };
+                },
+                16 => {
+                    let mut tag = [0 as c_char; 5];
+                    for t in tag.iter_mut().take(4) {
+                        *t = script.byte() as c_char;
+                    }
+                    let p_tag = if script.byte() & 0x80 != 0 { ptr::null() } else { tag.as_ptr() };
+                    let bytes = script.bytes();
+                    mtpng_encoder_write_chunk(encoder, p_tag, bytes.as_ptr(), bytes.len());
+                },
+                17 => {
+                    let bits = script.byte();
+                    mtpng_encoder_write_significant_bits(encoder, bits);
+                },
+                18 => {
+                    let which = script.byte();
+                    let method = script.int();
+                    match which % 3 {
+                        0 => mtpng_header_set_compression_method(header, method),
+                        1 => mtpng_header_set_filter_method(header, method),
+                        _ => mtpng_header_set_interlace_method(header, method),
+                    };
+                },
+                19 => {
+                    let stride = script.byte() as size_t;
+                    let null = script.byte() & 0x80 != 0;
+                    let bytes = script.bytes();
+                    let p_bytes = if null { ptr::null() } else { bytes.as_ptr() };
+                    mtpng_encoder_write_image_rows_with_stride(encoder, p_bytes, bytes.len(), stride);
+                },
+                20 => {
+                    let null = script.byte() & 0x80 != 0;
+                    let bits = script.bytes();
+                    let p_bits = if null { ptr::null() } else { bits.as_ptr() };
+                    mtpng_encoder_write_significant_bits_per_channel(encoder, p_bits, bits.len());
+                },
+                21 => {
+                    let rows = script.byte() as size_t;
+                    mtpng_encoder_options_set_chunk_rows(options, rows);
+                },
+                _ => {
+                    let pp = maybe_null(&mut script, &mut encoder);
+                    if script.byte() & 1 != 0 {
+                        mtpng_encoder_finish(pp);
+                    } else {
+                        mtpng_encoder_release(pp);
+                    }
+                },
+            }
+        }
+
+        // Clean up whatever the script left behind.
+        if !encoder.is_null() {
+            mtpng_encoder_release(&mut encoder);
+        }
+        if !header.is_null() {
+            mtpng_header_release(&mut header);
+        }
+        if !options.is_null() {
+            mtpng_encoder_options_release(&mut options);
+        }
+        if !pool.is_null() {
+            mtpng_threadpool_release(&mut pool);
+        }
+    }
+});
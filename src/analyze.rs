@@ -0,0 +1,228 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// analyze.rs - image content sampling for automatic settings
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+
+//! Image content sampling, for picking encoder settings automatically.
+//!
+//! A few rows spread over the image are enough to tell flat, few-color
+//! content such as screenshots from smooth, many-color content such as
+//! photographs, which want quite different filters and strategies.
+
+use std::collections::HashSet;
+use std::io;
+
+use super::ColorType;
+use super::Header;
+
+use super::utils::invalid_input;
+
+// How many rows to sample, spread evenly over the image.
+const SAMPLE_ROWS: usize = 16;
+
+/// Broad kind of image content, as guessed from sampled rows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Content {
+    /// Few colors in long runs, as in screenshots, UI, and line art.
+    Flat,
+    /// Many colors changing smoothly, as in photographs and renders.
+    Photographic,
+    /// Neither clearly, or too little to go on.
+    Mixed,
+}
+
+/// Statistics from the sampled rows of an image.
+#[derive(Copy, Clone, Debug)]
+pub struct Sample {
+    pixels: usize,
+    colors: usize,
+    runs: f64,
+    gradient: f64,
+    partial_alpha: f64,
+}
+
+impl Sample {
+    /// Number of pixels sampled.
+    pub fn pixels(&self) -> usize {
+        self.pixels
+    }
+
+    /// Number of distinct colors among the sampled pixels.
+    pub fn colors(&self) -> usize {
+        self.colors
+    }
+
+    /// Fraction of sampled pixels the same as their left neighbor.
+    pub fn runs(&self) -> f64 {
+        self.runs
+    }
+
+    /// Mean difference per channel between neighboring pixels that
+    /// differ, from 0 to 255. Low for smooth gradients.
+    pub fn gradient(&self) -> f64 {
+        self.gradient
+    }
+
+    /// Fraction of sampled pixels that are neither fully opaque nor
+    /// fully transparent. Zero for images without alpha.
+    pub fn partial_alpha(&self) -> f64 {
+        self.partial_alpha
+    }
+
+    /// Guess the kind of content from the statistics.
+    pub fn content(&self) -> Content {
+        if self.pixels == 0 {
+            return Content::Mixed;
+        }
+        // Greyscale can't have more than 256 colors, however smooth.
+        let many_colors = self.colors as f64 > (self.pixels as f64 * 0.1).min(128.0);
+        let few_colors = self.colors <= 64;
+        if self.partial_alpha < 0.25 && (self.runs >= 0.5 || (few_colors && self.runs >= 0.2)) {
+            Content::Flat
+        } else if self.runs < 0.1 && many_colors && self.gradient < 32.0 {
+            Content::Photographic
+        } else {
+            Content::Mixed
+        }
+    }
+}
+
+/// Sample rows of packed image data and gather statistics on them.
+///
+/// Indexed images and greyscale below 8 bits are measured on their
+/// packed bytes. 16-bit samples are measured on their high bytes.
+pub fn sample(header: &Header, data: &[u8]) -> io::Result<Sample> {
+    if data.len() != header.stride() * header.height() as usize {
+        return Err(invalid_input("Image data does not match the header size"));
+    }
+    sample_rows(header, data)
+}
+
+/// Sample rows as with sample(), from however many whole rows of
+/// the image are given, such as the first rows sent to an encoder.
+pub fn sample_rows(header: &Header, data: &[u8]) -> io::Result<Sample> {
+    let stride = header.stride();
+    if !data.len().is_multiple_of(stride) {
+        return Err(invalid_input("Buffer must be an integral number of rows"));
+    }
+    let height = data.len() / stride;
+
+    let bytes = if header.depth() == 16 { 2 } else { 1 };
+    let pixel = if header.depth() >= 8 { header.bytes_per_pixel() } else { 1 };
+    let alpha = match header.color_type() {
+        ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha => Some(pixel - bytes),
+        _ => None,
+    };
+
+    let mut colors = HashSet::new();
+    let mut pixels = 0;
+    let mut runs = 0;
+    let mut changes = 0;
+    let mut gradient = 0u64;
+    let mut partial_alpha = 0;
+
+    let rows = SAMPLE_ROWS.min(height);
+    for i in 0 .. rows {
+        let y = i * height / rows;
+        let row = &data[y * stride .. (y + 1) * stride];
+        let mut prev: Option<&[u8]> = None;
+        for p in row.chunks_exact(pixel) {
+            pixels += 1;
+            colors.insert(p);
+            if let Some(a) = alpha {
+                if p[a] != 0 && p[a] != 0xff {
+                    partial_alpha += 1;
+                }
+            }
+            if let Some(prev) = prev {
+                if prev == p {
+                    runs += 1;
+                } else {
+                    changes += 1;
+                    let diff: u64 = prev.iter().zip(p.iter())
+                                        .step_by(bytes)
+                                        .map(|(&a, &b)| u64::from(a.abs_diff(b)))
+                                        .sum();
+                    gradient += diff / (pixel / bytes) as u64;
+                }
+            }
+            prev = Some(p);
+        }
+    }
+
+    let fraction = |n: usize| if pixels == 0 { 0.0 } else { n as f64 / pixels as f64 };
+    Ok(Sample {
+        pixels,
+        colors: colors.len(),
+        runs: fraction(runs),
+        gradient: if changes == 0 { 0.0 } else { gradient as f64 / changes as f64 },
+        partial_alpha: fraction(partial_alpha),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sample, Content};
+    use super::super::ColorType;
+    use super::super::Header;
+
+    fn header(color_type: ColorType, width: u32, height: u32) -> Header {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(color_type, 8).unwrap();
+        header
+    }
+
+    #[test]
+    fn content() {
+        // Solid blocks of a few colors, like a window on a desktop.
+        let header = header(ColorType::Truecolor, 200, 100);
+        let flat: Vec<u8> = (0 .. 200 * 100).flat_map(|i| {
+            let (x, y) = (i % 200, i / 200);
+            if (20 .. 180).contains(&x) && (10 .. 90).contains(&y) {
+                [240, 240, 240]
+            } else {
+                [30, 60, 120]
+            }
+        }).collect();
+        let stats = sample(&header, &flat).unwrap();
+        assert_eq!(stats.pixels(), 200 * 16);
+        assert_eq!(stats.colors(), 2);
+        assert!(stats.runs() > 0.9);
+        assert_eq!(stats.content(), Content::Flat);
+
+        // Smooth shading with a little noise in every pixel.
+        let photo: Vec<u8> = (0 .. 200 * 100u32).flat_map(|i| {
+            let (x, y) = (i % 200, i / 200);
+            let noise = (i.wrapping_mul(2654435761) >> 28) as u8;
+            [x as u8 + noise, (y * 2) as u8 + noise, (x + y) as u8 / 2 + noise]
+        }).collect();
+        let stats = sample(&header, &photo).unwrap();
+        assert!(stats.runs() < 0.1);
+        assert!(stats.gradient() < 32.0);
+        assert_eq!(stats.content(), Content::Photographic);
+
+        assert!(sample(&header, &photo[1 ..]).is_err());
+    }
+}
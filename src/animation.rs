@@ -0,0 +1,608 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// animation.rs - animated PNG output
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Animated PNG (APNG) output.
+//!
+//! An AnimationEncoder writes the first frame as the regular image
+//! data, so viewers without APNG support show it as a still image,
+//! and each later frame after it in fcTL and fdAT chunks. Every frame
+//! compresses in parallel as with a still image.
+//!
+//! https://wiki.mozilla.org/APNG_Specification
+
+use std::io::Write;
+
+use super::ColorType;
+use super::Header;
+use super::encoder::{Encoder, Options};
+use super::error;
+use super::layout;
+use super::utils::*;
+
+/// What to do with a frame's region of the canvas once its delay is
+/// up, before the next frame is drawn.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DisposeOp {
+    /// Leave the frame on the canvas.
+    None = 0,
+    /// Clear the region to fully transparent black.
+    Background = 1,
+    /// Put the region back as it was before the frame was drawn.
+    Previous = 2,
+}
+
+/// How a frame is drawn onto its region of the canvas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlendOp {
+    /// Replace the region's pixels, alpha included.
+    Source = 0,
+    /// Composite the frame over the region using its alpha channel.
+    Over = 1,
+}
+
+/// Placement, timing, and dispose and blend operations for one frame
+/// of an animation, as written to its fcTL chunk.
+///
+/// By default a frame covers the whole canvas, shows for no time at
+/// all, replaces what was there, and is left in place afterwards.
+#[derive(Copy, Clone, Debug)]
+pub struct FrameControl {
+    region: Option<(u32, u32, u32, u32)>,
+    delay: (u16, u16),
+    dispose_op: DisposeOp,
+    blend_op: BlendOp,
+}
+
+impl Default for FrameControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameControl {
+    /// Create a new FrameControl with the default settings.
+    pub fn new() -> FrameControl {
+        FrameControl {
+            region: None,
+            delay: (0, 100),
+            dispose_op: DisposeOp::None,
+            blend_op: BlendOp::Source,
+        }
+    }
+
+    /// Draw the frame into part of the canvas, with its top-left
+    /// corner at x, y. Frame data then holds width by height pixels.
+    ///
+    /// The first frame must cover the whole canvas.
+    ///
+    /// Returns an error if width or height is 0.
+    pub fn set_region(&mut self, x: u32, y: u32, width: u32, height: u32) -> error::Result<()> {
+        if width == 0 || height == 0 {
+            return Err(invalid_input("Frame width and height must be at least 1."));
+        }
+        self.region = Some((x, y, width, height));
+        Ok(())
+    }
+
+    /// Show the frame for numerator / denominator seconds. A zero
+    /// denominator is taken as 100, for a delay in hundredths of a
+    /// second.
+    pub fn set_delay(&mut self, numerator: u16, denominator: u16) -> error::Result<()> {
+        self.delay = (numerator, denominator);
+        Ok(())
+    }
+
+    /// Set what happens to the frame's region after its delay.
+    pub fn set_dispose_op(&mut self, dispose_op: DisposeOp) -> error::Result<()> {
+        self.dispose_op = dispose_op;
+        Ok(())
+    }
+
+    /// Set how the frame is drawn over its region.
+    pub fn set_blend_op(&mut self, blend_op: BlendOp) -> error::Result<()> {
+        self.blend_op = blend_op;
+        Ok(())
+    }
+
+    /// The frame's region as x, y, width, and height, or None for
+    /// the whole canvas.
+    pub fn region(&self) -> Option<(u32, u32, u32, u32)> {
+        self.region
+    }
+
+    /// The frame's delay as numerator and denominator.
+    pub fn delay(&self) -> (u16, u16) {
+        self.delay
+    }
+
+    /// What happens to the frame's region after its delay.
+    pub fn dispose_op(&self) -> DisposeOp {
+        self.dispose_op
+    }
+
+    /// How the frame is drawn over its region.
+    pub fn blend_op(&self) -> BlendOp {
+        self.blend_op
+    }
+}
+
+/// Encodes an animated PNG, one frame at a time.
+///
+/// Write the header with the number of frames, then the palette and
+/// any metadata through encoder(), then each frame with write_frame.
+///
+/// Frame data is given as for Encoder::write_image_rows, all of it at
+/// once. Input pixel formats other than PixelFormat::Png and input
+/// transforms such as significant bits or greyscale packing aren't
+/// supported, and depth reduction and palette deduplication are
+/// turned off, so every frame keeps the header's format.
+pub struct AnimationEncoder<'a, W: Write> {
+    encoder: Encoder<'a, W>,
+    options: Options<'a>,
+    header: Header,
+    frames: u32,
+    frames_written: u32,
+    // Shared by fcTL and fdAT chunks, in order.
+    sequence: u32,
+    delta_frames: bool,
+    // With delta frames, the last frame as given.
+    canvas: Vec<u8>,
+}
+
+impl<'a, W: Write> AnimationEncoder<'a, W> {
+    /// Creates a new AnimationEncoder writing to the given output.
+    pub fn new(write: W, options: &Options<'a>) -> AnimationEncoder<'a, W> {
+        let mut options = *options;
+        // Neither can fail.
+        options.set_depth_reduction(false).unwrap();
+        options.set_palette_dedup(false).unwrap();
+        AnimationEncoder {
+            encoder: Encoder::new(write, &options),
+            options,
+            header: Header::new(),
+            frames: 0,
+            frames_written: 0,
+            sequence: 0,
+            delta_frames: false,
+            canvas: Vec::new(),
+        }
+    }
+
+    /// The underlying encoder, for writing the palette, metadata, and
+    /// other chunks between the header and the first frame. Write
+    /// frames with write_frame.
+    pub fn encoder(&mut self) -> &mut Encoder<'a, W> {
+        &mut self.encoder
+    }
+
+    /// Take every frame as a whole-canvas image, and encode only the
+    /// part that changed since the previous frame.
+    ///
+    /// Regions, dispose operations, and blend operations are then
+    /// chosen automatically: each frame covers the smallest region
+    /// holding all its changes, replaces it, and is left in place for
+    /// the next frame to build on. Only delays are taken from the
+    /// frame controls given.
+    ///
+    /// Must be set before the first frame.
+    pub fn set_delta_frames(&mut self, delta_frames: bool) -> error::Result<()> {
+        if self.frames_written > 0 {
+            return Err(invalid_input("Cannot change delta frames after the first frame."));
+        }
+        self.delta_frames = delta_frames;
+        Ok(())
+    }
+
+    /// Write the PNG signature, header chunk, and animation control
+    /// chunk, for the given number of frames played the given number
+    /// of times, where 0 is forever.
+    pub fn write_header(&mut self, header: &Header, frames: u32, plays: u32) -> error::Result<()> {
+        if frames == 0 {
+            return Err(invalid_input("Animation must have at least one frame."));
+        }
+        self.encoder.write_header(header)?;
+        self.header = *header;
+        self.frames = frames;
+
+        let mut data = Vec::with_capacity(8);
+        write_be32(&mut data, frames)?;
+        write_be32(&mut data, plays)?;
+        self.encoder.write_chunk(b"acTL", &data)
+    }
+
+    /// Encode and write the next frame.
+    ///
+    /// Data covers the frame's region, or with delta frames on the
+    /// whole canvas, in whole rows packed as for the header's color
+    /// type and depth.
+    pub fn write_frame(&mut self, control: &FrameControl, data: &[u8]) -> error::Result<()> {
+        if self.frames == 0 {
+            return Err(invalid_input("Cannot write frame before header."));
+        }
+        if self.frames_written == self.frames {
+            return Err(invalid_input(&format!("Animation only has {} frames.", self.frames)));
+        }
+
+        let (width, height) = (self.header.width(), self.header.height());
+        let mut control = *control;
+        let mut cropped = None;
+        if self.delta_frames {
+            if control.region.is_some() {
+                return Err(invalid_input("Delta frames cover the whole canvas."));
+            }
+            check_frame_size(&self.header, width, height, data)?;
+            if self.frames_written > 0 {
+                // A frame with no changes still needs a pixel.
+                let (x, y, w, h) = changed_region(&self.header, &self.canvas, data)
+                    .unwrap_or((0, 0, 1, 1));
+                control.region = Some((x, y, w, h));
+                cropped = Some(crop(&self.header, data, x, y, w, h));
+            }
+            control.dispose_op = DisposeOp::None;
+            control.blend_op = BlendOp::Source;
+            self.canvas.clear();
+            self.canvas.extend_from_slice(data);
+        }
+        let data = cropped.as_deref().unwrap_or(data);
+
+        let (x, y, w, h) = control.region.unwrap_or((0, 0, width, height));
+        if u64::from(x) + u64::from(w) > u64::from(width) || u64::from(y) + u64::from(h) > u64::from(height) {
+            return Err(invalid_input("Frame region must be within the canvas."));
+        }
+        if self.frames_written == 0 && (x, y, w, h) != (0, 0, width, height) {
+            return Err(invalid_input("The first frame must cover the whole canvas."));
+        }
+        check_frame_size(&self.header, w, h, data)?;
+
+        let mut fctl = Vec::with_capacity(26);
+        write_be32(&mut fctl, self.sequence)?;
+        write_be32(&mut fctl, w)?;
+        write_be32(&mut fctl, h)?;
+        write_be32(&mut fctl, x)?;
+        write_be32(&mut fctl, y)?;
+        fctl.extend_from_slice(&control.delay.0.to_be_bytes());
+        fctl.extend_from_slice(&control.delay.1.to_be_bytes());
+        fctl.push(control.dispose_op as u8);
+        fctl.push(control.blend_op as u8);
+
+        if self.frames_written == 0 {
+            // The first frame is the image data, so it goes through the
+            // encoder as usual.
+            let (_, row) = self.encoder.input_position()?;
+            if row > 0 {
+                return Err(invalid_input("Image data must be written with write_frame."));
+            }
+            if self.encoder.has_input_transform() {
+                return Err(invalid_input("Input transforms are not supported for animations."));
+            }
+            self.encoder.write_chunk(b"fcTL", &fctl)?;
+            self.sequence += 1;
+            self.encoder.write_image_rows(data)?;
+            self.encoder.flush()?;
+        } else {
+            let frame = self.encode_frame(w, h, data)?;
+            self.encoder.write_late_chunk(b"fcTL", &fctl)?;
+            self.sequence += 1;
+            for idat in image_data_chunks(&frame) {
+                let mut fdat = Vec::with_capacity(4 + idat.len());
+                write_be32(&mut fdat, self.sequence)?;
+                fdat.extend_from_slice(idat);
+                self.encoder.write_late_chunk(b"fdAT", &fdat)?;
+                self.sequence += 1;
+            }
+        }
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    // Encode a frame after the first as a PNG of its own, whose image
+    // data chunks become its fdAT chunks.
+    fn encode_frame(&self, width: u32, height: u32, data: &[u8]) -> error::Result<Vec<u8>> {
+        let mut header = self.header;
+        header.set_size(width, height)?;
+        let mut encoder = Encoder::new(Vec::new(), &self.options);
+        encoder.write_header(&header)?;
+        if let ColorType::IndexedColor = header.color_type() {
+            // Only the image data is kept, but indices are checked
+            // against the palette's size.
+            encoder.write_palette(&vec![0u8; self.encoder.palette_length() * 3])?;
+        }
+        encoder.write_image_rows(data)?;
+        encoder.finish()
+    }
+
+    /// Flush output and return the Write sink. Consumes the encoder.
+    ///
+    /// Returns an error if fewer frames were written than the header
+    /// said.
+    pub fn finish(self) -> error::Result<W> {
+        if self.frames_written < self.frames {
+            return Err(invalid_input(&format!("Animation has {} frames but only {} were written.",
+                                              self.frames, self.frames_written)));
+        }
+        self.encoder.finish()
+    }
+}
+
+// Check frame data holds exactly the rows of a frame of the given size.
+fn check_frame_size(header: &Header, width: u32, height: u32, data: &[u8]) -> error::Result<()> {
+    let stride = layout::stride(header.color_type(), header.depth(), width)
+        .ok_or_else(|| invalid_input::<error::Error>("Frame is too large to encode on this platform."))?;
+    if stride.checked_mul(height as usize) != Some(data.len()) {
+        return Err(invalid_input(&format!("Frame data must be {} rows of {} bytes.", height, stride)));
+    }
+    Ok(())
+}
+
+// The contents of each IDAT chunk in an encoded PNG, in order.
+fn image_data_chunks(png: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    // After the signature, each chunk is a length, tag, data, and CRC.
+    let mut pos = 8;
+    while pos + 12 <= png.len() {
+        let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+        if &png[pos + 4 .. pos + 8] == b"IDAT" {
+            chunks.push(&png[pos + 8 .. pos + 8 + len]);
+        }
+        pos += len + 12;
+    }
+    chunks
+}
+
+//
+// The smallest region holding every pixel that differs between two
+// whole-canvas images, as x, y, width, and height, or None if they're
+// the same.
+//
+// Below 8 bits per pixel the region is widened to whole bytes, which
+// only takes in pixels that didn't change.
+//
+fn changed_region(header: &Header, old: &[u8], new: &[u8]) -> Option<(u32, u32, u32, u32)> {
+    let stride = header.stride();
+    let width = header.width() as usize;
+    let bits = layout::bits_per_pixel(header.color_type(), header.depth());
+    let mut region: Option<(usize, usize, usize, usize)> = None;
+    for (y, (a, b)) in old.chunks(stride).zip(new.chunks(stride)).enumerate() {
+        let first = match a.iter().zip(b).position(|(a, b)| a != b) {
+            Some(first) => first,
+            None => continue,
+        };
+        let last = a.iter().zip(b).rposition(|(a, b)| a != b).unwrap();
+        let (start, end) = if bits >= 8 {
+            (first / (bits / 8), last / (bits / 8) + 1)
+        } else {
+            (first * 8 / bits, ((last + 1) * 8 / bits).min(width))
+        };
+        if start >= end {
+            // Only padding bits changed.
+            continue;
+        }
+        region = Some(match region {
+            Some((x0, y0, x1, _)) => (x0.min(start), y0, x1.max(end), y + 1),
+            None => (start, y, end, y + 1),
+        });
+    }
+    region.map(|(x0, y0, x1, y1)| (x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32))
+}
+
+// Cut a region out of a whole-canvas image.
+fn crop(header: &Header, data: &[u8], x: u32, y: u32, width: u32, height: u32) -> Vec<u8> {
+    let stride = header.stride();
+    let depth = header.depth();
+    let bits = layout::bits_per_pixel(header.color_type(), depth);
+    let (x, width) = (x as usize, width as usize);
+    let rows = data.chunks(stride).skip(y as usize).take(height as usize);
+    if bits >= 8 {
+        let bytes = bits / 8;
+        rows.flat_map(|row| &row[x * bytes .. (x + width) * bytes]).copied().collect()
+    } else {
+        // Only single-channel images go below 8 bits.
+        rows.flat_map(|row| layout::pack(&layout::unpack(row, depth, x + width)[x ..], depth)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ColorType;
+    use super::super::Header;
+    use super::super::encoder::Options;
+    use super::{AnimationEncoder, BlendOp, DisposeOp, FrameControl};
+
+    // Decoded frames of an APNG, with each frame's control and pixels.
+    fn decode_frames(png: &[u8]) -> (png::AnimationControl, Vec<(png::FrameControl, Vec<u8>)>) {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let animation = reader.info().animation_control.unwrap();
+        let mut frames = Vec::new();
+        for _ in 0 .. animation.num_frames {
+            let mut buf = vec![0u8; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buf).unwrap();
+            buf.truncate(info.buffer_size());
+            frames.push((reader.info().frame_control.unwrap(), buf));
+        }
+        (animation, frames)
+    }
+
+    fn rgba_header(width: u32, height: u32) -> Header {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        header
+    }
+
+    #[test]
+    fn frames() {
+        let header = rgba_header(200, 120);
+        let first: Vec<u8> = (0 .. 200 * 120 * 4).map(|i| (i % 251) as u8).collect();
+        let second: Vec<u8> = (0 .. 50 * 40 * 4).map(|i| (i % 7) as u8).collect();
+        let third = vec![255u8; 200 * 4];
+
+        // Streaming with small chunks splits frames over several fdATs.
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+        options.set_streaming(true).unwrap();
+        let mut encoder = AnimationEncoder::new(Vec::new(), &options);
+        encoder.write_header(&header, 3, 2).unwrap();
+        encoder.encoder().write_chunk(b"tEXt", b"Title\0Animated").unwrap();
+
+        let mut control = FrameControl::new();
+        control.set_delay(1, 10).unwrap();
+        control.set_dispose_op(DisposeOp::Background).unwrap();
+        encoder.write_frame(&control, &first).unwrap();
+
+        let mut control = FrameControl::new();
+        control.set_region(10, 20, 50, 40).unwrap();
+        control.set_delay(3, 0).unwrap();
+        control.set_dispose_op(DisposeOp::Previous).unwrap();
+        control.set_blend_op(BlendOp::Over).unwrap();
+        encoder.write_frame(&control, &second).unwrap();
+
+        let mut control = FrameControl::new();
+        control.set_region(0, 119, 200, 1).unwrap();
+        encoder.write_frame(&control, &third).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let (animation, frames) = decode_frames(&png);
+        assert_eq!((animation.num_frames, animation.num_plays), (3, 2));
+
+        let expected = [
+            ((200, 120, 0, 0), (1, 10), png::DisposeOp::Background, png::BlendOp::Source, &first),
+            ((50, 40, 10, 20), (3, 0), png::DisposeOp::Previous, png::BlendOp::Over, &second),
+            ((200, 1, 0, 119), (0, 100), png::DisposeOp::None, png::BlendOp::Source, &third),
+        ];
+        for (n, ((control, data), (region, delay, dispose, blend, pixels))) in frames.iter().zip(expected.iter()).enumerate() {
+            assert_eq!((control.width, control.height, control.x_offset, control.y_offset), *region, "frame {}", n);
+            assert_eq!((control.delay_num, control.delay_den), *delay, "frame {}", n);
+            assert_eq!(control.dispose_op, *dispose, "frame {}", n);
+            assert_eq!(control.blend_op, *blend, "frame {}", n);
+            assert_eq!(&data[..], &pixels[..], "frame {}", n);
+        }
+    }
+
+    #[test]
+    fn delta_frames() {
+        // 2-bit indexed pixels, so regions are worked out below a byte.
+        let mut header = Header::new();
+        header.set_size(37, 23).unwrap();
+        header.set_color(ColorType::IndexedColor, 2).unwrap();
+        let stride = header.stride();
+
+        let mut frames = vec![vec![0u8; stride * 23]];
+        let mut frame = frames[0].clone();
+        frame[5 * stride + 3] = 0x1b;
+        frame[9 * stride + 1] = 0x40;
+        frames.push(frame.clone());
+        frames.push(frame.clone());
+        frame[22 * stride + stride - 1] = 0xc0;
+        frames.push(frame);
+
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.set_delta_frames(true).unwrap();
+        encoder.write_header(&header, frames.len() as u32, 0).unwrap();
+        encoder.encoder().write_palette(&[0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]).unwrap();
+        let mut control = FrameControl::new();
+        control.set_delay(1, 25).unwrap();
+        control.set_dispose_op(DisposeOp::Background).unwrap();
+        for frame in frames.iter() {
+            encoder.write_frame(&control, frame).unwrap();
+        }
+        assert!(encoder.set_delta_frames(false).is_err());
+        let png = encoder.finish().unwrap();
+
+        let (_, decoded) = decode_frames(&png);
+        let regions: Vec<_> = decoded.iter().map(|(control, _)| {
+            assert_eq!(control.dispose_op, png::DisposeOp::None);
+            assert_eq!(control.blend_op, png::BlendOp::Source);
+            assert_eq!((control.delay_num, control.delay_den), (1, 25));
+            (control.x_offset, control.y_offset, control.width, control.height)
+        }).collect();
+        // Whole bytes of four pixels around the changes, a single
+        // pixel for no change, and the end of the row clipped to the
+        // image width.
+        assert_eq!(regions, [(0, 0, 37, 23), (4, 5, 12, 5), (0, 0, 1, 1), (36, 22, 1, 1)]);
+
+        // Drawing each region over the last frame gives the frames back.
+        let mut canvas = vec![0u8; 37 * 23];
+        for ((control, data), frame) in decoded.iter().zip(frames.iter()) {
+            let width = control.width as usize;
+            let unpacked = data.chunks(data.len() / control.height as usize)
+                               .flat_map(|row| (0 .. width).map(move |x| row[x / 4] >> (6 - x % 4 * 2) & 3));
+            for (i, index) in unpacked.enumerate() {
+                let (x, y) = (control.x_offset as usize + i % width, control.y_offset as usize + i / width);
+                canvas[y * 37 + x] = index;
+            }
+            let expected: Vec<u8> = frame.chunks(stride)
+                .flat_map(|row| (0 .. 37).map(move |x| row[x / 4] >> (6 - x % 4 * 2) & 3))
+                .collect();
+            assert_eq!(canvas, expected);
+        }
+    }
+
+    #[test]
+    fn frame_errors() {
+        let header = rgba_header(16, 16);
+        let frame = vec![0u8; 16 * 16 * 4];
+        let mut region = FrameControl::new();
+        region.set_region(8, 8, 8, 8).unwrap();
+        assert!(FrameControl::new().set_region(0, 0, 0, 1).is_err());
+
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        assert!(encoder.write_frame(&FrameControl::new(), &frame).is_err());
+        assert!(encoder.write_header(&header, 0, 0).is_err());
+
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header, 2, 0).unwrap();
+        // The first frame covers the canvas.
+        assert!(encoder.write_frame(&region, &frame[.. 8 * 8 * 4]).is_err());
+        assert!(encoder.write_frame(&FrameControl::new(), &frame[1 ..]).is_err());
+        encoder.write_frame(&FrameControl::new(), &frame).unwrap();
+
+        let mut outside = FrameControl::new();
+        outside.set_region(9, 8, 8, 8).unwrap();
+        assert!(encoder.write_frame(&outside, &frame[.. 8 * 8 * 4]).is_err());
+        assert!(encoder.write_frame(&region, &frame).is_err());
+        encoder.write_frame(&region, &frame[.. 8 * 8 * 4]).unwrap();
+        assert!(encoder.write_frame(&region, &frame[.. 8 * 8 * 4]).is_err());
+        encoder.finish().unwrap();
+
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header, 2, 0).unwrap();
+        encoder.write_frame(&FrameControl::new(), &frame).unwrap();
+        assert!(encoder.finish().is_err());
+
+        // Delta frames take whole-canvas images.
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.set_delta_frames(true).unwrap();
+        encoder.write_header(&header, 1, 0).unwrap();
+        assert!(encoder.write_frame(&region, &frame[.. 8 * 8 * 4]).is_err());
+
+        // Image data goes through write_frame.
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header, 1, 0).unwrap();
+        encoder.encoder().write_image_rows(&frame[.. 16 * 4]).unwrap();
+        assert!(encoder.write_frame(&FrameControl::new(), &frame).is_err());
+    }
+}
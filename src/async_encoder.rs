@@ -0,0 +1,208 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// async_encoder.rs - encoding to an async output
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Encoding to a tokio AsyncWrite output.
+//!
+//! An AsyncEncoder runs the usual encoder into memory and passes its
+//! output on to the sink as it's produced, awaiting the sink before
+//! taking more rows. Waits for the worker threads are awaited too, so
+//! the task never blocks its runtime thread.
+
+use std::io;
+use std::mem;
+use std::sync::Arc;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+
+use super::Header;
+use super::encoder::{Encoder, Options};
+use super::utils::*;
+
+/// Encodes a PNG to an AsyncWrite output.
+///
+/// Use streaming mode (Options::set_streaming) for output to start
+/// before the whole image is compressed; otherwise image data is held
+/// until the end, as with a non-seekable Encoder.
+pub struct AsyncEncoder<'a, W: AsyncWrite + Unpin> {
+    encoder: Encoder<'a, Vec<u8>>,
+    output: W,
+    job_done: Arc<Notify>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncEncoder<'a, W> {
+    /// Creates a new AsyncEncoder writing to the given output.
+    pub fn new(output: W, options: &Options<'a>) -> AsyncEncoder<'a, W> {
+        let mut encoder = Encoder::new(Vec::new(), options);
+        let job_done = Arc::new(Notify::new());
+        let notify = Arc::clone(&job_done);
+        encoder.set_job_done(Arc::new(move || notify.notify_one()));
+        AsyncEncoder {
+            encoder,
+            output,
+            job_done,
+        }
+    }
+
+    /// The underlying encoder, for writing the palette, metadata,
+    /// and other chunks; what they write goes out to the sink on the
+    /// next awaited call. Write image rows with the methods here so
+    /// as not to block.
+    pub fn encoder(&mut self) -> &mut Encoder<'a, Vec<u8>> {
+        &mut self.encoder
+    }
+
+    // Pass on everything written so far.
+    async fn write_output(&mut self) -> IoResult {
+        let data = mem::take(self.encoder.output_mut());
+        if !data.is_empty() {
+            self.output.write_all(&data).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the PNG signature and header chunk.
+    pub async fn write_header(&mut self, header: &Header) -> IoResult {
+        self.encoder.write_header(header)?;
+        self.write_output().await
+    }
+
+    /// Encode and compress the given image data, as for
+    /// Encoder::write_image_rows, writing out whatever is ready.
+    ///
+    /// Rows are taken as the worker threads keep up, so this can
+    /// wait for them as well as for the output.
+    pub async fn write_image_rows(&mut self, buf: &[u8]) -> IoResult {
+        let stride = self.encoder.input_stride();
+        if !buf.len().is_multiple_of(stride) {
+            return Err(invalid_input("Buffer must be an integral number of rows"));
+        }
+        for row in buf.chunks(stride) {
+            while self.encoder.is_saturated() {
+                self.job_done.notified().await;
+                self.encoder.poll()?;
+                self.write_output().await?;
+            }
+            self.encoder.write_image_rows(row)?;
+            self.write_output().await?;
+        }
+        Ok(())
+    }
+
+    /// Write out all image data compressed so far, waiting
+    /// for chunks in progress.
+    pub async fn flush(&mut self) -> IoResult {
+        loop {
+            self.encoder.poll()?;
+            self.write_output().await?;
+            if !self.encoder.has_pending_output() {
+                break;
+            }
+            self.job_done.notified().await;
+        }
+        self.output.flush().await
+    }
+
+    /// Finish the image and return the output.
+    ///
+    /// Returns an error if the image data isn't complete.
+    pub async fn finish(mut self) -> io::Result<W> {
+        self.flush().await?;
+        let rest = self.encoder.finish()?;
+        self.output.write_all(&rest).await?;
+        self.output.flush().await?;
+        Ok(self.output)
+    }
+
+    /// Stop encoding without finishing the image and return the
+    /// output, as for Encoder::abort. Output not yet passed on to
+    /// the sink is dropped.
+    pub fn abort(self) -> W {
+        self.encoder.abort();
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncEncoder;
+    use super::super::{ColorType, Header};
+    use super::super::decoder::decode;
+    use super::super::encoder::Options;
+
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn async_output() {
+        let width = 640;
+        let height = 480;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        for &streaming in [false, true].iter() {
+            let mut options = Options::new();
+            options.set_chunk_size(32768).unwrap();
+            options.set_streaming(streaming).unwrap();
+
+            // A duplex pipe gives backpressure from the reading end.
+            let (writer, mut reader) = tokio::io::duplex(4096);
+            let png = block_on(async {
+                let read = tokio::spawn(async move {
+                    let mut png = Vec::new();
+                    tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut png).await.map(|_| png)
+                });
+                let mut encoder = AsyncEncoder::new(writer, &options);
+                encoder.write_header(&header).await.unwrap();
+                for rows in data.chunks(width * 3 * 100) {
+                    encoder.write_image_rows(rows).await.unwrap();
+                }
+                drop(encoder.finish().await.unwrap());
+                read.await.unwrap().unwrap()
+            });
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+        }
+
+        // Usable from tasks on a multithreaded runtime.
+        fn assert_send<T: Send>(_: &T) {}
+        let options = Options::new();
+        let mut encoder = AsyncEncoder::new(Vec::new(), &options);
+        assert_send(&encoder.write_image_rows(&[]));
+
+        // Incomplete image data is an error, not a hang.
+        let result = block_on(async {
+            let mut encoder = AsyncEncoder::new(Vec::new(), &options);
+            encoder.write_header(&header).await.unwrap();
+            encoder.write_image_rows(&data[.. width * 3]).await.unwrap();
+            encoder.finish().await
+        });
+        assert!(result.is_err());
+    }
+}
@@ -24,9 +24,13 @@
 //
 
 use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, Write};
+use std::path::Path;
 
 // CLI options
 extern crate clap;
@@ -42,17 +46,92 @@ use rayon::{ThreadPool, ThreadPoolBuilder};
 extern crate time;
 use time::OffsetDateTime;
 
+// For config files
+extern crate serde;
+use serde::Deserialize;
+extern crate toml;
+
 // Hey that's us!
 extern crate mtpng;
-use mtpng::{ColorType, CompressionLevel, Header};
+use mtpng::{Backend, ColorType, CompressionLevel, Header, InterlaceMethod, Mode};
 use mtpng::Mode::{Adaptive, Fixed};
-use mtpng::encoder::{Encoder, Options};
+use mtpng::encoder::{EncodeStats, Encoder, Options, Preset};
 use mtpng::Strategy;
 use mtpng::Filter;
+use mtpng::decoder;
+use mtpng::decoder::Decoded;
+use mtpng::layout;
+use mtpng::optimize;
+use mtpng::quantize::{Dither, Quantizer};
+use mtpng::reduce;
 
 pub fn err(payload: &str) -> Error
 {
-    Error::new(ErrorKind::Other, payload)
+    Error::other(payload)
+}
+
+//
+// What went wrong, for picking an exit code that scripts can act on.
+//
+#[derive(Copy, Clone, Debug)]
+enum Failure {
+    Internal = 1,
+    Usage = 2,
+    Input = 3,
+    Output = 4,
+    Verify = 5,
+}
+
+impl Failure {
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Internal => "internal",
+            Failure::Usage    => "usage",
+            Failure::Input    => "input",
+            Failure::Output   => "output",
+            Failure::Verify   => "verify",
+        }
+    }
+}
+
+// An error tagged with the stage it came from.
+#[derive(Debug)]
+struct Tagged {
+    failure: Failure,
+    error: Error,
+}
+
+impl std::fmt::Display for Tagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Tagged {}
+
+// Tag an error with its stage, keeping any tag it already has.
+fn tag(failure: Failure) -> impl Fn(Error) -> Error {
+    move |error| {
+        if error.get_ref().is_some_and(|inner| inner.is::<Tagged>()) {
+            error
+        } else {
+            Error::new(error.kind(), Tagged { failure, error })
+        }
+    }
+}
+
+//
+// Sort errors from encoding and writing: the encoder reports bad
+// requests as InvalidInput and its own failures as Other, leaving
+// the rest to come from the output stream.
+//
+fn write_failure(error: Error) -> Error {
+    let failure = match error.kind() {
+        io::ErrorKind::InvalidInput => Failure::Usage,
+        io::ErrorKind::Other => Failure::Internal,
+        _ => Failure::Output,
+    };
+    tag(failure)(error)
 }
 
 fn expand(src: &[u8]) -> io::Result<Vec<u8>>
@@ -67,9 +146,224 @@ struct Image {
     data: Vec<u8>,
     palette: Option<Vec<u8>>,
     transparency: Option<Vec<u8>>,
+    metadata: Vec<Chunk>,
+    // Filter to use for each row, replayed from a file.
+    filters: Option<Vec<Filter>>,
+}
+
+// A raw ancillary chunk, carried over verbatim from the input file.
+struct Chunk {
+    tag: [u8; 4],
+    data: Vec<u8>,
+    // Whether it came before PLTE in the input file.
+    before_palette: bool,
+}
+
+impl Chunk {
+    // These must come before PLTE in the output file.
+    fn is_color_info(&self) -> bool {
+        self.before_palette || matches!(&self.tag, b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" |
+                                                      b"cICP" | b"mDCv" | b"cLLi")
+    }
+}
+
+// Which ancillary chunks to copy from the input file.
+#[derive(Copy, Clone, PartialEq)]
+enum Keep {
+    Nothing,
+    Metadata,
+    All,
+}
+
+// Ancillary chunks that are safe to copy into a re-encoded file,
+// as they don't depend on the pixel data layout.
+const METADATA_TAGS: [&[u8; 4]; 12] = [
+    b"tEXt", b"zTXt", b"iTXt",
+    b"gAMA", b"cHRM", b"sRGB", b"iCCP",
+    b"cICP", b"mDCv", b"cLLi",
+    b"pHYs", b"eXIf",
+];
+
+// APNG chunks, which describe frames beyond the default image we
+// re-encode. fdAT in particular can't move ahead of the image data.
+const ANIMATION_TAGS: [&[u8; 4]; 3] = [b"acTL", b"fcTL", b"fdAT"];
+
+//
+// Pull the copyable metadata chunks out of a PNG file, or every
+// ancillary chunk but tRNS and animation chunks when keeping all.
+// The png crate has already validated the file structure
+// and checksums by the time we get here.
+//
+fn read_metadata(filename: &str, keep: Keep) -> io::Result<Vec<Chunk>>
+{
+    let bytes = fs::read(filename)?;
+    let mut chunks = Vec::new();
+
+    let mut seen_palette = false;
+
+    // Skip the 8-byte signature.
+    let mut pos = 8;
+    while pos + 12 <= bytes.len() {
+        let len = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let start = pos + 8;
+        let end = start + len;
+        if end + 4 > bytes.len() {
+            return Err(err("Truncated chunk in input file"));
+        }
+
+        let tag = [bytes[pos + 4], bytes[pos + 5], bytes[pos + 6], bytes[pos + 7]];
+        let wanted = match keep {
+            Keep::All => tag[0].is_ascii_lowercase() && &tag != b"tRNS" && !ANIMATION_TAGS.contains(&&tag),
+            _ => METADATA_TAGS.contains(&&tag),
+        };
+        if &tag == b"PLTE" {
+            seen_palette = true;
+        }
+        if wanted {
+            chunks.push(Chunk {
+                tag,
+                data: bytes[start .. end].to_vec(),
+                before_palette: keep == Keep::All && !seen_palette,
+            });
+        }
+        if &tag == b"IEND" {
+            break;
+        }
+        pos = end + 4;
+    }
+    Ok(chunks)
+}
+
+// Convert a string to Latin-1 bytes, if it can be represented.
+fn latin1(s: &str) -> Option<Vec<u8>>
+{
+    s.chars().map(|c| u8::try_from(c as u32).ok()).collect()
+}
+
+//
+// Build a tEXt chunk from a key=value argument, or an iTXt chunk
+// if the value can't be represented in Latin-1. A value of @filename
+// reads the text from the given UTF-8 file.
+//
+// https://www.w3.org/TR/PNG/#11keywords
+//
+fn text_chunk(arg: &str) -> io::Result<Chunk>
+{
+    let (key, value) = match arg.find('=') {
+        Some(i) => (&arg[0 .. i], &arg[i + 1 ..]),
+        None => return Err(err("Text must be given as key=value")),
+    };
+
+    let keyword = match latin1(key) {
+        Some(k) => k,
+        None => return Err(err("Text keyword must be Latin-1")),
+    };
+    if keyword.is_empty() || keyword.len() > 79 {
+        return Err(err("Text keyword must be 1-79 bytes"));
+    }
+    if keyword.iter().any(|&c| !(32 ..= 126).contains(&c) && c < 161) {
+        return Err(err("Text keyword must be printable"));
+    }
+    if key.starts_with(' ') || key.ends_with(' ') || key.contains("  ") {
+        return Err(err("Text keyword must not have leading, trailing, or consecutive spaces"));
+    }
+
+    let text = match value.strip_prefix('@') {
+        Some(filename) => fs::read_to_string(filename)?,
+        None => value.to_string(),
+    };
+    // Null separates the fields of text chunks, so it can't be in the text.
+    if text.contains('\0') {
+        return Err(err("Text must not contain null characters"));
+    }
+
+    let mut data = keyword;
+    data.push(0);
+    match latin1(&text) {
+        Some(bytes) => {
+            data.extend_from_slice(&bytes);
+            Ok(Chunk {
+                tag: *b"tEXt",
+                data,
+                before_palette: false,
+            })
+        },
+        None => {
+            // Uncompressed, with empty language tag and translated keyword.
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(text.as_bytes());
+            Ok(Chunk {
+                tag: *b"iTXt",
+                data,
+                before_palette: false,
+            })
+        }
+    }
+}
+
+//
+// Build a custom chunk from a tag=filename argument.
+// The encoder checks the tag and chunk ordering when writing.
+//
+fn custom_chunk(arg: &str) -> io::Result<Chunk> {
+    let (tag, filename) = arg.split_once('=')
+                             .ok_or_else(|| err("Custom chunks must be given as tag=filename"))?;
+    let tag = <[u8; 4]>::try_from(tag.as_bytes())
+        .map_err(|_e| err("Chunk tags must be 4 characters"))?;
+    Ok(Chunk {
+        tag,
+        data: fs::read(filename)?,
+        before_palette: false,
+    })
+}
+
+//
+// Parse a resolution given as "N" or "XxY".
+//
+fn parse_resolution(arg: &str) -> io::Result<(f64, f64)>
+{
+    let parse = |s: &str| -> io::Result<f64> {
+        match s.parse::<f64>() {
+            Ok(n) if n > 0.0 => Ok(n),
+            _ => Err(err("Resolution must be a positive number, or XxY")),
+        }
+    };
+    match arg.find('x') {
+        Some(i) => Ok((parse(&arg[0 .. i])?, parse(&arg[i + 1 ..])?)),
+        None => {
+            let n = parse(arg)?;
+            Ok((n, n))
+        }
+    }
+}
+
+//
+// Build a pHYs chunk from a resolution in pixels per meter.
+//
+// https://www.w3.org/TR/PNG/#11pHYs
+//
+fn phys_chunk(x: f64, y: f64) -> io::Result<Chunk>
+{
+    let ppu = |n: f64| -> io::Result<u32> {
+        let n = n.round();
+        if n < 1.0 || n > f64::from(u32::MAX) {
+            Err(err("Resolution out of range"))
+        } else {
+            Ok(n as u32)
+        }
+    };
+    let mut data = Vec::with_capacity(9);
+    data.write_all(&ppu(x)?.to_be_bytes())?;
+    data.write_all(&ppu(y)?.to_be_bytes())?;
+    data.push(1); // unit is the meter
+    Ok(Chunk {
+        tag: *b"pHYs",
+        data,
+        before_palette: false,
+    })
 }
 
-fn read_png(filename: &str)
+fn read_png(filename: &str, keep: Keep)
     -> io::Result<Image>
 {
     use png::Decoder;
@@ -98,22 +392,251 @@ fn read_png(filename: &str)
     let mut data = vec![0u8; reader.output_buffer_size()];
     reader.next_frame(&mut data)?;
 
+    let metadata = match keep {
+        Keep::Nothing => Vec::new(),
+        _ => read_metadata(filename, keep)?,
+    };
+
     Ok(Image {
         header,
         data,
         palette,
-        transparency
+        transparency,
+        metadata,
+        filters: None,
     })
 }
 
-fn write_png(pool: &ThreadPool,
-             args: &ArgMatches,
-             filename: &str,
-             image: &Image)
-   -> io::Result<()>
+//
+// Reduce a truecolor image to an indexed palette.
+//
+fn quantize(image: &mut Image, colors: &str, dither: Option<&str>) -> io::Result<()>
 {
-    let writer = File::create(filename)?;
-    let mut options = Options::new();
+    let n = colors.parse::<usize>().map_err(|_e| err("Invalid palette size"))?;
+    if image.transparency.is_some() {
+        return Err(err("Cannot quantize an image with a transparent color key"));
+    }
+
+    let mut quantizer = Quantizer::new(n)?;
+    quantizer.set_dithering(match dither {
+        None => Dither::None,
+        Some("ordered") => Dither::Ordered,
+        Some(_) => Dither::FloydSteinberg,
+    });
+    let result = quantizer.quantize(&image.header, &image.data)?;
+
+    image.header = *result.header();
+    image.data = result.data().to_vec();
+    image.palette = Some(result.palette().to_vec());
+    image.transparency = result.transparency().map(|t| t.to_vec());
+    Ok(())
+}
+
+//
+// Build an sPLT chunk suggesting a palette for a truecolor image.
+//
+fn suggested_palette(image: &Image, colors: &str) -> io::Result<Chunk>
+{
+    let n = colors.parse::<usize>().map_err(|_e| err("Invalid palette size"))?;
+    let data = Quantizer::new(n)?.suggest_palette(&image.header, &image.data, "mtpng")?;
+    Ok(Chunk {
+        tag: *b"sPLT",
+        data,
+        before_palette: false,
+    })
+}
+
+//
+// Losslessly convert to a smaller color type if possible,
+// and report what was done.
+//
+fn reduce(image: &mut Image) -> io::Result<()>
+{
+    let result = reduce::reduce(&image.header,
+                                &image.data,
+                                image.palette.as_deref(),
+                                image.transparency.as_deref())?;
+
+    if result.reductions().is_empty() {
+        eprintln!("Reduced: no reduction possible");
+    } else {
+        let list: Vec<String> = result.reductions().iter().map(|r| r.to_string()).collect();
+        eprintln!("Reduced: {}", list.join(", "));
+    }
+
+    image.header = *result.header();
+    image.data = result.data().to_vec();
+    image.palette = result.palette().map(|p| p.to_vec());
+    image.transparency = result.transparency().map(|t| t.to_vec());
+    Ok(())
+}
+
+//
+// Write to a temporary file next to the destination and rename it into
+// place on success, so a failed run never truncates an existing file --
+// including the input, when rewriting in place.
+//
+fn write_atomic<T, F>(filename: &str, write: F) -> io::Result<T>
+    where F: FnOnce(File) -> io::Result<T>
+{
+    let path = Path::new(filename);
+    let name = path.file_name().ok_or_else(|| err("Invalid output filename"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = path.with_file_name(temp_name);
+
+    let result = File::create(&temp).and_then(|file| {
+        let value = write(file.try_clone()?)?;
+        file.sync_all()?;
+        Ok(value)
+    }).and_then(|value| {
+        fs::rename(&temp, path)?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+const FILTER_NAMES: [(&str, Mode<Filter>); 6] = [
+    ("adaptive", Adaptive),
+    ("none", Fixed(Filter::None)),
+    ("sub", Fixed(Filter::Sub)),
+    ("up", Fixed(Filter::Up)),
+    ("average", Fixed(Filter::Average)),
+    ("paeth", Fixed(Filter::Paeth)),
+];
+
+const STRATEGY_NAMES: [(&str, Mode<Strategy>); 6] = [
+    ("auto", Adaptive),
+    ("default", Fixed(Strategy::Default)),
+    ("filtered", Fixed(Strategy::Filtered)),
+    ("huffman", Fixed(Strategy::HuffmanOnly)),
+    ("rle", Fixed(Strategy::Rle)),
+    ("fixed", Fixed(Strategy::Fixed)),
+];
+
+const LEVEL_NAMES: [(&str, CompressionLevel); 11] = [
+    ("1", CompressionLevel::Fast),
+    ("2", CompressionLevel::Specific(2)),
+    ("3", CompressionLevel::Specific(3)),
+    ("4", CompressionLevel::Specific(4)),
+    ("5", CompressionLevel::Specific(5)),
+    ("6", CompressionLevel::Default),
+    ("7", CompressionLevel::Specific(7)),
+    ("8", CompressionLevel::Specific(8)),
+    ("9", CompressionLevel::High),
+    ("default", CompressionLevel::Default),
+    ("max", CompressionLevel::Max),
+];
+
+fn parse_filter(s: &str) -> io::Result<Mode<Filter>> {
+    FILTER_NAMES.iter()
+                .find(|(name, _)| *name == s)
+                .map(|(_, mode)| *mode)
+                .ok_or_else(|| err("Unsupported filter type"))
+}
+
+fn parse_strategy(s: &str) -> io::Result<Mode<Strategy>> {
+    STRATEGY_NAMES.iter()
+                  .find(|(name, _)| *name == s)
+                  .map(|(_, mode)| *mode)
+                  .ok_or_else(|| err("Invalid compression strategy mode"))
+}
+
+fn parse_level(s: &str) -> io::Result<CompressionLevel> {
+    LEVEL_NAMES.iter()
+               .find(|(name, _)| *name == s)
+               .map(|(_, level)| *level)
+               .ok_or_else(|| err("Unsupported compression level (try 1 through 9, default, or max)"))
+}
+
+fn parse_backend(s: &str) -> io::Result<Backend> {
+    Backend::available().into_iter()
+                        .find(|backend| backend.name() == s)
+                        .ok_or_else(|| err("Deflate backend not compiled in"))
+}
+
+fn filter_name(mode: Mode<Filter>) -> &'static str {
+    let key = |m: Mode<Filter>| match m {
+        Adaptive => None,
+        Fixed(f) => Some(f as u8),
+    };
+    FILTER_NAMES.iter().find(|(_, m)| key(*m) == key(mode)).unwrap().0
+}
+
+fn strategy_name(mode: Mode<Strategy>) -> &'static str {
+    let key = |m: Mode<Strategy>| match m {
+        Adaptive => None,
+        Fixed(s) => Some(s as i32),
+    };
+    STRATEGY_NAMES.iter().find(|(_, m)| key(*m) == key(mode)).unwrap().0
+}
+
+fn level_name(level: CompressionLevel) -> &'static str {
+    LEVEL_NAMES.iter().find(|(_, l)| l.level() == level.level()).unwrap().0
+}
+
+//
+// Read a list of per-row filters, one per line, by name or by
+// type number. Blank lines and lines starting with # are skipped.
+//
+fn read_filters(filename: &str) -> io::Result<Vec<Filter>>
+{
+    let source = fs::read_to_string(filename)?;
+    let mut filters = Vec::new();
+    for (n, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let filter = FILTERS.iter()
+                            .find(|(name, _)| *name == line)
+                            .map(|(_, filter)| *filter)
+                            .or_else(|| line.parse::<u8>().ok().and_then(|n| Filter::try_from(n).ok()))
+                            .ok_or_else(|| err(&format!("{}:{}: unknown filter {}", filename, n + 1, line)))?;
+        filters.push(filter);
+    }
+    Ok(filters)
+}
+
+//
+// Write the filter used on each row, one name per line, in the
+// format read_filters takes.
+//
+fn write_filters(filename: &str, filters: &[Filter]) -> io::Result<()>
+{
+    let mut out = String::new();
+    for filter in filters.iter() {
+        let name = FILTERS.iter().find(|(_, f)| f == filter).unwrap().0;
+        out.push_str(name);
+        out.push('\n');
+    }
+    write_atomic(filename, |mut writer| writer.write_all(out.as_bytes()))
+}
+
+//
+// Set up encoder options from the command line. The image is needed
+// for the auto preset; without one, it's only checked.
+//
+fn encoder_options<'a>(pool: &'a ThreadPool, args: &ArgMatches, image: Option<&Image>)
+   -> io::Result<Options<'a>>
+{
+    // Presets go first, so individual options can override them.
+    let mut options = match args.value_of("preset") {
+        None | Some("default") => Options::preset(Preset::Balanced),
+        Some("fast") => Options::preset(Preset::Fastest),
+        Some("max") => Options::preset(Preset::Smallest),
+        Some("screenshot") => Options::preset(Preset::Screenshot),
+        Some("photo") => Options::preset(Preset::Photo),
+        Some("auto") => match image {
+            Some(image) => Options::auto(&image.header, &image.data)?,
+            None => Options::new(),
+        },
+        _ => return Err(err("Unsupported preset (try fast, default, max, screenshot, photo, or auto)")),
+    };
 
     // Encoding options
     options.set_thread_pool(pool)?;
@@ -126,34 +649,30 @@ fn write_png(pool: &ThreadPool,
         },
     }
 
-    match args.value_of("filter") {
-        None             => {},
-        Some("adaptive") => options.set_filter_mode(Adaptive)?,
-        Some("none")     => options.set_filter_mode(Fixed(Filter::None))?,
-        Some("up")       => options.set_filter_mode(Fixed(Filter::Up))?,
-        Some("sub")      => options.set_filter_mode(Fixed(Filter::Sub))?,
-        Some("average")  => options.set_filter_mode(Fixed(Filter::Average))?,
-        Some("paeth")    => options.set_filter_mode(Fixed(Filter::Paeth))?,
-        _                => return Err(err("Unsupported filter type")),
+    if let Some(s) = args.value_of("chunk-rows") {
+        let rows = s.parse::<usize>().map_err(|_e| err("Invalid chunk row count"))?;
+        options.set_chunk_rows(rows)?;
+    }
+
+    if let Some(s) = args.value_of("filter") {
+        options.set_filter_mode(parse_filter(s)?)?;
+    }
+
+    if let Some(s) = args.value_of("filter-lock-in") {
+        let rows = s.parse::<usize>().map_err(|_e| err("Invalid filter lock-in row count"))?;
+        options.set_filter_lock_in(rows)?;
     }
 
-    match args.value_of("level") {
-        None            => {},
-        Some("default") => options.set_compression_level(CompressionLevel::Default)?,
-        Some("1")       => options.set_compression_level(CompressionLevel::Fast)?,
-        Some("9")       => options.set_compression_level(CompressionLevel::High)?,
-        _               => return Err(err("Unsupported compression level (try default, 1, or 9)")),
+    if let Some(s) = args.value_of("level") {
+        options.set_compression_level(parse_level(s)?)?;
     }
 
-    match args.value_of("strategy") {
-        None             => {},
-        Some("auto")     => options.set_strategy_mode(Adaptive)?,
-        Some("default")  => options.set_strategy_mode(Fixed(Strategy::Default))?,
-        Some("filtered") => options.set_strategy_mode(Fixed(Strategy::Filtered))?,
-        Some("huffman")  => options.set_strategy_mode(Fixed(Strategy::HuffmanOnly))?,
-        Some("rle")      => options.set_strategy_mode(Fixed(Strategy::Rle))?,
-        Some("fixed")    => options.set_strategy_mode(Fixed(Strategy::Fixed))?,
-        _                => return Err(err("Invalid compression strategy mode"))?,
+    if let Some(s) = args.value_of("strategy") {
+        options.set_strategy_mode(parse_strategy(s)?)?;
+    }
+
+    if let Some(s) = args.value_of("backend") {
+        options.set_backend(parse_backend(s)?)?;
     }
 
     match args.value_of("streaming") {
@@ -163,27 +682,599 @@ fn write_png(pool: &ThreadPool,
         _           => return Err(err("Invalid streaming mode, try yes or no."))
     }
 
-    let mut encoder = Encoder::new(writer, &options);
+    options.set_validation(args.is_present("validate"))?;
+    options.set_palette_dedup(args.is_present("dedup-palette"))?;
+
+    Ok(options)
+}
 
-    // Image data
+fn write_image<W: Write>(encoder: &mut Encoder<W>, image: &Image) -> io::Result<()>
+{
     encoder.write_header(&image.header)?;
-    match &image.palette {
-        Some(v) => encoder.write_palette(v)?,
-        None => {},
+    for chunk in image.metadata.iter().filter(|c| c.is_color_info()) {
+        encoder.write_chunk(&chunk.tag, &chunk.data)?;
+    }
+    if let Some(v) = &image.palette {
+        encoder.write_palette(v)?;
     }
-    match &image.transparency {
-        Some(v) => encoder.write_transparency(v)?,
-        None => {},
+    if let Some(v) = &image.transparency {
+        encoder.write_transparency(v)?;
     }
-    encoder.write_image_rows(&image.data)?;
-    encoder.finish()?;
+    for chunk in image.metadata.iter().filter(|c| !c.is_color_info()) {
+        encoder.write_chunk(&chunk.tag, &chunk.data)?;
+    }
+    if let Some(filters) = &image.filters {
+        encoder.set_row_filters(filters)?;
+    }
+    encoder.write_image_borrowed(&image.data)?;
+    Ok(())
+}
+
+//
+// Try every combination of the filter, strategy, and level options that
+// weren't pinned on the command line, and keep the smallest file.
+//
+fn optimize_png(options: &Options,
+                args: &ArgMatches,
+                filename: Option<&str>,
+                image: &Image)
+   -> io::Result<Encoded>
+{
+    let filters = match args.value_of("filter") {
+        Some(s) => vec![parse_filter(s)?],
+        None => FILTER_NAMES.iter().map(|(_, mode)| *mode).collect(),
+    };
+    let strategies = match args.value_of("strategy") {
+        Some(s) => vec![parse_strategy(s)?],
+        // Auto picks one of these anyway, and fixed Huffman rarely wins.
+        None => STRATEGY_NAMES.iter()
+                              .map(|(_, mode)| *mode)
+                              .filter(|mode| !matches!(mode, Adaptive | Fixed(Strategy::Fixed)))
+                              .collect(),
+    };
+    let levels = match args.value_of("level") {
+        Some(s) => vec![parse_level(s)?],
+        None => vec![CompressionLevel::Default, CompressionLevel::High],
+    };
+
+    let trials = optimize::trials(&filters, &strategies, &levels);
+    let result = optimize::optimize(options, &trials, |encoder| write_image(encoder, image))?;
 
+    let trial = result.trial();
+    eprintln!("Optimized: filter {}, strategy {}, level {} (best of {} trials)",
+              filter_name(trial.filter_mode()),
+              strategy_name(trial.strategy_mode()),
+              level_name(trial.compression_level()),
+              result.trials_run());
+
+    if let Some(filename) = filename {
+        write_atomic(filename, |mut writer| writer.write_all(result.data()))?;
+    }
+    Ok(Encoded {
+        stats: result.stats(),
+        bytes: result.data().len() as u64,
+        filters: Vec::new(),
+    })
+}
+
+// Result of encoding one file.
+struct Encoded {
+    stats: EncodeStats,
+    bytes: u64,
+    // Filter used on each row, if known.
+    filters: Vec<Filter>,
+}
+
+//
+// Feed the image to the encoder and finish it off. The output size
+// comes from the encoder's count, which dry runs into io::sink()
+// use for the would-be file size.
+//
+fn encode_png<W: Write>(mut encoder: Encoder<W>, image: &Image)
+   -> io::Result<Encoded>
+{
+    write_image(&mut encoder, image)?;
+    encoder.flush()?;
+    let filters = encoder.row_filters().to_vec();
+    let (_writer, stats) = encoder.finish_with_stats()?;
+    Ok(Encoded {
+        bytes: stats.output_bytes(),
+        stats,
+        filters,
+    })
+}
+
+//
+// Encode the image into a null sink once with each compiled-in
+// deflate backend, and print a size and time table.
+//
+fn compare_backends(pool: &ThreadPool, args: &ArgMatches, image: &Image) -> io::Result<()>
+{
+    let mut options = encoder_options(pool, args, Some(image))?;
+    println!("{:<10} {:>12} {:>10}", "backend", "bytes", "ms");
+    for backend in Backend::available() {
+        options.set_backend(backend)?;
+        let start_time = OffsetDateTime::now_utc();
+        let encoded = encode_png(Encoder::new(io::sink(), &options), image)?;
+        let delta = OffsetDateTime::now_utc() - start_time;
+        println!("{:<10} {:>12} {:>10}", backend.name(), encoded.bytes, ms(delta));
+    }
+    Ok(())
+}
+
+//
+// Encode the image to the given file, or to nowhere for a dry run,
+// returning the encoder stats and the output size in bytes.
+//
+fn write_png(pool: &ThreadPool,
+             args: &ArgMatches,
+             filename: Option<&str>,
+             image: &Image)
+   -> io::Result<Encoded>
+{
+    let options = encoder_options(pool, args, Some(image))?;
+    if args.is_present("optimize") {
+        return optimize_png(&options, args, filename, image);
+    }
+
+    // Files can seek, so image data can go straight out to disk
+    // as it's compressed instead of waiting in memory for the end.
+    match filename {
+        Some(filename) => write_atomic(filename, |writer| {
+            encode_png(Encoder::new_seekable(writer, &options), image)
+        }),
+        None => encode_png(Encoder::new(io::sink(), &options), image),
+    }
+}
+
+//
+// Decode a written file and check it matches the image we encoded.
+//
+//
+// The color of each pixel of an indexed image as RGBA, for comparing
+// images whose palettes were rearranged.
+//
+fn palette_colors(image: &Image) -> Vec<[u8; 4]>
+{
+    let palette = image.palette.as_deref().unwrap_or(&[]);
+    let transparency = image.transparency.as_deref().unwrap_or(&[]);
+    let width = image.header.width() as usize;
+    let depth = image.header.depth();
+    image.data.chunks(image.header.stride())
+        .flat_map(|row| layout::unpack(row, depth, width))
+        .map(|index| {
+            let i = index as usize;
+            let rgb = palette.get(i * 3 .. i * 3 + 3).unwrap_or(&[0, 0, 0]);
+            [rgb[0], rgb[1], rgb[2], transparency.get(i).copied().unwrap_or(255)]
+        })
+        .collect()
+}
+
+//
+// Check the written file decodes to the input image. With the palette
+// deduplicated, indexed images are compared by the colors of their
+// pixels, as their palette entries and indices change.
+//
+fn verify(filename: &str, image: &Image, dedup_palette: bool) -> io::Result<()>
+{
+    let written = read_png(filename, Keep::Nothing)?;
+    let a = &image.header;
+    let b = &written.header;
+    if a.width() != b.width() || a.height() != b.height() {
+        return Err(err("Verification failed: image size differs"));
+    }
+    if a.color_type() as u8 != b.color_type() as u8 || a.depth() != b.depth() {
+        return Err(err("Verification failed: color type or depth differs"));
+    }
+    if dedup_palette && a.color_type() as u8 == ColorType::IndexedColor as u8 {
+        if palette_colors(image) != palette_colors(&written) {
+            return Err(err("Verification failed: pixel colors differ"));
+        }
+        return Ok(());
+    }
+    if image.palette != written.palette {
+        return Err(err("Verification failed: palette differs"));
+    }
+    if image.transparency != written.transparency {
+        return Err(err("Verification failed: transparency differs"));
+    }
+    if image.data != written.data {
+        return Err(err("Verification failed: pixel data differs"));
+    }
+    Ok(())
+}
+
+fn ms(delta: time::Duration) -> f64
+{
+    (delta.as_seconds_f64() * 1000.0).round()
+}
+
+// Quote a string for JSON output.
+fn json_string(s: &str) -> String
+{
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Timing for each stage of a run, in milliseconds.
+struct Timings {
+    read: f64,
+    prepare: f64,
+    encode: f64,
+}
+
+const FILTERS: [(&str, Filter); 5] = [
+    ("none", Filter::None),
+    ("sub", Filter::Sub),
+    ("up", Filter::Up),
+    ("average", Filter::Average),
+    ("paeth", Filter::Paeth),
+];
+
+fn print_stats(format: &str,
+               infile: &str,
+               outfile: Option<&str>,
+               threads: usize,
+               image: &Image,
+               timings: &Timings,
+               encoded: &Encoded)
+    -> io::Result<()>
+{
+    let stats = &encoded.stats;
+    let input_bytes = image.data.len() as u64;
+    let output_bytes = encoded.bytes;
+    let ratio = output_bytes as f64 / input_bytes as f64;
+    let times = stats.times();
+    let stages = [
+        ("pixel_copy", times.pixel_copy()),
+        ("filter", times.filter()),
+        ("deflate", times.deflate()),
+        ("checksum", times.checksum()),
+        ("write", times.write()),
+    ];
+
+    match format {
+        "json" => {
+            let filters: Vec<String> = FILTERS.iter()
+                .map(|(name, filter)| format!("\"{}\":{}", name, stats.filter_rows(*filter)))
+                .collect();
+            let stages: Vec<String> = stages.iter()
+                .map(|(name, time)| format!("\"{}\":{:.3}", name, time.as_secs_f64() * 1000.0))
+                .collect();
+            println!("{{\"input\":{},\"output\":{},\"threads\":{},\"chunks\":{},\
+                      \"time_ms\":{{\"read\":{},\"prepare\":{},\"encode\":{}}},\
+                      \"stage_ms\":{{{}}},\
+                      \"filters\":{{{}}},\
+                      \"input_bytes\":{},\"filtered_bytes\":{},\"compressed_bytes\":{},\"output_bytes\":{},\
+                      \"ratio\":{:.6}}}",
+                     json_string(infile), outfile.map_or("null".to_string(), json_string), threads, stats.chunks(),
+                     timings.read, timings.prepare, timings.encode,
+                     stages.join(","),
+                     filters.join(","),
+                     input_bytes, stats.filtered_bytes(), stats.compressed_bytes(), output_bytes,
+                     ratio);
+        },
+        _ => {
+            let filters: Vec<String> = FILTERS.iter()
+                .map(|(name, filter)| format!("{} {}", name, stats.filter_rows(*filter)))
+                .collect();
+            println!("Done in {} ms (read {} ms, prepare {} ms)",
+                     timings.encode, timings.read, timings.prepare);
+            println!("  chunks: {} on {} threads", stats.chunks(), threads);
+            let stages: Vec<String> = stages.iter()
+                .map(|(name, time)| format!("{} {:.1} ms", name.replace('_', " "), time.as_secs_f64() * 1000.0))
+                .collect();
+            println!("  stages: {}", stages.join(", "));
+            println!("  filters: {}", filters.join(", "));
+            println!("  input: {} bytes, filtered: {} bytes, compressed: {} bytes, output: {} bytes",
+                     input_bytes, stats.filtered_bytes(), stats.compressed_bytes(), output_bytes);
+            println!("  ratio: {:.2}%", ratio * 100.0);
+        },
+    }
+    Ok(())
+}
+
+//
+// Print input and output file sizes, the percent change, and output
+// bits per pixel, over the given number of conversions of the image.
+//
+fn print_report(label: &str, runs: u64, input_bytes: u64, output_bytes: u64, header: &Header)
+{
+    let change = (output_bytes as f64 - input_bytes as f64) * 100.0 / input_bytes as f64;
+    let pixels = header.width() as f64 * header.height() as f64 * runs as f64;
+    println!("  {}: {} -> {} bytes ({:+.2}%), {:.3} bits per pixel",
+             label, input_bytes, output_bytes, change, output_bytes as f64 * 8.0 / pixels);
+}
+
+const DEFAULT_CONFIG: &str = "mtpng.toml";
+
+// Value for an option that takes one, which TOML may give as a
+// string or an integer.
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "a string or integer")]
+enum ConfigValue {
+    Integer(i64),
+    String(String),
+}
+
+// Value for an option whose value is optional, like --stats.
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "true, false, or a string")]
+enum ConfigFlag {
+    Bool(bool),
+    Value(String),
+}
+
+// How a config value turns back into command-line arguments.
+trait ConfigArg {
+    fn push_args(&self, name: &str, out: &mut Vec<OsString>);
+}
+
+impl ConfigArg for bool {
+    fn push_args(&self, name: &str, out: &mut Vec<OsString>) {
+        if *self {
+            out.push(OsString::from(format!("--{}", name)));
+        }
+    }
+}
+
+impl ConfigArg for ConfigValue {
+    fn push_args(&self, name: &str, out: &mut Vec<OsString>) {
+        let value = match self {
+            ConfigValue::Integer(n) => n.to_string(),
+            ConfigValue::String(s) => s.clone(),
+        };
+        out.push(OsString::from(format!("--{}={}", name, value)));
+    }
+}
+
+impl ConfigArg for ConfigFlag {
+    fn push_args(&self, name: &str, out: &mut Vec<OsString>) {
+        match self {
+            ConfigFlag::Bool(flag) => flag.push_args(name, out),
+            ConfigFlag::Value(s) => out.push(OsString::from(format!("--{}={}", name, s))),
+        }
+    }
+}
+
+impl ConfigArg for Vec<String> {
+    fn push_args(&self, name: &str, out: &mut Vec<OsString>) {
+        for s in self {
+            out.push(OsString::from(format!("--{}={}", name, s)));
+        }
+    }
+}
+
+// Declare the Config struct and its conversion to arguments from
+// one list of fields, named as the options with - spelled _.
+macro_rules! config {
+    ($($field:ident: $type:ty,)*) => {
+        #[derive(Debug, Deserialize)]
+        #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+        struct Config {
+            $($field: Option<$type>,)*
+        }
+
+        impl Config {
+            // The equivalent command-line arguments. Options already
+            // given on the command line take precedence and are skipped.
+            fn args(&self, matches: &ArgMatches) -> Vec<OsString> {
+                let mut out = Vec::new();
+                $(
+                    if let Some(value) = &self.$field {
+                        let name = stringify!($field).replace('_', "-");
+                        if matches.occurrences_of(name.as_str()) == 0 {
+                            value.push_args(&name, &mut out);
+                        }
+                    }
+                )*
+                out
+            }
+        }
+    }
+}
+
+//
+// Config files are TOML, with a top-level key for each long option
+// other than --config and the input and output files: true or false
+// for options that take no value, a string or integer for those that
+// do, and an array of strings for --text and --chunk. Unknown keys
+// and values of the wrong type are errors.
+//
+config! {
+    chunk_size: ConfigValue,
+    chunk_rows: ConfigValue,
+    preset: ConfigValue,
+    filter: ConfigValue,
+    filter_lock_in: ConfigValue,
+    dump_filters: ConfigValue,
+    filters_from: ConfigValue,
+    interlace: bool,
+    level: ConfigValue,
+    strategy: ConfigValue,
+    backend: ConfigValue,
+    streaming: ConfigValue,
+    threads: ConfigValue,
+    repeat: ConfigValue,
+    optimize: bool,
+    strip: bool,
+    keep_metadata: bool,
+    keep_chunks: bool,
+    text: Vec<String>,
+    chunk: Vec<String>,
+    dpi: ConfigValue,
+    ppm: ConfigValue,
+    quantize: ConfigValue,
+    dither: ConfigFlag,
+    suggest_palette: ConfigValue,
+    dedup_palette: bool,
+    reduce: bool,
+    stats: ConfigFlag,
+    report: bool,
+    verify: bool,
+    validate: bool,
+    decode: bool,
+    compare_backends: bool,
+    dry_run: bool,
+    errors: ConfigValue,
+}
+
+// Read a config file and return the equivalent command-line arguments.
+fn config_args(path: &str, matches: &ArgMatches) -> io::Result<Vec<OsString>>
+{
+    let source = fs::read_to_string(path)
+        .map_err(|e| err(&format!("Cannot read config file {}: {}", path, e)))?;
+    let config: Config = toml::from_str(&source)
+        .map_err(|e| err(&format!("Invalid config file {}: {}", path, e.to_string().trim())))?;
+    Ok(config.args(matches))
+}
+
+// Logical CPUs available to this process.
+fn logical_cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+//
+// Count physical cores as distinct (package, core) pairs in the Linux
+// sysfs CPU topology. Returns None where that isn't available.
+//
+fn physical_cores() -> Option<usize> {
+    let mut cores = std::collections::HashSet::new();
+    for entry in fs::read_dir("/sys/devices/system/cpu").ok()? {
+        let path = entry.ok()?.path();
+        let is_cpu = path.file_name()
+                         .and_then(|name| name.to_str())
+                         .and_then(|name| name.strip_prefix("cpu"))
+                         .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        if !is_cpu {
+            continue;
+        }
+        let read = |name: &str| fs::read_to_string(path.join("topology").join(name))
+                                    .ok()
+                                    .map(|s| s.trim().to_string());
+        // Offline CPUs have no topology.
+        if let (Some(package), Some(core)) = (read("physical_package_id"), read("core_id")) {
+            cores.insert((package, core));
+        }
+    }
+    if cores.is_empty() {
+        None
+    } else {
+        Some(cores.len())
+    }
+}
+
+//
+// Unpack a decoded image into whole samples per pixel, expanding
+// palette entries to RGB or RGBA. Returns channel count, max value,
+// and the samples, one or two big-endian bytes each.
+//
+fn unpack_samples(decoded: &Decoded) -> (usize, u32, Vec<u8>)
+{
+    let header = decoded.header();
+    let depth = header.depth() as usize;
+    let channels = header.color_type().channels();
+    let width = header.width() as usize;
+
+    let mut samples = Vec::new();
+    for row in decoded.data().chunks_exact(header.stride()) {
+        samples.extend(layout::unpack(row, header.depth(), width * channels));
+    }
+
+    match (header.color_type(), decoded.palette()) {
+        (ColorType::IndexedColor, Some(palette)) => {
+            let alpha = decoded.transparency();
+            let out_channels = if alpha.is_some() { 4 } else { 3 };
+            let mut out = Vec::with_capacity(samples.len() * out_channels);
+            for &index in samples.iter() {
+                let i = index as usize;
+                out.extend_from_slice(palette.get(i * 3 .. i * 3 + 3).unwrap_or(&[0, 0, 0]));
+                if let Some(alpha) = alpha {
+                    out.push(*alpha.get(i).unwrap_or(&255));
+                }
+            }
+            (out_channels, 255, out)
+        },
+        _ => (channels, (1u32 << depth) - 1, samples),
+    }
+}
+
+//
+// Decode a PNG to raw packed rows, or to a Netpbm format
+// picked by the output extension.
+//
+fn decode_file(infile: &str, outfile: &str) -> io::Result<()>
+{
+    let decoded = File::open(infile).and_then(|file| decoder::decode(io::BufReader::new(file)))
+                                    .map_err(tag(Failure::Input))?;
+    let header = decoded.header();
+    let extension = Path::new(outfile).extension()
+                                      .and_then(|ext| ext.to_str())
+                                      .map(|ext| ext.to_ascii_lowercase());
+
+    let mut out = Vec::new();
+    match extension.as_deref() {
+        Some("raw") => {
+            out.extend_from_slice(decoded.data());
+        },
+        Some(format @ "pgm") | Some(format @ "ppm") | Some(format @ "pam") => {
+            let (channels, maxval, mut samples) = unpack_samples(&decoded);
+            let (width, height) = (header.width(), header.height());
+            match (format, channels) {
+                ("pgm", 1) => {
+                    write!(out, "P5\n{} {}\n{}\n", width, height, maxval)?;
+                },
+                ("ppm", 1) | ("ppm", 3) => {
+                    if channels == 1 {
+                        let bytes = if maxval > 255 { 2 } else { 1 };
+                        samples = samples.chunks_exact(bytes)
+                                         .flat_map(|s| s.repeat(3))
+                                         .collect();
+                    }
+                    write!(out, "P6\n{} {}\n{}\n", width, height, maxval)?;
+                },
+                ("pam", _) => {
+                    let tuple_type = ["GRAYSCALE", "GRAYSCALE_ALPHA", "RGB", "RGB_ALPHA"][channels - 1];
+                    write!(out, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                           width, height, channels, maxval, tuple_type)?;
+                },
+                _ => return Err(err("Image does not fit this Netpbm format; try .pam")),
+            }
+            out.extend_from_slice(&samples);
+        },
+        _ => return Err(err("Unsupported decode output; use .raw, .pgm, .ppm, or .pam")),
+    }
+
+    write_atomic(outfile, |mut writer| writer.write_all(&out)).map_err(tag(Failure::Output))?;
+    eprintln!("Decoded {}x{}, color type {}, depth {}{}",
+              header.width(), header.height(), header.color_type() as u8, header.depth(),
+              if decoded.is_interlaced() { ", interlaced" } else { "" });
     Ok(())
 }
 
 fn doit(args: ArgMatches) -> io::Result<()> {
+    if args.is_present("decode") {
+        return decode_file(args.value_of("input").unwrap(),
+                           args.value_of("output").ok_or_else(|| err("Decoding needs an output file"))?);
+    }
+
     let threads = match args.value_of("threads") {
-        None    => 0, // Means default
+        None             => 0, // Means default
+        Some("logical")  => logical_cores(),
+        Some("physical") => {
+            // Hyperthreads rarely help deflate; fall back if unknown.
+            let logical = logical_cores();
+            physical_cores().map_or(logical, |n| n.min(logical))
+        },
         Some(s) => {
             s.parse::<usize>().map_err(|_e| err("invalid threads"))?
         },
@@ -201,46 +1292,210 @@ fn doit(args: ArgMatches) -> io::Result<()> {
         None => 1,
     };
 
-    // input and output are guaranteed to be present
+    // input is guaranteed to be present, and output unless a dry run
     let infile = args.value_of("input").unwrap();
-    let outfile = args.value_of("output").unwrap();
+    let outfile = if args.is_present("dry-run") || args.is_present("compare-backends") {
+        None
+    } else {
+        args.value_of("output")
+    };
+
+    let stats_format = match args.value_of("stats") {
+        None => None,
+        Some(format @ "text") | Some(format @ "json") => Some(format),
+        _ => return Err(err("Invalid stats format, try text or json.")),
+    };
+
+    // Check encoding options up front, so mistakes count as usage errors.
+    encoder_options(&pool, &args, None)?;
+
+    // Keep stdout clean for machine-readable output.
+    if stats_format != Some("json") {
+        println!("{} -> {}", infile, outfile.unwrap_or("(dry run)"));
+    }
+
+    // Taken up front, as an in-place rewrite replaces the input.
+    let input_size = fs::metadata(infile).map_err(tag(Failure::Input))?.len();
+
+    let read_time = OffsetDateTime::now_utc();
+    let keep = if args.is_present("keep-chunks") {
+        Keep::All
+    } else if args.is_present("keep-metadata") {
+        Keep::Metadata
+    } else {
+        Keep::Nothing
+    };
+    let mut image = read_png(infile, keep).map_err(tag(Failure::Input))?;
+    let prepare_time = OffsetDateTime::now_utc();
+    if let Some(colors) = args.value_of("suggest-palette") {
+        // Taken from the original colors, before any reduction.
+        image.metadata.push(suggested_palette(&image, colors)?);
+    }
+    if let Some(colors) = args.value_of("quantize") {
+        quantize(&mut image, colors, args.value_of("dither"))?;
+    }
+    if args.is_present("reduce") {
+        reduce(&mut image)?;
+    }
+    if args.is_present("interlace") {
+        image.header.set_interlace_method(InterlaceMethod::Adam7)?;
+    }
 
-    println!("{} -> {}", infile, outfile);
-    let image = read_png(infile)?;
+    let resolution = match (args.value_of("dpi"), args.value_of("ppm")) {
+        (Some(dpi), _) => {
+            let (x, y) = parse_resolution(dpi)?;
+            Some(phys_chunk(x / 0.0254, y / 0.0254)?)
+        },
+        (None, Some(ppm)) => {
+            let (x, y) = parse_resolution(ppm)?;
+            Some(phys_chunk(x, y)?)
+        },
+        (None, None) => None,
+    };
+    if let Some(chunk) = resolution {
+        // Replaces any resolution copied from the source file.
+        image.metadata.retain(|c| &c.tag != b"pHYs");
+        image.metadata.push(chunk);
+    }
+    if let Some(texts) = args.values_of("text") {
+        for text in texts {
+            image.metadata.push(text_chunk(text)?);
+        }
+    }
+    if let Some(chunks) = args.values_of("chunk") {
+        for chunk in chunks {
+            image.metadata.push(custom_chunk(chunk)?);
+        }
+    }
+    if args.is_present("strip") {
+        // Nothing but critical chunks and tRNS, whatever else is set up.
+        image.metadata.clear();
+    }
+    if let Some(filename) = args.value_of("filters-from") {
+        image.filters = Some(read_filters(filename).map_err(tag(Failure::Input))?);
+    }
+
+    let start_time = OffsetDateTime::now_utc();
+    let timings = Timings {
+        read: ms(prepare_time - read_time),
+        prepare: ms(start_time - prepare_time),
+        encode: 0.0,
+    };
+
+    if args.is_present("compare-backends") {
+        for _i in 0 .. reps {
+            compare_backends(&pool, &args, &image).map_err(write_failure)?;
+        }
+        return Ok(());
+    }
+
+    let report = args.is_present("report") && stats_format != Some("json");
+    let mut total_output = 0;
 
     for _i in 0 .. reps {
         let start_time = OffsetDateTime::now_utc();
-        write_png(&pool, &args, outfile, &image)?;
+        let encoded = write_png(&pool, &args, outfile, &image).map_err(write_failure)?;
         let delta = OffsetDateTime::now_utc() - start_time;
 
-        println!("Done in {} ms", (delta.as_seconds_f64() * 1000.0).round());
+        if let (true, Some(outfile)) = (args.is_present("verify"), outfile) {
+            verify(outfile, &image, args.is_present("dedup-palette")).map_err(tag(Failure::Verify))?;
+        }
+        if let Some(filename) = args.value_of("dump-filters") {
+            write_filters(filename, &encoded.filters).map_err(tag(Failure::Output))?;
+        }
+
+        match stats_format {
+            Some(format) => {
+                let timings = Timings {
+                    encode: ms(delta),
+                    ..timings
+                };
+                print_stats(format, infile, outfile, pool.current_num_threads(),
+                            &image, &timings, &encoded)?;
+            },
+            None if outfile.is_none() => {
+                println!("Done in {} ms, {} bytes", ms(delta), encoded.bytes);
+            },
+            None => println!("Done in {} ms", ms(delta)),
+        }
+        if report {
+            print_report("size", 1, input_size, encoded.bytes, &image.header);
+        }
+        total_output += encoded.bytes;
+    }
+
+    if report && reps > 1 {
+        print_report(&format!("total over {} runs", reps), reps as u64,
+                     input_size * reps as u64, total_output, &image.header);
     }
 
     Ok(())
 }
 
-pub fn main() {
-    let matches = Command::new("mtpng parallel PNG encoder")
+fn command() -> Command<'static> {
+    Command::new("mtpng parallel PNG encoder")
         .version("0.4.1")
         .author("Brooke Vibber <bvibber@pobox.com>")
         .about("Re-encodes PNG images using multiple CPU cores to exercise the mtpng library.")
+        .arg(Arg::new("config")
+            .long("config")
+            .value_name("file")
+            .help("Read default options from a TOML file of option = value pairs; \
+                   mtpng.toml in the current directory is used if present."))
         .arg(Arg::new("chunk-size")
             .long("chunk-size")
             .value_name("bytes")
             .help("Divide image into chunks of at least this given size.")
             .takes_value(true))
+        .arg(Arg::new("chunk-rows")
+            .long("chunk-rows")
+            .value_name("rows")
+            .help("Divide image into chunks of this many rows, instead of by size.")
+            .takes_value(true))
+        .arg(Arg::new("preset")
+            .long("preset")
+            .value_name("preset")
+            .help("Speed/size trade-off: one of fast, default, or max; screenshot for UI captures and \
+                   line art; photo for photographs and renders; or auto to pick filter, strategy, and level from the image content. \
+                   Other options override the preset."))
         .arg(Arg::new("filter")
             .long("filter")
             .value_name("filter")
             .help("Set a fixed filter: one of none, sub, up, average, or paeth."))
+        .arg(Arg::new("filter-lock-in")
+            .long("filter-lock-in")
+            .value_name("rows")
+            .help("With adaptive filtering, keep using a filter for the rest of each chunk once \
+                   this many rows in a row have picked it."))
+        .arg(Arg::new("dump-filters")
+            .long("dump-filters")
+            .value_name("file")
+            .conflicts_with_all(&["optimize", "compare-backends"])
+            .help("Write the filter picked for each row to a file, one per line, for replaying with --filters-from."))
+        .arg(Arg::new("filters-from")
+            .long("filters-from")
+            .value_name("file")
+            .conflicts_with_all(&["filter", "optimize"])
+            .help("Filter each row as listed in a file, one filter name or type number per line, \
+                   as written by --dump-filters."))
+        .arg(Arg::new("interlace")
+            .long("interlace")
+            .help("Write an Adam7 interlaced file, which viewers can show a coarse version of \
+                   while it loads. Files usually come out larger."))
         .arg(Arg::new("level")
             .long("level")
             .value_name("level")
-            .help("Set deflate compression level, from 1-9."))
+            .help("Set deflate compression level: 1 through 9, default, or max to compress with Zopfli \
+                   when built with the zopfli feature; much slower, for the smallest files."))
         .arg(Arg::new("strategy")
             .long("strategy")
             .value_name("strategy")
             .help("Deflate strategy: one of filtered, huffman, rle, or fixed."))
+        .arg(Arg::new("backend")
+            .long("backend")
+            .value_name("backend")
+            .help("Deflate backend: zlib, fastest for a quick precomputed-code encoder, \
+                   or miniz or zlib-rs when compiled in."))
         .arg(Arg::new("streaming")
             .long("streaming")
             .value_name("streaming")
@@ -248,23 +1503,307 @@ pub fn main() {
         .arg(Arg::new("threads")
             .long("threads")
             .value_name("threads")
-            .help("Override default number of threads."))
+            .help("Override default number of threads: a count, or 'physical' for one per \
+                   physical core or 'logical' for one per logical CPU."))
         .arg(Arg::new("repeat")
             .long("repeat")
             .value_name("n")
             .help("Run conversion n times, as load benchmarking helper."))
+        .arg(Arg::new("optimize")
+            .long("optimize")
+            .help("Try filter, strategy, and level combinations in parallel and keep the smallest file. \
+                   Any of --filter, --strategy, or --level given are held fixed."))
+        .arg(Arg::new("strip")
+            .long("strip")
+            .conflicts_with_all(&["keep-metadata", "keep-chunks", "text", "dpi", "ppm", "chunk"])
+            .help("Write no metadata. The file holds only the PNG signature, IHDR, PLTE for indexed \
+                   images, tRNS when the image has transparency, IDAT, and IEND."))
+        .arg(Arg::new("keep-metadata")
+            .long("keep-metadata")
+            .help("Copy text, color space, ICC profile, pHYs, and eXIf chunks from the input."))
+        .arg(Arg::new("keep-chunks")
+            .long("keep-chunks")
+            .conflicts_with_all(&["keep-metadata", "quantize", "reduce"])
+            .help("Copy every ancillary chunk from the input byte for byte, including private ones, \
+                   in their original order; those after the image data move before it. \
+                   Animated files come out as just their default image."))
+        .arg(Arg::new("text")
+            .long("text")
+            .value_name("key=value")
+            .multiple_occurrences(true)
+            .help("Add a text chunk; may be repeated. Use key=@filename to read the value from a file."))
+        .arg(Arg::new("chunk")
+            .long("chunk")
+            .value_name("tag=file")
+            .multiple_occurrences(true)
+            .help("Add an ancillary chunk with the given 4-letter tag and the contents of a file; \
+                   may be repeated."))
+        .arg(Arg::new("dpi")
+            .long("dpi")
+            .value_name("dpi")
+            .conflicts_with("ppm")
+            .help("Record physical resolution in dots per inch, as N or XxY."))
+        .arg(Arg::new("ppm")
+            .long("ppm")
+            .value_name("ppm")
+            .help("Record physical resolution in pixels per meter, as N or XxY."))
+        .arg(Arg::new("quantize")
+            .long("quantize")
+            .value_name("colors")
+            .help("Reduce 8-bit truecolor input to an indexed palette of up to this many colors."))
+        .arg(Arg::new("dither")
+            .long("dither")
+            .value_name("method")
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("floyd-steinberg")
+            .possible_values(["floyd-steinberg", "ordered"])
+            .requires("quantize")
+            .help("Dither when quantizing, with Floyd-Steinberg error diffusion or an ordered pattern."))
+        .arg(Arg::new("suggest-palette")
+            .long("suggest-palette")
+            .value_name("colors")
+            .help("Add an sPLT chunk suggesting a palette of up to this many colors for 8-bit truecolor input, \
+                   leaving the pixels unchanged."))
+        .arg(Arg::new("dedup-palette")
+            .long("dedup-palette")
+            .help("Merge duplicate palette entries and remap the image data to match."))
+        .arg(Arg::new("reduce")
+            .long("reduce")
+            .help("Losslessly reduce the color type where possible: strip unused alpha, convert to greyscale or palette."))
+        .arg(Arg::new("stats")
+            .long("stats")
+            .value_name("format")
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("text")
+            .help("Print timing, per-stage time, chunk, filter, and size statistics after each run; \
+                   use --stats=json for JSON."))
+        .arg(Arg::new("report")
+            .long("report")
+            .help("Report input and output file sizes, percent change, and bits per pixel, \
+                   with a total when repeating. Not printed with --stats=json."))
+        .arg(Arg::new("verify")
+            .long("verify")
+            .conflicts_with("dry-run")
+            .help("Decode the output after writing and check it matches the input image."))
+        .arg(Arg::new("validate")
+            .long("validate")
+            .help("Check image data while encoding, such as palette indices being in range."))
+        .arg(Arg::new("decode")
+            .long("decode")
+            .conflicts_with("dry-run")
+            .help("Decode the input PNG instead, writing raw packed rows (.raw) \
+                   or Netpbm (.pgm, .ppm, .pam) by output extension."))
+        .arg(Arg::new("compare-backends")
+            .long("compare-backends")
+            .conflicts_with_all(&["optimize", "verify", "stats", "report"])
+            .help("Encode with each compiled-in deflate backend and print a size and time table \
+                   instead of writing a file."))
+        .arg(Arg::new("dry-run")
+            .long("dry-run")
+            .help("Encode without writing a file, reporting the would-be size; handy with --repeat."))
+        .arg(Arg::new("errors")
+            .long("errors")
+            .value_name("format")
+            .possible_values(["text", "json"])
+            .help("Report errors as text or as a JSON object on stderr. Exit codes: 1 internal, \
+                   2 bad arguments, 3 input read or decode, 4 output write, 5 verification."))
         .arg(Arg::new("input")
             .help("Input filename, must be another PNG.")
             .required(true)
             .index(1))
         .arg(Arg::new("output")
             .help("Output filename.")
-            .required(true)
+            .required_unless_present_any(["dry-run", "compare-backends"])
             .index(2))
-        .get_matches();
+}
+
+// Parse the command line, merging in options from a config file.
+fn parse_args(argv: &[OsString], json_errors: bool) -> io::Result<ArgMatches> {
+    let parse = |argv: &[OsString]| match command().try_get_matches_from(argv) {
+        Ok(matches) => Ok(matches),
+        Err(e) => match e.kind() {
+            clap::ErrorKind::DisplayHelp | clap::ErrorKind::DisplayVersion => e.exit(),
+            // Let clap print its usual usage message unless asked for JSON.
+            _ if !json_errors => e.exit(),
+            _ => Err(err(e.to_string().trim())),
+        },
+    };
+    let matches = parse(argv)?;
+
+    let path = match matches.value_of("config") {
+        Some(path) => path.to_string(),
+        None if Path::new(DEFAULT_CONFIG).is_file() => DEFAULT_CONFIG.to_string(),
+        None => return Ok(matches),
+    };
+    let extra = config_args(&path, &matches)?;
+    if extra.is_empty() {
+        return Ok(matches);
+    }
+
+    let mut merged = vec![argv[0].clone()];
+    merged.extend(extra);
+    merged.extend(argv[1..].iter().cloned());
+    parse(&merged)
+}
+
+//
+// Check the raw arguments for --errors json, so even argument
+// parsing failures can be reported as JSON.
+//
+fn wants_json_errors(argv: &[OsString]) -> bool {
+    argv.iter().zip(argv.iter().skip(1).map(Some).chain(Some(None)))
+        .any(|(arg, next)| arg == "--errors=json" ||
+                           (arg == "--errors" && next.is_some_and(|n| n == "json")))
+}
 
-    match doit(matches) {
-        Ok(()) => {},
-        Err(e) => eprintln!("Error: {}", e),
+pub fn main() {
+    let argv: Vec<OsString> = env::args_os().collect();
+    let mut json_errors = wants_json_errors(&argv);
+    let result = parse_args(&argv, json_errors).and_then(|args| {
+        json_errors = args.value_of("errors") == Some("json");
+        doit(args)
+    });
+    if let Err(e) = result {
+        // Errors not tagged with a stage come from argument checks.
+        let (failure, message) = match e.get_ref().and_then(|inner| inner.downcast_ref::<Tagged>()) {
+            Some(tagged) => (tagged.failure, tagged.error.to_string()),
+            None => (Failure::Usage, e.to_string()),
+        };
+        if json_errors {
+            eprintln!("{{\"error\":{},\"code\":{},\"message\":{}}}",
+                      json_string(failure.name()), failure as i32, json_string(&message));
+        } else {
+            eprintln!("Error: {}", message);
+        }
+        std::process::exit(failure as i32);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_chunks() {
+        let chunk = text_chunk("Title=Caf\u{e9}").unwrap();
+        assert_eq!(&chunk.tag, b"tEXt");
+        assert_eq!(chunk.data, b"Title\0Caf\xe9");
+
+        let chunk = text_chunk("Title=\u{732b}").unwrap();
+        assert_eq!(&chunk.tag, b"iTXt");
+
+        assert!(text_chunk("Title").is_err());
+        assert!(text_chunk(" Title=x").is_err());
+        assert!(text_chunk("Title=a\0b").is_err());
+        assert!(text_chunk("Title=\u{732b}\0").is_err());
+
+        let path = env::temp_dir().join(format!("mtpng-text-{}.txt", std::process::id()));
+        fs::write(&path, "from\0file").unwrap();
+        let result = text_chunk(&format!("Comment=@{}", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    // Write a config file, parse the given arguments with it, and clean up.
+    fn parse_with_config(name: &str, config: &str, args: &[&str]) -> io::Result<ArgMatches> {
+        let path = env::temp_dir().join(format!("mtpng-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, config).unwrap();
+        let mut argv: Vec<OsString> = vec!["mtpng".into(), "--config".into(), path.clone().into()];
+        argv.extend(args.iter().map(OsString::from));
+        let result = parse_args(&argv, true);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn config_file() {
+        let config = "# defaults\n\
+                      chunk-size = 65_536\n\
+                      level = 9 # trailing comment\n\
+                      preset = \"fast\"\n\
+                      text = [\"Title=From config\", 'Author=Me']\n\
+                      validate = true\n\
+                      verify = false\n";
+        let matches = parse_with_config("merge", config, &["--level", "default", "in.png", "out.png"]).unwrap();
+        assert_eq!(matches.value_of("chunk-size"), Some("65536"));
+        assert_eq!(matches.value_of("preset"), Some("fast"));
+        // The command line takes precedence.
+        assert_eq!(matches.value_of("level"), Some("default"));
+        assert_eq!(matches.values_of("text").unwrap().collect::<Vec<_>>(),
+                   ["Title=From config", "Author=Me"]);
+        assert!(matches.is_present("validate"));
+        assert!(!matches.is_present("verify"));
+        assert_eq!(matches.value_of("input"), Some("in.png"));
+
+        let matches = parse_with_config("override", "text = [\"Title=a\"]\n",
+                                        &["--text", "Title=b", "in.png", "out.png"]).unwrap();
+        assert_eq!(matches.values_of("text").unwrap().collect::<Vec<_>>(), ["Title=b"]);
+
+        for bad in ["[encoder]\nlevel = 9\n", "encoder.level = 9\n", "level = 9\nlevel = 1\n",
+                    "level = default\n", "nonsense = 1\n", "config = \"other.toml\"\n",
+                    "chunk_size = 1024\n", "verify = \"yes\"\n", "level = true\n",
+                    "level = [\"default\", \"max\"]\n", "text = \"Title=a\"\n", "level\n"] {
+            assert!(parse_with_config("bad", bad, &["in.png", "out.png"]).is_err(), "accepted {}", bad);
+        }
+    }
+
+    #[test]
+    fn verify_dedup_palette() {
+        let dir = env::temp_dir();
+        let infile = dir.join(format!("mtpng-dedup-in-{}.png", std::process::id()));
+        let outfile = dir.join(format!("mtpng-dedup-out-{}.png", std::process::id()));
+
+        // Red appears twice in the palette, so deduplicating remaps its indices.
+        let mut encoder = png::Encoder::new(File::create(&infile).unwrap(), 16, 16);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(vec![255, 0, 0, 0, 255, 0, 255, 0, 0, 0, 0, 255]);
+        let data: Vec<u8> = (0 .. 16 * 16).map(|i| (i % 4) as u8).collect();
+        encoder.write_header().unwrap().write_image_data(&data).unwrap();
+
+        let argv: Vec<OsString> = vec!["mtpng".into(), "--dedup-palette".into(), "--verify".into(),
+                                       infile.clone().into(), outfile.clone().into()];
+        let result = doit(parse_args(&argv, true).unwrap());
+        let written = read_png(outfile.to_str().unwrap(), Keep::Nothing);
+        fs::remove_file(&infile).unwrap();
+        fs::remove_file(&outfile).unwrap();
+        result.unwrap();
+        assert_eq!(written.unwrap().palette.unwrap().len(), 9);
+    }
+
+    #[test]
+    fn keep_chunks_drops_animation() {
+        use mtpng::encoder::{AnimationEncoder, FrameControl};
+
+        let dir = env::temp_dir();
+        let infile = dir.join(format!("mtpng-apng-in-{}.png", std::process::id()));
+        let outfile = dir.join(format!("mtpng-apng-out-{}.png", std::process::id()));
+
+        let mut header = Header::new();
+        header.set_size(16, 16).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let first: Vec<u8> = (0 .. 16 * 16 * 3).map(|i| (i % 251) as u8).collect();
+        let second = vec![255u8; 16 * 16 * 3];
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header, 2, 0).unwrap();
+        encoder.write_frame(&FrameControl::new(), &first).unwrap();
+        encoder.write_frame(&FrameControl::new(), &second).unwrap();
+        fs::write(&infile, encoder.finish().unwrap()).unwrap();
+
+        let argv: Vec<OsString> = vec!["mtpng".into(), "--keep-chunks".into(), "--verify".into(),
+                                       infile.clone().into(), outfile.clone().into()];
+        let result = doit(parse_args(&argv, true).unwrap());
+        let written = fs::read(&outfile);
+        fs::remove_file(&infile).unwrap();
+        fs::remove_file(&outfile).unwrap();
+        result.unwrap();
+
+        let written = written.unwrap();
+        let mut reader = png::Decoder::new(&written[..]).read_info().unwrap();
+        assert!(reader.info().animation_control.is_none());
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(data, first);
+    }
+}
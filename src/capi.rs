@@ -39,10 +39,15 @@ use std::os::raw::c_char;
 use libc::{c_void, c_int, size_t};
 
 use super::ColorType;
+use super::CompressionMethod;
+use super::FilterMethod;
+use super::InterlaceMethod;
 use super::Strategy;
 use super::CompressionLevel;
 use super::Mode::{Adaptive, Fixed};
 use super::Header;
+use super::error;
+use super::error::Error;
 
 use super::encoder::Encoder;
 use super::encoder::Options;
@@ -58,8 +63,8 @@ pub enum CResult {
     Err = 1,
 }
 
-impl From<Result<(),io::Error>> for CResult {
-    fn from(result: Result<(),io::Error>) -> CResult {
+impl From<Result<(),Error>> for CResult {
+    fn from(result: Result<(),Error>) -> CResult {
         match result {
             Ok(()) => CResult::Ok,
             Err(_) => CResult::Err,
@@ -132,18 +137,22 @@ impl CWriter {
     -> CWriter
     {
         CWriter {
-            write_func: write_func,
-            flush_func: flush_func,
-            user_data: user_data,
+            write_func,
+            flush_func,
+            user_data,
         }
     }
 }
 
 impl Write for CWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Indexing an empty buffer would panic across the FFI boundary.
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let ret = unsafe {
             (self.write_func)(self.user_data,
-                                   &buf[0],
+                                   buf.as_ptr(),
                                    buf.len())
         };
         if ret == buf.len() {
@@ -174,12 +183,17 @@ pub type PEncoder = *mut CEncoder;
 pub type PHeader = *mut Header;
 
 
+/// Create a thread pool with the given number of threads, or the default for 0.
+///
+/// # Safety
+///
+/// `pp_pool` must be null or point to a writable pool pointer.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_threadpool_new(pp_pool: *mut PThreadPool, threads: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_pool.is_null() {
             return Err(invalid_input("pp_pool must not be null"));
         }
@@ -188,18 +202,24 @@ fn mtpng_threadpool_new(pp_pool: *mut PThreadPool, threads: size_t)
         }
         let pool = ThreadPoolBuilder::new().num_threads(threads)
                                             .build()
-                                            .map_err(|err| other(&err.to_string()))?;
+                                            .map_err(|err| other::<Error>(&err.to_string()))?;
         *pp_pool = Box::into_raw(Box::new(pool));
         Ok(())
     }())
 }
 
+/// Release a thread pool and set `*pp_pool` to null.
+///
+/// # Safety
+///
+/// `pp_pool` must be null or point to a pool pointer that is null or from
+/// `mtpng_threadpool_new`. No options or encoders may still be using the pool.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_threadpool_release(pp_pool: *mut PThreadPool)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_pool.is_null() {
             return Err(invalid_input("pp_pool must not be null"));
         }
@@ -213,12 +233,17 @@ fn mtpng_threadpool_release(pp_pool: *mut PThreadPool)
 }
 
 
+/// Create encoder options with the defaults.
+///
+/// # Safety
+///
+/// `pp_options` must be null or point to a writable options pointer.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_new(pp_options: *mut PEncoderOptions)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_options.is_null() {
             return Err(invalid_input("pp_options must not be null"));
         }
@@ -230,12 +255,18 @@ fn mtpng_encoder_options_new(pp_options: *mut PEncoderOptions)
     }())
 }
 
+/// Release encoder options and set `*pp_options` to null.
+///
+/// # Safety
+///
+/// `pp_options` must be null or point to an options pointer that is null or
+/// from `mtpng_encoder_options_new`.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_release(pp_options: *mut PEncoderOptions)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_options.is_null() {
             return Err(invalid_input("pp_header must not be null"));
         }
@@ -249,32 +280,49 @@ fn mtpng_encoder_options_release(pp_options: *mut PEncoderOptions)
 }
 
 
+/// Run jobs on the given thread pool instead of the global one.
+///
+/// # Safety
+///
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
+/// `p_pool` must be null or a pool from `mtpng_threadpool_new`, which must
+/// outlive the options and any encoder created with them.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_set_thread_pool(p_options: PEncoderOptions,
                                          p_pool: PThreadPool)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
+        if p_pool.is_null() {
+            return Err(invalid_input("p_pool must not be null"));
+        }
         (*p_options).set_thread_pool(&*p_pool)
     }())
 }
 
 
+/// Set a fixed filter type, or adaptive filtering for a negative value.
+///
+/// # Safety
+///
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_set_filter(p_options: PEncoderOptions,
                                     filter_mode: c_int)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
-        if filter_mode > u8::max_value() as c_int {
+        if filter_mode > u8::MAX as c_int {
             return Err(invalid_input("Invalid filter mode"));
         }
         let mode = if filter_mode < 0 {
@@ -286,17 +334,23 @@ fn mtpng_encoder_options_set_filter(p_options: PEncoderOptions,
     }())
 }
 
+/// Set a fixed deflate strategy, or adaptive for a negative value.
+///
+/// # Safety
+///
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_set_strategy(p_options: PEncoderOptions,
                                       strategy_mode: c_int)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
-        if strategy_mode > u8::max_value() as c_int {
+        if strategy_mode > u8::MAX as c_int {
             return Err(invalid_input("Invalid strategy mode"));
         }
         let mode = if strategy_mode < 0 {
@@ -308,17 +362,23 @@ fn mtpng_encoder_options_set_strategy(p_options: PEncoderOptions,
     }())
 }
 
+/// Set the deflate compression level.
+///
+/// # Safety
+///
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_set_compression_level(p_options: PEncoderOptions,
                                                compression_level: c_int)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
-        if compression_level < 0 || compression_level > 9 {
+        if !(0 ..= 10).contains(&compression_level) {
             return Err(invalid_input("Invalid compression level"));
         }
         let level = CompressionLevel::try_from(compression_level as u8)?;
@@ -326,13 +386,19 @@ fn mtpng_encoder_options_set_compression_level(p_options: PEncoderOptions,
     }())
 }
 
+/// Set the minimum size in bytes of the chunks the image is split into.
+///
+/// # Safety
+///
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_set_chunk_size(p_options: PEncoderOptions,
                                         chunk_size: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_options.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
@@ -340,13 +406,38 @@ fn mtpng_encoder_options_set_chunk_size(p_options: PEncoderOptions,
     }())
 }
 
+/// Split the image into chunks of this many rows instead of by size.
+///
+/// # Safety
+///
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_options_set_chunk_rows(p_options: PEncoderOptions,
+                                        rows: size_t)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_options.is_null() {
+            return Err(invalid_input("p_options must not be null"));
+        }
+        (*p_options).set_chunk_rows(rows)
+    }())
+}
+
 
+/// Create an image header with the defaults.
+///
+/// # Safety
+///
+/// `pp_header` must be null or point to a writable header pointer.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_header_new(pp_header: *mut PHeader)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_header.is_null() {
             return Err(invalid_input("pp_header must not be null"));
         }
@@ -358,12 +449,18 @@ fn mtpng_header_new(pp_header: *mut PHeader)
     }())
 }
 
+/// Release an image header and set `*pp_header` to null.
+///
+/// # Safety
+///
+/// `pp_header` must be null or point to a header pointer that is null or
+/// from `mtpng_header_new`.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_header_release(pp_header: *mut PHeader)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_header.is_null() {
             return Err(invalid_input("pp_header must not be null"));
         }
@@ -376,6 +473,12 @@ fn mtpng_header_release(pp_header: *mut PHeader)
     }())
 }
 
+/// Set the image width and height.
+///
+/// # Safety
+///
+/// `p_header` must be null or a header from `mtpng_header_new` that has not
+/// been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_header_set_size(p_header: PHeader,
@@ -383,14 +486,20 @@ fn mtpng_header_set_size(p_header: PHeader,
                          height: u32)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_header.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
-        (*p_header).set_size(width, height)
+        Ok((*p_header).set_size(width, height)?)
     }())
 }
 
+/// Set the color type and bit depth.
+///
+/// # Safety
+///
+/// `p_header` must be null or a header from `mtpng_header_new` that has not
+/// been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_header_set_color(p_header: PHeader,
@@ -398,20 +507,101 @@ fn mtpng_header_set_color(p_header: PHeader,
                                    depth: u8)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_header.is_null() {
             return Err(invalid_input("p_header must not be null"));
         }
-        if color_type < 0 || color_type > u8::max_value() as c_int {
+        if color_type < 0 || color_type > u8::MAX as c_int {
             return Err(invalid_input("Invalid color type"));
         }
         let color = ColorType::try_from(color_type as u8)?;
-        (*p_header).set_color(color, depth)
+        Ok((*p_header).set_color(color, depth)?)
     }())
 }
 
+/// Set the compression method; only 0 is defined.
+///
+/// # Safety
+///
+/// `p_header` must be null or a header from `mtpng_header_new` that has not
+/// been released.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_header_set_compression_method(p_header: PHeader,
+                                       compression_method: c_int)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if compression_method < 0 || compression_method > u8::MAX as c_int {
+            return Err(invalid_input("Invalid compression method"));
+        }
+        let method = CompressionMethod::try_from(compression_method as u8)?;
+        Ok((*p_header).set_compression_method(method)?)
+    }())
+}
 
+/// Set the filter method; only 0 is defined.
+///
+/// # Safety
+///
+/// `p_header` must be null or a header from `mtpng_header_new` that has not
+/// been released.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_header_set_filter_method(p_header: PHeader,
+                                  filter_method: c_int)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if filter_method < 0 || filter_method > u8::MAX as c_int {
+            return Err(invalid_input("Invalid filter method"));
+        }
+        let method = FilterMethod::try_from(filter_method as u8)?;
+        Ok((*p_header).set_filter_method(method)?)
+    }())
+}
 
+/// Set the interlace method: 0 for none or 1 for Adam7.
+///
+/// # Safety
+///
+/// `p_header` must be null or a header from `mtpng_header_new` that has not
+/// been released.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_header_set_interlace_method(p_header: PHeader,
+                                     interlace_method: c_int)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if interlace_method < 0 || interlace_method > u8::MAX as c_int {
+            return Err(invalid_input("Invalid interlace method"));
+        }
+        let method = InterlaceMethod::try_from(interlace_method as u8)?;
+        Ok((*p_header).set_interlace_method(method)?)
+    }())
+}
+
+
+
+/// Create an encoder writing through the given callbacks.
+///
+/// # Safety
+///
+/// `pp_encoder` must be null or point to a writable encoder pointer.
+/// The callbacks are called with `user_data` until the encoder is released
+/// or finished, and must be safe to call with it until then.
+/// `p_options` must be null or options from `mtpng_encoder_options_new` that
+/// have not been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_new(pp_encoder: *mut PEncoder,
@@ -421,7 +611,7 @@ fn mtpng_encoder_new(pp_encoder: *mut PEncoder,
                      p_options: PEncoderOptions)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_encoder.is_null() {
             return Err(invalid_input("pp_encoder must not be null"));
         }
@@ -444,12 +634,18 @@ fn mtpng_encoder_new(pp_encoder: *mut PEncoder,
     }())
 }
 
+/// Release an encoder without finishing the file, and set `*pp_encoder` to null.
+///
+/// # Safety
+///
+/// `pp_encoder` must be null or point to an encoder pointer that is null or
+/// from `mtpng_encoder_new`.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_release(pp_encoder: *mut PEncoder)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_encoder.is_null() {
             return Err(invalid_input("pp_encoder must not be null"))
         }
@@ -463,13 +659,21 @@ fn mtpng_encoder_release(pp_encoder: *mut PEncoder)
 }
 
 
+/// Write the signature and IHDR chunk.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_header` must be null or a header from `mtpng_header_new` that has not
+/// been released.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_write_header(p_encoder: PEncoder,
                               p_header: PHeader)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_encoder.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
@@ -481,6 +685,13 @@ fn mtpng_encoder_write_header(p_encoder: PEncoder,
     }())
 }
 
+/// Write a PLTE chunk.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_bytes` must be null or point to `len` readable bytes.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_write_palette(p_encoder: PEncoder,
@@ -488,7 +699,7 @@ fn mtpng_encoder_write_palette(p_encoder: PEncoder,
                                len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_encoder.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
@@ -500,6 +711,13 @@ fn mtpng_encoder_write_palette(p_encoder: PEncoder,
     }())
 }
 
+/// Write a tRNS chunk.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_bytes` must be null or point to `len` readable bytes.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_write_transparency(p_encoder: PEncoder,
@@ -507,7 +725,7 @@ fn mtpng_encoder_write_transparency(p_encoder: PEncoder,
                                     len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_encoder.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
@@ -519,6 +737,60 @@ fn mtpng_encoder_write_transparency(p_encoder: PEncoder,
     }())
 }
 
+/// Write an sBIT chunk with the same depth for every channel.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_significant_bits(p_encoder: PEncoder,
+                                        bits: u8)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        (*p_encoder).write_significant_bits(bits)
+    }())
+}
+
+/// Write an sBIT chunk with a depth for each channel.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_bits` must be null or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_significant_bits_per_channel(p_encoder: PEncoder,
+                                                    p_bits: *const u8,
+                                                    len: size_t)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bits.is_null() {
+            return Err(invalid_input("p_bits must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bits, len);
+        (*p_encoder).write_significant_bits_per_channel(slice)
+    }())
+}
+
+/// Write a custom chunk.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_tag` must be null or a nul-terminated string.
+/// `p_bytes` must be null or point to `len` readable bytes.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_write_chunk(p_encoder: PEncoder,
@@ -527,7 +799,7 @@ fn mtpng_encoder_write_chunk(p_encoder: PEncoder,
                              len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_encoder.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
@@ -543,6 +815,13 @@ fn mtpng_encoder_write_chunk(p_encoder: PEncoder,
     }())
 }
 
+/// Write one or more whole rows of image data.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_bytes` must be null or point to `len` readable bytes.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_write_image_rows(p_encoder: PEncoder,
@@ -550,7 +829,7 @@ fn mtpng_encoder_write_image_rows(p_encoder: PEncoder,
                                   len: size_t)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if p_encoder.is_null() {
             return Err(invalid_input("p_encoder must not be null"));
         }
@@ -562,12 +841,45 @@ fn mtpng_encoder_write_image_rows(p_encoder: PEncoder,
     }())
 }
 
+/// Write rows of image data laid out `stride` bytes apart.
+///
+/// # Safety
+///
+/// `p_encoder` must be null or an encoder from `mtpng_encoder_new` that has
+/// not been released or finished.
+/// `p_bytes` must be null or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_image_rows_with_stride(p_encoder: PEncoder,
+                                              p_bytes: *const u8,
+                                              len: size_t,
+                                              stride: size_t)
+-> CResult
+{
+    CResult::from(|| -> error::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_image_rows_with_stride(slice, stride)
+    }())
+}
+
+/// Finish the file, release the encoder, and set `*pp_encoder` to null.
+///
+/// # Safety
+///
+/// `pp_encoder` must be null or point to an encoder pointer that is null or
+/// from `mtpng_encoder_new`.
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_finish(pp_encoder: *mut PEncoder)
 -> CResult
 {
-    CResult::from(|| -> io::Result<()> {
+    CResult::from(|| -> error::Result<()> {
         if pp_encoder.is_null() {
             return Err(invalid_input("pp_encoder must not be null"));
         }
@@ -0,0 +1,218 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// checksum.rs - CRC-32 and Adler-32 checksums
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! PNG chunk CRC-32 and zlib stream Adler-32 checksums.
+//!
+//! The fastest implementation available on the running CPU is picked
+//! the first time each checksum is used, independent of which deflate
+//! backend is in use: hardware carry-less multiply or CRC instructions
+//! for CRC-32 with a table-driven fallback, and SIMD for Adler-32 with
+//! zlib's implementation as the fallback.
+
+use std::os::raw::{c_long, c_uint, c_ulong};
+use std::sync::OnceLock;
+
+use crc32fast::Hasher;
+use simd_adler32::Adler32;
+
+/// Checksum implementations that may be picked at runtime.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Implementation {
+    /// CPU CRC or carry-less multiply instructions.
+    Hardware,
+    /// Vector instructions.
+    Simd,
+    /// Lookup tables in portable code.
+    Table,
+    /// The system zlib library.
+    Zlib,
+}
+
+impl Implementation {
+    /// Short name for display.
+    pub fn name(self) -> &'static str {
+        match self {
+            Implementation::Hardware => "hardware",
+            Implementation::Simd => "simd",
+            Implementation::Table => "table",
+            Implementation::Zlib => "zlib",
+        }
+    }
+}
+
+/// Which CRC-32 implementation is in use on this CPU.
+pub fn crc32_implementation() -> Implementation {
+    static PICKED: OnceLock<Implementation> = OnceLock::new();
+    *PICKED.get_or_init(|| {
+        let picked = pick_crc32();
+        debug_log!("using {} CRC-32", picked.name());
+        picked
+    })
+}
+
+fn pick_crc32() -> Implementation {
+    // The same features crc32fast checks for its fast paths.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.1") {
+            return Implementation::Hardware;
+        }
+    }
+    // On aarch64 that's the ARMv8 CRC32 instructions, which crc32fast
+    // uses without the nightly feature from 1.5 on, built with Rust
+    // 1.80 or later.
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("crc") {
+            return Implementation::Hardware;
+        }
+    }
+    Implementation::Table
+}
+
+/// Which Adler-32 implementation is in use on this CPU.
+pub fn adler32_implementation() -> Implementation {
+    static PICKED: OnceLock<Implementation> = OnceLock::new();
+    *PICKED.get_or_init(|| {
+        let picked = pick_adler32();
+        debug_log!("using {} Adler-32", picked.name());
+        picked
+    })
+}
+
+fn pick_adler32() -> Implementation {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") || is_x86_feature_detected!("ssse3") {
+            return Implementation::Simd;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Implementation::Simd;
+        }
+    }
+    Implementation::Zlib
+}
+
+/// CRC-32 (ISO 3309) of the given bytes, as used for PNG chunks.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32fast::hash(bytes)
+}
+
+/// Incremental CRC-32, for checksumming a chunk's tag and data
+/// without copying them together.
+pub struct Crc32 {
+    hasher: Hasher,
+}
+
+impl Crc32 {
+    /// Start a new checksum.
+    pub fn new() -> Crc32 {
+        Crc32 {
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// Add bytes to the checksum.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+
+    /// Return the checksum of everything added so far.
+    pub fn sum(self) -> u32 {
+        self.hasher.finalize()
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Starting value for an Adler-32 checksum.
+pub fn adler32_initial() -> u32 {
+    1
+}
+
+/// Continue an Adler-32 checksum over more bytes.
+pub fn adler32(sum: u32, bytes: &[u8]) -> u32 {
+    match adler32_implementation() {
+        Implementation::Simd => {
+            let mut adler = Adler32::from_checksum(sum);
+            adler.write(bytes);
+            adler.finish()
+        },
+        _ => {
+            // zlib takes lengths as unsigned ints, so feed it in pieces.
+            bytes.chunks(c_uint::MAX as usize).fold(sum, |sum, piece| unsafe {
+                ::libz_sys::adler32(c_ulong::from(sum), piece.as_ptr(), piece.len() as c_uint) as u32
+            })
+        },
+    }
+}
+
+/// Combine the Adler-32 checksums of two consecutive runs of bytes,
+/// given the length of the second.
+pub fn adler32_combine(sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
+    unsafe {
+        ::libz_sys::adler32_combine(c_ulong::from(sum_a), c_ulong::from(sum_b), len_b as c_long) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+        let mut digest = Crc32::new();
+        digest.update(b"IEND");
+        digest.update(b"");
+        assert_eq!(digest.sum(), 0xae426082);
+    }
+
+    #[test]
+    fn adler() {
+        let bytes: Vec<u8> = (0 .. 100_000).map(|i| (i * 13 % 256) as u8).collect();
+        let zlib = unsafe {
+            ::libz_sys::adler32(1, bytes.as_ptr(), bytes.len() as c_uint) as u32
+        };
+        assert_eq!(adler32(adler32_initial(), &bytes), zlib);
+        assert_eq!(adler32(adler32_initial(), b""), 1);
+        assert_eq!(adler32(adler32_initial(), b"Wikipedia"), 0x11e60398);
+
+        let (a, b) = bytes.split_at(12345);
+        let sum_a = adler32(adler32_initial(), a);
+        let sum_b = adler32(adler32_initial(), b);
+        assert_eq!(adler32_combine(sum_a, sum_b, b.len()), zlib);
+        assert_eq!(adler32(sum_a, b), zlib);
+    }
+}
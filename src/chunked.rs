@@ -0,0 +1,329 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// chunked.rs - parallel chunked deflate compression
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+
+//! Parallel deflate compression, independent of PNG.
+//!
+//! This is the machinery the encoder uses for image data, opened up
+//! for building other parallel deflate containers. Input is split
+//! into chunks that compress at the same time on a Rayon thread pool.
+//! By default every chunk but the last ends with a sync flush, and
+//! starts with the end of the previous chunk's input as a preset
+//! dictionary, so the pieces join into a single deflate stream that
+//! compresses nearly as well as a serial one. Alternatively each
+//! chunk can be a complete stream of its own, as for parallel gzip
+//! members or independently decodable tiles.
+//!
+//! Checksums for the container can be worked out per chunk and then
+//! combined with the functions in the checksum module.
+
+use rayon::ThreadPool;
+use rayon::prelude::*;
+
+use std::io;
+
+use super::Backend;
+use super::CompressionLevel;
+use super::Flush;
+use super::Strategy;
+use super::checksum;
+use super::deflate;
+use super::deflate::Deflate;
+use super::utils::*;
+
+// Deflate's window, and so the most dictionary that can help.
+const WINDOW_SIZE: usize = 32768;
+
+/// Settings for chunked compression.
+#[derive(Copy, Clone)]
+pub struct Options<'a> {
+    backend: Backend,
+    compression_level: CompressionLevel,
+    strategy: Strategy,
+    chunk_size: usize,
+    thread_pool: Option<&'a ThreadPool>,
+}
+
+impl<'a> Options<'a> {
+    /// Return a default set of options:
+    ///
+    /// * backend: Zlib
+    /// * compression level: Default
+    /// * strategy: Default
+    /// * chunk size: 256 KiB
+    /// * thread pool: the global Rayon pool
+    pub fn new() -> Options<'a> {
+        Options {
+            backend: Backend::Zlib,
+            compression_level: CompressionLevel::Default,
+            strategy: Strategy::Default,
+            chunk_size: 256 * 1024,
+            thread_pool: None,
+        }
+    }
+
+    /// Select the deflate implementation. Returns an error if the
+    /// backend's cargo feature was not enabled.
+    pub fn set_backend(&mut self, backend: Backend) -> IoResult {
+        if !backend.is_available() {
+            return Err(invalid_input("Deflate backend not compiled in"));
+        }
+        self.backend = backend;
+        Ok(())
+    }
+
+    /// Set the deflate compression level.
+    ///
+    /// Max returns an error if the "zopfli" feature was not enabled.
+    pub fn set_compression_level(&mut self, level: CompressionLevel) -> IoResult {
+        level.validate()?;
+        self.compression_level = level;
+        Ok(())
+    }
+
+    /// Set the deflate compression strategy.
+    pub fn set_strategy(&mut self, strategy: Strategy) -> IoResult {
+        self.strategy = strategy;
+        Ok(())
+    }
+
+    /// Set the size in bytes of the chunks input is split into.
+    ///
+    /// Chunk size must be at least 32 KiB.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> IoResult {
+        if chunk_size < WINDOW_SIZE {
+            Err(invalid_input("chunk size must be at least 32768"))
+        } else {
+            self.chunk_size = chunk_size;
+            Ok(())
+        }
+    }
+
+    /// Use a custom Rayon ThreadPool instance instead of the global pool.
+    pub fn set_thread_pool(&mut self, thread_pool: &'a ThreadPool) -> IoResult {
+        self.thread_pool = Some(thread_pool);
+        Ok(())
+    }
+
+    fn deflate_options(&self) -> deflate::Options {
+        let mut options = deflate::Options::new();
+        // Negative for raw deflate, with no zlib header or checksum.
+        options.set_window_bits(-15);
+        match self.compression_level {
+            CompressionLevel::Default => {},
+            CompressionLevel::Max => options.set_zopfli(true),
+            level => options.set_level(i32::from(level.level())),
+        }
+        options.set_strategy(self.strategy);
+        options.set_backend(self.backend);
+        options
+    }
+}
+
+impl<'a> Default for Options<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compress one chunk as raw deflate data, with no zlib header or
+/// checksum.
+///
+/// The dictionary, if not empty, should be the input just before this
+/// chunk; only its last 32 KiB are used. The decoder must have seen
+/// the same bytes, so leave it empty for a chunk that starts a stream.
+///
+/// End with Flush::SyncFlush for a chunk that more will follow in the
+/// same stream, or Flush::Finish for the last one.
+pub fn compress_chunk(options: &Options,
+                      dictionary: &[u8],
+                      data: &[u8],
+                      flush: Flush) -> io::Result<Vec<u8>>
+{
+    let mut encoder = Deflate::new(options.deflate_options(), Vec::new());
+    if !dictionary.is_empty() {
+        let start = dictionary.len().saturating_sub(WINDOW_SIZE);
+        encoder.set_dictionary(&dictionary[start ..])?;
+    }
+    encoder.write(data, flush)?;
+    encoder.finish()
+}
+
+//
+// Run a job over each chunk on the chosen thread pool, keeping
+// results in order. Empty input still makes a single empty chunk,
+// so there is a stream to finish.
+//
+fn run_chunks<T, F>(options: &Options, data: &[u8], job: F) -> io::Result<Vec<T>>
+    where T: Send,
+          F: Fn(usize, &[u8]) -> io::Result<T> + Sync
+{
+    let starts: Vec<usize> = if data.is_empty() {
+        vec![0]
+    } else {
+        (0 .. data.len()).step_by(options.chunk_size).collect()
+    };
+    let run = || {
+        starts.par_iter()
+              .map(|&start| job(start, &data[start .. data.len().min(start + options.chunk_size)]))
+              .collect()
+    };
+    match options.thread_pool {
+        Some(pool) => pool.install(run),
+        None => run(),
+    }
+}
+
+/// Compress data in parallel as a single raw deflate stream, returned
+/// in pieces, one per chunk, which join up in order.
+pub fn compress_raw(options: &Options, data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    run_chunks(options, data, |start, chunk| {
+        let flush = if start + chunk.len() == data.len() {
+            Flush::Finish
+        } else {
+            Flush::SyncFlush
+        };
+        compress_chunk(options, &data[.. start], chunk, flush)
+    })
+}
+
+/// Compress data in parallel as independent raw deflate streams, one
+/// per chunk, each of which decodes on its own.
+pub fn compress_members(options: &Options, data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    run_chunks(options, data, |_start, chunk| {
+        compress_chunk(options, &[], chunk, Flush::Finish)
+    })
+}
+
+/// Compress data in parallel as a complete zlib stream.
+pub fn compress_zlib(options: &Options, data: &[u8]) -> io::Result<Vec<u8>> {
+    let pieces = compress_raw(options, data)?;
+
+    // Checksum the chunks in parallel too, then combine them.
+    let sums = run_chunks(options, data, |_start, chunk| {
+        Ok((checksum::adler32(checksum::adler32_initial(), chunk), chunk.len()))
+    })?;
+    let sum = sums.iter().fold(checksum::adler32_initial(), |sum, &(chunk_sum, len)| {
+        checksum::adler32_combine(sum, chunk_sum, len)
+    });
+
+    // Deflate with a 32 KiB window, a hint of the compression level,
+    // and check bits making the header a multiple of 31.
+    let cmf = 0x78u8;
+    let mut flg: u8 = match options.compression_level.level() {
+        1 => 0,
+        2 ..= 5 => 1,
+        6 => 2,
+        _ => 3,
+    } << 6;
+    flg |= ((31 - (u16::from(cmf) * 256 + u16::from(flg)) % 31) % 31) as u8;
+
+    let mut output = Vec::with_capacity(pieces.iter().map(|p| p.len()).sum::<usize>() + 6);
+    output.push(cmf);
+    output.push(flg);
+    for piece in pieces.iter() {
+        output.extend_from_slice(piece);
+    }
+    output.extend_from_slice(&sum.to_be_bytes());
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::inflate::Inflate;
+
+    use rayon::ThreadPoolBuilder;
+
+    fn sample() -> Vec<u8> {
+        // Compressible, but not so much that chunks vanish.
+        let mut seed = 1u32;
+        (0 .. 300_000).map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            if i % 7 == 0 { (seed >> 24) as u8 } else { (i / 100) as u8 }
+        }).collect()
+    }
+
+    fn inflate(zlib: &[u8]) -> Vec<u8> {
+        let mut inflate = Inflate::new();
+        let mut output = Vec::new();
+        inflate.write(zlib, &mut output).unwrap();
+        assert!(inflate.is_finished());
+        output
+    }
+
+    // Wrap raw deflate data as a zlib stream, to check with Inflate.
+    fn wrap(raw: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut zlib = vec![0x78, 0x01];
+        zlib.extend_from_slice(raw);
+        zlib.extend_from_slice(&checksum::adler32(checksum::adler32_initial(), data).to_be_bytes());
+        zlib
+    }
+
+    #[test]
+    fn zlib_stream() {
+        let data = sample();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for &level in [CompressionLevel::Fast, CompressionLevel::Default, CompressionLevel::High].iter() {
+            let mut options = Options::new();
+            options.set_chunk_size(65536).unwrap();
+            options.set_compression_level(level).unwrap();
+            options.set_thread_pool(&pool).unwrap();
+            let zlib = compress_zlib(&options, &data).unwrap();
+            assert_eq!((u16::from(zlib[0]) * 256 + u16::from(zlib[1])) % 31, 0);
+            assert_eq!(inflate(&zlib), data);
+        }
+
+        let empty = compress_zlib(&Options::new(), &[]).unwrap();
+        assert!(inflate(&empty).is_empty());
+    }
+
+    #[test]
+    fn raw_and_members() {
+        let data = sample();
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+        assert!(options.set_chunk_size(1000).is_err());
+
+        let pieces = compress_raw(&options, &data).unwrap();
+        assert_eq!(pieces.len(), 5);
+        assert_eq!(inflate(&wrap(&pieces.concat(), &data)), data);
+
+        // Each member stands alone, and without the dictionary
+        // they come out a little bigger.
+        let members = compress_members(&options, &data).unwrap();
+        assert_eq!(members.len(), 5);
+        for (member, chunk) in members.iter().zip(data.chunks(65536)) {
+            assert_eq!(inflate(&wrap(member, chunk)), chunk);
+        }
+        assert!(members.concat().len() > pieces.concat().len());
+
+        // Chunks can be driven by hand as well.
+        let (a, b) = data.split_at(100_000);
+        let mut raw = compress_chunk(&options, &[], a, Flush::SyncFlush).unwrap();
+        raw.extend(compress_chunk(&options, a, b, Flush::Finish).unwrap());
+        assert_eq!(inflate(&wrap(&raw, &data)), data);
+    }
+}
@@ -0,0 +1,752 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// decoder.rs - PNG decoder for verification and round-trips
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! PNG decoding, for verifying and round-tripping encoded files.
+//!
+//! Decodes to the same packed row layout the encoder takes as input,
+//! so decoded data may be fed straight back into an Encoder.
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+
+use rayon::prelude::*;
+
+use super::checksum;
+
+use super::ColorType;
+use super::Header;
+use super::layout;
+use super::layout::ADAM7;
+use super::metadata::{ChunkPosition, Metadata};
+
+use super::filter::Filter;
+use super::filter::unfilter;
+use super::inflate::Inflate;
+
+use super::utils::*;
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// A decoded PNG image.
+pub struct Decoded {
+    header: Header,
+    interlaced: bool,
+    data: Vec<u8>,
+    palette: Option<Vec<u8>>,
+    transparency: Option<Vec<u8>>,
+    metadata: Metadata,
+}
+
+impl Decoded {
+    /// Header for the decoded data. Interlaced files are deinterlaced,
+    /// so the header always describes non-interlaced rows.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Whether the source file was Adam7 interlaced.
+    pub fn is_interlaced(&self) -> bool {
+        self.interlaced
+    }
+
+    /// Packed pixel rows, with no padding between rows.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Take the packed pixel rows.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// PLTE chunk contents, if present.
+    pub fn palette(&self) -> Option<&[u8]> {
+        self.palette.as_deref()
+    }
+
+    /// tRNS chunk contents, if present.
+    pub fn transparency(&self) -> Option<&[u8]> {
+        self.transparency.as_deref()
+    }
+
+    /// Ancillary chunk metadata from the file.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+//
+// Bytes in a packed row of the given width.
+//
+fn row_bytes(header: &Header, width: usize) -> io::Result<usize> {
+    // Pass widths never exceed the header's, so this fits in u32.
+    layout::stride(header.color_type, header.depth, width as u32)
+        .ok_or_else(|| invalid_input("Image too large"))
+}
+
+fn read_be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn parse_header(data: &[u8]) -> io::Result<(Header, bool)> {
+    if data.len() != 13 {
+        return Err(invalid_input("Invalid IHDR length"));
+    }
+    let mut header = Header::new();
+    header.set_size(read_be32(&data[0 .. 4]), read_be32(&data[4 .. 8]))?;
+    header.set_color(ColorType::try_from(data[9])?, data[8])?;
+    if data[10] != 0 {
+        return Err(invalid_input("Unsupported compression method"));
+    }
+    if data[11] != 0 {
+        return Err(invalid_input("Unsupported filter method"));
+    }
+    let interlaced = match data[12] {
+        0 => false,
+        1 => true,
+        _ => return Err(invalid_input("Unsupported interlace method")),
+    };
+    Ok((header, interlaced))
+}
+
+//
+// Unfilter a sequence of rows from the decompressed stream into
+// packed output, returning the remainder of the stream.
+//
+fn unfilter_rows<'a>(header: &Header, width: usize, height: usize, raw: &'a [u8], out: &mut [u8])
+    -> io::Result<&'a [u8]>
+{
+    let stride = row_bytes(header, width)?;
+    let bpp = header.bytes_per_pixel();
+    if raw.len() < (stride + 1) * height {
+        return Err(invalid_input("Not enough image data"));
+    }
+    let mut prev = vec![0u8; stride];
+    for (y, row) in out.chunks_exact_mut(stride).take(height).enumerate() {
+        let src = &raw[y * (stride + 1) .. (y + 1) * (stride + 1)];
+        let filter = Filter::try_from(src[0])?;
+        row.copy_from_slice(&src[1 ..]);
+        unfilter(filter, bpp, &prev, row);
+        prev.copy_from_slice(row);
+    }
+    Ok(&raw[(stride + 1) * height ..])
+}
+
+//
+// Reconstruct the seven Adam7 passes and scatter their pixels
+// into a full-size packed image.
+//
+// Each pass's rows only depend on the rows above them in the same
+// pass, so the passes are unfiltered in parallel on the current
+// thread pool once the stream is split up between them.
+//
+fn deinterlace(header: &Header, raw: &[u8], out: &mut [u8]) -> io::Result<usize> {
+    let width = header.width as usize;
+    let height = header.height as usize;
+    let stride = row_bytes(header, width)?;
+    let bits = header.color_type.channels() * header.depth as usize;
+
+    let mut passes = Vec::new();
+    let mut used = 0;
+    for &(x0, y0, dx, dy) in ADAM7.iter() {
+        if width <= x0 || height <= y0 {
+            continue;
+        }
+        let pass_width = (width - x0).div_ceil(dx);
+        let pass_height = (height - y0).div_ceil(dy);
+        let size = (row_bytes(header, pass_width)? + 1) * pass_height;
+        if raw.len() - used < size {
+            return Err(invalid_input("Not enough image data"));
+        }
+        passes.push(((x0, y0, dx, dy), pass_width, pass_height, &raw[used .. used + size]));
+        used += size;
+    }
+
+    let unfiltered = passes.par_iter().map(|&(_, pass_width, pass_height, src)| {
+        let mut pass = vec![0u8; row_bytes(header, pass_width)? * pass_height];
+        unfilter_rows(header, pass_width, pass_height, src, &mut pass)?;
+        Ok(pass)
+    }).collect::<io::Result<Vec<Vec<u8>>>>()?;
+
+    for (&((x0, y0, dx, dy), pass_width, _, _), pass) in passes.iter().zip(unfiltered.iter()) {
+        let pass_stride = row_bytes(header, pass_width)?;
+        for (py, src) in pass.chunks_exact(pass_stride).enumerate() {
+            let dest = &mut out[(y0 + py * dy) * stride .. (y0 + py * dy + 1) * stride];
+            for px in 0 .. pass_width {
+                let x = x0 + px * dx;
+                if bits >= 8 {
+                    let n = bits / 8;
+                    dest[x * n .. (x + 1) * n].copy_from_slice(&src[px * n .. (px + 1) * n]);
+                } else {
+                    // Sub-byte pixels are packed high bits first.
+                    let mask = (1u8 << bits) - 1;
+                    let src_shift = 8 - bits - (px * bits) % 8;
+                    let dest_shift = 8 - bits - (x * bits) % 8;
+                    let val = (src[px * bits / 8] >> src_shift) & mask;
+                    dest[x * bits / 8] |= val << dest_shift;
+                }
+            }
+        }
+    }
+    Ok(used)
+}
+
+/// Push-based decoder for PNG data arriving in pieces, such as
+/// over a network connection.
+///
+/// Feed it bytes as they come in, and it hands back each row of pixel
+/// data as soon as it can be reconstructed, in the same packed layout
+/// as decode(). Rows of interlaced files can only be put together once
+/// the last pass arrives, so they all come back at the end.
+///
+/// Chunk checksums and ordering are checked as in decode().
+pub struct StreamDecoder {
+    // Input not yet parsed into chunks.
+    buffer: Vec<u8>,
+    signature: bool,
+    header: Option<(Header, bool)>,
+    palette: Option<Vec<u8>>,
+    transparency: Option<Vec<u8>>,
+    metadata: Metadata,
+    inflate: Inflate,
+    // Decompressed image data not yet unfiltered.
+    raw: Vec<u8>,
+    prev: Vec<u8>,
+    rows_out: usize,
+    in_idat: bool,
+    done_idat: bool,
+    finished: bool,
+}
+
+impl StreamDecoder {
+    /// Create a decoder waiting for the start of a PNG file.
+    pub fn new() -> StreamDecoder {
+        StreamDecoder {
+            buffer: Vec::new(),
+            signature: false,
+            header: None,
+            palette: None,
+            transparency: None,
+            metadata: Metadata::default(),
+            inflate: Inflate::new(),
+            raw: Vec::new(),
+            prev: Vec::new(),
+            rows_out: 0,
+            in_idat: false,
+            done_idat: false,
+            finished: false,
+        }
+    }
+
+    /// Header of the image, once the IHDR chunk has arrived.
+    /// Interlaced files are described as non-interlaced, as for decode().
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref().map(|(header, _)| header)
+    }
+
+    /// Whether the file is Adam7 interlaced, once the IHDR chunk has arrived.
+    pub fn is_interlaced(&self) -> Option<bool> {
+        self.header.map(|(_, interlaced)| interlaced)
+    }
+
+    /// PLTE chunk contents, once seen.
+    pub fn palette(&self) -> Option<&[u8]> {
+        self.palette.as_deref()
+    }
+
+    /// tRNS chunk contents, once seen.
+    pub fn transparency(&self) -> Option<&[u8]> {
+        self.transparency.as_deref()
+    }
+
+    /// Ancillary chunk metadata seen so far.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Whether the IEND chunk has been reached. Any further input is ignored.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Decode the given input, returning any rows completed by it.
+    pub fn feed(&mut self, bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut rows = Vec::new();
+        if self.finished {
+            return Ok(rows);
+        }
+        self.buffer.extend_from_slice(bytes);
+
+        let mut pos = 0;
+        if !self.signature {
+            if self.buffer.len() < SIGNATURE.len() {
+                return Ok(rows);
+            }
+            if self.buffer[.. SIGNATURE.len()] != SIGNATURE {
+                return Err(invalid_input("Not a PNG file"));
+            }
+            self.signature = true;
+            pos = SIGNATURE.len();
+        }
+
+        while !self.finished && self.buffer.len() - pos >= 8 {
+            let length = read_be32(&self.buffer[pos ..]);
+            if length > 0x7fff_ffff {
+                return Err(invalid_input("Chunk too long"));
+            }
+            let end = pos + 12 + length as usize;
+            if self.buffer.len() < end {
+                break;
+            }
+            let chunk = self.buffer[pos + 4 .. end].to_vec();
+            pos = end;
+
+            let (body, crc) = chunk.split_at(length as usize + 4);
+            if checksum::crc32(body) != read_be32(crc) {
+                return Err(invalid_input("Chunk checksum mismatch"));
+            }
+            let (tag, data) = body.split_at(4);
+            self.chunk(tag, data, &mut rows)?;
+        }
+        self.buffer.drain(.. pos);
+        Ok(rows)
+    }
+
+    fn chunk(&mut self, tag: &[u8], data: &[u8], rows: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        if self.header.is_none() && tag != b"IHDR" {
+            return Err(invalid_input("First chunk must be IHDR"));
+        }
+        if self.in_idat && tag != b"IDAT" {
+            self.in_idat = false;
+            self.done_idat = true;
+        }
+        match tag {
+            b"IHDR" => {
+                if self.header.is_some() {
+                    return Err(invalid_input("Duplicate IHDR"));
+                }
+                let (header, interlaced) = parse_header(data)?;
+                self.prev = vec![0u8; row_bytes(&header, header.width as usize)?];
+                self.header = Some((header, interlaced));
+            },
+            b"PLTE" => {
+                if self.palette.is_some() || self.transparency.is_some() || self.in_idat || self.done_idat {
+                    return Err(invalid_input("Misplaced PLTE"));
+                }
+                if data.is_empty() || !data.len().is_multiple_of(3) || data.len() > 256 * 3 {
+                    return Err(invalid_input("Invalid PLTE length"));
+                }
+                self.palette = Some(data.to_vec());
+            },
+            b"tRNS" => {
+                if self.transparency.is_some() || self.in_idat || self.done_idat {
+                    return Err(invalid_input("Misplaced tRNS"));
+                }
+                self.transparency = Some(data.to_vec());
+            },
+            b"IDAT" => {
+                if self.done_idat {
+                    return Err(invalid_input("IDAT chunks must be consecutive"));
+                }
+                let (header, interlaced) = self.header.unwrap();
+                if !self.in_idat && header.color_type as u8 == ColorType::IndexedColor as u8 && self.palette.is_none() {
+                    return Err(invalid_input("Indexed image has no palette"));
+                }
+                self.in_idat = true;
+                self.inflate.write(data, &mut self.raw)?;
+                if !interlaced {
+                    self.unfilter_ready(&header, rows)?;
+                }
+            },
+            b"IEND" => {
+                self.finish(rows)?;
+            },
+            _ => {
+                if tag[0].is_ascii_uppercase() {
+                    return Err(invalid_input("Unknown critical chunk"));
+                }
+                let position = if self.done_idat {
+                    ChunkPosition::AfterImage
+                } else if self.palette.is_some() {
+                    ChunkPosition::BeforeImage
+                } else {
+                    ChunkPosition::BeforePalette
+                };
+                self.metadata.add_chunk(tag, data, position)?;
+            },
+        }
+        Ok(())
+    }
+
+    //
+    // Unfilter as many whole rows as have been decompressed.
+    //
+    fn unfilter_ready(&mut self, header: &Header, rows: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        let stride = self.prev.len();
+        let bpp = header.bytes_per_pixel();
+        let height = header.height as usize;
+        let mut used = 0;
+        while self.raw.len() - used > stride && self.rows_out < height {
+            let src = &self.raw[used .. used + stride + 1];
+            let filter = Filter::try_from(src[0])?;
+            let mut row = src[1 ..].to_vec();
+            unfilter(filter, bpp, &self.prev, &mut row);
+            self.prev.copy_from_slice(&row);
+            rows.push(row);
+            self.rows_out += 1;
+            used += stride + 1;
+        }
+        self.raw.drain(.. used);
+        if self.rows_out == height && !self.raw.is_empty() {
+            return Err(invalid_input("Too much image data"));
+        }
+        Ok(())
+    }
+
+    //
+    // Check the image data is complete at IEND, and put together
+    // the rows of an interlaced image.
+    //
+    fn finish(&mut self, rows: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        let (header, interlaced) = self.header.unwrap();
+        if !self.inflate.is_finished() {
+            return Err(invalid_input("Image data stream is incomplete"));
+        }
+        let stride = self.prev.len();
+        if interlaced {
+            let size = stride.checked_mul(header.height as usize)
+                             .ok_or_else(|| invalid_input::<io::Error>("Image too large"))?;
+            let mut data = vec![0u8; size];
+            let used = deinterlace(&header, &self.raw, &mut data)?;
+            if used != self.raw.len() {
+                return Err(invalid_input("Too much image data"));
+            }
+            rows.extend(data.chunks(stride.max(1)).take(header.height as usize).map(|row| row.to_vec()));
+        } else if self.rows_out < header.height as usize {
+            return Err(invalid_input("Not enough image data"));
+        }
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode a complete PNG file from the given reader.
+///
+/// Chunk checksums and ordering are checked, as in a strict decoder.
+/// Ancillary chunks other than tRNS are collected into the metadata,
+/// and malformed ones are an error.
+///
+/// The passes of interlaced files are unfiltered in parallel on the
+/// current Rayon thread pool; call from ThreadPool::install to use
+/// a custom one.
+pub fn decode<R: Read>(mut reader: R) -> io::Result<Decoded> {
+    let mut decoder = StreamDecoder::new();
+    let mut data = Vec::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    while !decoder.is_finished() {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Err(invalid_input("Truncated PNG file"));
+        }
+        for row in decoder.feed(&buffer[.. n])? {
+            data.extend_from_slice(&row);
+        }
+    }
+
+    let (header, interlaced) = decoder.header.unwrap();
+    Ok(Decoded {
+        header,
+        interlaced,
+        data,
+        palette: decoder.palette,
+        transparency: decoder.transparency,
+        metadata: decoder.metadata,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::StreamDecoder;
+    use super::super::checksum;
+    use super::super::deflate::compress;
+    use super::super::metadata::ChunkPosition;
+    use super::super::ColorType;
+    use super::super::Header;
+    use super::super::layout::ADAM7;
+    use super::super::encoder::{Encoder, Options};
+
+    fn encode(color_type: ColorType, depth: u8, width: u32, height: u32) -> (Vec<u8>, Vec<u8>) {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(color_type, depth).unwrap();
+
+        let bits = color_type.channels() * depth as usize;
+        let stride = (bits * width as usize).div_ceil(8);
+        let mut data: Vec<u8> = (0 .. stride * height as usize).map(|i| (i * 31 % 256) as u8).collect();
+        // Clear padding bits at the end of each row.
+        let pad = stride * 8 - bits * width as usize;
+        for row in data.chunks_exact_mut(stride) {
+            row[stride - 1] &= 0xffu8 << pad;
+        }
+
+        let mut encoder = Encoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        if depth <= 8 && color_type as u8 == ColorType::IndexedColor as u8 {
+            encoder.write_palette(&[0u8; 256 * 3][.. 3 << depth]).unwrap();
+        }
+        encoder.write_image_rows(&data).unwrap();
+        (encoder.finish().unwrap(), data)
+    }
+
+    #[test]
+    fn round_trip() {
+        let cases = [
+            (ColorType::Greyscale, 1),
+            (ColorType::Greyscale, 2),
+            (ColorType::Greyscale, 16),
+            (ColorType::Truecolor, 8),
+            (ColorType::Truecolor, 16),
+            (ColorType::IndexedColor, 4),
+            (ColorType::GreyscaleAlpha, 8),
+            (ColorType::TruecolorAlpha, 16),
+        ];
+        for &(color_type, depth) in cases.iter() {
+            // Odd widths leave partial bytes at the end of sub-byte rows.
+            for &width in [16, 13, 5, 1].iter() {
+                let (png, data) = encode(color_type, depth, width, 7);
+                let decoded = decode(&png[..]).unwrap();
+                assert_eq!(decoded.header().width(), width);
+                assert_eq!(decoded.header().depth(), depth);
+                assert!(!decoded.is_interlaced());
+                assert_eq!(decoded.data(), &data[..]);
+            }
+        }
+    }
+
+    fn push_chunk(out: &mut Vec<u8>, tag: &[u8], data: &[u8]) {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend_from_slice(tag);
+        out.extend_from_slice(data);
+        let crc = checksum::crc32(&out[start ..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    // Re-encode the image in a non-interlaced PNG as Adam7,
+    // leaving every pass row unfiltered, and adding or dropping
+    // bytes at the end of the image data.
+    fn interlace(png: &[u8], data: &[u8], extra: isize) -> Vec<u8> {
+        let decoded = decode(png).unwrap();
+        let header = decoded.header();
+        let width = header.width() as usize;
+        let height = header.height() as usize;
+        let bits = header.color_type().channels() * header.depth() as usize;
+        let stride = (bits * width).div_ceil(8);
+
+        let mut raw = Vec::new();
+        for &(x0, y0, dx, dy) in ADAM7.iter() {
+            if width <= x0 || height <= y0 {
+                continue;
+            }
+            let pass_width = (width - x0).div_ceil(dx);
+            for y in (y0 .. height).step_by(dy) {
+                let mut row = vec![0u8; (bits * pass_width).div_ceil(8)];
+                for (px, x) in (x0 .. width).step_by(dx).enumerate() {
+                    for bit in 0 .. bits {
+                        let src = x * bits + bit;
+                        let dest = px * bits + bit;
+                        if data[y * stride + src / 8] & (0x80 >> (src % 8)) != 0 {
+                            row[dest / 8] |= 0x80 >> (dest % 8);
+                        }
+                    }
+                }
+                raw.push(0);
+                raw.extend_from_slice(&row);
+            }
+        }
+        raw.resize((raw.len() as isize + extra) as usize, 0);
+
+        let mut ihdr = png[16 .. 29].to_vec();
+        ihdr[12] = 1;
+        let mut out = png[.. 8].to_vec();
+        push_chunk(&mut out, b"IHDR", &ihdr);
+        if let Some(palette) = decoded.palette() {
+            push_chunk(&mut out, b"PLTE", palette);
+        }
+        push_chunk(&mut out, b"IDAT", &compress(&raw).unwrap());
+        push_chunk(&mut out, b"IEND", b"");
+        out
+    }
+
+    #[test]
+    fn interlaced() {
+        let cases = [
+            (ColorType::Greyscale, 1),
+            (ColorType::Greyscale, 4),
+            (ColorType::Truecolor, 8),
+            (ColorType::IndexedColor, 2),
+            (ColorType::TruecolorAlpha, 16),
+        ];
+        for &(color_type, depth) in cases.iter() {
+            // Small sizes leave some passes empty.
+            for &(width, height) in [(33, 17), (13, 5), (3, 1), (1, 1)].iter() {
+                let (png, data) = encode(color_type, depth, width, height);
+                let interlaced = interlace(&png, &data, 0);
+                let decoded = decode(&interlaced[..]).unwrap();
+                assert!(decoded.is_interlaced());
+                assert_eq!(decoded.header().width(), width);
+                assert_eq!(decoded.data(), &data[..]);
+
+                let mut decoder = StreamDecoder::new();
+                let rows = decoder.feed(&interlaced).unwrap();
+                assert_eq!(decoder.is_interlaced(), Some(true));
+                assert_eq!(rows.concat(), data);
+
+                // Cutting the last pass short, or running over, is caught.
+                assert!(decode(&interlace(&png, &data, -1)[..]).is_err());
+                assert!(decode(&interlace(&png, &data, 1)[..]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let mut header = Header::new();
+        header.set_size(1, 1).unwrap();
+        let mut encoder = Encoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_chunk(b"gAMA", &45455u32.to_be_bytes()).unwrap();
+        let chrm: Vec<u8> = [31270u32, 32900, 64000, 33000, 30000, 60000, 15000, 6000].iter()
+                                                                                   .flat_map(|v| v.to_be_bytes())
+                                                                                   .collect();
+        encoder.write_chunk(b"cHRM", &chrm).unwrap();
+        encoder.write_chunk(b"pHYs", &[0, 0, 0x0b, 0x13, 0, 0, 0x0b, 0x13, 1]).unwrap();
+        encoder.write_chunk(b"tIME", &[0x07, 0xe8, 2, 29, 23, 59, 60]).unwrap();
+        encoder.write_chunk(b"prIV", b"secret").unwrap();
+        encoder.write_text("Title", "Caf\u{e9}").unwrap();
+        encoder.write_text("Comment", "\u{2603}").unwrap();
+        encoder.write_image_rows(&[1, 2, 3, 4]).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let decoded = decode(&png[..]).unwrap();
+        let metadata = decoded.metadata();
+        assert_eq!(metadata.gamma(), Some(0.45455));
+        let chrm = metadata.chromaticities().unwrap();
+        assert_eq!(chrm.white(), (0.3127, 0.329));
+        assert_eq!(chrm.blue(), (0.15, 0.06));
+        let phys = metadata.physical_dimensions().unwrap();
+        assert_eq!((phys.x(), phys.y(), phys.is_meters()), (2835, 2835, true));
+        let time = metadata.time().unwrap();
+        assert_eq!((time.year(), time.month(), time.day(), time.second()), (2024, 2, 29, 60));
+        assert!(metadata.icc_profile().is_none());
+
+        let text = metadata.text();
+        assert_eq!(text.len(), 2);
+        assert_eq!((text[0].keyword(), text[0].text()), ("Title", "Caf\u{e9}"));
+        assert!(!text[0].is_international());
+        assert_eq!((text[1].keyword(), text[1].text()), ("Comment", "\u{2603}"));
+        assert!(text[1].is_international() && !text[1].is_compressed());
+
+        let unknown = metadata.unknown();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].tag(), b"prIV");
+        assert_eq!(unknown[0].data(), b"secret");
+        assert_eq!(unknown[0].position(), ChunkPosition::BeforePalette);
+
+        // Compressed text comes back as written.
+        let mut options = Options::new();
+        options.set_text_compression(Some(0)).unwrap();
+        let mut encoder = Encoder::new(Vec::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_text("Title", "Caf\u{e9}").unwrap();
+        encoder.write_text("Comment", "\u{2603}").unwrap();
+        encoder.write_image_rows(&[1, 2, 3, 4]).unwrap();
+        let png = encoder.finish().unwrap();
+        let decoded = decode(&png[..]).unwrap();
+        let text = decoded.metadata().text();
+        assert_eq!(text[0].text(), "Caf\u{e9}");
+        assert_eq!(text[1].text(), "\u{2603}");
+        assert!(text.iter().all(|t| t.is_compressed()));
+    }
+
+    #[test]
+    fn streaming() {
+        // Big enough to span several compressed chunks, each in its own IDAT.
+        let mut header = Header::new();
+        header.set_size(512, 256).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 512 * 3 * 256usize).map(|i| (i * i / 7 % 251) as u8).collect();
+        let mut options = Options::new();
+        options.set_streaming(true).unwrap();
+        options.set_chunk_size(32768).unwrap();
+        let mut encoder = Encoder::new(Vec::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let mut decoder = StreamDecoder::new();
+        let mut rows = Vec::new();
+        let mut progress = Vec::new();
+        for piece in png.chunks(97) {
+            assert!(!decoder.is_finished());
+            rows.extend(decoder.feed(piece).unwrap());
+            progress.push(rows.len());
+        }
+        assert!(decoder.is_finished());
+        assert!(decoder.feed(b"trailing").unwrap().is_empty());
+        assert_eq!(decoder.header().unwrap().width(), 512);
+        assert_eq!(rows.len(), 256);
+        assert!(rows.iter().all(|row| row.len() == 512 * 3));
+        assert_eq!(rows.concat(), data);
+        // Rows come out as the data arrives, not all at the end.
+        assert!(progress.iter().any(|&n| n > 0 && n < 256));
+
+        let mut decoder = StreamDecoder::new();
+        assert!(decoder.feed(b"GIF89a..").is_err());
+    }
+
+    #[test]
+    fn corrupt_checksum() {
+        let (mut png, _) = encode(ColorType::Truecolor, 8, 4, 4);
+        let last = png.len() - 20;
+        png[last] ^= 1;
+        assert!(decode(&png[..]).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let (png, _) = encode(ColorType::Truecolor, 8, 4, 4);
+        assert!(decode(&png[.. png.len() - 12]).is_err());
+        assert!(decode(&png[.. 4]).is_err());
+    }
+}
@@ -23,40 +23,76 @@
 // THE SOFTWARE.
 //
 
+use std::cell::RefCell;
 use std::io;
 use std::io::Write;
 
 use std::mem;
 
-use std::ptr;
-
 use std::convert::TryFrom;
 
 use std::os::raw::*;
 
 use ::libz_sys::*;
 
+use super::checksum;
+use super::fixed::FixedEncoder;
 use super::utils::*;
 
-pub fn adler32(sum: u32, bytes: &[u8]) -> u32 {
-    unsafe {
-        ::libz_sys::adler32(c_ulong::from(sum), &bytes[0], bytes.len() as c_uint) as u32
-    }
+/// Deflate compression implementations.
+///
+/// zlib is always built in; the others need their cargo features.
+#[derive(Copy, Clone)]
+pub enum Backend {
+    /// The C zlib library, via libz-sys.
+    Zlib,
+    /// The pure-Rust miniz_oxide library, with the "miniz" feature.
+    /// It cannot use a preset dictionary, so compresses slightly
+    /// worse at chunk boundaries.
+    Miniz,
+    /// The pure-Rust zlib-rs library, with the "zlib-rs" feature.
+    ZlibRs,
+    /// A built-in encoder that uses a precomputed Huffman code suited
+    /// to filtered image data, and only matches runs of a repeated
+    /// byte, in the manner of fdeflate.
+    /// Several times faster than zlib at level 1, for somewhat larger
+    /// files. Ignores the compression level and strategy.
+    Fastest,
 }
 
-pub fn adler32_initial() -> u32 {
-    unsafe {
-        ::libz_sys::adler32(0, ptr::null(), 0) as u32
+impl Backend {
+    /// Whether this backend was compiled in.
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::Zlib => true,
+            Backend::Miniz => cfg!(feature = "miniz"),
+            Backend::ZlibRs => cfg!(feature = "zlib-rs"),
+            Backend::Fastest => true,
+        }
+    }
+
+    /// All backends that were compiled in.
+    pub fn available() -> Vec<Backend> {
+        [Backend::Zlib, Backend::Miniz, Backend::ZlibRs, Backend::Fastest].iter()
+                                                                        .cloned()
+                                                                        .filter(|b| b.is_available())
+                                                                        .collect()
     }
-}
 
-pub fn adler32_combine(sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
-    unsafe {
-        ::libz_sys::adler32_combine(c_ulong::from(sum_a), c_ulong::from(sum_b), len_b as c_long) as u32
+    /// Short lowercase name, as used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Zlib => "zlib",
+            Backend::Miniz => "miniz",
+            Backend::ZlibRs => "zlib-rs",
+            Backend::Fastest => "fastest",
+        }
     }
 }
 
 pub struct Options {
+    backend: Backend,
+    zopfli: bool,
     level: c_int,
     method: c_int,
     window_bits: c_int,
@@ -65,7 +101,7 @@ pub struct Options {
 }
 
 #[repr(i32)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum Strategy {
     Default = Z_DEFAULT_STRATEGY,
     Filtered = Z_FILTERED,
@@ -92,6 +128,8 @@ impl TryFrom<u8> for Strategy {
 impl Options {
     pub fn new() -> Options {
         Options {
+            backend: Backend::Zlib,
+            zopfli: false,
             level: Z_DEFAULT_COMPRESSION,
             method: Z_DEFLATED,
             window_bits: 15,
@@ -118,47 +156,242 @@ impl Options {
     pub fn set_strategy(&mut self, strategy: Strategy) {
         self.strategy = strategy as c_int;
     }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    //
+    // Compress with Zopfli instead of the backend, ignoring the
+    // level and strategy. Needs the "zopfli" feature.
+    //
+    pub fn set_zopfli(&mut self, zopfli: bool) {
+        self.zopfli = zopfli;
+    }
+
+    // Everything deflateInit2_ is given, to match up spare streams.
+    fn zlib_params(&self) -> [c_int; 5] {
+        [self.level, self.method, self.window_bits, self.mem_level, self.strategy]
+    }
+
+    // Level with the default resolved, for backends that want a number.
+    #[cfg(any(feature = "miniz", feature = "zlib-rs"))]
+    fn effective_level(&self) -> i32 {
+        if self.level == Z_DEFAULT_COMPRESSION {
+            6
+        } else {
+            self.level
+        }
+    }
+}
+
+//
+// Compress a buffer in one go as a zlib stream, for chunks such
+// as zTXt, iTXt, and iCCP that hold compressed data of their own.
+//
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut options = Options::new();
+    options.set_level(9);
+    let mut deflate = Deflate::new(options, Vec::new());
+    deflate.write(data, Flush::Finish)?;
+    deflate.finish()
+}
+
+//
+// Replace the empty fixed-code final block at the end of Zopfli's
+// output (a set final bit, block type 01, and a zero end code) with
+// an empty non-final stored block, byte aligned.
+//
+#[cfg(feature = "zopfli")]
+fn zopfli_sync_flush(out: &mut Vec<u8>) -> IoResult {
+    // The last set bit is the low bit of the block type.
+    let last = out.iter().rposition(|&byte| byte != 0).map(|i| i * 8 + 7 - out[i].leading_zeros() as usize);
+    let start = match last {
+        Some(bit) if bit >= 1 && (8 ..= 15).contains(&(out.len() * 8 - 1 - bit)) => bit - 1,
+        _ => return Err(other("Unexpected end of Zopfli output")),
+    };
+    if out[start / 8] & (1 << (start % 8)) == 0 {
+        return Err(other("Unexpected end of Zopfli output"));
+    }
+    // Three zero bits for the stored block header, then padding.
+    out.truncate((start + 3).div_ceil(8));
+    out[start / 8] &= (1 << (start % 8)) - 1;
+    for byte in out[start / 8 + 1 ..].iter_mut() {
+        *byte = 0;
+    }
+    out.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+    Ok(())
+}
+
+//
+// A zlib stream set up by deflateInit2_ with the given parameters,
+// reset and ready for a new stream.
+//
+struct SpareStream {
+    params: [c_int; 5],
+    stream: Option<Box<z_stream>>,
+}
+
+impl Drop for SpareStream {
+    fn drop(&mut self) {
+        if let Some(ref mut stream) = self.stream {
+            unsafe {
+                deflateEnd(&mut **stream);
+            }
+        }
+    }
 }
 
+// Enough for the first and later chunks at a couple of settings.
+const MAX_SPARE_STREAMS: usize = 4;
+
+thread_local! {
+    //
+    // Streams left by chunks compressed earlier on this thread, since
+    // setting up a stream and tearing it down costs as much as
+    // compressing a small chunk. Freed when the thread exits.
+    //
+    static SPARE_STREAMS: RefCell<Vec<SpareStream>> = const { RefCell::new(Vec::new()) };
+}
+
+fn take_stream(params: [c_int; 5]) -> Option<Box<z_stream>> {
+    SPARE_STREAMS.with(|spare| {
+        let mut spare = spare.borrow_mut();
+        let index = spare.iter().position(|s| s.params == params)?;
+        spare.swap_remove(index).stream.take()
+    })
+}
+
+// Reset the stream for reuse, or free it if there's no room.
+fn give_stream(params: [c_int; 5], mut stream: Box<z_stream>) -> IoResult {
+    match unsafe { deflateReset(&mut *stream) } {
+        Z_OK => {},
+        _ => {
+            unsafe {
+                deflateEnd(&mut *stream);
+            }
+            return Err(invalid_input("Inconsistent stream state"));
+        },
+    }
+    SPARE_STREAMS.with(|spare| {
+        // Dropping the oldest spare frees it.
+        let mut spare = spare.borrow_mut();
+        if spare.len() >= MAX_SPARE_STREAMS {
+            spare.remove(0);
+        }
+        spare.push(SpareStream {
+            params,
+            stream: Some(stream),
+        });
+    });
+    Ok(())
+}
+
+/// How to end a run of compressed data.
 #[derive(Copy, Clone)]
 pub enum Flush {
     // Only SyncFlush and Finish are used internally.
 
     //NoFlush = Z_NO_FLUSH as isize,
     //PartialFlush = Z_PARTIAL_FLUSH as isize,
+    /// Align to a byte boundary so that more compressed data can
+    /// follow in the same stream.
     SyncFlush = Z_SYNC_FLUSH as isize,
     //FullFlush = Z_FULL_FLUSH as isize,
+    /// End the stream.
     Finish = Z_FINISH as isize,
 }
 
+//
+// Compressor state for the chosen backend.
+// zlib is set up lazily on first use, the others on creation.
+//
+enum Engine {
+    Zlib(Box<z_stream>),
+    #[cfg(feature = "miniz")]
+    Miniz(Box<::miniz_oxide::deflate::core::CompressorOxide>),
+    #[cfg(feature = "zlib-rs")]
+    ZlibRs(Box<::zlib_rs::Deflate>),
+    Fastest(Box<FixedEncoder>),
+    #[cfg(feature = "zopfli")]
+    Zopfli,
+}
+
 pub struct Deflate<W: Write> {
     output: W,
     options: Options,
     initialized: bool,
     finished: bool,
-    stream: Box<z_stream>,
+    engine: Engine,
+
+    // Checksum of the input so far, for engines that leave
+    // the zlib header and trailer to us.
+    adler32: u32,
 }
 
 impl<W: Write> Deflate<W> {
     pub fn new(options: Options, w: W) -> Deflate<W> {
+        let engine = match options.backend {
+            #[cfg(feature = "zopfli")]
+            _ if options.zopfli => Engine::Zopfli,
+            Backend::Fastest => Engine::Fastest(Box::new(FixedEncoder::new())),
+            #[cfg(feature = "miniz")]
+            Backend::Miniz => {
+                use ::miniz_oxide::deflate::core::{CompressorOxide, create_comp_flags_from_zip_params};
+                let flags = create_comp_flags_from_zip_params(options.effective_level(),
+                                                              options.window_bits,
+                                                              options.strategy);
+                Engine::Miniz(Box::new(CompressorOxide::new(flags)))
+            },
+            #[cfg(feature = "zlib-rs")]
+            Backend::ZlibRs => {
+                let strategy = match options.strategy {
+                    Z_FILTERED => ::zlib_rs::Strategy::Filtered,
+                    Z_HUFFMAN_ONLY => ::zlib_rs::Strategy::HuffmanOnly,
+                    Z_RLE => ::zlib_rs::Strategy::Rle,
+                    Z_FIXED => ::zlib_rs::Strategy::Fixed,
+                    _ => ::zlib_rs::Strategy::Default,
+                };
+                let config = ::zlib_rs::DeflateConfig {
+                    level: options.effective_level(),
+                    window_bits: options.window_bits,
+                    mem_level: options.mem_level,
+                    strategy,
+                    ..Default::default()
+                };
+                Engine::ZlibRs(Box::new(::zlib_rs::Deflate::new_with_config(config)))
+            },
+            // Unavailable backends are rejected when setting options.
+            _ => Engine::Zlib(Box::new(unsafe {
+                let maybe = mem::MaybeUninit::<z_stream>::zeroed();
+                maybe.assume_init()
+            })),
+        };
         Deflate {
             output: w,
             options,
             initialized: false,
             finished: false,
-            stream: Box::new(unsafe {
-                let maybe = mem::MaybeUninit::<z_stream>::zeroed();
-                maybe.assume_init()
-            }),
+            engine,
+            adler32: checksum::adler32_initial(),
         }
     }
 
     pub fn init(&mut self) -> IoResult {
+        let stream = match self.engine {
+            Engine::Zlib(ref mut stream) => stream,
+            _ => return Ok(()),
+        };
         if self.initialized {
             Ok(())
+        } else if let Some(spare) = take_stream(self.options.zlib_params()) {
+            *stream = spare;
+            self.initialized = true;
+            Ok(())
         } else {
+            let stream = &mut **stream;
             let ret = unsafe {
-                deflateInit2_(&mut *self.stream,
+                deflateInit2_(stream,
                               self.options.level,
                               self.options.method,
                               self.options.window_bits,
@@ -172,33 +405,61 @@ impl<W: Write> Deflate<W> {
                     self.initialized = true;
                     Ok(())
                 },
-                Z_MEM_ERROR => Err(other("Out of memory")),
-                Z_STREAM_ERROR => Err(invalid_input("Invalid parameter")),
-                Z_VERSION_ERROR => Err(invalid_input("Incompatible version of zlib")),
-                _ => Err(other("Unexpected error")),
+                _ => Err(zlib_error(ret)),
             }
         }
     }
 
     pub fn set_dictionary(&mut self, dict: &[u8]) -> IoResult {
         self.init()?;
-        let ret = unsafe {
-            deflateSetDictionary(&mut *self.stream,
-                                 &dict[0],
-                                 dict.len() as c_uint)
-        };
-        match ret {
-            Z_OK => Ok(()),
-            Z_STREAM_ERROR => Err(invalid_input("Invalid parameter")),
-            _ => Err(other("Unexpected error")),
+        match self.engine {
+            Engine::Zlib(ref mut stream) => {
+                let ret = unsafe {
+                    deflateSetDictionary(&mut **stream,
+                                         &dict[0],
+                                         dict.len() as c_uint)
+                };
+                match ret {
+                    Z_OK => Ok(()),
+                    _ => Err(zlib_error(ret)),
+                }
+            },
+            // miniz_oxide has no preset dictionary support; output is
+            // still valid, just without matches into the previous chunk.
+            #[cfg(feature = "miniz")]
+            Engine::Miniz(_) => Ok(()),
+            #[cfg(feature = "zlib-rs")]
+            Engine::ZlibRs(ref mut deflate) => {
+                deflate.set_dictionary(dict)
+                       .map(|_| ())
+                       .map_err(|e| invalid_input(e.as_str()))
+            },
+            // Nor do the engines that compress each call on its own.
+            Engine::Fastest(_) => Ok(()),
+            #[cfg(feature = "zopfli")]
+            Engine::Zopfli => Ok(()),
         }
     }
 
     fn deflate(&mut self, data: &[u8], flush: Flush) -> IoResult {
         self.init()?;
         let mut buffer = [0u8; 128 * 1024];
-        let stream = &mut *self.stream;
-        stream.next_in = &data[0] as *const u8 as *mut u8;
+        let stream = match self.engine {
+            Engine::Zlib(ref mut stream) => &mut **stream,
+            Engine::Fastest(ref mut encoder) => {
+                encoder.block(data, matches!(flush, Flush::Finish));
+                if let Flush::SyncFlush = flush {
+                    encoder.sync_flush();
+                }
+                let body = encoder.take();
+                return self.write_framed([0x78, 0x01], data, &body, flush);
+            },
+            #[cfg(feature = "zopfli")]
+            Engine::Zopfli => return self.deflate_zopfli(data, flush),
+            #[cfg(any(feature = "miniz", feature = "zlib-rs"))]
+            _ => return self.deflate_alt(data, flush, &mut buffer),
+        };
+        stream.next_in = data.as_ptr() as *mut u8;
         stream.avail_in = data.len() as c_uint;
         loop {
             stream.next_out = &mut buffer[0] as *mut u8;
@@ -231,11 +492,116 @@ impl<W: Write> Deflate<W> {
                         _ => unreachable!(),
                     }
                 },
-                Z_STREAM_ERROR => return Err(invalid_input("Inconsistent stream state")),
-                Z_BUF_ERROR => return Err(other("No progress possible")),
-                _ => return Err(other("Unexpected error")),
+                _ => return Err(zlib_error(ret)),
+            }
+        }
+    }
+
+    //
+    // Compression loop for the pure-Rust backends, which take slices
+    // and report how much they consumed and produced on each call.
+    //
+    #[cfg(any(feature = "miniz", feature = "zlib-rs"))]
+    fn deflate_alt(&mut self, data: &[u8], flush: Flush, buffer: &mut [u8]) -> IoResult {
+        let mut consumed = 0;
+        loop {
+            let (read, written, done) = match self.engine {
+                Engine::Zlib(_) | Engine::Fastest(_) => unreachable!(),
+                #[cfg(feature = "zopfli")]
+                Engine::Zopfli => unreachable!(),
+                #[cfg(feature = "miniz")]
+                Engine::Miniz(ref mut compressor) => {
+                    use ::miniz_oxide::{MZFlush, MZStatus};
+                    let mz_flush = match flush {
+                        Flush::SyncFlush => MZFlush::Sync,
+                        Flush::Finish => MZFlush::Finish,
+                    };
+                    let result = ::miniz_oxide::deflate::stream::deflate(compressor,
+                                                                         &data[consumed ..],
+                                                                         buffer,
+                                                                         mz_flush);
+                    match result.status {
+                        Ok(status) => (result.bytes_consumed,
+                                       result.bytes_written,
+                                       status == MZStatus::StreamEnd),
+                        Err(err) => return Err(zlib_error(err as i32)),
+                    }
+                },
+                #[cfg(feature = "zlib-rs")]
+                Engine::ZlibRs(ref mut deflate) => {
+                    use ::zlib_rs::{DeflateFlush, Status};
+                    let zflush = match flush {
+                        Flush::SyncFlush => DeflateFlush::SyncFlush,
+                        Flush::Finish => DeflateFlush::Finish,
+                    };
+                    let (total_in, total_out) = (deflate.total_in(), deflate.total_out());
+                    let status = deflate.compress(&data[consumed ..], buffer, zflush)
+                                        .map_err(|e| invalid_input::<io::Error>(e.as_str()))?;
+                    ((deflate.total_in() - total_in) as usize,
+                     (deflate.total_out() - total_out) as usize,
+                     status == Status::StreamEnd)
+                },
+            };
+            consumed += read;
+            self.output.write_all(&buffer[0 .. written])?;
+            if done {
+                self.finished = true;
+                return Ok(());
+            }
+            if consumed == data.len() && written < buffer.len() {
+                if let Flush::SyncFlush = flush {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    //
+    // Write raw deflate output from one of the engines that don't
+    // do their own zlib framing, adding the header before the first
+    // data and the checksum at the end when not in raw mode.
+    //
+    fn write_framed(&mut self, header: [u8; 2], data: &[u8], body: &[u8], flush: Flush) -> IoResult {
+        let zlib = self.options.window_bits > 0;
+        if zlib && !self.initialized {
+            self.output.write_all(&header)?;
+        }
+        self.initialized = true;
+        self.adler32 = checksum::adler32(self.adler32, data);
+        self.output.write_all(body)?;
+        if let Flush::Finish = flush {
+            if zlib {
+                self.output.write_all(&self.adler32.to_be_bytes())?;
             }
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    //
+    // Zopfli compresses each call's data in one go. It always ends
+    // on a final block, so for a sync flush it's given an empty last
+    // block to write, which is swapped out for the empty stored block
+    // that zlib ends a sync flush on.
+    //
+    #[cfg(feature = "zopfli")]
+    fn deflate_zopfli(&mut self, data: &[u8], flush: Flush) -> IoResult {
+        use ::zopfli::{BlockType, DeflateEncoder};
+
+        let mut encoder = DeflateEncoder::new(::zopfli::Options::default(), BlockType::Dynamic, Vec::new());
+        let mut written = encoder.write(data)?;
+        if let Flush::SyncFlush = flush {
+            written += encoder.write(&[])?;
+        }
+        if written != data.len() {
+            return Err(other("Compression failed"));
         }
+        let mut body = encoder.finish()?;
+        if let Flush::SyncFlush = flush {
+            zopfli_sync_flush(&mut body)?;
+        }
+        // 32 KiB window, maximum compression hint.
+        self.write_framed([0x78, 0xda], data, &body, flush)
     }
 
     pub fn write(&mut self, data: &[u8], flush: Flush) -> IoResult {
@@ -244,22 +610,58 @@ impl<W: Write> Deflate<W> {
     }
 
     //
-    // Deallocate the zlib state and return the writer.
+    // Hand the compressor state on for reuse by a later chunk on
+    // this thread, and return the writer. Streams needn't be finished;
+    // chunks other than the last deliberately end on a sync flush.
     //
-    pub fn finish(mut self) -> io::Result<W> {
-        if self.initialized {
-            let ret = unsafe {
-                deflateEnd(&mut *self.stream)
-            };
-            match ret {
-                // Z_DATA_ERROR means we freed before finishing the stream.
-                // For our use case we do this deliberately, it's ok!
-                Z_OK | Z_DATA_ERROR => Ok(self.output),
-                Z_STREAM_ERROR => Err(invalid_input("Inconsistent stream state")),
-                _ => Err(other("Unexpected error")),
-            }
-        } else {
-            Ok(self.output)
+    pub fn finish(self) -> io::Result<W> {
+        match self.engine {
+            Engine::Zlib(stream) if self.initialized => {
+                give_stream(self.options.zlib_params(), stream)?;
+                Ok(self.output)
+            },
+            _ => Ok(self.output),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::inflate::Inflate;
+
+    fn spare_count() -> usize {
+        SPARE_STREAMS.with(|spare| spare.borrow().len())
+    }
+
+    #[test]
+    fn stream_reuse() {
+        let data: Vec<u8> = (0 .. 100_000u32).map(|i| ((i % 251) ^ (i / 640)) as u8).collect();
+        let before = spare_count();
+
+        // The second pass reuses the stream from the first,
+        // which must not change the output.
+        let first = compress(&data).unwrap();
+        assert_eq!(spare_count(), before + 1);
+        let second = compress(&data).unwrap();
+        assert_eq!(spare_count(), before + 1);
+        assert_eq!(first, second);
+
+        let mut inflate = Inflate::new();
+        let mut output = Vec::new();
+        inflate.write(&second, &mut output).unwrap();
+        assert!(inflate.is_finished());
+        assert_eq!(output, data);
+
+        // Mid-stream, as with chunks ending on a sync flush.
+        let mut deflate = Deflate::new(Options::new(), Vec::new());
+        deflate.write(&data, Flush::SyncFlush).unwrap();
+        deflate.finish().unwrap();
+        let mut deflate = Deflate::new(Options::new(), Vec::new());
+        deflate.write(&data[.. 1000], Flush::Finish).unwrap();
+        let mut inflate = Inflate::new();
+        let mut output = Vec::new();
+        inflate.write(&deflate.finish().unwrap(), &mut output).unwrap();
+        assert_eq!(output, &data[.. 1000]);
+    }
+}
@@ -25,63 +25,331 @@
 
 use rayon::ThreadPool;
 
-use std::collections::VecDeque;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 
 use std::io;
-use std::io::Write;
+use std::io::{Seek, Write};
+use std::mem;
 
-use std::sync::Arc;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use std::time::{Duration, Instant};
+
+use super::Backend;
 use super::ColorType;
 use super::CompressionLevel;
 use super::Strategy;
 use super::Header;
+use super::InterlaceMethod;
+use super::Heuristic;
 use super::Mode;
 use super::Mode::{Adaptive, Fixed};
 
+use super::analyze;
+use super::error;
+use super::error::Error;
+use super::analyze::Content;
+use super::layout;
+use super::layout::Reduction;
+use super::metadata::{Chromaticities, RenderingIntent};
+#[cfg(feature = "metrics")]
+use super::telemetry;
+
 use super::filter::AdaptiveFilter;
 use super::filter::Filter;
 use super::writer::Writer;
+use super::writer::{AFTER_PALETTE, BEFORE_PALETTE, SINGLE_CHUNKS};
 
+use super::checksum;
 use super::deflate;
 use super::deflate::Deflate;
 use super::deflate::Flush;
 
 use super::utils::*;
 
+#[cfg(feature = "tokio")]
+pub use super::async_encoder::AsyncEncoder;
+pub use super::pull::PullEncoder;
+pub use super::animation::{AnimationEncoder, BlendOp, DisposeOp, FrameControl};
+
+
+/// Curated speed/size trade-offs for Options::preset, matching the
+/// command-line tool's fast, default, and max presets.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Fast compression with smaller chunks, for the most parallelism.
+    Fastest,
+    /// The defaults, as from Options::new.
+    Balanced,
+    /// High compression with large chunks, which lose less at the
+    /// boundaries between them.
+    Smallest,
+    /// For screenshots, UI, and line art: no filtering and run-length
+    /// compression, with the solid row fast path.
+    Screenshot,
+    /// For photographs and renders: high-effort adaptive filtering and
+    /// compression, with large chunks.
+    Photo,
+}
+
+/// Byte order of pixels given to the encoder, for sources such as
+/// screenshots and GPU surfaces that don't lay them out as PNG does.
+/// Pixels are put in PNG's order as rows are filtered, on the worker
+/// threads.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// As the header's color type and bit depth.
+    Png,
+    /// Blue, green, red, for an 8-bit truecolor image.
+    Bgr8,
+    /// Blue, green, red, alpha, for an 8-bit truecolor image with alpha.
+    Bgra8,
+    /// Alpha, red, green, blue, for an 8-bit truecolor image with alpha.
+    Argb8,
+    /// Red, green, blue, and a byte that's left out, for an 8-bit
+    /// truecolor image.
+    Rgbx8,
+    /// Blue, green, red, and a byte that's left out, for an 8-bit
+    /// truecolor image.
+    Bgrx8,
+    /// One byte per sample, for 1-, 2-, and 4-bit greyscale and
+    /// indexed-color images, packed as rows are filtered.
+    Unpacked,
+}
+
+impl PixelFormat {
+    //
+    // Bytes per pixel, or None for the header's own layout.
+    //
+    fn bytes_per_pixel(self) -> Option<usize> {
+        match self {
+            PixelFormat::Png => None,
+            PixelFormat::Unpacked => Some(1),
+            PixelFormat::Bgr8 => Some(3),
+            _ => Some(4),
+        }
+    }
+
+    // Check the format is one for the header's color type and depth.
+    fn check(self, header: &Header) -> IoResult {
+        let (fits, image) = match self {
+            PixelFormat::Png => return Ok(()),
+            PixelFormat::Unpacked => {
+                let fits = matches!(header.color_type, ColorType::Greyscale | ColorType::IndexedColor);
+                if !fits || header.depth >= 8 {
+                    return Err(invalid_input("Unpacked input requires a 1-, 2-, or 4-bit greyscale or indexed-color image."));
+                }
+                return Ok(());
+            },
+            PixelFormat::Bgra8 | PixelFormat::Argb8 => {
+                (matches!(header.color_type, ColorType::TruecolorAlpha), "truecolor image with alpha")
+            },
+            _ => (matches!(header.color_type, ColorType::Truecolor), "truecolor image"),
+        };
+        if !fits || header.depth != 8 {
+            return Err(invalid_input(&format!("Input format {:?} requires an 8-bit {}.", self, image)));
+        }
+        Ok(())
+    }
+
+    // Length of a row of the given width.
+    fn stride(self, header: &Header) -> usize {
+        match self.bytes_per_pixel() {
+            Some(bytes) => bytes * header.width as usize,
+            None => header.stride(),
+        }
+    }
+
+    // Put rows of pixels in PNG's byte order.
+    fn to_png(self, header: &Header, data: &[u8]) -> Vec<u8> {
+        let pixels = data.chunks_exact(self.bytes_per_pixel().unwrap_or(1));
+        match self {
+            PixelFormat::Png => data.to_vec(),
+            PixelFormat::Unpacked => {
+                data.chunks(header.width as usize).flat_map(|row| layout::pack(row, header.depth)).collect()
+            },
+            PixelFormat::Bgr8 | PixelFormat::Bgrx8 => pixels.flat_map(|p| [p[2], p[1], p[0]]).collect(),
+            PixelFormat::Bgra8 => pixels.flat_map(|p| [p[2], p[1], p[0], p[3]]).collect(),
+            PixelFormat::Argb8 => pixels.flat_map(|p| [p[1], p[2], p[3], p[0]]).collect(),
+            PixelFormat::Rgbx8 => pixels.flat_map(|p| [p[0], p[1], p[2]]).collect(),
+        }
+    }
+}
+
+/// Background color for Encoder::write_background, in the form
+/// matching the image's color type.
+///
+/// https://www.w3.org/TR/PNG/#11bKGD
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Background {
+    /// Grey level, for greyscale images with or without alpha.
+    Greyscale(u16),
+    /// Red, green, and blue samples, for truecolor images with or
+    /// without alpha.
+    Rgb(u16, u16, u16),
+    /// Palette entry, for indexed-color images.
+    PaletteIndex(u8),
+}
+
+/// Unit for Encoder::write_offset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OffsetUnit {
+    /// Offset in pixels.
+    Pixel = 0,
+    /// Offset in micrometers, as for placing an image on a page.
+    Micrometer = 1,
+}
+
+/// Arrangement of the two halves of a stereo pair, for
+/// Encoder::write_stereo.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StereoLayout {
+    /// Right-eye image on the left, for cross-eyed viewing.
+    CrossFuse = 0,
+    /// Left-eye image on the left, for parallel viewing.
+    DivergingFuse = 1,
+}
 
 /// Options setup struct for the PNG encoder.
 /// May be modified and reused.
 #[derive(Copy, Clone)]
 pub struct Options<'a> {
-    chunk_size: usize,
+    backend: Backend,
+    chunk_size: Option<usize>,
+    chunk_rows: Option<usize>,
     compression_level: CompressionLevel,
     strategy_mode: Mode<Strategy>,
     filter_mode: Mode<Filter>,
+    pass_filter_modes: [Option<Mode<Filter>>; 7],
+    heuristic: Heuristic,
+    filter_lock_in: usize,
+    solid_rows: bool,
+    preset: Option<Preset>,
+    input_format: PixelFormat,
     streaming: bool,
+    validate: bool,
+    dedup_palette: bool,
+    reduce_depth: bool,
+    text_compression: Option<usize>,
+    max_width: u32,
+    max_height: u32,
+    dispatch_timeout: Option<Duration>,
+    max_pending_chunks: Option<usize>,
     thread_pool: Option<&'a ThreadPool>,
+    on_chunk_complete: Option<&'a ChunkCallback<'a>>,
+}
+
+/// Callback type for Options::set_on_chunk_complete.
+pub type ChunkCallback<'a> = dyn Fn(ChunkInfo) + Sync + 'a;
+
+/// Details of a completed image data chunk, passed to the callback
+/// set with Options::set_on_chunk_complete.
+#[derive(Copy, Clone, Debug)]
+pub struct ChunkInfo {
+    index: usize,
+    start_row: usize,
+    end_row: usize,
+    compressed_bytes: usize,
+    output_bytes: u64,
+}
+
+impl ChunkInfo {
+    /// Index of the chunk, counting from 0 in image order.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// First image row covered by the chunk.
+    ///
+    /// For interlaced images, rows count through the rows of each
+    /// Adam7 pass in turn, as they're laid out in the image data.
+    pub fn start_row(&self) -> usize {
+        self.start_row
+    }
+
+    /// Row after the last one covered by the chunk.
+    pub fn end_row(&self) -> usize {
+        self.end_row
+    }
+
+    /// Compressed bytes the chunk added to the image data stream,
+    /// including the stream's trailing checksum on the last chunk.
+    pub fn compressed_bytes(&self) -> usize {
+        self.compressed_bytes
+    }
+
+    /// Total bytes written to the output so far. In streaming mode
+    /// this includes the chunk itself; otherwise image data is held
+    /// back until the last chunk.
+    pub fn output_bytes(&self) -> u64 {
+        self.output_bytes
+    }
 }
 
+// Largest width or height the PNG spec allows.
+const PNG_MAX_DIMENSION: u32 = 0x7fff_ffff;
+
+#[cfg(target_pointer_width = "64")]
+const DEFAULT_MAX_DIMENSION: u32 = PNG_MAX_DIMENSION;
+
+#[cfg(not(target_pointer_width = "64"))]
+const DEFAULT_MAX_DIMENSION: u32 = 16384;
+
+// Smallest chunk size allowed, so each chunk's deflate stream can
+// use a full window of the one before as its dictionary.
+const MIN_CHUNK_SIZE: usize = 32 * 1024;
+
+// Largest chunk size picked automatically. Much bigger chunks barely
+// help compression, and a few are held in memory at once.
+const MAX_AUTO_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
 impl<'a> Options<'a> {
     /// Create a new Options struct using default options:
-    /// * chunk_size: 256 KiB
+    /// * backend: Zlib
+    /// * chunk_size: automatic, about two chunks per thread
+    /// * chunk_rows: none, sized by chunk_size
     /// * compression_level: Default
     /// * strategy_mode: Adaptive
     /// * filter_mode: Adaptive
+    /// * pass filter modes: same as filter_mode
+    /// * filter heuristic: MinSum
+    /// * filter lock-in: off
+    /// * solid row fast path: off
+    /// * input_format: Png
     /// * streaming: off
+    /// * validate: off (always on in debug builds)
+    /// * dedup_palette: off
+    /// * depth reduction: off
+    /// * text_compression: none
+    /// * max dimensions: 2^31-1 square, or 16384 square on 32-bit targets
+    /// * dispatch_timeout: 100 ms
+    /// * max_pending_chunks: none
     /// * thread_pool: global default
+    /// * on_chunk_complete: none
     ///
     /// The compression, strategy, and filtering use the same
     /// defaults as libpng.
     pub fn new() -> Options<'a> {
         Options {
+            backend: Backend::Zlib,
+
             //
-            // A chunk size of 256 KiB gives compression results very similar
-            // to a single stream when otherwise using defaults.
+            // Sized to the image and thread pool when encoding starts;
+            // see chunk_size_for.
             //
-            chunk_size: 256 * 1024,
+            chunk_size: None,
+            chunk_rows: None,
 
             //
             // Same defaults as libpng.
@@ -89,6 +357,14 @@ impl<'a> Options<'a> {
             compression_level: CompressionLevel::Default,
             strategy_mode: Adaptive,
             filter_mode: Adaptive,
+            pass_filter_modes: [None; 7],
+            heuristic: Heuristic::MinSum,
+            filter_lock_in: 0,
+            solid_rows: false,
+
+            preset: None,
+
+            input_format: PixelFormat::Png,
 
             //
             // Streaming mode can produce lower latency to first bytes hitting
@@ -100,37 +376,215 @@ impl<'a> Options<'a> {
             //
             streaming: false,
 
+            //
+            // Checking every pixel costs time, so only do it on request
+            // or in debug builds.
+            //
+            validate: false,
+
+            dedup_palette: false,
+
+            reduce_depth: false,
+
+            text_compression: None,
+
+            //
+            // PNG allows up to 2^31-1 pixels either way, but on 32-bit
+            // targets buffers for such images can't be addressed, so
+            // default to something that fits comfortably there.
+            //
+            max_width: DEFAULT_MAX_DIMENSION,
+            max_height: DEFAULT_MAX_DIMENSION,
+
+            //
+            // Waiting a while before pitching in keeps the work on the
+            // pool normally, while making sure a busy pool can't hang us.
+            //
+            dispatch_timeout: Some(Duration::from_millis(100)),
+
+            max_pending_chunks: None,
+
             //
             // Use the global thread pool.
             //
             thread_pool: None,
+
+            on_chunk_complete: None,
+        }
+    }
+
+    /// Create an Options struct set up for one of the presets, choosing
+    /// compression level, strategy, filter mode, chunk size, and backend
+    /// together. Other options are left at their defaults, and any may
+    /// be changed afterwards.
+    ///
+    /// * Fastest: Fixed(Up) filter and Filtered strategy at Fast level,
+    ///   with 128 KiB chunks, on zlib-rs if built in or zlib otherwise
+    /// * Balanced: the same as Options::new, with adaptive filter and
+    ///   strategy at Default level on zlib
+    /// * Smallest: adaptive filter and strategy at High level, with
+    ///   1 MiB chunks on zlib
+    /// * Screenshot: Fixed(None) filter and Rle strategy at High level,
+    ///   which stays quick with Rle, and the solid row fast path, on zlib
+    /// * Photo: adaptive filtering with the Entropy heuristic, Filtered
+    ///   strategy, High level, and 1 MiB chunks on zlib
+    ///
+    /// All use a backend with preset dictionary support to carry context
+    /// across chunks.
+    ///
+    /// With the "log" feature, the encoder samples the first rows it
+    /// gets and logs a warning if they don't look like what the preset
+    /// was made for.
+    pub fn preset(preset: Preset) -> Options<'a> {
+        let mut options = Options::new();
+        options.preset = Some(preset);
+        match preset {
+            Preset::Fastest => {
+                // Up is about as cheap as filtering gets, and on its
+                // own does nearly as well as trying them all.
+                options.backend = if Backend::ZlibRs.is_available() {
+                    Backend::ZlibRs
+                } else {
+                    Backend::Zlib
+                };
+                options.filter_mode = Fixed(Filter::Up);
+                options.strategy_mode = Fixed(Strategy::Filtered);
+                options.compression_level = CompressionLevel::Fast;
+                options.chunk_size = Some(128 * 1024);
+            },
+            Preset::Balanced => {
+                options.backend = Backend::Zlib;
+                options.filter_mode = Adaptive;
+                options.strategy_mode = Adaptive;
+                options.compression_level = CompressionLevel::Default;
+            },
+            Preset::Smallest => {
+                options.backend = Backend::Zlib;
+                options.filter_mode = Adaptive;
+                options.strategy_mode = Adaptive;
+                options.compression_level = CompressionLevel::High;
+                options.chunk_size = Some(1024 * 1024);
+            },
+            Preset::Screenshot => {
+                options.backend = Backend::Zlib;
+                options.filter_mode = Fixed(Filter::None);
+                options.strategy_mode = Fixed(Strategy::Rle);
+                options.compression_level = CompressionLevel::High;
+                options.solid_rows = true;
+            },
+            Preset::Photo => {
+                options.backend = Backend::Zlib;
+                options.filter_mode = Adaptive;
+                options.heuristic = Heuristic::Entropy;
+                options.strategy_mode = Fixed(Strategy::Filtered);
+                options.compression_level = CompressionLevel::High;
+                options.chunk_size = Some(1024 * 1024);
+            },
+        }
+        options
+    }
+
+    /// Create an Options struct with filter, strategy, and level picked
+    /// to suit the image, judging by a sample of its rows as taken by
+    /// analyze::sample:
+    ///
+    /// * Flat content such as screenshots: the Screenshot preset
+    /// * Photographic content: Fixed(Paeth) filter and Filtered strategy
+    ///   at Default level
+    /// * Anything else: the Balanced preset
+    ///
+    /// Other options are left at their defaults, and any may be changed
+    /// afterwards.
+    pub fn auto(header: &Header, data: &[u8]) -> error::Result<Options<'a>> {
+        let content = analyze::sample(header, data)?.content();
+        let mut options = match content {
+            Content::Flat => Options::preset(Preset::Screenshot),
+            _ => Options::preset(Preset::Balanced),
+        };
+        match content {
+            Content::Flat => {},
+            Content::Photographic => {
+                options.filter_mode = Fixed(Filter::Paeth);
+                options.strategy_mode = Fixed(Strategy::Filtered);
+                options.compression_level = CompressionLevel::Default;
+            },
+            Content::Mixed => {},
         }
+        debug_log!("picking settings for {:?} content", content);
+        Ok(options)
     }
 
     /// Use a custom Rayon ThreadPool instance instead of the global pool.
-    pub fn set_thread_pool(&mut self, thread_pool: &'a ThreadPool) -> IoResult {
+    pub fn set_thread_pool(&mut self, thread_pool: &'a ThreadPool) -> error::Result<()> {
         self.thread_pool = Some(thread_pool);
         Ok(())
     }
 
+    /// Call the given function as each chunk of image data is completed
+    /// and handed to the output, in image order. With streaming on, each
+    /// call follows that chunk's IDAT being written, so applications can
+    /// upload or checkpoint the output as it is produced.
+    ///
+    /// The callback runs on the thread calling into the encoder.
+    pub fn set_on_chunk_complete(&mut self, callback: &'a ChunkCallback<'a>) -> error::Result<()> {
+        self.on_chunk_complete = Some(callback);
+        Ok(())
+    }
+
+    /// Select the deflate implementation. Returns an error if the
+    /// backend's cargo feature was not enabled.
+    pub fn set_backend(&mut self, backend: Backend) -> error::Result<()> {
+        if !backend.is_available() {
+            return Err(invalid_input("Deflate backend not compiled in"));
+        }
+        self.backend = backend;
+        Ok(())
+    }
+
     /// Set the size in bytes of chunks used for distributing data to threads.
     /// The actual chunk size used will be a multiple of row lengths approximating
     /// the requested size.
     ///
-    /// Chunk size must be at least 32 KiB.
-    pub fn set_chunk_size(&mut self, chunk_size: usize) -> IoResult {
-        if chunk_size < 32768 {
+    /// Chunk size must be at least 32 KiB. Replaces any row count
+    /// set with set_chunk_rows.
+    ///
+    /// If not set, the chunk size is picked for each image to give
+    /// about twice as many chunks as the thread pool has threads,
+    /// from 32 KiB up to 4 MiB.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> error::Result<()> {
+        if chunk_size < MIN_CHUNK_SIZE {
             Err(invalid_input("chunk size must be at least 32768"))
         } else {
-            self.chunk_size = chunk_size;
+            self.chunk_size = Some(chunk_size);
+            self.chunk_rows = None;
             Ok(())
         }
     }
 
+    /// Set the number of rows in each chunk, instead of sizing chunks
+    /// by bytes with set_chunk_size. Chunk boundaries then fall on
+    /// multiples of the row count, such as tile heights, whatever the
+    /// row length. The last chunk takes whatever rows are left over.
+    ///
+    /// Each Adam7 pass of an interlaced image is split up on its own,
+    /// counting rows within the pass.
+    pub fn set_chunk_rows(&mut self, rows: usize) -> error::Result<()> {
+        if rows == 0 {
+            return Err(invalid_input("Chunk rows must be non-zero"));
+        }
+        self.chunk_rows = Some(rows);
+        Ok(())
+    }
+
     /// Set the deflate compression level.
     /// Currently supported are Fast (equivalent to gzip -1),
-    /// Default (gzip -6), and High (gzip -9).
-    pub fn set_compression_level(&mut self, level: CompressionLevel) -> IoResult {
+    /// Default (gzip -6), High (gzip -9), Max (Zopfli), and
+    /// Specific for any other level from 1 to 9.
+    ///
+    /// Max ignores the backend and strategy, and returns an error
+    /// if the "zopfli" feature was not enabled.
+    pub fn set_compression_level(&mut self, level: CompressionLevel) -> error::Result<()> {
+        level.validate()?;
         self.compression_level = level;
         Ok(())
     }
@@ -143,15 +597,57 @@ impl<'a> Options<'a> {
     /// which often does well but can pick poorly on some images.
     /// Fixed<*> may be used to override the mode for the whole image,
     /// which sometimes produces better results than the heuristic.
-    pub fn set_filter_mode(&mut self, filter_mode: Mode<Filter>) -> IoResult {
+    pub fn set_filter_mode(&mut self, filter_mode: Mode<Filter>) -> error::Result<()> {
         self.filter_mode = filter_mode;
         Ok(())
     }
 
+    /// Set how adaptive filtering picks a filter for each row. MinSum
+    /// is libpng's heuristic, and the default; Entropy costs more time
+    /// but often does better on photographic images.
+    pub fn set_filter_heuristic(&mut self, heuristic: Heuristic) -> error::Result<()> {
+        self.heuristic = heuristic;
+        Ok(())
+    }
+
+    /// Lock in adaptive filtering's pick once it has chosen the same
+    /// filter for this many rows in a row, using it for the rest of
+    /// the chunk without trying the others. This is much faster on
+    /// images that look alike throughout, usually for only a small
+    /// size cost. 0, the default, keeps trying every filter on every
+    /// row.
+    pub fn set_filter_lock_in(&mut self, rows: usize) -> error::Result<()> {
+        self.filter_lock_in = rows;
+        Ok(())
+    }
+
+    /// Enable or disable the solid row fast path, which filters rows
+    /// that repeat the one above with Up, and rows of a single color
+    /// with Sub, without trying the filter mode. Either way the row
+    /// filters to all zeros, which compresses to almost nothing.
+    pub fn set_solid_row_fast_path(&mut self, solid_rows: bool) -> error::Result<()> {
+        self.solid_rows = solid_rows;
+        Ok(())
+    }
+
+    /// Override the filter mode for one Adam7 pass of interlaced output,
+    /// numbered 1 to 7. The early passes hold only a few pixels per row,
+    /// where Fixed(None) often does best. Passes without an override
+    /// use the mode from set_filter_mode.
+    ///
+    /// Has no effect on non-interlaced images.
+    pub fn set_pass_filter_mode(&mut self, pass: usize, filter_mode: Mode<Filter>) -> error::Result<()> {
+        if !(1 ..= 7).contains(&pass) {
+            return Err(invalid_input("Adam7 pass must be between 1 and 7"));
+        }
+        self.pass_filter_modes[pass - 1] = Some(filter_mode);
+        Ok(())
+    }
+
     /// Set the deflate compression strategy. By default it will use Adaptive,
     /// which picks Default for Fixed<None> or Filtered for other filter types.
     /// This matches libpng's logic as well.
-    pub fn set_strategy_mode(&mut self, strategy_mode: Mode<Strategy>) -> IoResult {
+    pub fn set_strategy_mode(&mut self, strategy_mode: Mode<Strategy>) -> error::Result<()> {
         self.strategy_mode = strategy_mode;
         Ok(())
     }
@@ -160,10 +656,126 @@ impl<'a> Options<'a> {
     /// around each compressed data chunk. This allows for streaming a large file
     /// over a network etc during compression, at a cost of a few more bytes at
     /// chunk boundaries.
-    pub fn set_streaming(&mut self, streaming: bool) -> IoResult {
+    pub fn set_streaming(&mut self, streaming: bool) -> error::Result<()> {
         self.streaming = streaming;
         Ok(())
     }
+
+    /// Set the byte order of the pixels passed in as image data, for
+    /// BGRA and similar sources, which write_header checks against the
+    /// header. Defaults to Png, for pixels laid out as the header says.
+    pub fn set_input_format(&mut self, format: PixelFormat) -> error::Result<()> {
+        self.input_format = format;
+        Ok(())
+    }
+
+    /// Enable or disable validation of image data as it's written,
+    /// so bad input is caught before any decoder sees the output.
+    /// Currently this checks that indexed-color pixels only use
+    /// entries present in the palette. Debug builds always validate.
+    pub fn set_validation(&mut self, validate: bool) -> error::Result<()> {
+        self.validate = validate;
+        Ok(())
+    }
+
+    /// Merge duplicate entries in indexed-color palettes, remapping the
+    /// image data, bKGD, and hIST to match as they're written. Entries
+    /// only count as duplicates if their transparency matches too.
+    ///
+    /// The PLTE chunk is held back until the transparency, another
+    /// chunk, or image data is written, so write any tRNS chunk first
+    /// after the palette. Defaults to off.
+    pub fn set_palette_dedup(&mut self, dedup: bool) -> error::Result<()> {
+        self.dedup_palette = dedup;
+        Ok(())
+    }
+
+    /// Check 16-bit images for samples whose high and low bytes all
+    /// match, as from 8-bit sources scaled up, and write them as 8-bit
+    /// images instead, which loses nothing. Any tRNS and bKGD values
+    /// must match the same way, and sBIT values are capped at 8.
+    ///
+    /// This holds back the header, the chunks before the image data,
+    /// and the image data itself until the last row is in, keeping
+    /// the whole image in memory. Defaults to off.
+    pub fn set_depth_reduction(&mut self, reduce_depth: bool) -> error::Result<()> {
+        self.reduce_depth = reduce_depth;
+        Ok(())
+    }
+
+    /// Have Encoder::write_text compress values longer than the given
+    /// number of bytes, as zTXt for Latin-1 text or compressed iTXt for
+    /// other text. Short values never compress well enough to be worth
+    /// it. Defaults to None, leaving all text uncompressed.
+    pub fn set_text_compression(&mut self, threshold: Option<usize>) -> error::Result<()> {
+        self.text_compression = threshold;
+        Ok(())
+    }
+
+    /// Set the largest image width and height write_header will accept.
+    /// Services encoding user-controlled sizes can use this to refuse
+    /// huge allocations up front. Limits must be between 1 and 2^31-1.
+    pub fn set_max_dimensions(&mut self, max_width: u32, max_height: u32) -> error::Result<()> {
+        if max_width == 0 || max_height == 0 {
+            return Err(invalid_input("Maximum dimensions must be at least 1"));
+        }
+        if max_width > PNG_MAX_DIMENSION || max_height > PNG_MAX_DIMENSION {
+            return Err(invalid_input("Maximum dimensions cannot exceed 2^31-1"));
+        }
+        self.max_width = max_width;
+        self.max_height = max_height;
+        Ok(())
+    }
+
+    /// Set how long to wait on the thread pool before running queued
+    /// jobs on the calling thread instead. This keeps the encoder from
+    /// hanging if the pool is saturated by other work, or if the encoder
+    /// itself is running on the pool's only thread.
+    ///
+    /// None waits indefinitely, as older versions did.
+    pub fn set_dispatch_timeout(&mut self, timeout: Option<Duration>) -> error::Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(invalid_input("Dispatch timeout must be non-zero"));
+        }
+        self.dispatch_timeout = timeout;
+        Ok(())
+    }
+
+    /// Set how many chunks may be waiting between input and output,
+    /// in jobs or finished but not yet written. Once there are more,
+    /// writing rows waits for chunks to go out first, so a slow output
+    /// stalls the input rather than piling up compressed data.
+    ///
+    /// None, the default, only limits the jobs in flight at once to
+    /// a couple more than there are threads.
+    pub fn set_max_pending_chunks(&mut self, chunks: Option<usize>) -> error::Result<()> {
+        if chunks == Some(0) {
+            return Err(invalid_input("Max pending chunks must be non-zero"));
+        }
+        self.max_pending_chunks = chunks;
+        Ok(())
+    }
+
+    // Number of threads work will be spread over.
+    pub(crate) fn thread_count(&self) -> usize {
+        match self.thread_pool {
+            Some(pool) => pool.current_num_threads(),
+            None => rayon::current_num_threads(),
+        }
+    }
+
+    //
+    // Chunk size for an image with the given number of filtered bytes.
+    // Unless one was set, aim for a couple of chunks per thread, so
+    // every thread has work and the next chunk's ready as one ends.
+    //
+    fn chunk_size_for(&self, bytes: usize) -> usize {
+        match self.chunk_size {
+            Some(chunk_size) => chunk_size,
+            None => (bytes / (2 * self.thread_count()))
+                        .clamp(MIN_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE),
+        }
+    }
 }
 
 impl<'a> Default for Options<'a> {
@@ -172,154 +784,617 @@ impl<'a> Default for Options<'a> {
     }
 }
 
-// Accumulates a set of pixels, then gets sent off as input
-// to the deflate jobs.
-struct PixelChunk {
-    header: Header,
+/// Statistics about an encoding run, for tuning and benchmarking.
+///
+/// Counts cover the chunks processed so far; read them after
+/// the image data has been flushed, or take them from
+/// Encoder::finish_with_stats, for a complete picture.
+#[derive(Clone, Default)]
+pub struct EncodeStats {
+    chunks: usize,
+    filter_rows: [usize; 5],
+    filter_bytes: [u64; 5],
+    uncompressed_bytes: u64,
+    filtered_bytes: u64,
+    compressed_bytes: u64,
+    chunk_bytes: Vec<usize>,
+    output_bytes: u64,
+    times: StageTimes,
+    threads: usize,
+    busy_time: Duration,
+    elapsed: Duration,
+}
 
-    index: usize,
-    start_row: usize,
-    end_row: usize,
-    is_start: bool,
-    is_end: bool,
+impl EncodeStats {
+    /// Number of chunks the image is divided into for parallel processing.
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
 
-    stride: usize,
+    /// Number of rows that were filtered with the given filter type.
+    pub fn filter_rows(&self, filter: Filter) -> usize {
+        self.filter_rows[filter as usize]
+    }
 
-    // Rows of pixel data, each with stride bytes per row
-    rows: Vec<Vec<u8>>,
-}
+    /// Number of filtered bytes, including filter type bytes, in rows
+    /// that were filtered with the given filter type.
+    pub fn filter_bytes(&self, filter: Filter) -> u64 {
+        self.filter_bytes[filter as usize]
+    }
 
-impl PixelChunk {
-    fn new(header: Header, index: usize, start_row: usize, end_row: usize) -> PixelChunk {
-        assert!(start_row <= end_row);
+    /// Number of bytes of raw image data before filtering.
+    pub fn uncompressed_bytes(&self) -> u64 {
+        self.uncompressed_bytes
+    }
 
-        let height = header.height as usize;
-        assert!(end_row <= height);
+    /// Number of filtered bytes fed to the deflate compressor,
+    /// including each row's filter type byte.
+    pub fn filtered_bytes(&self) -> u64 {
+        self.filtered_bytes
+    }
 
-        PixelChunk {
-            header,
+    /// Number of compressed bytes in the image data stream.
+    pub fn compressed_bytes(&self) -> u64 {
+        self.compressed_bytes
+    }
 
-            index,
-            start_row,
-            end_row,
-            is_start: start_row == 0,
-            is_end: end_row == height,
+    /// Compressed bytes each chunk added to the image data stream,
+    /// in chunk order, as reported by ChunkInfo::compressed_bytes.
+    pub fn chunk_bytes(&self) -> &[usize] {
+        &self.chunk_bytes
+    }
 
-            stride: header.stride(),
+    /// Total number of bytes written to the output, including
+    /// the signature and all chunk framing.
+    pub fn output_bytes(&self) -> u64 {
+        self.output_bytes
+    }
 
-            rows: Vec::with_capacity(end_row - start_row),
-        }
+    /// Time spent in each stage, summed over all chunks.
+    pub fn times(&self) -> StageTimes {
+        self.times
     }
 
-    fn is_full(&self) -> bool {
-        self.rows.len() == (self.end_row - self.start_row)
+    /// Wall time from writing the header to when the stats were taken.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
     }
 
-    fn read_row(&mut self, row: &[u8])
-    {
-        let mut row_copy = Vec::with_capacity(self.stride);
-        row_copy.extend_from_slice(row);
+    /// Fraction of the available thread time spent running jobs,
+    /// from 0.0 to 1.0.
+    pub fn worker_utilization(&self) -> f64 {
+        utilization(self.busy_time, self.elapsed, self.threads)
+    }
+}
 
-        self.rows.push(row_copy);
+/// Wall time spent in each stage of encoding.
+///
+/// Filtering and deflate run on the thread pool, so summed over chunks
+/// they can add up to more than the elapsed time of the whole encode.
+#[derive(Copy, Clone, Default)]
+pub struct StageTimes {
+    pixel_copy: Duration,
+    filter: Duration,
+    deflate: Duration,
+    checksum: Duration,
+    write: Duration,
+}
+
+impl StageTimes {
+    /// Time spent copying input rows into chunk buffers.
+    pub fn pixel_copy(&self) -> Duration {
+        self.pixel_copy
     }
 
-    fn get_row(&self, row: usize) -> &[u8] {
-        if row < self.start_row {
-            panic!("Tried to access row from earlier chunk: {} < {}", row, self.start_row);
-        } else if row >= self.end_row {
-            panic!("Tried to access row from later chunk: {} >= {}", row, self.end_row);
-        } else {
-            &self.rows[row - self.start_row]
-        }
+    /// Time spent filtering rows.
+    pub fn filter(&self) -> Duration {
+        self.filter
     }
-}
 
-// Takes pixel chunks as input and accumulates filtered output.
-struct FilterChunk {
-    index: usize,
-    start_row: usize,
-    end_row: usize,
-    is_start: bool,
-    is_end: bool,
+    /// Time spent compressing filtered data.
+    pub fn deflate(&self) -> Duration {
+        self.deflate
+    }
 
-    stride: usize,
-    filter_mode: Mode<Filter>,
+    /// Time spent calculating and combining Adler-32 checksums.
+    pub fn checksum(&self) -> Duration {
+        self.checksum
+    }
 
-    // The input pixels for chunk n-1
-    // Needed for its last row only.
-    prior_input: Option<Arc<PixelChunk>>,
+    /// Time spent writing compressed data to the output.
+    pub fn write(&self) -> Duration {
+        self.write
+    }
 
-    // The input pixels for chunk n
-    input: Arc<PixelChunk>,
+    fn add(&mut self, other: &StageTimes) {
+        self.pixel_copy += other.pixel_copy;
+        self.filter += other.filter;
+        self.deflate += other.deflate;
+        self.checksum += other.checksum;
+        self.write += other.write;
+    }
+}
 
-    // Filtered output bytes
-    data: Vec<u8>,
+/// A snapshot of the encoder's runtime state, for monitoring the
+/// health of long-running or embedded encoders.
+#[derive(Copy, Clone, Default)]
+pub struct EncoderMetrics {
+    chunks_in_flight: usize,
+    queue_depth: usize,
+    bytes_buffered: usize,
+    threads: usize,
+    busy_time: Duration,
+    elapsed: Duration,
 }
 
-impl FilterChunk {
-    fn new(prior_input: Option<Arc<PixelChunk>>,
-           input: Arc<PixelChunk>,
-           filter_mode: Mode<Filter>) -> FilterChunk
-    {
-        // Prepend one byte for the filter selector.
-        let stride = input.stride + 1;
-        let nbytes = stride * (input.end_row - input.start_row);
+impl EncoderMetrics {
+    /// Number of filter and deflate jobs dispatched but not yet landed.
+    pub fn chunks_in_flight(&self) -> usize {
+        self.chunks_in_flight
+    }
 
-        FilterChunk {
-            index: input.index,
-            start_row: input.start_row,
-            end_row: input.end_row,
-            is_start: input.is_start,
-            is_end: input.is_end,
+    /// Number of dispatched jobs still waiting for a thread.
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
+    }
 
-            stride,
-            filter_mode,
+    /// Bytes of pixel, filtered, and compressed data held in memory
+    /// waiting for the next stage or for output.
+    pub fn bytes_buffered(&self) -> usize {
+        self.bytes_buffered
+    }
 
-            prior_input,
-            input,
-            data: Vec::with_capacity(nbytes),
-        }
+    /// Total time spent running jobs, summed across threads.
+    pub fn busy_time(&self) -> Duration {
+        self.busy_time
     }
 
-    // Return the last up-to-32kib, used as an input dictionary
-    // for the next chunk's deflate job.
-    fn get_trailer(&self) -> &[u8] {
-        let trailer = 32768;
-        let len = self.data.len();
-        if len > trailer {
-            &self.data[len - trailer .. len]
-        } else {
-            &self.data[0 .. len]
-        }
+    /// Time since the header was written.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Fraction of the available thread time spent running jobs
+    /// since the header was written, from 0.0 to 1.0.
+    pub fn worker_utilization(&self) -> f64 {
+        utilization(self.busy_time, self.elapsed, self.threads)
+    }
+}
+
+fn utilization(busy_time: Duration, elapsed: Duration, threads: usize) -> f64 {
+    let available = elapsed.as_secs_f64() * threads as f64;
+    if available > 0.0 {
+        (busy_time.as_secs_f64() / available).min(1.0)
+    } else {
+        0.0
+    }
+}
+
+
+// Chunks and rows of a 16-bit image held back to check for 8-bit samples.
+struct HeldImage {
+    chunks: Vec<([u8; 4], Vec<u8>)>,
+    rows: Vec<u8>,
+}
+
+// A run of rows in the image data that share one layout: the whole
+// image, or one Adam7 pass of an interlaced image, with a header
+// giving the pass's own size.
+#[derive(Copy, Clone)]
+struct Pass {
+    header: Header,
+
+    // Adam7 pass number from 0 to 6, if interlaced.
+    number: Option<usize>,
+
+    // First row of the pass in the image data.
+    start_row: usize,
+}
+
+impl Pass {
+    fn new(header: Header) -> Pass {
+        Pass {
+            header,
+            number: None,
+            start_row: 0,
+        }
+    }
+
+    fn end_row(&self) -> usize {
+        self.start_row + self.header.height as usize
+    }
+}
+
+// Spare buffers are kept from at most this many chunks.
+const MAX_SPARE_BUFFERS: usize = 32;
+
+//
+// Byte buffers from filter and deflate chunks that have been written
+// out, handed back out to later chunks instead of allocating anew.
+// Chunks of an image are near enough the same size that any will do.
+//
+#[derive(Clone, Default)]
+struct BufferPool(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl BufferPool {
+    // An empty buffer, with whatever capacity it had before.
+    fn take(&self) -> Vec<u8> {
+        let buffer = self.0.lock().ok().and_then(|mut spare| spare.pop());
+        let mut buffer = buffer.unwrap_or_default();
+        buffer.clear();
+        buffer
+    }
+
+    fn give(&self, buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        if let Ok(mut spare) = self.0.lock() {
+            if spare.len() < MAX_SPARE_BUFFERS {
+                spare.push(buffer);
+            }
+        }
+    }
+}
+
+// Accumulates a set of pixels, then gets sent off as input
+// to the deflate jobs.
+//
+// Rows are counted through the whole image data, but a chunk
+// never spans more than one pass.
+struct PixelChunk {
+    header: Header,
+    pass: Option<usize>,
+    pass_start: usize,
+
+    index: usize,
+    start_row: usize,
+    end_row: usize,
+    is_start: bool,
+    is_end: bool,
+
+    stride: usize,
+
+    // Rows of pixel data back to back, stride bytes per row,
+    // and how many have been read in so far.
+    data: Pixels,
+    rows: usize,
+
+    // Whether 16-bit samples are held little-endian, as they came
+    // in from write_image_rows_u16, to be swapped when filtered.
+    little_endian: bool,
+
+    // Byte order of the pixels, put in PNG's when filtered.
+    format: PixelFormat,
+
+    // How 8-bit greyscale rows are reduced to the header's depth and
+    // packed when filtered, if they are.
+    packing: Option<Reduction>,
+}
+
+// Where a pixel chunk's rows are kept.
+enum Pixels {
+    // Copied in a row at a time.
+    Owned(Vec<u8>),
+    // Left in the caller's buffer, from the given offset.
+    Shared(Arc<[u8]>, usize),
+}
+
+impl PixelChunk {
+    fn new(pass: &Pass, index: usize, start_row: usize, end_row: usize, total_rows: usize) -> PixelChunk {
+        assert!(pass.start_row <= start_row);
+        assert!(start_row <= end_row);
+        assert!(end_row <= pass.end_row());
+
+        PixelChunk {
+            header: pass.header,
+            pass: pass.number,
+            pass_start: pass.start_row,
+
+            index,
+            start_row,
+            end_row,
+            is_start: start_row == 0,
+            is_end: end_row == total_rows,
+
+            stride: pass.header.stride(),
+
+            data: Pixels::Owned(Vec::new()),
+            rows: 0,
+            little_endian: false,
+            format: PixelFormat::Png,
+            packing: None,
+        }
+    }
+
+    // Take rows laid out in the given format, before any are read.
+    fn set_format(&mut self, format: PixelFormat) {
+        assert!(self.rows == 0);
+        self.format = format;
+        self.stride = format.stride(&self.header);
+    }
+
+    // Take 8-bit greyscale rows to reduce, before any are read.
+    fn set_packing(&mut self, packing: Option<Reduction>) {
+        assert!(self.rows == 0);
+        self.packing = packing;
+        if packing.is_some() {
+            self.stride = self.header.width as usize;
+        }
     }
 
     //
-    // Run the filtering, on a background thread.
-    //
-    fn run(&mut self) -> IoResult {
-        let mut filter = AdaptiveFilter::new(self.input.header, self.filter_mode);
-        let zero = vec![0u8; self.stride - 1];
-        for i in self.start_row .. self.end_row {
-            let prior = if i == self.start_row {
-                match self.prior_input {
-                    Some(ref input) => input,
-                    None => &self.input, // Won't get used.
+    // Whether each chunk is dithered from the raw row above it, which
+    // leaves the packed row above unknown when its first row is filtered.
+    //
+    fn is_detached(&self) -> bool {
+        matches!(self.packing, Some(Reduction::Dither))
+    }
+
+    fn is_full(&self) -> bool {
+        self.rows == (self.end_row - self.start_row)
+    }
+
+    fn read_row(&mut self, row: &[u8])
+    {
+        let capacity = self.stride * (self.end_row - self.start_row);
+        match self.data {
+            Pixels::Owned(ref mut data) => {
+                if self.rows == 0 {
+                    data.reserve_exact(capacity);
+                }
+                if self.little_endian {
+                    data.extend_from_slice(&swap_samples(&row[.. self.stride]));
+                } else {
+                    data.extend_from_slice(&row[.. self.stride]);
+                }
+            },
+            Pixels::Shared(..) => panic!("Tried to add a row to a shared chunk"),
+        }
+        self.rows += 1;
+    }
+
+    //
+    // Add a row of native 16-bit samples, held little-endian unless
+    // the chunk already has big-endian rows in it.
+    //
+    fn read_row_u16(&mut self, row: &[u16]) {
+        if self.rows == 0 {
+            self.little_endian = true;
+        }
+        if !self.little_endian {
+            let row: Vec<u8> = row.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+            return self.read_row(&row);
+        }
+        match self.data {
+            Pixels::Owned(ref mut data) => {
+                if self.rows == 0 {
+                    data.reserve_exact(self.stride * (self.end_row - self.start_row));
                 }
+                data.extend(row[.. self.stride / 2].iter().flat_map(|sample| sample.to_le_bytes()));
+            },
+            Pixels::Shared(..) => panic!("Tried to add a row to a shared chunk"),
+        }
+        self.rows += 1;
+    }
+
+    // Whether rows have to be put in PNG's byte order to be filtered.
+    fn needs_conversion(&self) -> bool {
+        self.little_endian || self.format != PixelFormat::Png || self.packing.is_some()
+    }
+
+    //
+    // The given rows in PNG's byte order. Error diffusion starts from
+    // the raw row above, if given.
+    //
+    fn png_rows(&self, start_row: usize, end_row: usize, above: Option<&[u8]>) -> Cow<'_, [u8]> {
+        let pixels = &self.pixels()[(start_row - self.start_row) * self.stride ..
+                                    (end_row - self.start_row) * self.stride];
+        if let Some(reduction) = self.packing {
+            let mut packer = layout::GreyscalePacker::new(self.header.depth, reduction).unwrap();
+            if let Some(above) = above {
+                packer.set_row(start_row - 1);
+                packer.pack_row(above);
             } else {
-                &self.input
+                packer.set_row(start_row);
+            }
+            Cow::Owned(pixels.chunks(self.stride).flat_map(|row| packer.pack_row(row)).collect())
+        } else if self.little_endian {
+            Cow::Owned(swap_samples(pixels))
+        } else if self.format != PixelFormat::Png {
+            Cow::Owned(self.format.to_png(&self.header, pixels))
+        } else {
+            Cow::Borrowed(pixels)
+        }
+    }
+
+    // Take all the chunk's rows from the buffer at the given offset.
+    fn share_rows(&mut self, buf: Arc<[u8]>, offset: usize) {
+        assert!(self.rows == 0);
+        self.rows = self.end_row - self.start_row;
+        self.data = Pixels::Shared(buf, offset);
+    }
+
+    // The rows taken so far.
+    fn pixels(&self) -> &[u8] {
+        match self.data {
+            Pixels::Owned(ref data) => data,
+            Pixels::Shared(ref buf, offset) => &buf[offset .. offset + self.rows * self.stride],
+        }
+    }
+
+    fn get_row(&self, row: usize) -> &[u8] {
+        if row < self.start_row {
+            panic!("Tried to access row from earlier chunk: {} < {}", row, self.start_row);
+        } else if row >= self.end_row {
+            panic!("Tried to access row from later chunk: {} >= {}", row, self.end_row);
+        } else {
+            let start = (row - self.start_row) * self.stride;
+            &self.pixels()[start .. start + self.stride]
+        }
+    }
+}
+
+// Swap the bytes of each 16-bit sample.
+fn swap_samples(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2).flat_map(|pair| [pair[1], pair[0]]).collect()
+}
+
+// Takes pixel chunks as input and accumulates filtered output.
+struct FilterChunk {
+    index: usize,
+    start_row: usize,
+    end_row: usize,
+    is_start: bool,
+    is_end: bool,
+
+    stride: usize,
+    filter_mode: Mode<Filter>,
+    heuristic: Heuristic,
+    solid_rows: bool,
+    lock_in: usize,
+
+    // Filter to use for each row of the image, if given.
+    row_filters: Option<Arc<Vec<Filter>>>,
+
+    // The input pixels for chunk n-1
+    // Needed for its last row only.
+    prior_input: Option<Arc<PixelChunk>>,
+
+    // The input pixels for chunk n
+    input: Arc<PixelChunk>,
+
+    // Filtered output bytes
+    data: Vec<u8>,
+    pool: BufferPool,
+
+    // Number of rows using each filter type
+    filter_rows: [usize; 5],
+
+    // Time spent filtering
+    time: Duration,
+}
+
+impl Drop for FilterChunk {
+    fn drop(&mut self) {
+        self.pool.give(mem::take(&mut self.data));
+    }
+}
+
+impl FilterChunk {
+    fn new((prior_input, input): ChunkPair<PixelChunk>,
+           filter_mode: Mode<Filter>,
+           heuristic: Heuristic,
+           solid_rows: bool,
+           lock_in: usize,
+           row_filters: Option<Arc<Vec<Filter>>>,
+           pool: BufferPool) -> FilterChunk
+    {
+        // Prepend one byte for the filter selector.
+        let stride = input.header.stride() + 1;
+        let nbytes = stride * (input.end_row - input.start_row);
+
+        // Filtering overwrites every byte, so old contents can stay.
+        let mut data = pool.take();
+        data.resize(nbytes, 0);
+
+        FilterChunk {
+            index: input.index,
+            start_row: input.start_row,
+            end_row: input.end_row,
+            is_start: input.is_start,
+            is_end: input.is_end,
+
+            stride,
+            filter_mode,
+            heuristic,
+            solid_rows,
+            lock_in,
+            row_filters,
+
+            prior_input,
+            input,
+            data,
+            pool,
+            filter_rows: [0; 5],
+            time: Duration::default(),
+        }
+    }
+
+    // Return the last up-to-32kib, used as an input dictionary
+    // for the next chunk's deflate job.
+    fn get_trailer(&self) -> &[u8] {
+        let trailer = 32768;
+        let len = self.data.len();
+        if len > trailer {
+            &self.data[len - trailer .. len]
+        } else {
+            &self.data[0 .. len]
+        }
+    }
+
+    //
+    // Run the filtering, on a background thread.
+    //
+    fn run(&mut self) -> IoResult {
+        let input = Arc::clone(&self.input);
+        let prior_input = self.prior_input.clone();
+        let (input, prior_input) = (&*input, prior_input.as_deref());
+        if input.needs_conversion() || prior_input.is_some_and(PixelChunk::needs_conversion) {
+            // Put pixels in PNG's byte order here, off the main thread.
+            let (above, prior_row) = match prior_input {
+                Some(prior) if input.is_detached() => (Some(prior.get_row(prior.end_row - 1)), None),
+                Some(prior) => (None, Some(prior.png_rows(prior.end_row - 1, prior.end_row, None))),
+                None => (None, None),
             };
-            let prev = if i == 0 {
+            let pixels = input.png_rows(input.start_row, input.end_row, above);
+            let stride = self.stride - 1;
+            return self.filter_rows(|i| match prior_row {
+                Some(ref prior) if i < input.start_row => &prior[..],
+                _ => &pixels[(i - input.start_row) * stride ..][.. stride],
+            });
+        }
+        self.filter_rows(|i| match prior_input {
+            Some(prior) if i < input.start_row => prior.get_row(i),
+            _ => input.get_row(i),
+        })
+    }
+
+    //
+    // Filter the chunk's rows, taking the pixels of each row by
+    // its number, including the last row of the chunk before.
+    //
+    fn filter_rows<'r>(&mut self, get_row: impl Fn(usize) -> &'r [u8]) -> IoResult {
+        let start = Instant::now();
+        let mut filter = AdaptiveFilter::new(self.input.header,
+                                             self.filter_mode,
+                                             self.heuristic,
+                                             self.solid_rows,
+                                             self.lock_in);
+        let zero = vec![0u8; self.stride - 1];
+        let rows = self.data.chunks_mut(self.stride);
+        for (i, dest) in (self.start_row .. self.end_row).zip(rows) {
+            let row = get_row(i);
+            if i == self.start_row && i != self.input.pass_start && self.input.is_detached() {
+                // Sub is the filter that doesn't look at the row above.
+                filter.filter_with_into(Filter::Sub, &zero, row, dest);
+                self.filter_rows[dest[0] as usize] += 1;
+                continue;
+            }
+
+            let prev = if i == self.input.pass_start {
                 &zero
             } else {
-                prior.get_row(i - 1)
+                get_row(i - 1)
             };
 
-            let row = self.input.get_row(i);
-
-            let output = filter.filter(prev, row);
-
-            self.data.write_all(output)?
+            match self.row_filters {
+                Some(ref filters) => filter.filter_with_into(filters[i], prev, row, dest),
+                None => filter.filter_into(prev, row, dest),
+            }
+            self.filter_rows[dest[0] as usize] += 1;
         }
+        self.time = start.elapsed();
         Ok(())
     }
 }
@@ -330,6 +1405,7 @@ struct DeflateChunk {
     is_start: bool,
     is_end: bool,
 
+    backend: Backend,
     compression_level: CompressionLevel,
     strategy: Strategy,
 
@@ -343,36 +1419,47 @@ struct DeflateChunk {
 
     // Compressed output bytes
     data: Vec<u8>,
+    pool: BufferPool,
 
     // Checksum of this chunk
     adler32: u32,
+
+    // Time spent compressing and checksumming
+    deflate_time: Duration,
+    checksum_time: Duration,
 }
 
 impl DeflateChunk {
-    fn new(compression_level: CompressionLevel,
+    fn new(backend: Backend,
+           compression_level: CompressionLevel,
            strategy: Strategy,
            prior_input: Option<Arc<FilterChunk>>,
-           input: Arc<FilterChunk>) -> DeflateChunk {
+           input: Arc<FilterChunk>,
+           pool: BufferPool) -> DeflateChunk {
 
         DeflateChunk {
             index: input.index,
             is_start: input.is_start,
             is_end: input.is_end,
 
+            backend,
             compression_level,
             strategy,
 
             prior_input,
             input,
             data: Vec::new(),
-            adler32: deflate::adler32_initial(),
+            pool,
+            adler32: checksum::adler32_initial(),
+            deflate_time: Duration::default(),
+            checksum_time: Duration::default(),
         }
     }
 
     fn run(&mut self) -> IoResult {
         // Run the deflate!
-        // Todo: don't create an empty vector earlier, but reuse it sanely.
-        let data = Vec::<u8>::new();
+        let data = self.pool.take();
+        let start = Instant::now();
 
         let mut options = deflate::Options::new();
 
@@ -387,10 +1474,11 @@ impl DeflateChunk {
 
         match self.compression_level {
             CompressionLevel::Default => {},
-            CompressionLevel::Fast => options.set_level(1),
-            CompressionLevel::High => options.set_level(9),
+            CompressionLevel::Max => options.set_zopfli(true),
+            level => options.set_level(i32::from(level.level())),
         }
         options.set_strategy(self.strategy);
+        options.set_backend(self.backend);
 
         let mut encoder = Deflate::new(options, data);
 
@@ -407,16 +1495,26 @@ impl DeflateChunk {
         })?;
 
         // In raw deflate mode we have to calculate the checksum ourselves.
-        self.adler32 = deflate::adler32(1, &self.input.data);
+        let checksum_start = Instant::now();
+        self.adler32 = checksum::adler32(checksum::adler32_initial(), &self.input.data);
+        self.checksum_time = checksum_start.elapsed();
 
-        match encoder.finish() {
+        let result = match encoder.finish() {
             Ok(data) => {
                 // This seems lame to move the vector back, but it's actually cheap.
                 self.data = data;
                 Ok(())
             },
             Err(e) => Err(e)
-        }
+        };
+        self.deflate_time = start.elapsed() - self.checksum_time;
+        result
+    }
+}
+
+impl Drop for DeflateChunk {
+    fn drop(&mut self) {
+        self.pool.give(mem::take(&mut self.data));
     }
 }
 
@@ -438,6 +1536,9 @@ struct ChunkMap<T> {
     prev: Option<Arc<T>>,
 }
 
+// A completed chunk along with its predecessor, if any.
+type ChunkPair<T> = (Option<Arc<T>>, Arc<T>);
+
 impl<T> ChunkMap<T> {
     fn new() -> ChunkMap<T> {
         ChunkMap {
@@ -453,6 +1554,11 @@ impl<T> ChunkMap<T> {
         self.cursor_in > self.cursor_out
     }
 
+    // Total size of landed chunks not yet taken, as measured by the given function.
+    fn buffered(&self, size: impl Fn(&T) -> usize) -> usize {
+        self.chunks.iter().flatten().map(|chunk| size(chunk)).sum()
+    }
+
     fn running_jobs(&self) -> usize {
         self.running
     }
@@ -467,13 +1573,15 @@ impl<T> ChunkMap<T> {
 
     //
     // Record that this job has landed and save its data.
+    // Landing a chunk outside the in-flight window is an internal
+    // error, reported rather than panicking so the caller can bail.
     //
-    fn land(&mut self, index: usize, chunk: Arc<T>) {
+    fn land(&mut self, index: usize, chunk: Arc<T>) -> IoResult {
         if index < self.cursor_out {
-            panic!("Tried to land an expired chunk");
+            return Err(other("Internal error: tried to land an expired chunk"));
         }
-        if index > self.cursor_in {
-            panic!("Tried to land a future chunk");
+        if index >= self.cursor_in {
+            return Err(other("Internal error: tried to land a future chunk"));
         }
         self.running -= 1;
         let offset = index - self.cursor_out;
@@ -485,35 +1593,104 @@ impl<T> ChunkMap<T> {
         } else {
             self.chunks[offset] = Some(chunk);
         }
+        Ok(())
     }
 
-    fn pop_front(&mut self) -> Option<(Option<Arc<T>>, Arc<T>)> {
-        match self.chunks.get(0) {
+    fn pop_front(&mut self) -> io::Result<Option<ChunkPair<T>>> {
+        match self.chunks.front() {
             Some(Some(_)) => {
                 // Ok we're good we have something
-                self.cursor_out += 1;
                 match self.chunks.pop_front() {
                     Some(Some(item)) => {
-                        let prev = std::mem::replace(&mut self.prev, Some(Arc::clone(&item)));
-                        Some((prev, item))
+                        self.cursor_out += 1;
+                        let prev = self.prev.replace(Arc::clone(&item));
+                        Ok(Some((prev, item)))
                     },
                     _ => {
-                        panic!("Bad job queue state")
+                        Err(other("Internal error: bad job queue state"))
                     }
                 }
             },
             Some(None) => {
                 // Not ready yet but a later chunk landed.
-                None
+                Ok(None)
             },
             None => {
                 // Nothing yet.
-                None
+                Ok(None)
             }
         }
     }
 }
 
+//
+// Filter each chunk from rows of the borrowed image on the scope's
+// pool, catching panics as dispatched jobs do.
+//
+fn spawn_filters<'s>(scope: &::rayon::Scope<'s>,
+                     filters: &'s mut [FilterChunk],
+                     results: &'s mut [IoResult],
+                     image: &'s [u8],
+                     busy_nanos: &'s AtomicU64)
+{
+    for (filter, result) in filters.iter_mut().zip(results.iter_mut()) {
+        scope.spawn(move |_| {
+            let start = Instant::now();
+            let stride = filter.stride - 1;
+            let get_row = |i: usize| &image[i * stride .. (i + 1) * stride];
+            *result = panic::catch_unwind(AssertUnwindSafe(|| filter.filter_rows(get_row)))
+                            .unwrap_or_else(|_| Err(worker_panic()));
+            busy_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        });
+    }
+}
+
+// Work waiting to run on the thread pool.
+type Job = Box<dyn FnOnce() + Send>;
+type JobQueue = Arc<Mutex<VecDeque<Job>>>;
+
+fn take_job(jobs: &JobQueue) -> Option<Job> {
+    jobs.lock().ok().and_then(|mut jobs| jobs.pop_front())
+}
+
+//
+// Jobs waiting for a pool thread, and the channel that finished
+// jobs report back on.
+//
+// When the encoder is dropped, jobs that haven't started yet are
+// thrown away, so a half-fed encoder doesn't leave the pool busy
+// compressing an image nobody wants. Jobs already running finish
+// their chunk and find nobody listening.
+//
+struct Jobs {
+    queue: JobQueue,
+    cancelled: Arc<AtomicBool>,
+    rx: Receiver<ThreadMessage>,
+}
+
+impl Jobs {
+    fn new(rx: Receiver<ThreadMessage>) -> Jobs {
+        Jobs {
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            rx,
+        }
+    }
+}
+
+impl Drop for Jobs {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.clear();
+        }
+        // Results that came in but were never landed hold on to
+        // their buffers until the last running job lets go of the
+        // channel; drop them now instead.
+        while self.rx.try_recv().is_ok() {}
+    }
+}
+
 enum ThreadMessage {
     FilterDone(Arc<FilterChunk>),
     DeflateDone(Arc<DeflateChunk>),
@@ -531,24 +1708,178 @@ enum RowStatus {
     Done,
 }
 
-/// Parallel PNG encoder state.
-/// Takes an Options struct with initializer data and a Write struct
-/// to send output to.
-pub struct Encoder<'a, W: Write> {
-    writer: Writer<W>,
-    options: Options<'a>,
-
-    header: Header,
+// Convert a string to Latin-1 bytes, if it can be represented.
+fn latin1(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c as u32).ok()).collect()
+}
 
-    wrote_header: bool,
-    wrote_palette: bool,
-    palette_length: usize,
-    wrote_transparency: bool,
-    started_image: bool,
+//
+// Check a text chunk keyword and return it as Latin-1 bytes.
+//
+// https://www.w3.org/TR/PNG/#11keywords
+//
+fn text_keyword(keyword: &str) -> io::Result<Vec<u8>> {
+    let bytes = latin1(keyword).ok_or_else(|| invalid_input::<io::Error>("Text keyword must be Latin-1."))?;
+    if bytes.is_empty() || bytes.len() > 79 {
+        return Err(invalid_input("Text keyword must be 1-79 bytes."));
+    }
+    if bytes.iter().any(|&c| !(32 ..= 126).contains(&c) && c < 161) {
+        return Err(invalid_input("Text keyword must be printable."));
+    }
+    if keyword.starts_with(' ') || keyword.ends_with(' ') || keyword.contains("  ") {
+        return Err(invalid_input("Text keyword must not have leading, trailing, or consecutive spaces."));
+    }
+    Ok(bytes)
+}
+
+//
+// Convert gAMA and cHRM values to their fixed-point form,
+// in units of 1/100000.
+//
+fn fixed_point(value: f64) -> io::Result<u32> {
+    let scaled = (value * 100000.0).round();
+    if !(0.0 ..= f64::from(u32::MAX)).contains(&scaled) {
+        return Err(invalid_input(&format!("Value {} is out of range for a fixed-point chunk field.", value)));
+    }
+    Ok(scaled as u32)
+}
+
+//
+// Convert mDCv and cLLi luminance values in cd/m^2 to units of 0.0001.
+//
+fn luminance(value: f64) -> io::Result<u32> {
+    let scaled = (value * 10000.0).round();
+    if !(0.0 ..= f64::from(u32::MAX)).contains(&scaled) {
+        return Err(invalid_input(&format!("Luminance {} is out of range.", value)));
+    }
+    Ok(scaled as u32)
+}
+
+//
+// Check an iTXt language tag: empty, or hyphen-separated words of
+// 1-8 ASCII letters and digits.
+//
+fn check_language_tag(lang: &str) -> IoResult {
+    let valid = lang.is_empty() || lang.split('-').all(|word| {
+        (1 ..= 8).contains(&word.len()) && word.bytes().all(|c| c.is_ascii_alphanumeric())
+    });
+    if !valid {
+        return Err(invalid_input("Text language tag must be hyphen-separated words of 1-8 letters or digits."));
+    }
+    Ok(())
+}
+
+//
+// Merge palette entries with the same color and alpha, keeping them
+// in order of first appearance. Returns the merged PLTE data and the
+// new index for each original entry.
+//
+fn dedup_palette(palette: &[u8], alphas: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut merged = Vec::with_capacity(palette.len());
+    let mut map = Vec::with_capacity(palette.len() / 3);
+    let mut seen = HashMap::<[u8; 4], u8>::new();
+    for (i, rgb) in palette.chunks(3).enumerate() {
+        let key = [rgb[0], rgb[1], rgb[2], alphas.get(i).copied().unwrap_or(255)];
+        let next = (merged.len() / 3) as u8;
+        let index = *seen.entry(key).or_insert(next);
+        if index == next {
+            merged.extend_from_slice(rgb);
+        }
+        map.push(index);
+    }
+    (merged, map)
+}
+
+//
+// Rewrite a row of palette indices through the given map.
+// Indices past the end of the map are left alone.
+//
+fn remap_indices(row: &[u8], map: &[u8], depth: u8, width: usize) -> Vec<u8> {
+    let remap = |index: u8| map.get(index as usize).copied().unwrap_or(index);
+    match depth {
+        8 => row.iter().map(|&index| remap(index)).collect(),
+        _ => {
+            let indices: Vec<u8> = layout::unpack(row, depth, width).into_iter().map(remap).collect();
+            layout::pack(&indices, depth)
+        }
+    }
+}
+
+//
+// Find the largest palette index used in a row of indexed-color pixels,
+// ignoring any padding bits at the end of the row.
+//
+fn max_index(row: &[u8], depth: u8, width: usize) -> u8 {
+    layout::unpack(row, depth, width).into_iter().max().unwrap_or(0)
+}
+
+// Which method to use instead for each critical chunk.
+fn critical_chunk_method(tag: &[u8]) -> Option<&'static str> {
+    match tag {
+        b"IHDR" => Some("write_header"),
+        b"PLTE" => Some("write_palette"),
+        b"IDAT" => Some("write_image_rows"),
+        b"IEND" => Some("finish"),
+        _ => None,
+    }
+}
+
+/// Parallel PNG encoder state.
+/// Takes an Options struct with initializer data and a Write struct
+/// to send output to.
+pub struct Encoder<'a, W: Write> {
+    writer: Writer<W>,
+    options: Options<'a>,
+
+    header: Header,
+
+    wrote_header: bool,
+    wrote_palette: bool,
+    palette_length: usize,
+    wrote_transparency: bool,
+    started_image: bool,
+
+    // Tags of single-instance ancillary chunks already written.
+    single_chunks: Vec<[u8; 4]>,
+
+    // Names of sPLT chunks already written, which must be unique.
+    suggested_palettes: Vec<Vec<u8>>,
+
+    // Significant bits in each input channel, if they must be scaled up.
+    significant_bits: Option<Vec<u8>>,
+
+    // Packs one byte per pixel input into low-depth greyscale, if set.
+    greyscale_packer: Option<layout::GreyscalePacker>,
+
+    // Matte color to composite input with an alpha channel over, if set.
+    flatten_matte: Option<Vec<u16>>,
+
+    // With palette deduplication, the palette until it's written out,
+    // and the map from given to written indices if any were merged.
+    pending_palette: Option<Vec<u8>>,
+    palette_map: Option<Vec<u8>>,
+
+    // Passes making up the image data, and the pass and first row
+    // of each chunk.
+    passes: Vec<Pass>,
+    chunk_plan: Vec<(usize, usize)>,
 
     chunks_total: usize,
     chunks_output: usize,
 
+    // Filters to use for each row instead of the filter mode, if set,
+    // and the filter each row was given so far.
+    forced_filters: Option<Arc<Vec<Filter>>>,
+    row_filters: Vec<Filter>,
+
+    // Holds the whole image for interlaced output, until the last
+    // row is in and the passes can be picked out.
+    interlace_buffer: Vec<u8>,
+
+    // With depth reduction, everything held back from a 16-bit image
+    // until the last row is in and its depth is known.
+    held_image: Option<HeldImage>,
+
     // Accumulates input rows until enough are ready to fire off a filter job.
     pixel_accumulator: Arc<PixelChunk>,
     pixel_index: usize,
@@ -565,9 +1896,48 @@ pub struct Encoder<'a, W: Write> {
     // Accumulates IDAT output when not using streaming output mode
     idat_buffer: Vec<u8>,
 
+    stats: EncodeStats,
+
+    // Time spent in each stage, per chunk.
+    chunk_times: Vec<StageTimes>,
+
     // For messages from the thread pool.
     tx: Sender<ThreadMessage>,
-    rx: Receiver<ThreadMessage>,
+
+    // Jobs waiting for a pool thread, which we can run ourselves
+    // if the pool doesn't get to them in time.
+    jobs: Jobs,
+
+    // Buffers for filter and deflate output, reused across chunks.
+    buffers: BufferPool,
+
+    // For runtime metrics.
+    started: Option<Instant>,
+    busy_nanos: Arc<AtomicU64>,
+    #[cfg(feature = "metrics")]
+    gauges: telemetry::Gauges,
+
+    // Called after each job, for callers that wait for jobs some way
+    // other than blocking on their results.
+    job_done: Option<JobDone>,
+}
+
+// Wakes up whoever is waiting on a job to finish.
+pub(crate) type JobDone = Arc<dyn Fn() + Send + Sync>;
+
+impl<'a, W: Write + Seek> Encoder<'a, W> {
+    /// Creates a new Encoder instance for an output that can seek, such
+    /// as a file.
+    ///
+    /// Without streaming mode, image data then goes straight out as it
+    /// is compressed, into a single "IDAT" chunk whose length is filled
+    /// in at the end, instead of being held in memory until the image
+    /// is complete. The file is the same as Encoder::new would make.
+    pub fn new_seekable(write: W, options: &Options<'a>) -> Encoder<'a, W> {
+        let mut encoder = Encoder::new(write, options);
+        encoder.writer.set_seek(W::seek);
+        encoder
+    }
 }
 
 impl<'a, W: Write> Encoder<'a, W> {
@@ -586,11 +1956,33 @@ impl<'a, W: Write> Encoder<'a, W> {
             wrote_transparency: false,
             started_image: false,
 
+            single_chunks: Vec::new(),
+            suggested_palettes: Vec::new(),
+
+            significant_bits: None,
+
+            greyscale_packer: None,
+
+            flatten_matte: None,
+
+            pending_palette: None,
+            palette_map: None,
+
+            passes: Vec::new(),
+            chunk_plan: Vec::new(),
+
             chunks_total: 0,
             chunks_output: 0,
 
+            forced_filters: None,
+            row_filters: Vec::new(),
+
+            interlace_buffer: Vec::new(),
+
+            held_image: None,
+
             // hack, clean this up later
-            pixel_accumulator: Arc::new(PixelChunk::new(Header::new(), 0, 0, 0)),
+            pixel_accumulator: Arc::new(PixelChunk::new(&Pass::new(Header::new()), 0, 0, 0, 1)),
             pixel_index: 0,
             current_row: 0,
 
@@ -598,26 +1990,60 @@ impl<'a, W: Write> Encoder<'a, W> {
             filter_chunks: ChunkMap::new(),
             deflate_chunks: ChunkMap::new(),
 
-            adler32: deflate::adler32_initial(),
+            adler32: checksum::adler32_initial(),
             idat_buffer: Vec::new(),
 
+            stats: EncodeStats::default(),
+            chunk_times: Vec::new(),
+
             tx,
-            rx,
+            jobs: Jobs::new(rx),
+
+            buffers: BufferPool::default(),
+
+            started: None,
+            busy_nanos: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "metrics")]
+            gauges: telemetry::Gauges::default(),
+            job_done: None,
         }
     }
 
     /// Flush output and return the Write sink for further manipulation.
     /// Consumes the encoder instance.
-    pub fn finish(mut self) -> io::Result<W> {
+    pub fn finish(self) -> error::Result<W> {
+        self.finish_with_stats().map(|(writer, _)| writer)
+    }
+
+    /// Flush output and return the Write sink along with statistics
+    /// for the whole encoding run, including the end chunk.
+    /// Consumes the encoder instance.
+    pub fn finish_with_stats(mut self) -> error::Result<(W, EncodeStats)> {
         self.flush()?;
         if self.is_finished() {
             self.writer.write_end()?;
-            self.writer.finish()
+            let stats = self.stats();
+            #[cfg(feature = "metrics")]
+            telemetry::image_finished(&self.metrics());
+            Ok((self.writer.finish()?, stats))
         } else {
             Err(other("Incomplete image input"))
         }
     }
 
+    /// Stop encoding partway through and return the Write sink, as
+    /// when a client disconnects. Queued filter and compression jobs
+    /// are discarded, and any already running finish in the background
+    /// with their results dropped.
+    ///
+    /// Nothing more is written or flushed, so the output is left as
+    /// an incomplete PNG file. Consumes the encoder instance.
+    pub fn abort(self) -> W {
+        // The rest of the encoder is dropped on the way out,
+        // which cancels the jobs.
+        self.writer.into_inner()
+    }
+
     fn running_jobs(&self) -> usize {
         self.filter_chunks.running_jobs() + self.deflate_chunks.running_jobs()
     }
@@ -639,43 +2065,140 @@ impl<'a, W: Write> Encoder<'a, W> {
         where F: Fn(&Sender<ThreadMessage>) + Send + 'static
     {
         let tx = self.tx.clone();
-        match self.options.thread_pool {
-            Some(pool) => {
-                pool.spawn(move || {
-                    func(&tx);
-                });
-            },
-            None => {
-                ::rayon::spawn(move || {
-                    func(&tx);
-                });
+        let busy_nanos = Arc::clone(&self.busy_nanos);
+        let job_done = self.job_done.clone();
+        let cancelled = Arc::clone(&self.jobs.cancelled);
+        // A panicking job would never report back and leave us
+        // waiting forever, so turn it into an error message.
+        let job = move || {
+            if cancelled.load(Ordering::Relaxed) {
+                // Taken off the queue just as the encoder went away.
+                return;
+            }
+            let start = Instant::now();
+            if panic::catch_unwind(AssertUnwindSafe(|| func(&tx))).is_err() {
+                debug_log!("encoding job panicked; reporting as an error");
+                tx.send(ThreadMessage::Error(worker_panic())).ok();
             }
+            busy_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            if let Some(job_done) = job_done {
+                job_done();
+            }
+        };
+        if let Ok(mut jobs) = self.jobs.queue.lock() {
+            jobs.push_back(Box::new(job));
+        }
+
+        // Each pool task runs whichever job is next in line; if we
+        // already ran it inline, there's nothing left for it to do.
+        let jobs = Arc::clone(&self.jobs.queue);
+        let task = move || {
+            if let Some(job) = take_job(&jobs) {
+                job();
+            }
+        };
+        match self.options.thread_pool {
+            Some(pool) => pool.spawn(task),
+            None => ::rayon::spawn(task),
         }
     }
 
     fn start_row(&self, index: usize) -> usize {
-        index * self.header.height() as usize / self.chunks_total
+        self.chunk_plan[index].1
     }
 
     fn end_row(&self, index: usize) -> usize {
-        self.start_row(index + 1)
+        match self.chunk_plan.get(index + 1) {
+            Some(&(_, row)) => row,
+            None => self.total_rows(),
+        }
+    }
+
+    // Number of rows in the image data, across all passes.
+    fn total_rows(&self) -> usize {
+        self.passes.last().map_or(0, |pass| pass.end_row())
+    }
+
+    fn new_pixel_chunk(&self, index: usize) -> PixelChunk {
+        let mut chunk = PixelChunk::new(&self.passes[self.chunk_plan[index].0],
+                                        index,
+                                        self.start_row(index),
+                                        self.end_row(index),
+                                        self.total_rows());
+        chunk.set_format(self.chunk_format());
+        chunk.set_packing(self.chunk_packing());
+        chunk
+    }
+
+    //
+    // Greyscale reduction left to the filter jobs, if any. Those jobs
+    // can't replay row filters with error diffusion, as the first row
+    // of each chunk must use Sub.
+    //
+    fn chunk_packing(&self) -> Option<Reduction> {
+        let reduction = self.greyscale_packer.as_ref()?.reduction();
+        let interlaced = matches!(self.header.interlace_method, InterlaceMethod::Adam7);
+        let dithered = matches!(reduction, Reduction::Dither);
+        if interlaced || (dithered && self.forced_filters.is_some()) {
+            None
+        } else {
+            Some(reduction)
+        }
+    }
+
+    //
+    // Byte order pixel chunks hold rows in: as given when the filter
+    // jobs can convert them, or PNG's when rows are converted or
+    // interlaced as they're copied in.
+    //
+    fn chunk_format(&self) -> PixelFormat {
+        if self.has_input_transform() || matches!(self.header.interlace_method, InterlaceMethod::Adam7) {
+            PixelFormat::Png
+        } else {
+            self.options.input_format
+        }
     }
 
+    //
+    // When blocking, a pool that's saturated by other work (or that
+    // we're running on ourselves) might never get to our jobs. After
+    // the dispatch timeout, run queued jobs on this thread instead.
+    //
     fn receive(&mut self, blocking: DispatchMode) -> Option<ThreadMessage> {
-        match blocking {
-            DispatchMode::Blocking => match self.rx.recv() {
-                Ok(msg) => Some(msg),
-                _ => None,
+        match (blocking, self.options.dispatch_timeout) {
+            (DispatchMode::NonBlocking, _) => self.jobs.rx.try_recv().ok(),
+            (DispatchMode::Blocking, None) => self.jobs.rx.recv().ok(),
+            (DispatchMode::Blocking, Some(timeout)) => loop {
+                match self.jobs.rx.recv_timeout(timeout) {
+                    Ok(message) => return Some(message),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Some(job) = take_job(&self.jobs.queue) {
+                            debug_log!("no results from the thread pool after {:?}; running a queued job inline",
+                                       timeout);
+                            job();
+                        }
+                    },
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                }
             },
-            DispatchMode::NonBlocking => match self.rx.try_recv() {
-                Ok(msg) => Some(msg),
-                _ => None,
-            }
         }
     }
 
     fn filter_mode(&self) -> Mode<Filter> {
-        match self.options.filter_mode {
+        self.resolve_filter_mode(self.options.filter_mode)
+    }
+
+    // Filter mode for the rows of a pass, which interlaced
+    // images can override for each Adam7 pass.
+    fn pass_filter_mode(&self, pass: Option<usize>) -> Mode<Filter> {
+        match pass.and_then(|number| self.options.pass_filter_modes[number]) {
+            Some(mode) => self.resolve_filter_mode(mode),
+            None => self.filter_mode(),
+        }
+    }
+
+    fn resolve_filter_mode(&self, mode: Mode<Filter>) -> Mode<Filter> {
+        match mode {
             Fixed(s) => Fixed(s),
             Adaptive => match self.header.color_type {
                 ColorType::IndexedColor => Fixed(Filter::None),
@@ -694,16 +2217,38 @@ impl<'a, W: Write> Encoder<'a, W> {
         }
     }
 
+    fn land_filter_chunk(&mut self, filter: Arc<FilterChunk>) -> IoResult {
+        for (total, rows) in self.stats.filter_rows.iter_mut().zip(filter.filter_rows.iter()) {
+            *total += rows;
+        }
+        self.stats.filtered_bytes += filter.data.len() as u64;
+        self.chunk_times[filter.index].filter = filter.time;
+        for (dest, row) in self.row_filters[filter.start_row .. filter.end_row].iter_mut()
+                                                                                .zip(filter.data.chunks(filter.stride)) {
+            *dest = Filter::try_from(row[0])?;
+            self.stats.filter_bytes[row[0] as usize] += row.len() as u64;
+            self.stats.uncompressed_bytes += row.len() as u64 - 1;
+        }
+        trace_log!("filtered chunk {} in {:?}; rows per filter type {:?}",
+                   filter.index, filter.time, filter.filter_rows);
+        self.filter_chunks.land(filter.index, filter)
+    }
+
     fn dispatch(&mut self, mode: DispatchMode) -> IoResult {
         // See if anything interesting happened on the threads.
         let mut blocking_mode = mode;
         while self.filter_chunks.in_flight() || self.deflate_chunks.in_flight() {
             match self.receive(blocking_mode) {
                 Some(ThreadMessage::FilterDone(filter)) => {
-                    self.filter_chunks.land(filter.index, filter);
+                    self.land_filter_chunk(filter)?;
                 }
                 Some(ThreadMessage::DeflateDone(deflate)) => {
-                    self.deflate_chunks.land(deflate.index, deflate);
+                    let times = &mut self.chunk_times[deflate.index];
+                    times.deflate = deflate.deflate_time;
+                    times.checksum = deflate.checksum_time;
+                    trace_log!("deflated chunk {} to {} bytes in {:?}",
+                               deflate.index, deflate.data.len(), deflate.deflate_time);
+                    self.deflate_chunks.land(deflate.index, deflate)?;
                 },
                 Some(ThreadMessage::Error(e)) => {
                     return Err(e);
@@ -720,14 +2265,19 @@ impl<'a, W: Write> Encoder<'a, W> {
 
         // If we have more deflate work to do, dispatch them!
         while self.running_jobs() < self.max_threads() {
-            match self.filter_chunks.pop_front() {
+            match self.filter_chunks.pop_front()? {
                 Some((previous, current)) => {
                     // Prepare to dispatch the deflate job:
+                    let backend = self.options.backend;
                     let level = self.options.compression_level;
                     let strategy = self.compression_strategy();
+                    let pool = self.buffers.clone();
                     self.deflate_chunks.advance();
+                    trace_log!("dispatching deflate job for chunk {}", current.index);
                     self.dispatch_func(move |tx| {
-                        let mut deflate = DeflateChunk::new(level, strategy, previous.clone(), current.clone());
+                        let mut deflate = DeflateChunk::new(backend, level, strategy,
+                                                            previous.clone(), current.clone(),
+                                                            pool.clone());
                         tx.send(match deflate.run() {
                             Ok(()) => ThreadMessage::DeflateDone(Arc::new(deflate)),
                             Err(e) => ThreadMessage::Error(e),
@@ -742,15 +2292,25 @@ impl<'a, W: Write> Encoder<'a, W> {
 
         // If we have more filter work to do, dispatch them!
         while self.running_jobs() < self.max_threads() {
-            match self.pixel_chunks.pop_front() {
+            match self.pixel_chunks.pop_front()? {
                 Some((previous, current)) => {
                     // Prepare to dispatch the filter job:
                     self.filter_chunks.advance();
-                    let filter_mode = self.filter_mode();
+                    trace_log!("dispatching filter job for chunk {}", current.index);
+                    let filter_mode = self.pass_filter_mode(current.pass);
+                    let heuristic = self.options.heuristic;
+                    let solid_rows = self.options.solid_rows;
+                    let lock_in = self.options.filter_lock_in;
+                    let row_filters = self.forced_filters.clone();
+                    let pool = self.buffers.clone();
                     self.dispatch_func(move |tx| {
-                        let mut filter = FilterChunk::new(previous.clone(),
-                                                          current.clone(),
-                                                          filter_mode);
+                        let mut filter = FilterChunk::new((previous.clone(), current.clone()),
+                                                          filter_mode,
+                                                          heuristic,
+                                                          solid_rows,
+                                                          lock_in,
+                                                          row_filters.clone(),
+                                                          pool.clone());
                         tx.send(match filter.run() {
                             Ok(()) => ThreadMessage::FilterDone(Arc::new(filter)),
                             Err(e) => ThreadMessage::Error(e),
@@ -764,15 +2324,26 @@ impl<'a, W: Write> Encoder<'a, W> {
         }
 
         // If we have output to run, write it!
-        while let Some((_previous, current)) = self.deflate_chunks.pop_front() {
+        while let Some((_previous, current)) = self.deflate_chunks.pop_front()? {
             if self.chunks_output >= self.chunks_total {
-                panic!("Got extra output after end of file; should not happen.");
+                return Err(other("Internal error: got extra output after end of file"));
             }
 
             // Combine the checksums!
-            self.adler32 = deflate::adler32_combine(self.adler32,
+            let checksum_start = Instant::now();
+            self.adler32 = checksum::adler32_combine(self.adler32,
                                                     current.adler32,
                                                     current.input.data.len());
+            self.chunk_times[current.index].checksum += checksum_start.elapsed();
+            let write_start = Instant::now();
+
+            let mut compressed_bytes = current.data.len();
+            if current.is_end && !current.is_start {
+                // Trailing checksum
+                compressed_bytes += 4;
+            }
+            self.stats.compressed_bytes += compressed_bytes as u64;
+            self.stats.chunk_bytes.push(compressed_bytes);
 
             // if not streaming, append to an in-memory buffer
             // and output a giant tag later.
@@ -786,6 +2357,20 @@ impl<'a, W: Write> Encoder<'a, W> {
                     }
                     self.writer.write_chunk(b"IDAT", &chunk)?;
                 }
+            } else if self.writer.is_seekable() {
+                // Write into a single IDAT as we go, filling in
+                // its length at the end.
+                if current.is_start {
+                    self.writer.begin_chunk(b"IDAT")?;
+                }
+                self.writer.append_chunk(&current.data)?;
+
+                if current.is_end {
+                    if !current.is_start {
+                        self.writer.append_chunk(&self.adler32.to_be_bytes())?;
+                    }
+                    self.writer.end_chunk()?;
+                }
             } else {
                 self.idat_buffer.write_all(&current.data)?;
 
@@ -797,7 +2382,29 @@ impl<'a, W: Write> Encoder<'a, W> {
                 }
             }
 
+            self.chunk_times[current.index].write = write_start.elapsed();
             self.chunks_output += 1;
+            #[cfg(feature = "metrics")]
+            telemetry::chunk_written(&self.chunk_times[current.index], compressed_bytes);
+            trace_log!("wrote chunk {} of {} (rows {}..{}, {} bytes)",
+                       current.index + 1, self.chunks_total,
+                       current.input.start_row, current.input.end_row, compressed_bytes);
+
+            if let Some(callback) = self.options.on_chunk_complete {
+                callback(ChunkInfo {
+                    index: current.index,
+                    start_row: current.input.start_row,
+                    end_row: current.input.end_row,
+                    compressed_bytes,
+                    output_bytes: self.writer.bytes_written(),
+                });
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = self.metrics();
+            self.gauges.update(&metrics);
         }
 
         Ok(())
@@ -807,33 +2414,180 @@ impl<'a, W: Write> Encoder<'a, W> {
     /// Must be done before anything else is output.
     ///
     /// Subsequent image data must match the given header data.
-    pub fn write_header(&mut self, header: &Header) -> IoResult {
+    pub fn write_header(&mut self, header: &Header) -> error::Result<()> {
         if self.wrote_header {
             return Err(invalid_input("Cannot write header a second time."));
         }
+        if header.width() > self.options.max_width || header.height() > self.options.max_height {
+            return Err(invalid_input(&format!("Image size {}x{} exceeds the maximum of {}x{}.",
+                                              header.width(), header.height(),
+                                              self.options.max_width, self.options.max_height)));
+        }
+        self.options.input_format.check(header)?;
+
+        // Make sure the image buffers can be addressed before
+        // stride() or the chunk buffers get a chance to overflow.
+        let bits_per_pixel = header.color_type().channels() * header.depth() as usize;
+        let total_bytes = bits_per_pixel.checked_mul(header.width() as usize)
+                                        .and_then(|bits| (bits / 8 + 2).checked_mul(header.height() as usize));
+        if total_bytes.is_none() {
+            return Err(invalid_input("Image is too large to encode on this platform."));
+        }
 
         self.header = *header;
+        self.plan_image();
+        self.pixel_chunks.advance();
 
-        let stride = self.header.stride() + 1;
-        let height = self.header.height as usize;
+        self.wrote_header = true;
+        self.started = Some(Instant::now());
+
+        if self.options.reduce_depth && self.header.depth == 16 {
+            self.held_image = Some(HeldImage {
+                chunks: Vec::new(),
+                rows: Vec::with_capacity(self.header.stride() * self.header.height as usize),
+            });
+            return Ok(());
+        }
+        self.writer.write_signature()?;
+        Ok(self.writer.write_header(self.header)?)
+    }
 
-        let chunks = stride * height / self.options.chunk_size;
-        self.chunks_total = if chunks < 1 {
-            1
-        } else {
-            chunks
+    //
+    // Work out the passes and chunks of image data for the header,
+    // and start on the first chunk.
+    //
+    fn plan_image(&mut self) {
+        self.passes = match self.header.interlace_method {
+            InterlaceMethod::Standard => vec![Pass::new(self.header)],
+            InterlaceMethod::Adam7 => {
+                let mut passes = Vec::new();
+                let mut start_row = 0;
+                for number in 0 .. layout::ADAM7.len() {
+                    let (width, height) = layout::adam7_size(self.header.width, self.header.height, number);
+                    if width == 0 || height == 0 {
+                        // Small images leave some passes empty.
+                        continue;
+                    }
+                    let header = Header {
+                        width,
+                        height,
+                        ..self.header
+                    };
+                    passes.push(Pass {
+                        header,
+                        number: Some(number),
+                        start_row,
+                    });
+                    start_row += height as usize;
+                }
+                self.interlace_buffer.reserve(self.header.stride() * self.header.height as usize);
+                passes
+            },
         };
 
-        self.pixel_chunks.advance();
-        self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
-                                                          0, // index
-                                                          self.start_row(0),
-                                                          self.end_row(0)));
+        // Chunks hold whole rows, so very wide images can't be split
+        // into more chunks than they have rows; an empty chunk would
+        // never fill up and the image would never finish. Each pass
+        // of an interlaced image is split up on its own.
+        self.chunk_plan = Vec::new();
+        let bytes = self.passes.iter()
+                               .map(|pass| (pass.header.stride() + 1) * pass.header.height as usize)
+                               .sum();
+        let chunk_size = self.options.chunk_size_for(bytes);
+        for (i, pass) in self.passes.iter().enumerate() {
+            let stride = pass.header.stride() + 1;
+            let height = pass.header.height as usize;
+            match self.options.chunk_rows {
+                Some(rows) => {
+                    for start in (0 .. height).step_by(rows) {
+                        self.chunk_plan.push((i, pass.start_row + start));
+                    }
+                },
+                None => {
+                    let chunks = (stride * height / chunk_size).clamp(1, height);
+                    for chunk in 0 .. chunks {
+                        self.chunk_plan.push((i, pass.start_row + chunk * height / chunks));
+                    }
+                },
+            }
+        }
 
-        self.wrote_header = true;
+        let rows = self.total_rows();
+        self.chunks_total = self.chunk_plan.len();
+        self.stats.chunks = self.chunks_total;
+        self.chunk_times = vec![StageTimes::default(); self.chunks_total];
+        self.row_filters = vec![Filter::None; rows];
+        debug_log!("encoding {}x{} image in {} chunks of about {} rows on {} threads with {}",
+                   self.header.width(), self.header.height(), self.chunks_total,
+                   rows / self.chunks_total, self.threads(), self.options.backend.name());
+        if let (Adaptive, Fixed(filter)) = (self.options.filter_mode, self.filter_mode()) {
+            debug_log!("adaptive filtering resolved to {:?} for this color type", filter);
+        }
+        if let Adaptive = self.options.strategy_mode {
+            debug_log!("adaptive strategy resolved to {:?}", self.compression_strategy());
+        }
+
+        self.pixel_accumulator = Arc::new(self.new_pixel_chunk(0));
+    }
+
+    //
+    // Write a chunk that goes before the image data, or hold it back
+    // along with the header.
+    //
+    fn write_early_chunk(&mut self, tag: &[u8; 4], data: &[u8]) -> IoResult {
+        match self.held_image {
+            Some(ref mut held) => {
+                held.chunks.push((*tag, data.to_vec()));
+                Ok(())
+            },
+            None => self.writer.write_chunk(tag, data),
+        }
+    }
+
+    //
+    // With the last row of a held 16-bit image in, drop it to 8 bits
+    // if nothing is lost, then write out the header and chunks and
+    // send off the rows.
+    //
+    fn release_held_image(&mut self) -> IoResult {
+        let held = self.held_image.take().unwrap();
+        let exact = |bytes: &[u8]| bytes.chunks(2).all(|sample| sample[0] == sample[1]);
+        let keys_exact = held.chunks.iter().all(|(tag, data)| {
+            !(tag == b"tRNS" || tag == b"bKGD") || exact(data)
+        });
+        let (mut chunks, mut rows) = (held.chunks, held.rows);
+        if keys_exact && exact(&rows) {
+            debug_log!("every sample fits in 8 bits; writing an 8-bit image");
+            rows = rows.iter().step_by(2).copied().collect();
+            for (tag, data) in chunks.iter_mut() {
+                match &*tag {
+                    // 8-bit values are still two bytes, with a zero high byte.
+                    b"tRNS" | b"bKGD" => {
+                        for sample in data.chunks_mut(2) {
+                            sample[0] = 0;
+                        }
+                    },
+                    b"sBIT" => {
+                        for bits in data.iter_mut() {
+                            *bits = (*bits).min(8);
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            self.header.depth = 8;
+            self.plan_image();
+        }
 
         self.writer.write_signature()?;
-        self.writer.write_header(self.header)
+        self.writer.write_header(self.header)?;
+        for (tag, data) in chunks.iter() {
+            self.writer.write_chunk(tag, data)?;
+        }
+        for row in rows.chunks(self.header.stride()) {
+            self.store_row(row, Instant::now())?;
+        }
+        Ok(())
     }
 
     /// Write an indexed-color palette as a PLTE chunk.
@@ -842,7 +2596,11 @@ impl<'a, W: Write> Encoder<'a, W> {
     /// https://www.w3.org/TR/2003/REC-PNG-20031110/#11PLTE
     ///
     /// Note this chunk is allowed on truecolor images, though sPLT is preferred.
-    pub fn write_palette(&mut self, palette: &[u8]) -> io::Result<()> {
+    ///
+    /// Indexed-color palettes may have at most 2^depth entries, and other
+    /// palettes at most 256. With validation on (see Options::set_validation)
+    /// image data is also checked to only use indices present in the palette.
+    pub fn write_palette(&mut self, palette: &[u8]) -> error::Result<()> {
         if !self.wrote_header {
             return Err(invalid_input("Cannot write palette before header."));
         }
@@ -855,16 +2613,97 @@ impl<'a, W: Write> Encoder<'a, W> {
         if self.started_image {
             return Err(invalid_input("Cannot write palette after image data."));
         }
+        if matches!(self.header.color_type, ColorType::Greyscale | ColorType::GreyscaleAlpha) {
+            return Err(invalid_input("Greyscale images cannot have a palette."));
+        }
         if palette.len() < 3 {
             return Err(invalid_input("Palette must have at least one entry."));
         }
-        if palette.len() % 3 != 0 {
+        if !palette.len().is_multiple_of(3) {
             return Err(invalid_input("Palette must have an integral number of entries."));
         }
+        let max_entries = match self.header.color_type {
+            ColorType::IndexedColor => 1 << self.header.depth,
+            _ => 256,
+        };
+        if palette.len() / 3 > max_entries {
+            return Err(invalid_input(&format!("Palette has {} entries but at most {} are allowed.",
+                                              palette.len() / 3, max_entries)));
+        }
 
         self.wrote_palette = true;
         self.palette_length = palette.len() / 3;
-        self.writer.write_chunk(b"PLTE", palette)
+        if self.options.dedup_palette && matches!(self.header.color_type, ColorType::IndexedColor) {
+            // Wait to see the transparency before merging entries.
+            self.pending_palette = Some(palette.to_vec());
+            return Ok(());
+        }
+        Ok(self.write_early_chunk(b"PLTE", palette)?)
+    }
+
+    //
+    // Merge and write out a held-back palette, given the alpha
+    // values from tRNS if any.
+    //
+    fn flush_palette(&mut self, alphas: &[u8]) -> IoResult {
+        if let Some(palette) = self.pending_palette.take() {
+            let (merged, map) = dedup_palette(&palette, alphas);
+            if merged.len() < palette.len() {
+                debug_log!("merged {} duplicate palette entries", (palette.len() - merged.len()) / 3);
+                self.palette_map = Some(map);
+            }
+            self.write_early_chunk(b"PLTE", &merged)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Translate tRNS alpha values for merged palette entries.
+    //
+    fn remap_transparency(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let map = match self.palette_map {
+            Some(ref map) => map,
+            None => return Ok(data.to_vec()),
+        };
+        // Merged entries come in order of their first use, so the
+        // entries covered by tRNS stay at the front.
+        let length = map[.. data.len()].iter().map(|&index| index as usize + 1).max().unwrap_or(0);
+        let mut alphas = vec![None; length];
+        for (&index, &alpha) in map.iter().zip(data.iter()) {
+            match alphas[index as usize] {
+                Some(other) if other != alpha => {
+                    return Err(invalid_input("Duplicate palette entries have different transparency; \
+                                              write transparency right after the palette."));
+                },
+                _ => alphas[index as usize] = Some(alpha),
+            }
+        }
+        Ok(alphas.iter().map(|alpha| alpha.unwrap_or(255)).collect())
+    }
+
+    //
+    // Translate palette indices in bKGD and hIST chunks for merged entries.
+    //
+    fn remap_chunk(&self, tag: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        let map = match self.palette_map {
+            Some(ref map) => map,
+            None => return Ok(data.to_vec()),
+        };
+        let entries = map.iter().map(|&index| index as usize + 1).max().unwrap_or(0);
+        match (tag, data.len()) {
+            (b"bKGD", 1) if matches!(self.header.color_type, ColorType::IndexedColor) => {
+                Ok(vec![map.get(data[0] as usize).copied().unwrap_or(data[0])])
+            },
+            (b"hIST", len) if len == map.len() * 2 => {
+                let mut counts = vec![0u16; entries];
+                for (&index, pair) in map.iter().zip(data.chunks(2)) {
+                    let count = &mut counts[index as usize];
+                    *count = count.saturating_add(u16::from(pair[0]) << 8 | u16::from(pair[1]));
+                }
+                Ok(counts.iter().flat_map(|count| count.to_be_bytes()).collect())
+            },
+            _ => Ok(data.to_vec()),
+        }
     }
 
     /// Write a transparency info chunk.
@@ -873,10 +2712,11 @@ impl<'a, W: Write> Encoder<'a, W> {
     /// entry, up to but not exceeding the number of palette entries.
     ///
     /// Note this chunk is allowed on greyscale and truecolor images,
-    /// and there references a single color in 16-bit notation.
+    /// and there references a single color in 16-bit notation. Each
+    /// sample must fit in the header's bit depth.
     ///
     /// https://www.w3.org/TR/PNG/#11tRNS
-    pub fn write_transparency(&mut self, data: &[u8]) -> io::Result<()> {
+    pub fn write_transparency(&mut self, data: &[u8]) -> error::Result<()> {
         if !self.wrote_header {
             return Err(invalid_input("Cannot write transparency before header."));
         }
@@ -888,11 +2728,13 @@ impl<'a, W: Write> Encoder<'a, W> {
                 if data.len() != 2 {
                     return Err(invalid_input("Greyscale transparency data must be exactly 2 bytes."));
                 }
+                self.validate_samples(data)?;
             },
             ColorType::Truecolor => {
                 if data.len() != 6 {
                     return Err(invalid_input("Truecolor transparency data must be exactly 6 bytes."));
                 }
+                self.validate_samples(data)?;
             },
             ColorType::IndexedColor => {
                 if !self.wrote_palette {
@@ -904,6 +2746,10 @@ impl<'a, W: Write> Encoder<'a, W> {
                 if data.len() > self.palette_length {
                     return Err(invalid_input("Transparency data cannot contain more entries than palette."));
                 }
+                self.flush_palette(data)?;
+                let data = self.remap_transparency(data)?;
+                self.wrote_transparency = true;
+                return Ok(self.write_early_chunk(b"tRNS", &data)?);
             },
             _ => {
                 return Err(invalid_input("Transparency chunk is invalid for color types with alpha"));
@@ -911,185 +2757,3220 @@ impl<'a, W: Write> Encoder<'a, W> {
 
         }
         self.wrote_transparency = true;
-        self.writer.write_chunk(b"tRNS", data)
+        Ok(self.write_early_chunk(b"tRNS", data)?)
     }
 
-    //
-    // Write a custom ancillary chunk to the output stream.
-    // The tag must be a 4-byte slice. The data should be provided
-    // in the appropriate format for the tag.
-    //
-    pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> io::Result<()> {
-        self.writer.write_chunk(tag, data)
+    /// Declare that input samples hold only the given number of
+    /// significant bits, such as 10-bit data in 16-bit samples, and
+    /// write a matching sBIT chunk.
+    ///
+    /// Image data passed in afterwards is scaled up to the full range
+    /// of the header's bit depth, so the most significant bits come
+    /// out right for viewers that ignore sBIT. Only 8- and 16-bit
+    /// greyscale and truecolor images (with or without alpha) are
+    /// supported; the alpha channel is taken to have the same number
+    /// of significant bits. Transparency color keys are not scaled.
+    ///
+    /// Must come after the header and before the palette and image data.
+    /// With validation on (see Options::set_validation) samples too large
+    /// for the given bits are rejected, and otherwise they are clamped.
+    ///
+    /// https://www.w3.org/TR/PNG/#11sBIT
+    pub fn write_significant_bits(&mut self, bits: u8) -> error::Result<()> {
+        if matches!(self.header.color_type, ColorType::IndexedColor) {
+            return Err(invalid_input("Use write_significant_bits_per_channel for indexed-color images."));
+        }
+        let channels = self.header.color_type.channels();
+        self.write_significant_bits_per_channel(&vec![bits; channels])
     }
 
-    //
-    // Copy a row's pixel data into buffers for async compression.
-    // Returns immediately after copying.
-    //
-    fn process_row(&mut self, row: &[u8]) -> io::Result<RowStatus>
-    {
-        if self.pixel_index >= self.chunks_total {
-            return Err(other("invalid internal state"));
-        }
+    /// Like write_significant_bits, but with the significant bits
+    /// given for each channel in sBIT order: grey or red, green, blue,
+    /// then alpha. Each channel is scaled up by its own number of bits,
+    /// as for 5-6-5 color or 10-bit color with 2-bit alpha.
+    ///
+    /// Indexed-color images take three values for the palette's red,
+    /// green, and blue, up to 8 bits each; palette entries are written
+    /// as given, without scaling.
+    ///
+    /// https://www.w3.org/TR/PNG/#11sBIT
+    pub fn write_significant_bits_per_channel(&mut self, bits: &[u8]) -> error::Result<()> {
         if !self.wrote_header {
-            return Err(invalid_input("Cannot write image data before header."));
+            return Err(invalid_input("Cannot write significant bits before header."));
         }
-        if let ColorType::IndexedColor = self.header.color_type {
-            if !self.wrote_palette {
-                return Err(invalid_input("Cannot write indexed-color image data before palette."));
-            }
+        if self.wrote_palette {
+            return Err(invalid_input("Cannot write significant bits after palette."));
         }
-        if !self.started_image {
-            self.started_image = true;
+        if self.started_image {
+            return Err(invalid_input("Cannot write significant bits after image data."));
+        }
+        if self.single_chunks.iter().any(|tag| tag == b"sBIT") {
+            return Err(invalid_input("Cannot write significant bits a second time."));
+        }
+        if self.flatten_matte.is_some() {
+            return Err(invalid_input("Cannot write significant bits with alpha flattening."));
+        }
+        let indexed = matches!(self.header.color_type, ColorType::IndexedColor);
+        let (entries, depth) = if indexed {
+            (3, 8)
+        } else {
+            (self.header.color_type.channels(), self.header.depth)
+        };
+        if depth < 8 {
+            return Err(invalid_input("Significant bits are only supported for 8- and 16-bit non-indexed images."));
+        }
+        if bits.len() != entries {
+            return Err(invalid_input(&format!("Significant bits need {} values for this color type.", entries)));
+        }
+        if bits.iter().any(|&bits| bits < 1 || bits > depth) {
+            return Err(invalid_input(&format!("Significant bits must be between 1 and {}.", depth)));
         }
 
-        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row(row);
-
+        self.write_early_chunk(b"sBIT", bits)?;
+        self.single_chunks.push(*b"sBIT");
+        if !indexed && bits.iter().any(|&bits| bits < depth) {
+            self.significant_bits = Some(bits.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Accept image data for a 1, 2, or 4-bit greyscale image as one
+    /// 8-bit sample per pixel, reducing and packing it to the header's
+    /// bit depth as rows are copied in. Thresholding is only allowed
+    /// for 1-bit output.
+    ///
+    /// Must come after the header and before the image data. Rows
+    /// passed to write_image_rows are then `width` bytes long.
+    pub fn set_greyscale_packing(&mut self, reduction: Reduction) -> error::Result<()> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot set greyscale packing before header."));
+        }
+        if self.started_image {
+            return Err(invalid_input("Cannot set greyscale packing after image data."));
+        }
+        if !matches!(self.header.color_type, ColorType::Greyscale) {
+            return Err(invalid_input("Greyscale packing requires a greyscale image."));
+        }
+        if self.options.input_format != PixelFormat::Png {
+            return Err(invalid_input("Cannot set greyscale packing with an input pixel format."));
+        }
+        self.greyscale_packer = Some(layout::GreyscalePacker::new(self.header.depth, reduction)?);
+        Ok(())
+    }
+
+    /// Filter each row with the given filter, one per row of the image,
+    /// instead of choosing by the filter mode. This replays the choices
+    /// read back from row_filters on an earlier run, or ones picked by an
+    /// external optimizer.
+    ///
+    /// Interlaced images take one filter for each row of each Adam7 pass
+    /// in turn, as they're laid out in the image data.
+    ///
+    /// Must come after the header and before the image data.
+    pub fn set_row_filters(&mut self, filters: &[Filter]) -> error::Result<()> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot set row filters before header."));
+        }
+        if self.started_image {
+            return Err(invalid_input("Cannot set row filters after image data."));
+        }
+        if filters.len() != self.total_rows() {
+            return Err(invalid_input(&format!("Got {} row filters but image data has {} rows.",
+                                              filters.len(), self.total_rows())));
+        }
+        self.forced_filters = Some(Arc::new(filters.to_vec()));
+        Ok(())
+    }
+
+    /// Accept image data for a greyscale or truecolor image with an extra
+    /// alpha channel, as for GreyscaleAlpha or TruecolorAlpha at the
+    /// header's depth, and composite it over the given matte color as
+    /// rows are copied in. This suits consumers that can't handle alpha.
+    ///
+    /// The matte has one sample per channel of the header's color type,
+    /// at its bit depth. With background set, a matching bKGD chunk is
+    /// written too.
+    ///
+    /// Only 8- and 16-bit images are supported. Must come after the
+    /// header and before the image data, and can't be combined with
+    /// significant bits.
+    pub fn set_alpha_flattening(&mut self, matte: &[u16], background: bool) -> error::Result<()> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot set alpha flattening before header."));
+        }
+        if self.started_image {
+            return Err(invalid_input("Cannot set alpha flattening after image data."));
+        }
+        if !matches!(self.header.color_type, ColorType::Greyscale | ColorType::Truecolor) || self.header.depth < 8 {
+            return Err(invalid_input("Alpha flattening requires an 8- or 16-bit greyscale or truecolor image."));
+        }
+        if self.significant_bits.is_some() {
+            return Err(invalid_input("Cannot set alpha flattening with significant bits."));
+        }
+        if self.options.input_format != PixelFormat::Png {
+            return Err(invalid_input("Cannot set alpha flattening with an input pixel format."));
+        }
+        if matte.len() != self.header.color_type.channels() {
+            return Err(invalid_input("Matte color must have one sample per channel."));
+        }
+        let max = (1u32 << self.header.depth) - 1;
+        if matte.iter().any(|&sample| u32::from(sample) > max) {
+            return Err(invalid_input(&format!("Matte color samples must fit in {} bits.", self.header.depth)));
+        }
+        if background {
+            let data: Vec<u8> = matte.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+            self.write_chunk(b"bKGD", &data)?;
+        }
+        self.flatten_matte = Some(matte.to_vec());
+        Ok(())
+    }
+
+    /// Write a bKGD chunk with the given background color, which must
+    /// match the header's color type and fit its bit depth.
+    ///
+    /// For indexed-color images this must come after the palette and
+    /// name one of its entries.
+    ///
+    /// https://www.w3.org/TR/PNG/#11bKGD
+    pub fn write_background(&mut self, background: Background) -> error::Result<()> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot write background before header."));
+        }
+        let data = match (background, self.header.color_type) {
+            (Background::Greyscale(grey), ColorType::Greyscale) |
+            (Background::Greyscale(grey), ColorType::GreyscaleAlpha) => grey.to_be_bytes().to_vec(),
+            (Background::Rgb(red, green, blue), ColorType::Truecolor) |
+            (Background::Rgb(red, green, blue), ColorType::TruecolorAlpha) => {
+                [red, green, blue].iter().flat_map(|sample| sample.to_be_bytes()).collect()
+            },
+            (Background::PaletteIndex(index), ColorType::IndexedColor) => {
+                if self.wrote_palette && usize::from(index) >= self.palette_length {
+                    return Err(invalid_input(&format!("Background index {} is past the end of the palette.", index)));
+                }
+                vec![index]
+            },
+            _ => return Err(invalid_input("Background color does not match the image color type.")),
+        };
+        if !matches!(self.header.color_type, ColorType::IndexedColor) {
+            self.validate_samples(&data)?;
+        }
+        self.write_chunk(b"bKGD", &data)
+    }
+
+    /// Write an hIST chunk giving the approximate usage frequency of
+    /// each palette entry, with one count per entry.
+    ///
+    /// Must come after the palette and before the image data.
+    ///
+    /// https://www.w3.org/TR/PNG/#11hIST
+    pub fn write_histogram(&mut self, counts: &[u16]) -> error::Result<()> {
+        if !self.wrote_palette {
+            return Err(invalid_input("Cannot write histogram before palette."));
+        }
+        if counts.len() != self.palette_length {
+            return Err(invalid_input(&format!("Histogram has {} entries but palette has {}.",
+                                              counts.len(), self.palette_length)));
+        }
+        let data: Vec<u8> = counts.iter().flat_map(|count| count.to_be_bytes()).collect();
+        self.write_chunk(b"hIST", &data)
+    }
+
+    /// Write an sPLT chunk suggesting a palette for viewers that can't
+    /// show the full range of colors, as from Quantizer::suggest_palette.
+    ///
+    /// Sample depth is 8 or 16. Each entry is red, green, blue, and
+    /// alpha samples at that depth followed by a 16-bit big-endian
+    /// frequency, for 6 or 10 bytes per entry. The name follows the
+    /// rules for text keywords, and must be unique among the image's
+    /// suggested palettes.
+    ///
+    /// Must come before the image data.
+    ///
+    /// https://www.w3.org/TR/PNG/#11sPLT
+    pub fn write_suggested_palette(&mut self, name: &str, depth: u8, entries: &[u8]) -> error::Result<()> {
+        let mut data = text_keyword(name)?;
+        let stride = match depth {
+            8 => 6,
+            16 => 10,
+            _ => return Err(invalid_input("Suggested palette sample depth must be 8 or 16.")),
+        };
+        if !entries.len().is_multiple_of(stride) {
+            return Err(invalid_input(&format!("Suggested palette entries must be {} bytes each at depth {}.",
+                                              stride, depth)));
+        }
+        if self.suggested_palettes.contains(&data) {
+            return Err(invalid_input("Suggested palette names must be unique."));
+        }
+        let name = data.clone();
+        data.push(0);
+        data.push(depth);
+        data.extend_from_slice(entries);
+        self.write_chunk(b"sPLT", &data)?;
+        self.suggested_palettes.push(name);
+        Ok(())
+    }
+
+    /// Write an eXIf chunk carrying EXIF metadata, such as camera
+    /// settings and orientation. The data is an EXIF profile without
+    /// the JPEG APP1 "Exif" prefix, starting with the TIFF byte order
+    /// mark, "II" or "MM".
+    ///
+    /// Only one is allowed, and it must come before the image data.
+    ///
+    /// https://www.w3.org/TR/png-3/#eXIf
+    pub fn write_exif(&mut self, data: &[u8]) -> error::Result<()> {
+        if !(data.starts_with(b"II*\0") || data.starts_with(b"MM\0*")) {
+            return Err(invalid_input("EXIF data must start with a TIFF header."));
+        }
+        self.write_chunk(b"eXIf", data)
+    }
+
+    /// Write an oFFs chunk giving the position of the image on a page
+    /// or larger canvas, from its top left corner. Offsets are signed,
+    /// and can't be i32::MIN.
+    ///
+    /// Must come before the image data.
+    ///
+    /// https://ftp-osl.osuosl.org/pub/libpng/documents/pngext-1.5.0.html#C.oFFs
+    pub fn write_offset(&mut self, x: i32, y: i32, unit: OffsetUnit) -> error::Result<()> {
+        if x == i32::MIN || y == i32::MIN {
+            return Err(invalid_input("Image offset is out of range."));
+        }
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&x.to_be_bytes());
+        data.extend_from_slice(&y.to_be_bytes());
+        data.push(unit as u8);
+        self.write_chunk(b"oFFs", &data)
+    }
+
+    /// Write an sTER chunk marking the image as a stereo pair side by
+    /// side, with the given layout. The halves are each
+    /// (width - padding) / 2 pixels wide, with 0-7 columns of padding
+    /// between them so the right half starts on a byte boundary.
+    ///
+    /// Must come before the image data.
+    ///
+    /// https://ftp-osl.osuosl.org/pub/libpng/documents/pngext-1.5.0.html#C.sTER
+    pub fn write_stereo(&mut self, layout: StereoLayout) -> error::Result<()> {
+        if self.header.width < 2 {
+            return Err(invalid_input("Stereo images must be at least 2 pixels wide."));
+        }
+        self.write_chunk(b"sTER", &[layout as u8])
+    }
+
+    //
+    // Write a chunk after the image data, such as the frames that
+    // follow it in an animation.
+    //
+    pub(crate) fn write_late_chunk(&mut self, tag: &[u8; 4], data: &[u8]) -> IoResult {
+        if !self.wrote_header || !self.is_finished() {
+            return Err(invalid_input("Cannot write chunk before the image data is complete."));
+        }
+        self.writer.write_chunk(tag, data)
+    }
+
+    // Number of palette entries written, if any.
+    pub(crate) fn palette_length(&self) -> usize {
+        self.palette_length
+    }
+
+    /// Write a custom ancillary chunk to the output stream.
+    /// The tag must be a 4-byte slice. The data should be provided
+    /// in the appropriate format for the tag.
+    ///
+    /// Chunks must come after the header and before the image data.
+    /// Critical chunks (IHDR, PLTE, IDAT, IEND) and tRNS have their own
+    /// methods and are rejected here. Chunks the spec places before PLTE
+    /// are rejected once the palette has been written, bKGD and hIST are
+    /// rejected until it has, and chunks allowed only once (and sRGB
+    /// alongside iCCP) are rejected when repeated.
+    ///
+    /// https://www.w3.org/TR/PNG/#5ChunkOrdering
+    pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> error::Result<()> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot write chunk before header."));
+        }
+        if tag.len() != 4 || !tag.iter().all(|b| b.is_ascii_alphabetic()) {
+            return Err(invalid_input("Chunk tag must be 4 ASCII letters."));
+        }
+        if let Some(method) = critical_chunk_method(tag) {
+            return Err(invalid_input(&format!("Use {} for {} chunks.",
+                                              method, String::from_utf8_lossy(tag))));
+        }
+        if tag[0].is_ascii_uppercase() {
+            return Err(invalid_input("Cannot write critical chunks as custom chunks."));
+        }
+        if tag[2].is_ascii_lowercase() {
+            return Err(invalid_input("Chunk tag has the reserved bit set."));
+        }
+        if tag == b"tRNS" {
+            return Err(invalid_input("Use write_transparency for tRNS chunks."));
+        }
+        if self.started_image {
+            return Err(invalid_input("Cannot write chunk after image data."));
+        }
+        if self.wrote_palette && BEFORE_PALETTE.contains(&tag) {
+            return Err(invalid_input("Cannot write color space chunks after palette."));
+        }
+        let needs_palette = tag == b"hIST" || matches!(self.header.color_type, ColorType::IndexedColor);
+        if AFTER_PALETTE.contains(&tag) && needs_palette && !self.wrote_palette {
+            return Err(invalid_input("Cannot write bKGD or hIST chunks before palette."));
+        }
+        let single = SINGLE_CHUNKS.contains(&tag);
+        if single {
+            let conflicts = |other: &[u8; 4]| {
+                &other[..] == tag ||
+                (tag == b"sRGB" && other == b"iCCP") ||
+                (tag == b"iCCP" && other == b"sRGB")
+            };
+            if self.single_chunks.iter().any(conflicts) {
+                return Err(invalid_input("Chunk may only appear once, and not with both sRGB and iCCP."));
+            }
+        }
+        self.flush_palette(&[])?;
+        let data = self.remap_chunk(tag, data)?;
+        self.write_early_chunk(&[tag[0], tag[1], tag[2], tag[3]], &data)?;
+        if single {
+            self.single_chunks.push([tag[0], tag[1], tag[2], tag[3]]);
+        }
+        Ok(())
+    }
+
+    /// Write a text metadata chunk with the given keyword and value.
+    ///
+    /// Values that can be represented in Latin-1 go in a tEXt chunk, and
+    /// others in an iTXt chunk as UTF-8 with no language tag. Long values
+    /// are compressed if set up with Options::set_text_compression.
+    ///
+    /// Keywords must be 1-79 printable Latin-1 characters, without leading,
+    /// trailing, or consecutive spaces.
+    ///
+    /// https://www.w3.org/TR/PNG/#11textinfo
+    pub fn write_text(&mut self, keyword: &str, text: &str) -> error::Result<()> {
+        let compress = matches!(self.options.text_compression, Some(threshold) if text.len() > threshold);
+        match (latin1(text), compress) {
+            (Some(bytes), false) => {
+                let mut data = text_keyword(keyword)?;
+                data.push(0);
+                data.extend_from_slice(&bytes);
+                self.write_chunk(b"tEXt", &data)
+            },
+            (Some(_), true) => self.write_compressed_text(keyword, text),
+            (None, _) => self.write_itxt(keyword, "", "", text, compress),
+        }
+    }
+
+    /// Write a zTXt chunk with the given keyword and zlib-compressed value.
+    ///
+    /// The value must be representable in Latin-1; use write_itxt for
+    /// other text.
+    ///
+    /// https://www.w3.org/TR/PNG/#11zTXt
+    pub fn write_compressed_text(&mut self, keyword: &str, text: &str) -> error::Result<()> {
+        let bytes = latin1(text).ok_or_else(|| invalid_input::<Error>("Compressed text must be Latin-1."))?;
+        let mut data = text_keyword(keyword)?;
+        // Null separator, then compression method 0 for zlib.
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&deflate::compress(&bytes)?);
+        self.write_chunk(b"zTXt", &data)
+    }
+
+    /// Write an iTXt chunk with UTF-8 text, optionally compressed.
+    ///
+    /// The language tag is an RFC 3066 tag such as "en-gb", or empty if
+    /// unknown; the translated keyword is the keyword in that language,
+    /// or empty.
+    ///
+    /// https://www.w3.org/TR/PNG/#11iTXt
+    pub fn write_itxt(&mut self,
+                      keyword: &str,
+                      lang: &str,
+                      translated: &str,
+                      text: &str,
+                      compressed: bool) -> error::Result<()> {
+        let mut data = text_keyword(keyword)?;
+        check_language_tag(lang)?;
+        if translated.contains('\0') {
+            return Err(invalid_input("Translated keyword must not contain nulls."));
+        }
+        // Null separator, compression flag and method.
+        data.extend_from_slice(&[0, compressed as u8, 0]);
+        data.extend_from_slice(lang.as_bytes());
+        data.push(0);
+        data.extend_from_slice(translated.as_bytes());
+        data.push(0);
+        if compressed {
+            data.extend_from_slice(&deflate::compress(text.as_bytes())?);
+        } else {
+            data.extend_from_slice(text.as_bytes());
+        }
+        self.write_chunk(b"iTXt", &data)
+    }
+
+    /// Write an sRGB chunk, marking the image as in the sRGB color
+    /// space with the given rendering intent.
+    ///
+    /// This must come before the palette, and cannot be combined with
+    /// an iCCP chunk. The spec recommends also writing gAMA and cHRM
+    /// chunks with the sRGB values for older decoders.
+    ///
+    /// https://www.w3.org/TR/PNG/#11sRGB
+    pub fn write_srgb(&mut self, intent: RenderingIntent) -> error::Result<()> {
+        self.write_chunk(b"sRGB", &[intent as u8])
+    }
+
+    /// Write a gAMA chunk with the given image gamma, such as 0.45455
+    /// for sRGB. The value is stored in units of 1/100000.
+    ///
+    /// This must come before the palette.
+    ///
+    /// https://www.w3.org/TR/PNG/#11gAMA
+    pub fn write_gamma(&mut self, gamma: f64) -> error::Result<()> {
+        let value = fixed_point(gamma)?;
+        if value == 0 {
+            return Err(invalid_input("Gamma must be greater than zero."));
+        }
+        self.write_chunk(b"gAMA", &value.to_be_bytes())
+    }
+
+    /// Write a cHRM chunk with the given white point and primaries.
+    /// Values are stored in units of 1/100000.
+    ///
+    /// This must come before the palette.
+    ///
+    /// https://www.w3.org/TR/PNG/#11cHRM
+    pub fn write_chromaticities(&mut self, chromaticities: Chromaticities) -> error::Result<()> {
+        let mut data = Vec::with_capacity(32);
+        for (x, y) in [chromaticities.white(),
+                       chromaticities.red(),
+                       chromaticities.green(),
+                       chromaticities.blue()] {
+            data.extend_from_slice(&fixed_point(x)?.to_be_bytes());
+            data.extend_from_slice(&fixed_point(y)?.to_be_bytes());
+        }
+        self.write_chunk(b"cHRM", &data)
+    }
+
+    /// Write a cICP chunk identifying the color space by its ITU-T H.273
+    /// code points, as for HDR images: for instance primaries 9 (BT.2020)
+    /// with transfer function 16 (PQ) or 18 (HLG).
+    ///
+    /// The matrix coefficients must be 0, as PNG holds only RGB data.
+    /// This must come before the palette, and takes precedence over
+    /// sRGB, iCCP, gAMA, and cHRM in decoders that support it.
+    ///
+    /// https://www.w3.org/TR/png-3/#cICP-chunk
+    pub fn write_cicp(&mut self, primaries: u8, transfer: u8, matrix: u8, full_range: bool) -> error::Result<()> {
+        if matrix != 0 {
+            return Err(invalid_input("cICP matrix coefficients must be 0 for RGB data."));
+        }
+        self.write_chunk(b"cICP", &[primaries, transfer, matrix, full_range as u8])
+    }
+
+    /// Write an mDCv chunk describing the mastering display for HDR
+    /// content: its primaries and white point, and its largest and
+    /// smallest luminance in candelas per square meter.
+    ///
+    /// Chromaticities are stored in units of 0.00002, and luminance in
+    /// units of 0.0001 cd/m^2. This must come before the palette.
+    ///
+    /// https://www.w3.org/TR/png-3/#mDCv-chunk
+    pub fn write_mastering_display(&mut self,
+                                   chromaticities: Chromaticities,
+                                   max_luminance: f64,
+                                   min_luminance: f64) -> error::Result<()> {
+        if min_luminance > max_luminance {
+            return Err(invalid_input("Mastering display minimum luminance is above the maximum."));
+        }
+        let mut data = Vec::with_capacity(24);
+        for (x, y) in [chromaticities.red(),
+                       chromaticities.green(),
+                       chromaticities.blue(),
+                       chromaticities.white()] {
+            for value in [x, y] {
+                let scaled = (value * 50000.0).round();
+                if !(0.0 ..= 65535.0).contains(&scaled) {
+                    return Err(invalid_input(&format!("Chromaticity {} is out of range for mDCv.", value)));
+                }
+                data.extend_from_slice(&(scaled as u16).to_be_bytes());
+            }
+        }
+        data.extend_from_slice(&luminance(max_luminance)?.to_be_bytes());
+        data.extend_from_slice(&luminance(min_luminance)?.to_be_bytes());
+        self.write_chunk(b"mDCv", &data)
+    }
+
+    /// Write a cLLi chunk with the content light levels of HDR content:
+    /// the largest light level of any pixel, and the largest average
+    /// light level of any frame, in candelas per square meter.
+    ///
+    /// Levels are stored in units of 0.0001 cd/m^2. This must come
+    /// before the palette.
+    ///
+    /// https://www.w3.org/TR/png-3/#cLLi-chunk
+    pub fn write_content_light_level(&mut self, max_content: f64, max_frame_average: f64) -> error::Result<()> {
+        let mut data = Vec::with_capacity(8);
+        data.extend_from_slice(&luminance(max_content)?.to_be_bytes());
+        data.extend_from_slice(&luminance(max_frame_average)?.to_be_bytes());
+        self.write_chunk(b"cLLi", &data)
+    }
+
+    /// Write an iCCP chunk embedding the given ICC color profile,
+    /// which is compressed here as the spec requires.
+    ///
+    /// The profile name follows the same rules as text keywords. Like
+    /// other color space chunks this must come before the palette, and
+    /// cannot be combined with an sRGB chunk.
+    ///
+    /// https://www.w3.org/TR/PNG/#11iCCP
+    pub fn write_icc_profile(&mut self, name: &str, profile: &[u8]) -> error::Result<()> {
+        if profile.is_empty() {
+            return Err(invalid_input("ICC profile must not be empty."));
+        }
+        let mut data = text_keyword(name)?;
+        // Null separator, then compression method 0 for zlib.
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&deflate::compress(profile)?);
+        self.write_chunk(b"iCCP", &data)
+    }
+
+    //
+    // Check that big-endian 16-bit tRNS and bKGD sample values fit
+    // the bit depth.
+    //
+    fn validate_samples(&self, data: &[u8]) -> IoResult {
+        let max = (1u32 << self.header.depth) - 1;
+        for pair in data.chunks(2) {
+            let sample = u32::from(pair[0]) << 8 | u32::from(pair[1]);
+            if sample > max {
+                return Err(invalid_input(&format!("Sample {} does not fit in {} bits.",
+                                                  sample, self.header.depth)));
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Check that an indexed-color row only uses entries from the palette.
+    //
+    fn validate_row(&self, row: &[u8]) -> IoResult {
+        if self.chunk_format() == PixelFormat::Unpacked {
+            return self.validate_unpacked(row);
+        }
+        if let Some(ref bits) = self.significant_bits {
+            let max = layout::max_channel_samples(row, self.header.depth, bits.len());
+            for (&max, &bits) in max.iter().zip(bits) {
+                if u32::from(max) >= 1 << bits {
+                    return Err(invalid_input(&format!("Sample {} does not fit in {} significant bits.",
+                                                      max, bits)));
+                }
+            }
+        }
+        if let ColorType::IndexedColor = self.header.color_type {
+            let max = max_index(row, self.header.depth, self.header.width as usize);
+            if max as usize >= self.palette_length {
+                return Err(invalid_input(&format!("Pixel index {} is out of range for a palette of {} entries.",
+                                                  max, self.palette_length)));
+            }
+        }
+        Ok(())
+    }
+
+    // Check a row of samples that have yet to be packed.
+    fn validate_unpacked(&self, row: &[u8]) -> IoResult {
+        let max = row.iter().copied().max().unwrap_or(0);
+        if u32::from(max) >= 1 << self.header.depth {
+            return Err(invalid_input(&format!("Sample {} does not fit in {} bits.",
+                                              max, self.header.depth)));
+        }
+        if matches!(self.header.color_type, ColorType::IndexedColor) && max as usize >= self.palette_length {
+            return Err(invalid_input(&format!("Pixel index {} is out of range for a palette of {} entries.",
+                                              max, self.palette_length)));
+        }
+        Ok(())
+    }
+
+    //
+    // The header and the next row to be taken, for adapters that
+    // assemble rows before passing them in.
+    //
+    pub(crate) fn input_position(&self) -> io::Result<(Header, u32)> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot take image data before header."));
+        }
+        if self.input_stride() != self.header.stride() || self.options.input_format != PixelFormat::Png {
+            return Err(invalid_input("Input transforms are not supported here."));
+        }
+        Ok((self.header, self.current_row))
+    }
+
+    //
+    // Check that taking this many more rows stays within the header's height.
+    //
+    fn check_row_count(&self, rows: usize) -> IoResult {
+        let received = self.current_row as u64 + rows as u64;
+        if received > self.header.height as u64 {
+            return Err(invalid_input(&format!("Received {} rows but header declares {}.",
+                                              received, self.header.height)));
+        }
+        Ok(())
+    }
+
+    //
+    // Check a row can be taken now, and start the image data
+    // with the first one.
+    //
+    fn check_row(&mut self, row: &[u8]) -> IoResult {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot write image data before header."));
+        }
+        self.check_row_count(1)?;
+        if self.pixel_index >= self.chunks_total {
+            return Err(other("invalid internal state"));
+        }
+        if let ColorType::IndexedColor = self.header.color_type {
+            if !self.wrote_palette {
+                return Err(invalid_input("Cannot write indexed-color image data before palette."));
+            }
+        }
+        if !self.started_image {
+            self.flush_palette(&[])?;
+            self.started_image = true;
+            // Input transforms may have been set since the chunk was made.
+            let (format, packing) = (self.chunk_format(), self.chunk_packing());
+            let chunk = Arc::get_mut(&mut self.pixel_accumulator).unwrap();
+            chunk.set_format(format);
+            chunk.set_packing(packing);
+        }
+        if self.options.validate || cfg!(debug_assertions) {
+            self.validate_row(row)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Copy a row's pixel data into buffers for async compression.
+    // Returns immediately after copying.
+    //
+    fn process_row(&mut self, row: &[u8]) -> io::Result<RowStatus>
+    {
+        let reordered;
+        let row = if self.options.input_format != PixelFormat::Png && self.chunk_format() == PixelFormat::Png {
+            if self.options.input_format == PixelFormat::Unpacked && (self.options.validate || cfg!(debug_assertions)) {
+                // Packing drops bits that are out of range.
+                self.validate_unpacked(row)?;
+            }
+            reordered = self.options.input_format.to_png(&self.header, row);
+            &reordered
+        } else {
+            row
+        };
+        self.check_row(row)?;
+
+        let packed_later = self.chunk_packing().is_some();
+        let copy_start = Instant::now();
+        let converted;
+        let row = if let Some(ref matte) = self.flatten_matte {
+            converted = layout::flatten_alpha(row, self.header.depth, matte);
+            &converted
+        } else if let Some(ref bits) = self.significant_bits {
+            converted = layout::rescale_channels(row, self.header.depth, bits);
+            &converted
+        } else if let (Some(packer), false) = (self.greyscale_packer.as_mut(), packed_later) {
+            converted = packer.pack_row(row);
+            &converted
+        } else if let Some(ref map) = self.palette_map {
+            converted = remap_indices(row, map, self.header.depth, self.header.width as usize);
+            &converted
+        } else {
+            row
+        };
+        if let Some(ref mut held) = self.held_image {
+            held.rows.extend_from_slice(row);
+            if self.current_row + 1 == self.header.height {
+                self.release_held_image()?;
+            }
+        } else {
+            self.store_row(row, copy_start)?;
+        }
+
+        self.current_row += 1;
+        if self.current_row == self.header.height {
+            Ok(RowStatus::Done)
+        } else {
+            Ok(RowStatus::Continue)
+        }
+    }
+
+    //
+    // Put a row of image data in the pixel accumulator, or the
+    // interlace buffer, and send it off when full.
+    //
+    fn store_row(&mut self, row: &[u8], copy_start: Instant) -> IoResult {
+        if let InterlaceMethod::Adam7 = self.header.interlace_method {
+            self.interlace_buffer.extend_from_slice(row);
+            self.chunk_times[self.pixel_index].pixel_copy += copy_start.elapsed();
+            if self.interlace_buffer.len() == self.header.stride() * self.header.height as usize {
+                self.split_passes()?;
+            }
+        } else {
+            Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row(row);
+            self.chunk_times[self.pixel_index].pixel_copy += copy_start.elapsed();
+
+            if self.pixel_accumulator.is_full() {
+                self.land_pixel_chunk()?;
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Send off the full pixel accumulator and start the next one.
+    //
+    fn land_pixel_chunk(&mut self) -> IoResult {
+        // Move the item off to the completed stack...
+        self.pixel_chunks.land(self.pixel_index, self.pixel_accumulator.clone())?;
+
+        // Make a nice new buffer to accumulate data into.
+        self.pixel_index += 1;
+        if self.pixel_index < self.chunks_total {
+            self.pixel_chunks.advance();
+            self.pixel_accumulator = Arc::new(self.new_pixel_chunk(self.pixel_index));
+        }
+
+        // Dispatch any available async tasks and output.
+        while self.running_jobs() >= self.max_threads() || self.too_many_pending(0) {
+            self.dispatch(DispatchMode::Blocking)?;
+        }
+        self.dispatch(DispatchMode::NonBlocking)
+    }
+
+    // Whether more chunks would have come in than the options allow to
+    // be waiting on jobs or output, counting `more` yet to come.
+    fn too_many_pending(&self, more: usize) -> bool {
+        match self.options.max_pending_chunks {
+            Some(max) => self.pixel_index + more - self.chunks_output > max,
+            None => false,
+        }
+    }
+
+    //
+    // With the last row of an interlaced image in, pick the rows of
+    // each Adam7 pass out of the buffered image and send them off.
+    //
+    fn split_passes(&mut self) -> IoResult {
+        let image = mem::take(&mut self.interlace_buffer);
+        let stride = self.header.stride();
+        let bits = layout::bits_per_pixel(self.header.color_type, self.header.depth);
+        while self.pixel_index < self.chunks_total {
+            let copy_start = Instant::now();
+            let pass = self.passes[self.chunk_plan[self.pixel_index].0];
+            let number = pass.number.unwrap();
+            let (_, y0, _, dy) = layout::ADAM7[number];
+            let chunk = Arc::get_mut(&mut self.pixel_accumulator).unwrap();
+            for row in chunk.start_row .. chunk.end_row {
+                let y = y0 + (row - pass.start_row) * dy;
+                let src = &image[y * stride .. (y + 1) * stride];
+                chunk.read_row(&layout::adam7_row(src, bits, self.header.width, number));
+            }
+            self.chunk_times[self.pixel_index].pixel_copy += copy_start.elapsed();
+            self.land_pixel_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Encode and compress the given image data and write to output.
+    /// Input data must be packed in the correct format for the given
+    /// color type and depth, with no padding at the end of rows.
+    ///
+    /// An integral number of rows must be provided at once.
+    ///
+    /// If not all of the image rows are provided, multiple calls are
+    /// required to finish out the data.
+    ///
+    /// With greyscale packing on (see set_greyscale_packing) rows
+    /// are instead one byte per pixel, and with alpha flattening on
+    /// (see set_alpha_flattening) they have an extra alpha channel.
+    pub fn write_image_rows(&mut self, buf: &[u8]) -> error::Result<()> {
+        let stride = self.input_stride();
+        if !buf.len().is_multiple_of(stride) {
+            Err(invalid_input("Buffer must be an integral number of rows"))
+        } else {
+            if self.wrote_header {
+                // Refuse the whole buffer rather than taking part of it.
+                self.check_row_count(buf.len() / stride)?;
+            }
+            if !self.started_image && stride == self.header.stride() && self.options.input_format == PixelFormat::Png {
+                self.check_preset(buf);
+            }
+            for row in buf.chunks(stride) {
+                self.process_row(row)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Encode rows of 16-bit samples in native byte order, for a
+    /// 16-bit image. Putting them in PNG's big-endian order is done
+    /// on the worker threads, saving callers a swapped copy.
+    ///
+    /// Otherwise as for write_image_rows.
+    pub fn write_image_rows_u16(&mut self, buf: &[u16]) -> error::Result<()> {
+        if self.header.depth != 16 {
+            return Err(invalid_input("16-bit samples can only go in a 16-bit image."));
+        }
+        let stride = self.input_stride() / 2;
+        if !buf.len().is_multiple_of(stride) {
+            return Err(invalid_input("Buffer must be an integral number of rows"));
+        }
+        if self.wrote_header {
+            // Refuse the whole buffer rather than taking part of it.
+            self.check_row_count(buf.len() / stride)?;
+        }
+        for row in buf.chunks(stride) {
+            self.process_row_u16(row)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Take a row of native 16-bit samples. Rows that go straight to
+    // the filters are stored as they are; any others are put in
+    // big-endian order first.
+    //
+    fn process_row_u16(&mut self, row: &[u16]) -> io::Result<RowStatus> {
+        let interlaced = matches!(self.header.interlace_method, InterlaceMethod::Adam7);
+        if cfg!(target_endian = "big") || interlaced || self.has_input_transform() || self.held_image.is_some() {
+            let row: Vec<u8> = row.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+            return self.process_row(&row);
+        }
+
+        // There's nothing to validate in 16-bit rows without transforms.
+        self.check_row(&[])?;
+
+        let copy_start = Instant::now();
+        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row_u16(row);
+        self.chunk_times[self.pixel_index].pixel_copy += copy_start.elapsed();
         if self.pixel_accumulator.is_full() {
-            // Move the item off to the completed stack...
-            self.pixel_chunks.land(self.pixel_index, self.pixel_accumulator.clone());
+            self.land_pixel_chunk()?;
+        }
+
+        self.current_row += 1;
+        if self.current_row == self.header.height {
+            Ok(RowStatus::Done)
+        } else {
+            Ok(RowStatus::Continue)
+        }
+    }
+
+    // Whether input rows are converted before they're filtered. A
+    // palette held for deduplication may yet turn out to need its
+    // indices remapped.
+    pub(crate) fn has_input_transform(&self) -> bool {
+        self.flatten_matte.is_some() ||
+        self.significant_bits.is_some() ||
+        self.greyscale_packer.is_some() ||
+        self.palette_map.is_some() ||
+        self.pending_palette.is_some()
+    }
+
+    /// Encode rows from a buffer with padding after each row, such as
+    /// a GPU readback or a Windows DIB, skipping the padding. Rows
+    /// start every src_stride bytes, which must be at least the row
+    /// length; the last row's padding may be left off.
+    ///
+    /// Otherwise as for write_image_rows.
+    pub fn write_image_rows_with_stride(&mut self, buf: &[u8], src_stride: usize) -> error::Result<()> {
+        let stride = self.input_stride();
+        if src_stride < stride {
+            return Err(invalid_input(&format!("Source stride {} is shorter than a row of {} bytes.",
+                                              src_stride, stride)));
+        }
+        let rows = (buf.len() + src_stride - stride) / src_stride;
+        if buf.len() != rows * src_stride && buf.len() + src_stride - stride != rows * src_stride {
+            return Err(invalid_input("Buffer must be an integral number of rows at the given stride"));
+        }
+        if self.wrote_header {
+            // Refuse the whole buffer rather than taking part of it.
+            self.check_row_count(rows)?;
+        }
+        for row in buf.chunks(src_stride) {
+            self.process_row(&row[.. stride])?;
+        }
+        Ok(())
+    }
+
+    //
+    // Length of an input row, which differs from the header's stride
+    // when an input transform is on.
+    //
+    pub(crate) fn input_stride(&self) -> usize {
+        if self.greyscale_packer.is_some() {
+            self.header.width as usize
+        } else if self.flatten_matte.is_some() {
+            let channels = self.header.color_type.channels() + 1;
+            channels * self.header.depth as usize / 8 * self.header.width as usize
+        } else {
+            self.options.input_format.stride(&self.header)
+        }
+    }
+
+    /// Encode rows from a producer that can fail partway through, such
+    /// as a network source or a decoder. Each item holds an integral
+    /// number of rows, as for write_image_rows.
+    ///
+    /// The first error, from the producer or the encoder, stops the
+    /// encode and is returned as is. Rows before it have been taken,
+    /// but the image is left incomplete.
+    pub fn write_image_rows_try_iter<I>(&mut self, rows: I) -> error::Result<()>
+        where I: IntoIterator<Item = io::Result<Vec<u8>>>
+    {
+        for buf in rows {
+            self.write_image_rows(&buf?)?;
+        }
+        Ok(())
+    }
+
+    /// Encode rows held in a shared buffer, keeping a reference to it
+    /// instead of copying them where a chunk's rows all fall within
+    /// it. Rows otherwise go in as for write_image_rows, as do all
+    /// rows with an input transform on or for interlaced images.
+    ///
+    /// The buffer holds an integral number of rows, and as with
+    /// write_image_rows more calls may follow for the rest.
+    pub fn write_image_rows_shared(&mut self, buf: Arc<[u8]>) -> error::Result<()> {
+        if !self.wrote_header || self.has_input_transform() || self.options.input_format != PixelFormat::Png ||
+           self.held_image.is_some() || matches!(self.header.interlace_method, InterlaceMethod::Adam7) {
+            return self.write_image_rows(&buf);
+        }
+        let stride = self.header.stride();
+        if !buf.len().is_multiple_of(stride) {
+            return Err(invalid_input("Buffer must be an integral number of rows"));
+        }
+        self.check_row_count(buf.len() / stride)?;
+        if !self.started_image {
+            self.check_preset(&buf);
+        }
+
+        let mut offset = 0;
+        while offset < buf.len() {
+            let rows = self.end_row(self.pixel_index) - self.current_row as usize;
+            let len = rows * stride;
+            if self.pixel_accumulator.rows > 0 || offset + len > buf.len() {
+                // Partway through a chunk; copy rows to fill it out.
+                let end = buf.len().min(offset + len);
+                self.write_image_rows(&buf[offset .. end])?;
+                offset = end;
+                continue;
+            }
+            for row in buf[offset .. offset + len].chunks(stride) {
+                self.check_row(row)?;
+            }
+            Arc::get_mut(&mut self.pixel_accumulator).unwrap().share_rows(Arc::clone(&buf), offset);
+            self.current_row += rows as u32;
+            offset += len;
+            self.land_pixel_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Encode rows from a height × width × samples array, such as an
+    /// image kept in an ndarray for processing. The array may be a
+    /// view in any memory layout; rows that aren't contiguous are
+    /// copied out one at a time.
+    ///
+    /// Samples are bytes packed as for write_image_rows, so 16-bit
+    /// images take two per sample, most significant first. As with
+    /// write_image_rows the array may hold some of the rows, with
+    /// more calls for the rest.
+    #[cfg(feature = "ndarray")]
+    pub fn write_array<S>(&mut self, array: &::ndarray::ArrayBase<S, ::ndarray::Ix3>) -> error::Result<()>
+        where S: ::ndarray::Data<Elem = u8>
+    {
+        let (_, width, samples) = array.dim();
+        if width != self.header.width as usize || width * samples != self.input_stride() {
+            return Err(invalid_input(&format!("Array of {} x {} samples doesn't match rows of {} pixels \
+                                               and {} bytes.",
+                                              width, samples, self.header.width, self.input_stride())));
+        }
+        if let Some(data) = array.as_slice() {
+            return self.write_image_rows(data);
+        }
+        let mut buf = Vec::with_capacity(width * samples);
+        for row in array.outer_iter() {
+            match row.as_slice() {
+                Some(data) => self.write_image_rows(data)?,
+                None => {
+                    buf.clear();
+                    buf.extend(row.iter());
+                    self.write_image_rows(&buf)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the whole image straight from the caller's buffer,
+    /// without first copying its rows: filter jobs read from it
+    /// directly, in scoped batches on the thread pool. Returns once
+    /// all rows are filtered; as with write_image_rows, compressed
+    /// output may still be pending until flush or finish.
+    ///
+    /// The buffer must hold every row of the image, packed as for
+    /// write_image_rows. Input transforms and interlacing need the
+    /// rows rearranged anyway, so with either of those on this is the
+    /// same as write_image_rows.
+    pub fn write_image_borrowed(&mut self, image: &[u8]) -> error::Result<()> {
+        if !self.wrote_header {
+            return Err(invalid_input("Cannot write image data before header."));
+        }
+        if self.current_row != 0 {
+            return Err(invalid_input("Image data was already written."));
+        }
+        if image.len() != self.input_stride() * self.header.height as usize {
+            return Err(invalid_input("Buffer must hold every row of the image"));
+        }
+        if self.has_input_transform() || self.options.input_format != PixelFormat::Png ||
+           self.held_image.is_some() || matches!(self.header.interlace_method, InterlaceMethod::Adam7) {
+            return self.write_image_rows(image);
+        }
+        if let ColorType::IndexedColor = self.header.color_type {
+            if !self.wrote_palette {
+                return Err(invalid_input("Cannot write indexed-color image data before palette."));
+            }
+        }
+
+        let stride = self.header.stride();
+        if !self.started_image {
+            self.check_preset(image);
+            self.flush_palette(&[])?;
+            self.started_image = true;
+        }
+        if self.options.validate || cfg!(debug_assertions) {
+            for row in image.chunks(stride) {
+                self.validate_row(row)?;
+            }
+        }
+
+        while self.pixel_index < self.chunks_total {
+            // Hold off while compression and output catch up, as when
+            // landing pixel chunks, so filtered data doesn't pile up.
+            while self.running_jobs() >= self.max_threads() || self.too_many_pending(1) {
+                self.dispatch(DispatchMode::Blocking)?;
+            }
+
+            // The pixel chunks only carry the rows' positions here.
+            let first = self.pixel_index;
+            let mut last = (first + self.max_threads()).min(self.chunks_total);
+            if let Some(max) = self.options.max_pending_chunks {
+                last = last.min(self.chunks_output + max);
+            }
+            let mut filters = Vec::with_capacity(last - first);
+            for index in first .. last {
+                self.filter_chunks.advance();
+                filters.push(FilterChunk::new((None, Arc::new(self.new_pixel_chunk(index))),
+                                              self.pass_filter_mode(None),
+                                              self.options.heuristic,
+                                              self.options.solid_rows,
+                                              self.options.filter_lock_in,
+                                              self.forced_filters.clone(),
+                                              self.buffers.clone()));
+            }
+
+            let mut results: Vec<IoResult> = filters.iter().map(|_| Ok(())).collect();
+            let busy_nanos = &*self.busy_nanos;
+            match self.options.thread_pool {
+                Some(pool) => pool.scope(|scope| {
+                    spawn_filters(scope, &mut filters, &mut results, image, busy_nanos)
+                }),
+                None => ::rayon::scope(|scope| {
+                    spawn_filters(scope, &mut filters, &mut results, image, busy_nanos)
+                }),
+            }
+
+            for (filter, result) in filters.into_iter().zip(results) {
+                result?;
+                self.land_filter_chunk(Arc::new(filter))?;
+            }
+            self.pixel_index = last;
+            self.current_row = self.end_row(last - 1) as u32;
+
+            // Send off deflate jobs for the batch, and write what's done.
+            self.dispatch(DispatchMode::NonBlocking)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Log a warning if the first rows look like content the preset
+    // in use is a poor fit for.
+    //
+    fn check_preset(&self, rows: &[u8]) {
+        if !cfg!(feature = "log") || rows.is_empty() {
+            return;
+        }
+        let poor_fit = match self.options.preset {
+            Some(Preset::Screenshot) => Content::Photographic,
+            Some(Preset::Photo) => Content::Flat,
+            _ => return,
+        };
+        if let Ok(sample) = analyze::sample_rows(&self.header, rows) {
+            if sample.content() == poor_fit {
+                warn_log!("image looks {:?}, which the {:?} preset is a poor fit for \
+                           ({} colors, {:.0}% runs in {} sampled pixels)",
+                          poor_fit, self.options.preset.unwrap(),
+                          sample.colors(), sample.runs() * 100.0, sample.pixels());
+            }
+        }
+    }
+
+    /// Return completion progress as a fraction of 1.0
+    ///
+    /// Currently progress is measured in chunks, so small files may
+    /// not report values between 0.0 and 1.0.
+    pub fn progress(&self) -> f64 {
+        self.chunks_output as f64 / self.chunks_total as f64
+    }
+
+    /// Return statistics on the work done so far.
+    pub fn stats(&self) -> EncodeStats {
+        let mut stats = self.stats.clone();
+        stats.output_bytes = self.writer.bytes_written();
+        for times in self.chunk_times.iter() {
+            stats.times.add(times);
+        }
+        stats.threads = self.threads();
+        stats.busy_time = Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed));
+        stats.elapsed = self.started.map(|started| started.elapsed()).unwrap_or_default();
+        stats
+    }
+
+    /// Return the filter used on each row of the image, in row order.
+    /// Interlaced images list the rows of each Adam7 pass in turn.
+    ///
+    /// Only rows that have been filtered so far are filled in, and the
+    /// rest read as None; flush first for a complete list.
+    pub fn row_filters(&self) -> &[Filter] {
+        &self.row_filters
+    }
+
+    /// Return the time spent in each stage for each chunk so far,
+    /// in chunk order.
+    pub fn chunk_times(&self) -> &[StageTimes] {
+        &self.chunk_times
+    }
+
+    /// Return a snapshot of the encoder's runtime metrics.
+    pub fn metrics(&self) -> EncoderMetrics {
+        let pixel_bytes = |chunk: &PixelChunk| chunk.pixels().len();
+        EncoderMetrics {
+            chunks_in_flight: self.running_jobs(),
+            queue_depth: self.jobs.queue.lock().map(|jobs| jobs.len()).unwrap_or(0),
+            bytes_buffered: self.interlace_buffer.len() +
+                            pixel_bytes(&self.pixel_accumulator) +
+                            self.pixel_chunks.buffered(pixel_bytes) +
+                            self.filter_chunks.buffered(|chunk| chunk.data.len()) +
+                            self.deflate_chunks.buffered(|chunk| chunk.data.len()) +
+                            self.idat_buffer.len(),
+            threads: self.threads(),
+            busy_time: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+            elapsed: self.started.map(|started| started.elapsed()).unwrap_or_default(),
+        }
+    }
+
+    //
+    // For adapters that wait on jobs without blocking: a function to
+    // call as each one finishes, and whether taking another row now
+    // would block until one does.
+    //
+    #[cfg(feature = "tokio")]
+    pub(crate) fn set_job_done(&mut self, job_done: JobDone) {
+        self.job_done = Some(job_done);
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn is_saturated(&self) -> bool {
+        self.running_jobs() >= self.max_threads()
+    }
+
+    //
+    // For adapters that pass output on as it's written: whether landed
+    // chunks have yet to be written out, ways to take in finished jobs
+    // with or without waiting for one, and the output so far.
+    //
+    pub(crate) fn has_pending_output(&self) -> bool {
+        self.chunks_output < self.pixel_index
+    }
+
+    pub(crate) fn poll(&mut self) -> IoResult {
+        self.dispatch(DispatchMode::NonBlocking)
+    }
+
+    pub(crate) fn wait(&mut self) -> IoResult {
+        self.dispatch(DispatchMode::Blocking)
+    }
+
+    pub(crate) fn output_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
+
+    /// Return finished-ness state.
+    /// Is it finished? Yeah or no.
+    pub fn is_finished(&self) -> bool {
+        self.chunks_output == self.chunks_total
+    }
+
+    /// Flush all currently in-progress data to output
+    /// Warning: this may block.
+    pub fn flush(&mut self) -> error::Result<()> {
+        while self.chunks_output < self.pixel_index {
+            // Dispatch any available async tasks and output.
+            self.dispatch(DispatchMode::Blocking)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Backend;
+    use super::super::CompressionLevel;
+    use super::super::Header;
+    use super::super::Heuristic;
+    use super::super::InterlaceMethod;
+    use super::super::Mode::Fixed;
+    use super::super::Strategy;
+    use super::super::decoder::decode;
+    use super::super::ColorType;
+    use super::super::layout;
+    use super::super::layout::Reduction;
+    use super::super::metadata::{Chromaticities, RenderingIntent};
+    use super::ChunkInfo;
+    use super::ChunkMap;
+    use super::Background;
+    use super::Encoder;
+    use super::OffsetUnit;
+    use super::StereoLayout;
+    use super::Filter;
+    use super::Options;
+    use super::PixelFormat;
+    use super::Preset;
+    use super::error;
+    use super::error::Error;
+
+    use proptest::prelude::*;
+    use proptest::test_runner::{RngAlgorithm, TestRng};
+    use rayon::ThreadPoolBuilder;
+
+    use std::convert::TryFrom;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[allow(clippy::blocks_in_conditions, clippy::assertions_on_constants)]
+    fn test_encoder<F>(width: u32, height: u32, func: F)
+        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> error::Result<()>
+    {
+        match {
+            || -> error::Result<Vec<u8>> {
+                let mut data = Vec::<u8>::with_capacity(width as usize * 3);
+                for i in 0 .. width as usize * 3 {
+                    data.push((i % 255) as u8);
+                }
+
+                let writer = Vec::<u8>::new();
+                let options = Options::new();
+                let mut encoder = Encoder::new(writer, &options);
+
+                let mut header = Header::new();
+                header.set_size(width, height).unwrap();
+                header.set_color(ColorType::Truecolor, 8).unwrap();
+                encoder.write_header(&header)?;
+
+                func(&mut encoder, &data)?;
+                encoder.finish()
+            }()
+        } {
+            Ok(_writer) => {},
+            Err(e) => assert!(false, "Error {}", e),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn create_and_state() {
+        test_encoder(1920, 1080, |encoder, data| {
+
+            assert_eq!(encoder.is_finished(), false);
+            assert_eq!(encoder.progress(), 0.0);
+
+            // We must finish out the file or it'll whinge.
+            for _y in 0 .. 1080 {
+                encoder.write_image_rows(data)?;
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn backends() {
+        let width = 640;
+        let height = 480;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        for backend in Backend::available() {
+            let mut options = Options::new();
+            options.set_backend(backend).unwrap();
+            // Several chunks, to exercise the dictionary and flush paths.
+            options.set_chunk_size(65536).unwrap();
+
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            let mut header = Header::new();
+            header.set_size(width as u32, height as u32).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+            encoder.write_header(&header).unwrap();
+            encoder.write_image_rows(&data).unwrap();
+            let png = encoder.finish().unwrap();
+
+            let decoded = decode(&png[..]).unwrap();
+            assert_eq!(decoded.data(), &data[..], "backend {}", backend.name());
+        }
+    }
+
+    #[test]
+    fn buffer_reuse() {
+        let width = 640;
+        let height = 480;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        encoder.flush().unwrap();
+
+        // Chunks written out have given their buffers back.
+        let spare = encoder.buffers.0.lock().unwrap().len();
+        assert!(spare > 0 && spare <= super::MAX_SPARE_BUFFERS);
+
+        let png = encoder.finish().unwrap();
+        assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+    }
+
+    #[test]
+    fn zero_copy_input() {
+        let width = 640;
+        let height = 480;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        for &interlace in [InterlaceMethod::Standard, InterlaceMethod::Adam7].iter() {
+            let mut header = Header::new();
+            header.set_size(width as u32, height as u32).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+            header.set_interlace_method(interlace).unwrap();
+            let mut options = Options::new();
+            options.set_chunk_size(32768).unwrap();
+            options.set_thread_pool(&pool).unwrap();
+
+            let encode = |borrowed: bool| {
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                if borrowed {
+                    encoder.write_image_borrowed(&data).unwrap();
+                } else {
+                    encoder.write_image_rows(&data).unwrap();
+                }
+                encoder.finish().unwrap()
+            };
+            let png = encode(true);
+            assert_eq!(png, encode(false));
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+        }
+
+        // Shared buffers, some ending partway through a chunk.
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+        let encode = |splits: &[usize]| {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            let mut start = 0;
+            for &end in splits.iter().chain(&[height]) {
+                let rows: Arc<[u8]> = data[start * width * 3 .. end * width * 3].into();
+                encoder.write_image_rows_shared(rows).unwrap();
+                start = end;
+            }
+            encoder.finish().unwrap()
+        };
+        let png = encode(&[]);
+        assert_eq!(png, encode(&[1, 100, 101, 317]));
+        assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+
+        // Only the whole image, all at once.
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_image_borrowed(&data[.. width * 3]).is_err());
+        encoder.write_image_rows(&data[.. width * 3]).unwrap();
+        assert!(encoder.write_image_borrowed(&data).is_err());
+    }
+
+    #[test]
+    fn borrowed_input_transforms() {
+        let width = 64;
+        let height = 48;
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let options = Options::new();
+
+        // 4-bit samples, scaled up to 8 bits.
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 % 16) as u8).collect();
+        let encode = |borrowed: bool| {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            encoder.write_significant_bits(4).unwrap();
+            if borrowed {
+                encoder.write_image_borrowed(&data).unwrap();
+            } else {
+                encoder.write_image_rows(&data).unwrap();
+            }
+            encoder.finish().unwrap()
+        };
+        let png = encode(true);
+        assert_eq!(png, encode(false));
+        let scaled: Vec<u8> = data.iter().map(|&sample| sample * 17).collect();
+        assert_eq!(decode(&png[..]).unwrap().data(), &scaled[..]);
+
+        // Indices remapped after merging duplicate palette entries.
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::IndexedColor, 4).unwrap();
+        let mut options = Options::new();
+        options.set_palette_dedup(true).unwrap();
+        let palette = [1, 2, 3, 9, 9, 9, 1, 2, 3, 9, 9, 9, 5, 5, 5];
+        let indices: Vec<u8> = (0 .. width * height).map(|i| (i * 3 % 5) as u8).collect();
+        let data: Vec<u8> = indices.chunks(width).flat_map(|row| layout::pack(row, 4)).collect();
+        let encode = |borrowed: bool| {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            encoder.write_palette(&palette).unwrap();
+            if borrowed {
+                encoder.write_image_borrowed(&data).unwrap();
+            } else {
+                encoder.write_image_rows(&data).unwrap();
+            }
+            encoder.finish().unwrap()
+        };
+        let png = encode(true);
+        assert_eq!(png, encode(false));
+        let decoded = decode(&png[..]).unwrap();
+        assert_eq!(decoded.palette().unwrap(), &[1, 2, 3, 9, 9, 9, 5, 5, 5]);
+        let merged = [0, 1, 0, 1, 2];
+        let remapped: Vec<u8> = indices.iter().map(|&index| merged[index as usize]).collect();
+        let unpacked: Vec<u8> = decoded.data().chunks(header.stride())
+                                       .flat_map(|row| layout::unpack(row, 4, width))
+                                       .collect();
+        assert_eq!(unpacked, remapped);
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn arrays() {
+        use ndarray::{s, Array3, ShapeBuilder};
+
+        let (width, height) = (64, 48);
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let encode = |write: &dyn Fn(&mut Encoder<Vec<u8>>) -> error::Result<()>| {
+            let options = Options::new();
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            write(&mut encoder)?;
+            encoder.finish()
+        };
+
+        let array = Array3::from_shape_vec((height, width, 3), data.clone()).unwrap();
+        let expected = encode(&|encoder| encoder.write_image_rows(&data)).unwrap();
+        assert_eq!(encode(&|encoder| encoder.write_array(&array)).unwrap(), expected);
+
+        // Column-major, and a view of the top and bottom halves.
+        let mut fortran = Array3::zeros((height, width, 3).f());
+        fortran.assign(&array);
+        assert_eq!(encode(&|encoder| encoder.write_array(&fortran)).unwrap(), expected);
+        let halves = encode(&|encoder| {
+            encoder.write_array(&fortran.slice(s![.. height / 2, .., ..]))?;
+            encoder.write_array(&array.slice(s![height / 2 .., .., ..]))
+        });
+        assert_eq!(halves.unwrap(), expected);
+
+        // Shapes must match the header.
+        let rgba = Array3::<u8>::zeros((height, width, 4));
+        assert!(encode(&|encoder| encoder.write_array(&rgba)).is_err());
+        let narrow = Array3::<u8>::zeros((height, width - 1, 3));
+        assert!(encode(&|encoder| encoder.write_array(&narrow)).is_err());
+    }
+
+    #[test]
+    fn padded_rows() {
+        let (width, height) = (61, 40);
+        let stride = width * 3;
+        let data: Vec<u8> = (0 .. stride * height).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        let pitch = stride + 13;
+        let mut padded = vec![0xee; pitch * height];
+        for (dest, row) in padded.chunks_mut(pitch).zip(data.chunks(stride)) {
+            dest[.. stride].copy_from_slice(row);
+        }
+
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let options = Options::new();
+        let encode = |write: &dyn Fn(&mut Encoder<Vec<u8>>) -> error::Result<()>| {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            write(&mut encoder)?;
+            encoder.finish()
+        };
+
+        let expected = encode(&|encoder| encoder.write_image_rows(&data)).unwrap();
+        let png = encode(&|encoder| encoder.write_image_rows_with_stride(&padded, pitch)).unwrap();
+        assert_eq!(png, expected);
+        // Without the last row's padding, and in two goes.
+        let png = encode(&|encoder| {
+            encoder.write_image_rows_with_stride(&padded[.. pitch * 10], pitch)?;
+            encoder.write_image_rows_with_stride(&padded[pitch * 10 .. pitch * (height - 1) + stride], pitch)
+        }).unwrap();
+        assert_eq!(png, expected);
+        assert_eq!(encode(&|encoder| encoder.write_image_rows_with_stride(&data, stride)).unwrap(), expected);
+
+        assert!(encode(&|encoder| encoder.write_image_rows_with_stride(&padded, stride - 1)).is_err());
+        assert!(encode(&|encoder| encoder.write_image_rows_with_stride(&padded[.. pitch + 1], pitch)).is_err());
+        assert!(encode(&|encoder| encoder.write_image_rows_with_stride(&padded[.. pitch * 2 - 1], pitch)).is_err());
+    }
+
+    #[test]
+    fn native_samples() {
+        let (width, height) = (37, 300);
+        let samples: Vec<u16> = (0 .. width * height * 4).map(|i| (i * 2579 % 65536) as u16).collect();
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+        let (first, last) = (width * 4 * 100, width * 4 * 250);
+
+        for &interlace in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            let mut header = Header::new();
+            header.set_size(width as u32, height as u32).unwrap();
+            header.set_color(ColorType::TruecolorAlpha, 16).unwrap();
+            header.set_interlace_method(interlace).unwrap();
+            let mut options = Options::new();
+            options.set_chunk_size(32768).unwrap();
+            let encode = |write: &dyn Fn(&mut Encoder<Vec<u8>>) -> error::Result<()>| {
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                write(&mut encoder)?;
+                encoder.finish()
+            };
+
+            let expected = encode(&|encoder| encoder.write_image_rows(&bytes)).unwrap();
+            assert_eq!(encode(&|encoder| encoder.write_image_rows_u16(&samples)).unwrap(), expected);
+            // Mixed with big-endian rows, in and across chunks.
+            let png = encode(&|encoder| {
+                encoder.write_image_rows_u16(&samples[.. first])?;
+                encoder.write_image_rows(&bytes[first * 2 .. last * 2])?;
+                encoder.write_image_rows_u16(&samples[last ..])
+            }).unwrap();
+            assert_eq!(png, expected);
+            assert!(encode(&|encoder| encoder.write_image_rows_u16(&samples[.. 5])).is_err());
+        }
+
+        let mut header = Header::new();
+        header.set_size(4, 4).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_image_rows_u16(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn pixel_formats() {
+        let (width, height) = (53, 700);
+        let rgba: Vec<u8> = (0 .. width * height * 4).map(|i| (i * 13 / 7 % 251) as u8).collect();
+        let rgb: Vec<u8> = rgba.chunks(4).flat_map(|p| [p[0], p[1], p[2]]).collect();
+        let formats = [
+            (PixelFormat::Bgr8, ColorType::Truecolor, rgb.chunks(3).flat_map(|p| [p[2], p[1], p[0]]).collect::<Vec<u8>>()),
+            (PixelFormat::Bgra8, ColorType::TruecolorAlpha, rgba.chunks(4).flat_map(|p| [p[2], p[1], p[0], p[3]]).collect()),
+            (PixelFormat::Argb8, ColorType::TruecolorAlpha, rgba.chunks(4).flat_map(|p| [p[3], p[0], p[1], p[2]]).collect()),
+            (PixelFormat::Rgbx8, ColorType::Truecolor, rgb.chunks(3).flat_map(|p| [p[0], p[1], p[2], 0xff]).collect()),
+            (PixelFormat::Bgrx8, ColorType::Truecolor, rgb.chunks(3).flat_map(|p| [p[2], p[1], p[0], 0]).collect()),
+        ];
+
+        for (format, color_type, data) in formats.iter() {
+            let expected_data = match color_type {
+                ColorType::Truecolor => &rgb,
+                _ => &rgba,
+            };
+            for &interlace in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+                let mut header = Header::new();
+                header.set_size(width as u32, height as u32).unwrap();
+                header.set_color(*color_type, 8).unwrap();
+                header.set_interlace_method(interlace).unwrap();
+                let encode = |options: &Options, data: &[u8]| {
+                    let mut encoder = Encoder::new(Vec::<u8>::new(), options);
+                    encoder.write_header(&header)?;
+                    encoder.write_image_rows(data)?;
+                    encoder.finish()
+                };
+
+                let mut options = Options::new();
+                options.set_chunk_size(32768).unwrap();
+                let expected = encode(&options, expected_data).unwrap();
+                options.set_input_format(*format).unwrap();
+                assert_eq!(encode(&options, data).unwrap(), expected);
+            }
+        }
+
+        let mut header = Header::new();
+        header.set_size(4, 4).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut options = Options::new();
+        options.set_input_format(PixelFormat::Bgra8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        assert!(encoder.write_header(&header).is_err());
+    }
+
+    #[test]
+    fn unpacked_samples() {
+        let (width, height) = (77, 900);
+        for &(color_type, depth) in &[(ColorType::Greyscale, 1), (ColorType::Greyscale, 2),
+                                      (ColorType::Greyscale, 4), (ColorType::IndexedColor, 4)] {
+            let samples: Vec<u8> = (0 .. width * height).map(|i| (i * 5 / 3 % (1 << depth)) as u8).collect();
+            for &interlace in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+                let mut header = Header::new();
+                header.set_size(width as u32, height as u32).unwrap();
+                header.set_color(color_type, depth).unwrap();
+                header.set_interlace_method(interlace).unwrap();
+                let mut packed = vec![0; header.stride() * height];
+                for (row, dest) in samples.chunks(width).zip(packed.chunks_mut(header.stride())) {
+                    header.pack_row(row, dest).unwrap();
+                }
+                let mut unpacked = vec![0; width];
+                header.unpack_row(&packed[.. header.stride()], &mut unpacked).unwrap();
+                assert_eq!(unpacked, &samples[.. width]);
+
+                let encode = |options: &Options, data: &[u8]| {
+                    let mut encoder = Encoder::new(Vec::<u8>::new(), options);
+                    encoder.write_header(&header)?;
+                    if let ColorType::IndexedColor = color_type {
+                        encoder.write_palette(&[0; 48])?;
+                    }
+                    encoder.write_image_rows(data)?;
+                    encoder.finish()
+                };
+                let mut options = Options::new();
+                options.set_chunk_size(32768).unwrap();
+                let expected = encode(&options, &packed).unwrap();
+                options.set_input_format(PixelFormat::Unpacked).unwrap();
+                options.set_validation(true).unwrap();
+                assert_eq!(encode(&options, &samples).unwrap(), expected);
+                let mut bad = samples.clone();
+                bad[width * 3] = 1 << depth;
+                assert!(encode(&options, &bad).is_err());
+            }
+        }
+
+        let mut header = Header::new();
+        header.set_size(4, 4).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        assert!(header.pack_row(&[0; 4], &mut [0; 4]).is_err());
+        let mut options = Options::new();
+        options.set_input_format(PixelFormat::Unpacked).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        assert!(encoder.write_header(&header).is_err());
+    }
+
+    #[test]
+    fn depth_reduction() {
+        let (width, height) = (90, 500);
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 11 / 9 % 256) as u8).collect();
+        let doubled: Vec<u8> = data.iter().flat_map(|&value| [value, value]).collect();
+        let low: Vec<u8> = data.iter().flat_map(|&value| [0, value]).collect();
+        for &interlace in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            let mut header = Header::new();
+            header.set_size(width as u32, height as u32).unwrap();
+            header.set_color(ColorType::Truecolor, 16).unwrap();
+            header.set_interlace_method(interlace).unwrap();
+            let encode = |reduce: bool, rows: &[u8], key: &[u8], bits: Option<u8>| {
+                let mut options = Options::new();
+                options.set_chunk_size(32768).unwrap();
+                options.set_depth_reduction(reduce).unwrap();
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                if let Some(bits) = bits {
+                    encoder.write_significant_bits(bits).unwrap();
+                }
+                encoder.write_transparency(key).unwrap();
+                encoder.write_chunk(b"tEXt", b"Comment\0kept").unwrap();
+                encoder.write_image_rows(&rows[.. width * 6 * 100]).unwrap();
+                encoder.write_image_rows(&rows[width * 6 * 100 ..]).unwrap();
+                encoder.finish().unwrap()
+            };
+            let key = [1, 1, 2, 2, 3, 3];
+
+            let png = encode(true, &doubled, &key, None);
+            let decoded = decode(&png[..]).unwrap();
+            assert_eq!(decoded.header().depth(), 8);
+            assert_eq!(decoded.data(), &data[..]);
+            assert_eq!(decoded.transparency(), Some(&[0, 1, 0, 2, 0, 3][..]));
+            assert_eq!(decode(&encode(false, &doubled, &key, None)[..]).unwrap().header().depth(), 16);
+            // 8 significant bits scale up to samples like 0xXYXY.
+            let png = encode(true, &low, &key, Some(8));
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+
+            // A single sample or key that doesn't match keeps 16 bits.
+            let odd_key = [1, 2, 2, 2, 3, 3];
+            assert_eq!(encode(true, &doubled, &odd_key, None), encode(false, &doubled, &odd_key, None));
+            let mut odd = doubled.clone();
+            odd[width * 6 * 7 + 1] ^= 1;
+            assert_eq!(encode(true, &odd, &key, None), encode(false, &odd, &key, None));
+        }
+    }
+
+    #[test]
+    fn all_levels() {
+        let width = 256usize;
+        let height = 32;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i / 3 % width + i / 48) as u8).collect();
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        for val in 1 ..= 9 {
+            let level = CompressionLevel::try_from(val).unwrap();
+            assert_eq!(level.level(), val);
+            let mut options = Options::new();
+            options.set_compression_level(level).unwrap();
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            encoder.write_image_rows(&data).unwrap();
+            let png = encoder.finish().unwrap();
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..], "level {}", val);
+        }
+
+        let mut options = Options::new();
+        assert!(CompressionLevel::try_from(0).is_err());
+        assert!(options.set_compression_level(CompressionLevel::Specific(0)).is_err());
+        assert!(options.set_compression_level(CompressionLevel::Specific(10)).is_err());
+    }
+
+    #[test]
+    fn max_compression() {
+        let mut options = Options::new();
+        if !cfg!(feature = "zopfli") {
+            assert!(options.set_compression_level(CompressionLevel::Max).is_err());
+            return;
+        }
+        let width = 256;
+        let height = 96;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| {
+            let (x, y) = (i / 3 % width, i / 3 / width);
+            ((x ^ y) + x * y / 64 + i % 3 * 40) as u8
+        }).collect();
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        // One chunk, and several to exercise the flush path.
+        for &(chunk_size, streaming) in [(1 << 20, false), (32768, false), (32768, true)].iter() {
+            let encode = |level| {
+                let mut options = Options::new();
+                options.set_compression_level(level).unwrap();
+                options.set_chunk_size(chunk_size).unwrap();
+                options.set_streaming(streaming).unwrap();
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                encoder.write_image_rows(&data).unwrap();
+                encoder.finish().unwrap()
+            };
+            let png = encode(CompressionLevel::Max);
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+            assert!(png.len() < encode(CompressionLevel::High).len());
+        }
+    }
+
+    //
+    // Every compiled-in backend must produce streams that decode, with
+    // valid CRCs and Adler-32, back to the same pixels, across the
+    // settings that change how chunks are compressed and framed.
+    //
+    #[test]
+    fn backend_conformance() {
+        // Small, but still a few chunks at the minimum chunk size.
+        let width = 256u32;
+        let height = 96u32;
+        let gradient: Vec<u8> = (0 .. width * height * 3).map(|i| (i / 3 % width) as u8).collect();
+        let noise = noise((width * height * 3) as usize);
+        let flat = vec![42u8; (width * height * 3) as usize];
+
+        let levels = [CompressionLevel::Fast, CompressionLevel::Default, CompressionLevel::High];
+        let strategies = [Strategy::Default, Strategy::Filtered, Strategy::Rle, Strategy::Fixed];
+
+        for (name, data) in [("gradient", &gradient), ("noise", &noise), ("flat", &flat)] {
+            for &level in levels.iter() {
+                for &strategy in strategies.iter() {
+                    for &streaming in [false, true].iter() {
+                        for backend in Backend::available() {
+                            let mut options = Options::new();
+                            options.set_backend(backend).unwrap();
+                            options.set_chunk_size(32768).unwrap();
+                            options.set_compression_level(level).unwrap();
+                            options.set_strategy_mode(Fixed(strategy)).unwrap();
+                            options.set_streaming(streaming).unwrap();
+
+                            let mut header = Header::new();
+                            header.set_size(width, height).unwrap();
+                            header.set_color(ColorType::Truecolor, 8).unwrap();
+                            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                            encoder.write_header(&header).unwrap();
+                            encoder.write_image_rows(data).unwrap();
+                            let png = encoder.finish().unwrap();
+
+                            let decoded = decode(&png[..]).unwrap_or_else(|e| {
+                                panic!("{} with {} level {} strategy {} streaming {}: {}",
+                                       name, backend.name(), level.level(), strategy as u8, streaming, e)
+                            });
+                            assert!(decoded.data() == &data[..],
+                                    "{} with {} level {} strategy {} streaming {} decoded differently",
+                                    name, backend.name(), level.level(), strategy as u8, streaming);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_ordering() {
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(encoder.write_chunk(b"tEXt", b"a\0b").is_err());
+
+        let mut header = Header::new();
+        header.set_size(1, 1).unwrap();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        encoder.write_header(&header).unwrap();
+
+        assert!(encoder.write_chunk(b"tEX", b"").is_err());
+        for tag in [b"IHDR", b"PLTE", b"IDAT", b"IEND", b"CRIT"] {
+            assert!(encoder.write_chunk(tag, b"").is_err());
+        }
+        assert!(encoder.write_chunk(b"tRNS", b"").is_err());
+        assert!(encoder.write_chunk(b"prvt", b"").is_err());
+        encoder.write_chunk(b"gAMA", &[0, 0, 0xb1, 0x8f]).unwrap();
+        assert!(encoder.write_chunk(b"gAMA", &[0, 0, 0xb1, 0x8f]).is_err());
+        encoder.write_chunk(b"iCCP", b"p\0\0").unwrap();
+        assert!(encoder.write_chunk(b"sRGB", &[0]).is_err());
+        encoder.write_chunk(b"prVt", b"private").unwrap();
+        assert!(encoder.write_chunk(b"bKGD", &[0]).is_err());
+
+        encoder.write_palette(&[0, 0, 0]).unwrap();
+        assert!(encoder.write_chunk(b"sBIT", &[8, 8, 8]).is_err());
+        encoder.write_chunk(b"bKGD", &[0]).unwrap();
+        encoder.write_chunk(b"pHYs", &[0; 9]).unwrap();
+
+        encoder.write_image_rows(&[0]).unwrap();
+        assert!(encoder.write_chunk(b"tEXt", b"a\0b").is_err());
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn color_space() {
+        let srgb = Chromaticities::new((0.3127, 0.329), (0.64, 0.33), (0.3, 0.6), (0.15, 0.06));
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&Header::new()).unwrap();
+        assert!(encoder.write_gamma(0.0).is_err());
+        assert!(encoder.write_gamma(-1.0).is_err());
+        assert!(encoder.write_gamma(f64::NAN).is_err());
+        assert!(encoder.write_gamma(1e6).is_err());
+        encoder.write_srgb(RenderingIntent::Perceptual).unwrap();
+        assert!(encoder.write_srgb(RenderingIntent::Saturation).is_err());
+        assert!(encoder.write_icc_profile("Display", &[1]).is_err());
+        encoder.write_gamma(0.45455).unwrap();
+        encoder.write_chromaticities(srgb).unwrap();
+        encoder.write_image_rows(&[0, 0, 0, 0]).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let find = |needle: &[u8]| png.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"gAMA\0\0\xb1\x8f"));
+        assert!(find(b"cHRM\0\0\x7a\x26\0\0\x80\x84"));
+
+        let decoded = decode(&png[..]).unwrap();
+        let metadata = decoded.metadata();
+        assert_eq!(metadata.rendering_intent(), Some(RenderingIntent::Perceptual));
+        assert_eq!(metadata.gamma(), Some(0.45455));
+        assert_eq!(metadata.chromaticities(), Some(&srgb));
+    }
+
+    #[test]
+    fn background() {
+        let mut header = Header::new();
+        header.set_size(1, 1).unwrap();
+        header.set_color(ColorType::Greyscale, 4).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_background(Background::Rgb(0, 0, 0)).is_err());
+        assert!(encoder.write_background(Background::PaletteIndex(0)).is_err());
+        assert!(encoder.write_background(Background::Greyscale(16)).is_err());
+        encoder.write_background(Background::Greyscale(15)).unwrap();
+        assert!(encoder.write_background(Background::Greyscale(15)).is_err());
+        encoder.write_image_rows(&[0]).unwrap();
+        let png = encoder.finish().unwrap();
+        assert!(png.windows(6).any(|w| w == b"bKGD\0\x0f"));
+
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_background(Background::PaletteIndex(0)).is_err());
+        encoder.write_palette(&[0, 0, 0, 255, 255, 255]).unwrap();
+        assert!(encoder.write_background(Background::PaletteIndex(2)).is_err());
+        encoder.write_background(Background::PaletteIndex(1)).unwrap();
+        encoder.write_image_rows(&[0]).unwrap();
+        let png = encoder.finish().unwrap();
+        assert!(png.windows(5).any(|w| w == b"bKGD\x01"));
+    }
+
+    #[test]
+    fn histogram() {
+        let mut header = Header::new();
+        header.set_size(1, 1).unwrap();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_histogram(&[1, 0]).is_err());
+        encoder.write_palette(&[0, 0, 0, 255, 255, 255]).unwrap();
+        assert!(encoder.write_histogram(&[1]).is_err());
+        assert!(encoder.write_histogram(&[1, 0, 0]).is_err());
+        encoder.write_histogram(&[1, 0x102]).unwrap();
+        assert!(encoder.write_histogram(&[1, 0]).is_err());
+        encoder.write_image_rows(&[0]).unwrap();
+        assert!(encoder.write_histogram(&[1, 0]).is_err());
+        let png = encoder.finish().unwrap();
+        assert!(png.windows(8).any(|w| w == b"hIST\0\x01\x01\x02"));
+    }
+
+    #[test]
+    fn suggested_palette() {
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&Header::new()).unwrap();
+        assert!(encoder.write_suggested_palette("Web", 4, &[]).is_err());
+        assert!(encoder.write_suggested_palette("Web", 8, &[0; 10]).is_err());
+        assert!(encoder.write_suggested_palette("Web", 16, &[0; 6]).is_err());
+        assert!(encoder.write_suggested_palette(" Web", 8, &[0; 6]).is_err());
+        encoder.write_suggested_palette("Web", 8, &[1, 2, 3, 255, 0, 9]).unwrap();
+        assert!(encoder.write_suggested_palette("Web", 16, &[0; 10]).is_err());
+        encoder.write_suggested_palette("Deep", 16, &[0; 20]).unwrap();
+        encoder.write_image_rows(&[0, 0, 0, 0]).unwrap();
+        let png = encoder.finish().unwrap();
+        assert!(png.windows(15).any(|w| w == b"sPLTWeb\0\x08\x01\x02\x03\xff\0\x09"));
+        assert!(png.windows(10).any(|w| w == b"sPLTDeep\0\x10"));
+    }
+
+    #[test]
+    fn exif() {
+        let exif = b"MM\0*\0\0\0\x08\0\0";
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(encoder.write_exif(exif).is_err());
+        encoder.write_header(&Header::new()).unwrap();
+        assert!(encoder.write_exif(b"").is_err());
+        assert!(encoder.write_exif(b"Exif\0\0MM\0*\0\0\0\x08").is_err());
+        encoder.write_exif(exif).unwrap();
+        assert!(encoder.write_exif(b"II*\0\x08\0\0\0").is_err());
+        encoder.write_image_rows(&[0, 0, 0, 0]).unwrap();
+        let png = encoder.finish().unwrap();
+        assert!(png.windows(14).any(|w| w == b"eXIfMM\0*\0\0\0\x08\0\0"));
+    }
+
+    #[test]
+    fn hdr() {
+        let bt2020 = Chromaticities::new((0.3127, 0.329), (0.708, 0.292), (0.17, 0.797), (0.131, 0.046));
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&Header::new()).unwrap();
+        assert!(encoder.write_cicp(9, 16, 1, true).is_err());
+        encoder.write_cicp(9, 16, 0, true).unwrap();
+        assert!(encoder.write_cicp(9, 18, 0, true).is_err());
+        assert!(encoder.write_mastering_display(bt2020, 0.005, 1000.0).is_err());
+        assert!(encoder.write_mastering_display(bt2020, -1.0, -2.0).is_err());
+        encoder.write_mastering_display(bt2020, 1000.0, 0.005).unwrap();
+        assert!(encoder.write_content_light_level(f64::NAN, 400.0).is_err());
+        encoder.write_content_light_level(1000.0, 400.0).unwrap();
+        encoder.write_image_rows(&[0, 0, 0, 0]).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let find = |needle: &[u8]| png.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"cICP\x09\x10\0\x01"));
+        // Red x of 0.708 is 35400, then white y of 0.329 is 16450,
+        // and 1000 and 0.005 cd/m^2.
+        assert!(find(b"mDCv\x8a\x48"));
+        assert!(find(b"\x40\x42\0\x98\x96\x80\0\0\0\x32"));
+        assert!(find(b"cLLi\0\x98\x96\x80\0\x3d\x09\0"));
+
+        // Not after the palette.
+        let mut header = Header::new();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_palette(&[0, 0, 0]).unwrap();
+        assert!(encoder.write_cicp(1, 13, 0, true).is_err());
+        assert!(encoder.write_content_light_level(1000.0, 400.0).is_err());
+    }
+
+    #[test]
+    fn extension_chunks() {
+        let mut header = Header::new();
+        header.set_size(2, 1).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_offset(i32::MIN, 0, OffsetUnit::Pixel).is_err());
+        encoder.write_offset(-2, 300, OffsetUnit::Micrometer).unwrap();
+        assert!(encoder.write_offset(0, 0, OffsetUnit::Pixel).is_err());
+        encoder.write_stereo(StereoLayout::DivergingFuse).unwrap();
+        assert!(encoder.write_stereo(StereoLayout::CrossFuse).is_err());
+        encoder.write_image_rows(&[0, 0]).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let find = |needle: &[u8]| png.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"oFFs\xff\xff\xff\xfe\0\0\x01\x2c\x01"));
+        assert!(find(b"sTER\x01"));
+
+        header.set_size(1, 1).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_stereo(StereoLayout::CrossFuse).is_err());
+    }
 
-            // Make a nice new buffer to accumulate data into.
-            self.pixel_index += 1;
-            if self.pixel_index < self.chunks_total {
-                self.pixel_chunks.advance();
-                self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
-                                                                  self.pixel_index,
-                                                                  self.start_row(self.pixel_index),
-                                                                  self.end_row(self.pixel_index)));
-            }
+    #[test]
+    fn icc_profile() {
+        let mut header = Header::new();
+        header.set_size(1, 1).unwrap();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        let profile: Vec<u8> = (0 .. 1000u32).map(|i| (i % 7) as u8).collect();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_icc_profile("Display", &[]).is_err());
+        assert!(encoder.write_icc_profile("", &profile).is_err());
+        encoder.write_icc_profile("Display", &profile).unwrap();
+        assert!(encoder.write_icc_profile("Display", &profile).is_err());
+        assert!(encoder.write_chunk(b"sRGB", &[0]).is_err());
+        encoder.write_palette(&[0, 0, 0]).unwrap();
+        encoder.write_image_rows(&[0]).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let decoded = decode(&png[..]).unwrap();
+        let icc = decoded.metadata().icc_profile().unwrap();
+        assert_eq!(icc.name(), "Display");
+        assert_eq!(icc.profile(), &profile[..]);
+
+        // Not after the palette.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_palette(&[0, 0, 0]).unwrap();
+        assert!(encoder.write_icc_profile("Display", &profile).is_err());
+    }
 
-            // Dispatch any available async tasks and output.
-            while self.running_jobs() >= self.max_threads() {
-                self.dispatch(DispatchMode::Blocking)?;
+    #[test]
+    fn stats() {
+        test_encoder(1920, 1080, |encoder, data| {
+            for _y in 0 .. 1080 {
+                encoder.write_image_rows(data)?;
             }
-            self.dispatch(DispatchMode::NonBlocking)?;
-        }
+            encoder.flush()?;
 
-        self.current_row += 1;
-        if self.current_row == self.header.height {
-            Ok(RowStatus::Done)
-        } else {
-            Ok(RowStatus::Continue)
+            let stats = encoder.stats();
+            let rows: usize = [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth]
+                .iter()
+                .map(|&filter| stats.filter_rows(filter))
+                .sum();
+            assert_eq!(rows, 1080);
+            assert_eq!(stats.filtered_bytes(), (1920 * 3 + 1) * 1080);
+            assert!(stats.compressed_bytes() > 0);
+            assert!(stats.output_bytes() > stats.compressed_bytes());
+            assert!(stats.chunks() > 1);
+
+            let chunk_times = encoder.chunk_times();
+            assert_eq!(chunk_times.len(), stats.chunks());
+            assert!(chunk_times.iter().all(|times| times.deflate() > Duration::from_secs(0)));
+            let deflate: Duration = chunk_times.iter().map(|times| times.deflate()).sum();
+            assert_eq!(stats.times().deflate(), deflate);
+            assert!(stats.times().filter() > Duration::from_secs(0));
+            assert!(stats.times().pixel_copy() > Duration::from_secs(0));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn finish_with_stats() {
+        let mut header = Header::new();
+        header.set_size(1920, 1080).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 1920 * 3 * 1080).map(|i| (i % 251) as u8).collect();
+
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        let (writer, stats) = encoder.finish_with_stats().unwrap();
+
+        assert_eq!(stats.output_bytes(), writer.len() as u64);
+        assert_eq!(stats.uncompressed_bytes(), 1920 * 3 * 1080);
+        let filters = [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth];
+        let filter_bytes: u64 = filters.iter().map(|&filter| stats.filter_bytes(filter)).sum();
+        assert_eq!(filter_bytes, stats.filtered_bytes());
+        for &filter in filters.iter() {
+            assert_eq!(stats.filter_bytes(filter), stats.filter_rows(filter) as u64 * (1920 * 3 + 1));
         }
+        assert_eq!(stats.chunk_bytes().len(), stats.chunks());
+        let chunk_bytes: usize = stats.chunk_bytes().iter().sum();
+        assert_eq!(chunk_bytes as u64, stats.compressed_bytes());
+        assert!(stats.elapsed() > Duration::from_secs(0));
+        assert!(stats.worker_utilization() > 0.0 && stats.worker_utilization() <= 1.0);
     }
 
-    /// Encode and compress the given image data and write to output.
-    /// Input data must be packed in the correct format for the given
-    /// color type and depth, with no padding at the end of rows.
-    ///
-    /// An integral number of rows must be provided at once.
-    ///
-    /// If not all of the image rows are provided, multiple calls are
-    /// required to finish out the data.
-    pub fn write_image_rows(&mut self, buf: &[u8]) -> IoResult {
-        let stride = self.header.stride();
-        if buf.len() % stride != 0 {
-            Err(invalid_input("Buffer must be an integral number of rows"))
-        } else {
-            for row in buf.chunks(stride) {
-                self.process_row(row)?;
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn test_rows() {
+        test_encoder(1920, 1080, |encoder, data| {
+            assert_eq!(encoder.is_finished(), false);
+            assert_eq!(encoder.progress(), 0.0);
+
+            for _y in 0 .. 1080 {
+                encoder.write_image_rows(data)?;
             }
+
+            // Should trigger all blocks!
+            encoder.flush()?;
+            assert_eq!(encoder.is_finished(), true);
+            assert_eq!(encoder.progress(), 1.0);
+
             Ok(())
+        });
+    }
+
+    #[test]
+    fn chunk_map_errors() {
+        let mut map = ChunkMap::<u32>::new();
+        assert!(map.land(0, Arc::new(0)).is_err());
+
+        map.advance();
+        map.land(0, Arc::new(0)).unwrap();
+        assert!(map.pop_front().unwrap().is_some());
+        assert!(map.pop_front().unwrap().is_none());
+
+        // Landing the same chunk again is now expired.
+        map.advance();
+        assert!(map.land(0, Arc::new(0)).is_err());
+        assert!(map.land(2, Arc::new(2)).is_err());
+    }
+
+    #[test]
+    fn palette_limits() {
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        let mut header = Header::new();
+        header.set_size(4, 1).unwrap();
+        header.set_color(ColorType::IndexedColor, 2).unwrap();
+        encoder.write_header(&header).unwrap();
+
+        assert!(encoder.write_palette(&[0; 15]).is_err());
+        encoder.write_palette(&[0; 9]).unwrap();
+
+        // Indices 0, 1, 2, 3; the last is past the three-entry palette.
+        let result = encoder.write_image_rows(&[0b00011011]);
+        assert_eq!(result.is_err(), cfg!(debug_assertions));
+    }
+
+    #[test]
+    fn validation() {
+        let mut options = Options::new();
+        options.set_validation(true).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        let mut header = Header::new();
+        header.set_size(4, 1).unwrap();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        encoder.write_header(&header).unwrap();
+        encoder.write_palette(&[0; 6]).unwrap();
+        assert!(encoder.write_image_rows(&[0, 1, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn transparency_samples() {
+        let encoder_for = |color_type, depth| {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+            let mut header = Header::new();
+            header.set_size(1, 1).unwrap();
+            header.set_color(color_type, depth).unwrap();
+            encoder.write_header(&header).unwrap();
+            encoder
+        };
+        assert!(encoder_for(ColorType::Greyscale, 4).write_transparency(&[0, 16]).is_err());
+        encoder_for(ColorType::Greyscale, 4).write_transparency(&[0, 15]).unwrap();
+        assert!(encoder_for(ColorType::Truecolor, 8).write_transparency(&[0, 1, 1, 0, 0, 2]).is_err());
+        encoder_for(ColorType::Truecolor, 16).write_transparency(&[0, 1, 1, 0, 0, 2]).unwrap();
+    }
+
+    #[test]
+    fn significant_bits() {
+        let mut options = Options::new();
+        options.set_validation(true).unwrap();
+        let encoder_for = |color_type, depth| {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            let mut header = Header::new();
+            header.set_size(2, 1).unwrap();
+            header.set_color(color_type, depth).unwrap();
+            encoder.write_header(&header).unwrap();
+            encoder
+        };
+        assert!(encoder_for(ColorType::IndexedColor, 8).write_significant_bits(4).is_err());
+        assert!(encoder_for(ColorType::Greyscale, 4).write_significant_bits(2).is_err());
+        assert!(encoder_for(ColorType::Greyscale, 8).write_significant_bits(0).is_err());
+        assert!(encoder_for(ColorType::Greyscale, 8).write_significant_bits(9).is_err());
+
+        let mut encoder = encoder_for(ColorType::Greyscale, 8);
+        encoder.write_significant_bits(8).unwrap();
+        assert!(encoder.write_significant_bits(8).is_err());
+        assert!(encoder.write_chunk(b"sBIT", &[8]).is_err());
+
+        // 10-bit samples in 16 bits come out at full scale.
+        let mut encoder = encoder_for(ColorType::Greyscale, 16);
+        encoder.write_significant_bits(10).unwrap();
+        assert!(encoder.write_image_rows(&[0x04, 0x00, 0x00, 0x00]).is_err());
+        encoder.write_image_rows(&[0x03, 0xff, 0x02, 0x00]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(data.windows(9).any(|w| w == b"\0\0\0\x01sBIT\x0a"));
+        assert_eq!(decode(&data[..]).unwrap().data(), &[0xff, 0xff, 0x80, 0x20]);
+
+        let mut encoder = encoder_for(ColorType::TruecolorAlpha, 8);
+        encoder.write_significant_bits(4).unwrap();
+        encoder.write_image_rows(&[0, 1, 2, 15, 8, 4, 7, 0]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(data.windows(12).any(|w| w == b"\0\0\0\x04sBIT\x04\x04\x04\x04"));
+        assert_eq!(decode(&data[..]).unwrap().data(), &[0, 17, 34, 255, 136, 68, 119, 0]);
+
+        // 5-6-5 color.
+        let mut encoder = encoder_for(ColorType::Truecolor, 8);
+        assert!(encoder.write_significant_bits_per_channel(&[5, 6]).is_err());
+        assert!(encoder.write_significant_bits_per_channel(&[5, 6, 9]).is_err());
+        encoder.write_significant_bits_per_channel(&[5, 6, 5]).unwrap();
+        assert!(encoder.write_image_rows(&[31, 63, 32, 0, 0, 0]).is_err());
+        encoder.write_image_rows(&[31, 63, 31, 16, 32, 1]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(data.windows(11).any(|w| w == b"\0\0\0\x03sBIT\x05\x06\x05"));
+        assert_eq!(decode(&data[..]).unwrap().data(), &[255, 255, 255, 132, 130, 8]);
+
+        // Palettes take red, green, and blue, and aren't scaled.
+        let mut encoder = encoder_for(ColorType::IndexedColor, 8);
+        assert!(encoder.write_significant_bits_per_channel(&[4]).is_err());
+        encoder.write_significant_bits_per_channel(&[4, 4, 4]).unwrap();
+        encoder.write_palette(&[0x11, 0x22, 0x33]).unwrap();
+        encoder.write_image_rows(&[0, 0]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(data.windows(11).any(|w| w == b"\0\0\0\x03sBIT\x04\x04\x04"));
+        assert!(data.windows(7).any(|w| w == b"PLTE\x11\x22\x33"));
+    }
+
+    #[test]
+    fn seekable_output() {
+        let mut header = Header::new();
+        header.set_size(256, 512).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let data: Vec<u8> = (0 .. header.stride() * 512).map(|i| (i * 7 / 5) as u8).collect();
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        let plain = encoder.finish().unwrap();
+
+        let mut encoder = Encoder::new_seekable(io::Cursor::new(Vec::<u8>::new()), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data[.. data.len() / 2]).unwrap();
+        // Nothing waits in memory for the end of the image.
+        encoder.flush().unwrap();
+        assert!(encoder.idat_buffer.is_empty());
+        assert!(encoder.stats().output_bytes > 100);
+        encoder.write_image_rows(&data[data.len() / 2 ..]).unwrap();
+        let seekable = encoder.finish().unwrap().into_inner();
+
+        assert_eq!(seekable, plain);
+        assert_eq!(decode(&seekable[..]).unwrap().data(), &data[..]);
+    }
+
+    #[test]
+    fn try_iter_rows() {
+        let mut header = Header::new();
+        header.set_size(4, 6).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let rows = || (0 .. 6u8).map(|i| Ok(vec![i; 4]));
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows_try_iter(rows()).unwrap();
+        let decoded = decode(&encoder.finish().unwrap()[..]).unwrap();
+        assert_eq!(decoded.data(), &rows().flat_map(|row| row.unwrap()).collect::<Vec<u8>>()[..]);
+
+        // The producer's own error comes back, and stops the rows.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        let failing = rows().take(2).chain(Some(Err(io::Error::new(io::ErrorKind::ConnectionReset, "lost source"))));
+        let err = encoder.write_image_rows_try_iter(failing.chain(rows())).unwrap_err();
+        assert!(matches!(err, Error::Io(ref err) if err.kind() == io::ErrorKind::ConnectionReset));
+        assert_eq!(err.to_string(), "lost source");
+        assert!(encoder.finish().is_err());
+    }
+
+    #[test]
+    fn replay_row_filters() {
+        let mut header = Header::new();
+        header.set_size(128, 96).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 128 * 96 * 3usize).map(|i| (i * 7 / 5 % 251) as u8).collect();
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        assert!(encoder.set_row_filters(&[Filter::None]).is_err());
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        encoder.flush().unwrap();
+        let filters = encoder.row_filters().to_vec();
+        let adaptive = encoder.finish().unwrap();
+        assert_eq!(filters.len(), 96);
+        assert!(filters.iter().any(|&filter| filter != filters[0]));
+
+        // Replaying the same choices with another mode makes the same file.
+        options.set_filter_mode(Fixed(Filter::Paeth)).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.set_row_filters(&filters[1 ..]).is_err());
+        encoder.set_row_filters(&filters).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        assert_eq!(encoder.finish().unwrap(), adaptive);
+    }
+
+    #[test]
+    fn greyscale_packing() {
+        let mut header = Header::new();
+        header.set_size(13, 3).unwrap();
+        header.set_color(ColorType::Greyscale, 1).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(encoder.set_greyscale_packing(Reduction::Nearest).is_err());
+        encoder.write_header(&header).unwrap();
+        encoder.set_greyscale_packing(Reduction::Threshold(128)).unwrap();
+        let input: Vec<u8> = (0 .. 13 * 3).map(|i| if i % 3 == 0 { 255 } else { 7 }).collect();
+        assert!(encoder.write_image_rows(&input[.. 2 * 3]).is_err());
+        encoder.write_image_rows(&input).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let decoded = decode(&data[..]).unwrap();
+        let expected: Vec<u8> = input.chunks(13)
+                                     .flat_map(|row| layout::pack(&row.iter().map(|&v| (v >= 128) as u8)
+                                                                        .collect::<Vec<u8>>(), 1))
+                                     .collect();
+        assert_eq!(decoded.data(), &expected[..]);
+
+        let mut header = Header::new();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.set_greyscale_packing(Reduction::Dither).is_err());
+    }
+
+    #[test]
+    fn chunked_greyscale_packing() {
+        // Wide enough rows for several chunks at the smallest chunk size.
+        let (width, height) = (1000, 1200);
+        let input: Vec<u8> = (0 .. width * height).map(|i| ((i % width) * 255 / width + i / width % 7) as u8).collect();
+        let reductions = [Reduction::Nearest, Reduction::Threshold(100), Reduction::Ordered, Reduction::Dither];
+        for &reduction in reductions.iter() {
+            for &interlace in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+                let starts = Mutex::new(Vec::new());
+                let callback = |info: ChunkInfo| starts.lock().unwrap().push(info.start_row());
+                let mut options = Options::new();
+                options.set_chunk_size(32768).unwrap();
+                options.set_on_chunk_complete(&callback).unwrap();
+
+                let depth = if let Reduction::Threshold(_) = reduction { 1 } else { 2 };
+                let mut header = Header::new();
+                header.set_size(width as u32, height as u32).unwrap();
+                header.set_color(ColorType::Greyscale, depth).unwrap();
+                header.set_interlace_method(interlace).unwrap();
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                encoder.set_greyscale_packing(reduction).unwrap();
+                encoder.write_image_rows(&input).unwrap();
+                let png = encoder.finish().unwrap();
+                let decoded = decode(&png[..]).unwrap();
+
+                // Error diffusion restarts at each chunk of a
+                // non-interlaced image, from the row above.
+                let mut starts = starts.into_inner().unwrap();
+                if matches!(interlace, InterlaceMethod::Adam7) || !matches!(reduction, Reduction::Dither) {
+                    starts = vec![0];
+                }
+                starts.sort_unstable();
+                starts.push(height);
+                let mut expected = Vec::new();
+                for pair in starts.windows(2) {
+                    let mut packer = layout::GreyscalePacker::new(depth, reduction).unwrap();
+                    if pair[0] > 0 {
+                        packer.set_row(pair[0] - 1);
+                        packer.pack_row(&input[(pair[0] - 1) * width .. pair[0] * width]);
+                    }
+                    for row in input[pair[0] * width .. pair[1] * width].chunks(width) {
+                        expected.extend(packer.pack_row(row));
+                    }
+                }
+                assert_eq!(decoded.data(), &expected[..], "{:?}", reduction);
+            }
         }
     }
 
-    /// Return completion progress as a fraction of 1.0
-    ///
-    /// Currently progress is measured in chunks, so small files may
-    /// not report values between 0.0 and 1.0.
-    pub fn progress(&self) -> f64 {
-        self.chunks_output as f64 / self.chunks_total as f64
+    #[test]
+    fn alpha_flattening() {
+        let mut header = Header::new();
+        header.set_size(2, 1).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(encoder.set_alpha_flattening(&[255, 255, 255], false).is_err());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.set_alpha_flattening(&[255, 255], false).is_err());
+        encoder.set_alpha_flattening(&[255, 255, 255], true).unwrap();
+        assert!(encoder.write_significant_bits(4).is_err());
+        assert!(encoder.write_image_rows(&[0; 6]).is_err());
+        encoder.write_image_rows(&[0, 0, 0, 255, 0, 0, 0, 0]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(data.windows(10).any(|w| w == b"bKGD\0\xff\0\xff\0\xff"));
+        assert_eq!(decode(&data[..]).unwrap().data(), &[0, 0, 0, 255, 255, 255]);
+
+        header.set_color(ColorType::Greyscale, 16).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.set_alpha_flattening(&[0xffff], false).is_ok());
+        encoder.write_image_rows(&[0x12, 0x34, 0xff, 0xff, 0x12, 0x34, 0, 0]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert!(!data.windows(4).any(|w| w == b"bKGD"));
+        assert_eq!(decode(&data[..]).unwrap().data(), &[0x12, 0x34, 0xff, 0xff]);
+
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.set_alpha_flattening(&[0, 0, 0, 0], false).is_err());
     }
 
-    /// Return finished-ness state.
-    /// Is it finished? Yeah or no.
-    pub fn is_finished(&self) -> bool {
-        self.chunks_output == self.chunks_total
+    #[test]
+    fn palette_dedup() {
+        let mut options = Options::new();
+        options.set_palette_dedup(true).unwrap();
+        let mut header = Header::new();
+        header.set_size(5, 2).unwrap();
+        header.set_color(ColorType::IndexedColor, 4).unwrap();
+
+        // Entries 0 and 2 match, as do 1 and 3; 4 differs from 0 only in alpha.
+        let palette = [1, 2, 3, 9, 9, 9, 1, 2, 3, 9, 9, 9, 1, 2, 3];
+        let alphas = [255, 255, 255, 255, 0];
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_palette(&palette).unwrap();
+        encoder.write_transparency(&alphas).unwrap();
+        encoder.write_chunk(b"hIST", &[0, 1, 0, 2, 0, 3, 0, 4, 0, 5]).unwrap();
+        let indices = [0, 1, 2, 3, 4, 4, 3, 2, 1, 0];
+        let mut rows = layout::pack(&indices[.. 5], 4);
+        rows.extend(layout::pack(&indices[5 ..], 4));
+        encoder.write_image_rows(&rows).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let decoded = decode(&data[..]).unwrap();
+        assert_eq!(decoded.palette().unwrap(), &[1, 2, 3, 9, 9, 9, 1, 2, 3]);
+        assert_eq!(decoded.transparency().unwrap(), &[255, 255, 0]);
+        assert!(data.windows(10).any(|w| w == b"hIST\0\x04\0\x06\0\x05"));
+        let row = |y: usize| layout::unpack(&decoded.data()[y * 3 ..], 4, 5);
+        assert_eq!(row(0), vec![0, 1, 0, 1, 2]);
+        assert_eq!(row(1), vec![2, 1, 0, 1, 0]);
+
+        // Transparency written after another chunk must agree for merged entries.
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_palette(&palette).unwrap();
+        encoder.write_chunk(b"tEXt", b"a\0b").unwrap();
+        assert!(encoder.write_transparency(&alphas).is_err());
+        encoder.write_transparency(&[128, 255, 128]).unwrap();
     }
 
-    /// Flush all currently in-progress data to output
-    /// Warning: this may block.
-    pub fn flush(&mut self) -> IoResult {
-        while self.chunks_output < self.pixel_index {
-            // Dispatch any available async tasks and output.
-            self.dispatch(DispatchMode::Blocking)?;
+    #[test]
+    fn max_index() {
+        assert_eq!(super::max_index(&[0b01000000], 2, 1), 1);
+        assert_eq!(super::max_index(&[0b01001111], 2, 2), 1);
+        assert_eq!(super::max_index(&[0x3a], 4, 2), 10);
+        assert_eq!(super::max_index(&[1, 7, 3], 8, 3), 7);
+    }
+
+    #[test]
+    fn pass_filter_modes() {
+        let mut options = Options::new();
+        assert!(options.set_pass_filter_mode(0, Fixed(Filter::None)).is_err());
+        assert!(options.set_pass_filter_mode(8, Fixed(Filter::None)).is_err());
+        for pass in 1 ..= 7 {
+            options.set_pass_filter_mode(pass, Fixed(Filter::None)).unwrap();
         }
-        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::super::Header;
-    use super::super::ColorType;
-    use super::Encoder;
-    use super::Options;
-    use super::IoResult;
+    #[test]
+    fn presets() {
+        let fastest = Options::preset(Preset::Fastest);
+        let balanced = Options::preset(Preset::Balanced);
+        let smallest = Options::preset(Preset::Smallest);
+        assert_eq!(fastest.compression_level.level(), 1);
+        assert_eq!(balanced.compression_level.level(), 6);
+        assert_eq!(smallest.compression_level.level(), 9);
+        assert!(fastest.chunk_size < smallest.chunk_size);
+        assert_eq!(balanced.chunk_size, None);
+        assert!(matches!(fastest.filter_mode, Fixed(Filter::Up)));
+        assert!(matches!(fastest.strategy_mode, Fixed(Strategy::Filtered)));
+        let fast_backend = if Backend::ZlibRs.is_available() { Backend::ZlibRs } else { Backend::Zlib };
+        assert_eq!(fastest.backend.name(), fast_backend.name());
+        for options in [balanced, smallest].iter() {
+            assert!(matches!(options.filter_mode, super::super::Mode::Adaptive));
+            assert!(matches!(options.strategy_mode, super::super::Mode::Adaptive));
+            assert_eq!(options.backend.name(), Backend::Zlib.name());
+        }
 
-    use std::io;
+        let screenshot = Options::preset(Preset::Screenshot);
+        assert!(matches!(screenshot.filter_mode, Fixed(Filter::None)));
+        assert!(matches!(screenshot.strategy_mode, Fixed(Strategy::Rle)));
+        assert!(screenshot.solid_rows && !balanced.solid_rows);
+        assert_eq!(screenshot.backend.name(), Backend::Zlib.name());
+
+        let photo = Options::preset(Preset::Photo);
+        assert!(matches!(photo.filter_mode, super::super::Mode::Adaptive));
+        assert_eq!(photo.heuristic, Heuristic::Entropy);
+        assert!(matches!(photo.strategy_mode, Fixed(Strategy::Filtered)));
+        assert_eq!(photo.backend.name(), Backend::Zlib.name());
+        assert_eq!(photo.chunk_size, smallest.chunk_size);
+
+        let mut header = Header::new();
+        header.set_size(256, 256).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 256 * 256 * 3usize).map(|i| (i * 7 % 253) as u8).collect();
+        for options in [fastest, smallest, screenshot, photo].iter() {
+            let mut encoder = Encoder::new(Vec::new(), options);
+            encoder.write_header(&header).unwrap();
+            encoder.write_image_rows(&data).unwrap();
+            let png = encoder.finish().unwrap();
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+        }
+    }
 
-    fn test_encoder<F>(width: u32, height: u32, func: F)
-        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> IoResult
-    {
-        match {
-            || -> io::Result<Vec<u8>> {
-                let mut data = Vec::<u8>::with_capacity(width as usize * 3);
-                for i in 0 .. width as usize * 3 {
-                    data.push((i % 255) as u8);
-                }
+    #[test]
+    fn auto_options() {
+        let mut header = Header::new();
+        header.set_size(64, 64).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let flat = vec![200u8; 64 * 64 * 3];
+        let options = Options::auto(&header, &flat).unwrap();
+        assert!(matches!(options.filter_mode, Fixed(Filter::None)));
+        assert!(matches!(options.strategy_mode, Fixed(Strategy::Rle)));
+
+        // A smooth ramp with a little noise in every sample.
+        let mut seed = 1u32;
+        let photo: Vec<u8> = (0 .. 64 * 64 * 3u32).map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (i / 3 % 64 + (seed >> 29)) as u8
+        }).collect();
+        let options = Options::auto(&header, &photo).unwrap();
+        assert!(matches!(options.filter_mode, Fixed(Filter::Paeth)));
+        assert!(matches!(options.strategy_mode, Fixed(Strategy::Filtered)));
+
+        assert!(Options::auto(&header, &photo[1 ..]).is_err());
+    }
 
-                let writer = Vec::<u8>::new();
-                let options = Options::new();
-                let mut encoder = Encoder::new(writer, &options);
+    #[test]
+    fn text() {
+        let mut options = Options::new();
+        options.set_text_compression(Some(100)).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&Header::new()).unwrap();
+        assert!(encoder.write_text("", "x").is_err());
+        assert!(encoder.write_text(" Title", "x").is_err());
+        assert!(encoder.write_text("Ti\u{2603}le", "x").is_err());
+        assert!(encoder.write_text(&"k".repeat(80), "x").is_err());
+        encoder.write_text("Title", "Caf\u{e9}").unwrap();
+        encoder.write_text("Comment", "\u{2603}").unwrap();
+        encoder.write_text("Description", &"long ".repeat(100)).unwrap();
+        encoder.write_text("Software", &"\u{2603}".repeat(100)).unwrap();
+        encoder.write_image_rows(&[0, 0, 0, 0]).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let find = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"tEXtTitle\0Caf\xe9"));
+        assert!(find(b"iTXtComment\0\0\0\0\0\xe2\x98\x83"));
+        assert!(find(b"zTXtDescription\0\0\x78"));
+        assert!(find(b"iTXtSoftware\0\x01\0\0\0\x78"));
+    }
+
+    #[test]
+    fn typed_text() {
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&Header::new()).unwrap();
+        assert!(encoder.write_compressed_text("Comment", "\u{2603}").is_err());
+        assert!(encoder.write_itxt("Title", "en_gb", "", "x", false).is_err());
+        assert!(encoder.write_itxt("Title", "en-", "", "x", false).is_err());
+        assert!(encoder.write_itxt("Title", "toolongword", "", "x", false).is_err());
+        assert!(encoder.write_itxt("Title", "", "T\0", "x", false).is_err());
+        encoder.write_compressed_text("Comment", "Caf\u{e9}").unwrap();
+        encoder.write_itxt("Title", "en-gb", "Title", "Colour", false).unwrap();
+        encoder.write_itxt("Author", "", "", "\u{2603}", true).unwrap();
+        encoder.write_image_rows(&[0, 0, 0, 0]).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let find = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"zTXtComment\0\0\x78"));
+        assert!(find(b"iTXtTitle\0\0\0en-gb\0Title\0Colour"));
+        assert!(find(b"iTXtAuthor\0\x01\0\0\0\x78"));
+    }
+
+    #[test]
+    fn max_dimensions() {
+        let mut options = Options::new();
+        assert!(options.set_max_dimensions(0, 10).is_err());
+        assert!(options.set_max_dimensions(10, 0x8000_0000).is_err());
+        options.set_max_dimensions(100, 50).unwrap();
+
+        let mut header = Header::new();
+        header.set_size(100, 51).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        assert!(encoder.write_header(&header).is_err());
+
+        header.set_size(100, 50).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+    }
+
+    #[test]
+    fn excess_rows() {
+        test_encoder(4, 2, |encoder, data| {
+            let row = &data[.. 12];
+            let rows = [row, row, row].concat();
+            let e = encoder.write_image_rows(&rows).unwrap_err();
+            assert!(matches!(e, Error::InvalidInput(_)));
+            assert_eq!(e.to_string(), "Received 3 rows but header declares 2.");
+
+            // Nothing was taken from the rejected buffer.
+            encoder.write_image_rows(row)?;
+            encoder.write_image_rows(row)?;
+            assert!(encoder.write_image_rows(row).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn saturated_pool() {
+        // Encoding from the pool's only thread leaves nobody else to
+        // run the jobs, so they have to run inline after the timeout.
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let png = pool.install(|| {
+            let mut options = Options::new();
+            options.set_thread_pool(&pool).unwrap();
+            options.set_chunk_size(32768).unwrap();
+            options.set_dispatch_timeout(Some(Duration::from_millis(1))).unwrap();
+
+            let mut header = Header::new();
+            header.set_size(256, 256).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            encoder.write_image_rows(&vec![128u8; 256 * 256 * 3]).unwrap();
+            encoder.finish().unwrap()
+        });
+        assert_eq!(decode(&png[..]).unwrap().data(), &vec![128u8; 256 * 256 * 3][..]);
+    }
+
+    // Reproducible noise for test images.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        TestRng::deterministic_rng(RngAlgorithm::ChaCha).fill_bytes(&mut data);
+        data
+    }
+
+    const SHAPE_FORMATS: [(ColorType, u8); 15] = [
+        (ColorType::Greyscale, 1), (ColorType::Greyscale, 2), (ColorType::Greyscale, 4),
+        (ColorType::Greyscale, 8), (ColorType::Greyscale, 16),
+        (ColorType::Truecolor, 8), (ColorType::Truecolor, 16),
+        (ColorType::IndexedColor, 1), (ColorType::IndexedColor, 2),
+        (ColorType::IndexedColor, 4), (ColorType::IndexedColor, 8),
+        (ColorType::GreyscaleAlpha, 8), (ColorType::GreyscaleAlpha, 16),
+        (ColorType::TruecolorAlpha, 8), (ColorType::TruecolorAlpha, 16),
+    ];
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn random_shapes(
+            format in 0 .. SHAPE_FORMATS.len(),
+            // Mostly narrow images, with a few wide ones to get more
+            // chunks than rows.
+            width in prop_oneof![3 => 1 ..= 300u32, 1 => 1 ..= 12000u32],
+            height in 1 ..= 40u32,
+            chunk_size in prop_oneof![Just(32768usize), 32768 .. 32768 + 65536usize],
+            seed in any::<[u8; 32]>(),
+        ) {
+            let (color_type, depth) = SHAPE_FORMATS[format];
+            let mut header = Header::new();
+            header.set_size(width, height).unwrap();
+            header.set_color(color_type, depth).unwrap();
+
+            let mut options = Options::new();
+            options.set_chunk_size(chunk_size).unwrap();
+
+            let mut data = vec![0u8; header.stride() * height as usize];
+            TestRng::from_seed(RngAlgorithm::ChaCha, &seed).fill_bytes(&mut data);
+
+            let result = (|| -> error::Result<Vec<u8>> {
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header)?;
+                if let ColorType::IndexedColor = color_type {
+                    encoder.write_palette(&vec![0u8; 3 << depth])?;
+                }
+                encoder.write_image_rows(&data)?;
+                encoder.finish()
+            })();
+            let png = result.map_err(|e| TestCaseError::fail(e.to_string()))?;
+            let decoded = decode(&png[..]).unwrap();
+
+            // Padding bits at the end of rows aren't preserved.
+            let bits = color_type.channels() * depth as usize * width as usize;
+            let stride = header.stride();
+            let mask = 0xffu8 << (stride * 8 - bits);
+            for (a, b) in decoded.data().chunks(stride).zip(data.chunks(stride)) {
+                prop_assert_eq!(&a[.. stride - 1], &b[.. stride - 1]);
+                prop_assert_eq!(a[stride - 1] & mask, b[stride - 1] & mask);
+            }
+        }
+    }
 
+    #[test]
+    fn interlaced() {
+        let formats = [
+            (ColorType::Greyscale, 1), (ColorType::Greyscale, 4),
+            (ColorType::Truecolor, 8), (ColorType::IndexedColor, 2),
+            (ColorType::TruecolorAlpha, 16),
+        ];
+        for &(color_type, depth) in formats.iter() {
+            // Small sizes leave some passes empty, and small chunks
+            // split the bigger passes up.
+            for &(width, height, chunk_size) in [(1, 1, 32768), (3, 2, 32768), (13, 5, 32768), (700, 90, 32768)].iter() {
                 let mut header = Header::new();
                 header.set_size(width, height).unwrap();
-                header.set_color(ColorType::Truecolor, 8).unwrap();
-                encoder.write_header(&header)?;
+                header.set_color(color_type, depth).unwrap();
+                header.set_interlace_method(InterlaceMethod::Adam7).unwrap();
+
+                let mut options = Options::new();
+                options.set_chunk_size(chunk_size).unwrap();
+                options.set_pass_filter_mode(1, Fixed(Filter::None)).unwrap();
+
+                // Leave the padding bits at the end of rows clear.
+                let stride = header.stride();
+                let bits = color_type.channels() * depth as usize * width as usize;
+                let mask = 0xffu8 << (stride * 8 - bits);
+                let mut data = noise(stride * height as usize);
+                for row in data.chunks_mut(stride) {
+                    row[stride - 1] &= mask;
+                }
 
-                func(&mut encoder, &data)?;
-                encoder.finish()
-            }()
-        } {
-            Ok(_writer) => {},
-            Err(e) => assert!(false, "Error {}", e),
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                if let ColorType::IndexedColor = color_type {
+                    encoder.write_palette(&vec![0u8; 3 << depth]).unwrap();
+                }
+                encoder.write_image_rows(&data).unwrap();
+                encoder.flush().unwrap();
+
+                // One filter per row of each pass, the first pass unfiltered.
+                let pass_rows: usize = (0 .. 7).map(|pass| {
+                    match layout::adam7_size(width, height, pass) {
+                        (0, _) => 0,
+                        (_, rows) => rows as usize,
+                    }
+                }).sum();
+                let filters = encoder.row_filters().to_vec();
+                assert_eq!(filters.len(), pass_rows);
+                let first_pass = layout::adam7_size(width, height, 0).1 as usize;
+                assert!(filters[.. first_pass].iter().all(|&filter| filter == Filter::None));
+
+                let png = encoder.finish().unwrap();
+                let decoded = decode(&png[..]).unwrap();
+                assert!(decoded.is_interlaced());
+                assert_eq!(decoded.data(), &data[..], "{}x{} type {} depth {}",
+                           width, height, color_type as u8, depth);
+
+                // Replaying the filters gives the same file.
+                let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+                encoder.write_header(&header).unwrap();
+                assert!(encoder.set_row_filters(&filters[1 ..]).is_err());
+                encoder.set_row_filters(&filters).unwrap();
+                if let ColorType::IndexedColor = color_type {
+                    encoder.write_palette(&vec![0u8; 3 << depth]).unwrap();
+                }
+                encoder.write_image_rows(&data).unwrap();
+                assert_eq!(encoder.finish().unwrap(), png);
+            }
         }
     }
 
     #[test]
-    fn create_and_state() {
-        test_encoder(1920, 1080, |encoder, data| {
+    fn auto_chunk_size() {
+        let chunks = |threads: usize, width: u32, height: u32| {
+            let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let mut options = Options::new();
+            options.set_thread_pool(&pool).unwrap();
+            let mut header = Header::new();
+            header.set_size(width, height).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            encoder.stats().chunks()
+        };
+        assert_eq!(chunks(4, 1920, 1080), 8);
+        assert_eq!(chunks(1, 1920, 1080), 2);
+        // Small images stop at the minimum chunk size...
+        assert_eq!(chunks(4, 100, 100), 1);
+        // ...and huge ones at the maximum.
+        assert_eq!(chunks(1, 8192, 8192), 48);
+    }
 
-            assert_eq!(encoder.is_finished(), false);
-            assert_eq!(encoder.progress(), 0.0);
+    #[test]
+    fn chunk_rows() {
+        let seen = Mutex::new(Vec::new());
+        let callback = |info: ChunkInfo| seen.lock().unwrap().push((info.start_row(), info.end_row()));
+
+        let mut options = Options::new();
+        assert!(options.set_chunk_rows(0).is_err());
+        options.set_chunk_rows(64).unwrap();
+        options.set_on_chunk_complete(&callback).unwrap();
+
+        let mut header = Header::new();
+        header.set_size(100, 200).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 100 * 3 * 200).map(|i| (i % 251) as u8).collect();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        let output = encoder.finish().unwrap();
+        assert_eq!(decode(&output[..]).unwrap().data(), &data[..]);
+        assert_eq!(*seen.lock().unwrap(), [(0, 64), (64, 128), (128, 192), (192, 200)]);
+    }
 
-            // We must finish out the file or it'll whinge.
-            for _y in 0 .. 1080 {
+    #[test]
+    fn max_pending_chunks() {
+        // Output that takes its time, as over a slow connection.
+        struct SlowWriter(Vec<u8>);
+        impl io::Write for SlowWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(1));
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut options = Options::new();
+        assert!(options.set_max_pending_chunks(Some(0)).is_err());
+        options.set_max_pending_chunks(Some(2)).unwrap();
+        options.set_chunk_size(32768).unwrap();
+        options.set_streaming(true).unwrap();
+
+        let mut header = Header::new();
+        header.set_size(1024, 256).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 1024 * 3 * 256).map(|i| (i % 251) as u8).collect();
+
+        let mut encoder = Encoder::new(SlowWriter(Vec::new()), &options);
+        encoder.write_header(&header).unwrap();
+        for row in data.chunks(1024 * 3) {
+            encoder.write_image_rows(row).unwrap();
+            assert!(encoder.pixel_index - encoder.chunks_output <= 2);
+        }
+        let output = encoder.finish().unwrap().0;
+        assert_eq!(decode(&output[..]).unwrap().data(), &data[..]);
+
+        // Borrowed images go out in batches, which must stay in bounds too.
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        options.set_thread_pool(&pool).unwrap();
+        let mut encoder = Encoder::new(SlowWriter(Vec::new()), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_borrowed(&data).unwrap();
+        assert!(encoder.pixel_index - encoder.chunks_output <= 2);
+        let output = encoder.finish().unwrap().0;
+        assert_eq!(decode(&output[..]).unwrap().data(), &data[..]);
+    }
+
+    #[test]
+    fn metrics() {
+        test_encoder(1920, 1080, |encoder, data| {
+            assert_eq!(encoder.metrics().chunks_in_flight(), 0);
+            // Stop partway into a chunk, so some rows are held.
+            for _y in 0 .. 539 {
                 encoder.write_image_rows(data)?;
             }
+            assert!(encoder.metrics().bytes_buffered() > 0);
 
+            for _y in 539 .. 1080 {
+                encoder.write_image_rows(data)?;
+            }
+            encoder.flush()?;
+            let metrics = encoder.metrics();
+            assert_eq!(metrics.chunks_in_flight(), 0);
+            assert_eq!(metrics.queue_depth(), 0);
+            assert!(metrics.busy_time() > Duration::from_secs(0));
+            assert!(metrics.worker_utilization() > 0.0);
+            assert!(metrics.worker_utilization() <= 1.0);
             Ok(())
         });
     }
 
-    #[test]
-    fn test_rows() {
-        test_encoder(1920, 1080, |encoder, data| {
-            assert_eq!(encoder.is_finished(), false);
-            assert_eq!(encoder.progress(), 0.0);
+    //
+    // A metrics recorder keeping the current and peak value of each
+    // gauge, the total of each counter, and the number of samples in
+    // each histogram, by metric name.
+    //
+    #[cfg(feature = "metrics")]
+    #[derive(Clone, Default)]
+    struct TestRecorder(Arc<std::sync::Mutex<std::collections::HashMap<String, (f64, f64)>>>);
+
+    #[cfg(feature = "metrics")]
+    struct TestHandle(String, TestRecorder);
+
+    #[cfg(feature = "metrics")]
+    impl TestRecorder {
+        fn handle(&self, key: &metrics::Key) -> Arc<TestHandle> {
+            Arc::new(TestHandle(key.name().to_string(), self.clone()))
+        }
+
+        fn set(&self, name: &str, new_value: f64) {
+            let mut values = self.0.lock().unwrap();
+            let (value, peak) = values.entry(name.to_string()).or_insert((0.0, 0.0));
+            *value = new_value;
+            *peak = peak.max(*value);
+        }
+
+        fn add(&self, name: &str, delta: f64) {
+            self.set(name, self.value(name) + delta);
+        }
+
+        fn value(&self, name: &str) -> f64 {
+            self.0.lock().unwrap().get(name).map(|&(value, _)| value).unwrap_or(0.0)
+        }
+
+        fn peak(&self, name: &str) -> f64 {
+            self.0.lock().unwrap().get(name).map(|&(_, peak)| peak).unwrap_or(0.0)
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::CounterFn for TestHandle {
+        fn increment(&self, value: u64) { self.1.add(&self.0, value as f64) }
+        fn absolute(&self, value: u64) { self.1.set(&self.0, value as f64) }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::GaugeFn for TestHandle {
+        fn increment(&self, value: f64) { self.1.add(&self.0, value) }
+        fn decrement(&self, value: f64) { self.1.add(&self.0, -value) }
+        fn set(&self, value: f64) { self.1.set(&self.0, value) }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::HistogramFn for TestHandle {
+        fn record(&self, _value: f64) { self.1.add(&self.0, 1.0) }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::Recorder for TestRecorder {
+        fn describe_counter(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+        fn describe_gauge(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+        fn describe_histogram(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+
+        fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
+            metrics::Counter::from_arc(self.handle(key))
+        }
+
+        fn register_gauge(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
+            metrics::Gauge::from_arc(self.handle(key))
+        }
+
+        fn register_histogram(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Histogram {
+            metrics::Histogram::from_arc(self.handle(key))
+        }
+    }
 
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_export() {
+        let recorder = TestRecorder::default();
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+        let mut header = Header::new();
+        header.set_size(1920, 1080).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 1920 * 3).map(|i| (i % 255) as u8).collect();
+
+        let stats = metrics::with_local_recorder(&recorder, || {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
             for _y in 0 .. 1080 {
-                encoder.write_image_rows(data)?;
+                encoder.write_image_rows(&data).unwrap();
+            }
+            encoder.flush().unwrap();
+            let stats = encoder.stats();
+            encoder.finish().unwrap();
+            stats
+        });
+        assert_eq!(recorder.value("mtpng_images_total"), 1.0);
+        assert_eq!(recorder.value("mtpng_chunks_written_total"), stats.chunks() as f64);
+        assert_eq!(recorder.value("mtpng_compressed_bytes_total"), stats.compressed_bytes() as f64);
+        assert_eq!(recorder.value("mtpng_chunk_stage_seconds"), 5.0 * stats.chunks() as f64);
+        assert_eq!(recorder.value("mtpng_image_seconds"), 1.0);
+        assert_eq!(recorder.value("mtpng_worker_utilization"), 1.0);
+        assert!(recorder.peak("mtpng_bytes_buffered") > 0.0);
+
+        // The gauges go back down when an encoder goes away, even
+        // without finishing.
+        metrics::with_local_recorder(&recorder, || {
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            for _y in 0 .. 540 {
+                encoder.write_image_rows(&data).unwrap();
             }
+        });
+        assert_eq!(recorder.value("mtpng_images_total"), 1.0);
+        for gauge in ["mtpng_chunks_in_flight", "mtpng_queue_depth", "mtpng_bytes_buffered"] {
+            assert_eq!(recorder.value(gauge), 0.0, "{}", gauge);
+        }
+    }
 
-            // Should trigger all blocks!
-            encoder.flush()?;
-            assert_eq!(encoder.is_finished(), true);
-            assert_eq!(encoder.progress(), 1.0);
+    #[test]
+    fn abort() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut options = Options::new();
+        options.set_thread_pool(&pool).unwrap();
+        options.set_chunk_size(32768).unwrap();
+        options.set_streaming(true).unwrap();
+
+        let mut header = Header::new();
+        header.set_size(1024, 1024).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let row: Vec<u8> = (0 .. 1024 * 3).map(|i| (i % 251) as u8).collect();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        for _y in 0 .. 512 {
+            encoder.write_image_rows(&row).unwrap();
+        }
+        let written = encoder.stats().output_bytes();
+        let output = encoder.abort();
 
-            Ok(())
+        assert_eq!(output.len() as u64, written);
+        assert_eq!(&output[.. 8], b"\x89PNG\r\n\x1a\n");
+        assert!(!output.windows(4).any(|tag| tag == b"IEND"));
+    }
+
+    #[test]
+    fn drop_cancels_jobs() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut options = Options::new();
+        options.set_thread_pool(&pool).unwrap();
+        options.set_chunk_size(32768).unwrap();
+
+        let mut header = Header::new();
+        header.set_size(1024, 1024).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let row: Vec<u8> = (0 .. 1024 * 3).map(|i| (i % 251) as u8).collect();
+
+        // Keep the pool's only thread busy so our jobs stay queued.
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        pool.spawn(move || {
+            blocked.recv().ok();
         });
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        for _y in 0 .. 25 {
+            encoder.write_image_rows(&row).unwrap();
+        }
+        let queue = Arc::clone(&encoder.jobs.queue);
+        let busy_nanos = Arc::clone(&encoder.busy_nanos);
+        assert!(!queue.lock().unwrap().is_empty());
+        drop(encoder);
+        assert!(queue.lock().unwrap().is_empty());
+
+        // The pool gets to its tasks in order, and finds nothing to do.
+        release.send(()).unwrap();
+        pool.install(|| {});
+        assert_eq!(busy_nanos.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn chunk_callback() {
+        let seen = Mutex::new(Vec::new());
+        let callback = |info: ChunkInfo| seen.lock().unwrap().push(info);
+
+        let mut options = Options::new();
+        options.set_chunk_size(65536).unwrap();
+        options.set_streaming(true).unwrap();
+        options.set_on_chunk_complete(&callback).unwrap();
+
+        let mut header = Header::new();
+        header.set_size(256, 256).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&vec![7u8; 256 * 256 * 3]).unwrap();
+        encoder.flush().unwrap();
+        let stats = encoder.stats();
+        encoder.finish().unwrap();
+
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), stats.chunks());
+        assert!(seen.len() > 1);
+        for (i, info) in seen.iter().enumerate() {
+            assert_eq!(info.index(), i);
+            assert!(info.output_bytes() > 0);
+        }
+        assert_eq!(seen[0].start_row(), 0);
+        assert_eq!(seen[seen.len() - 1].end_row(), 256);
+        assert!(seen.windows(2).all(|pair| pair[0].end_row() == pair[1].start_row() &&
+                                           pair[0].output_bytes() < pair[1].output_bytes()));
+        let total: usize = seen.iter().map(|info| info.compressed_bytes()).sum();
+        assert_eq!(total as u64, stats.compressed_bytes());
     }
 }
@@ -0,0 +1,126 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// error.rs - structured error type
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Errors from the encoder, by cause.
+///
+/// Converts to and from io::Error, so it works with `?` in functions
+/// returning io::Result. Errors converted to io::Error keep this as
+/// their inner error, and convert back to the same variant.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Bad arguments or data, or a call out of order, such as rows of
+    /// the wrong length or a chunk written after the image data.
+    InvalidInput(String),
+    /// The compression library returned the given error code.
+    ZlibError(i32),
+    /// A filter or compression job on the thread pool panicked.
+    WorkerPanic,
+    /// Reading input or writing output failed.
+    Io(io::Error),
+    /// Any other failure, such as an incomplete image at finish time.
+    Other(String),
+}
+
+/// Result type for the encoder's methods.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidInput(message) | Error::Other(message) => f.write_str(message),
+            Error::ZlibError(code) => {
+                let reason = match code {
+                    -2 => "inconsistent stream state or bad parameter",
+                    -3 => "bad data",
+                    -4 => "out of memory",
+                    -5 => "no progress possible",
+                    -6 => "incompatible version",
+                    _ => "unexpected error",
+                };
+                write!(f, "zlib error {}: {}", code, reason)
+            },
+            Error::WorkerPanic => f.write_str("Encoding job panicked"),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            // Checked just above, so neither of these can fail.
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::Io(err) => return err,
+            Error::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn round_trip() {
+        let err = io::Error::from(Error::ZlibError(-4));
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(matches!(Error::from(err), Error::ZlibError(-4)));
+
+        let err = io::Error::from(Error::InvalidInput("Bad stride".to_string()));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Bad stride");
+        assert!(matches!(Error::from(err), Error::InvalidInput(ref message) if message == "Bad stride"));
+
+        let err = Error::from(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+        assert!(matches!(err, Error::Io(ref err) if err.kind() == io::ErrorKind::BrokenPipe));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::BrokenPipe);
+    }
+}
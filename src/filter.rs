@@ -23,9 +23,11 @@
 // THE SOFTWARE.
 //
 
+use std::cell::RefCell;
 use std::cmp;
 use std::convert::TryFrom;
 use std::io;
+use std::mem;
 
 use super::Header;
 use super::Mode;
@@ -34,7 +36,7 @@ use super::Mode::{Adaptive, Fixed};
 use super::utils::invalid_input;
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Filter {
     None = 0,
     Sub = 1,
@@ -43,6 +45,17 @@ pub enum Filter {
     Paeth = 4,
 }
 
+/// How adaptive filtering judges which filter suits each row best.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Heuristic {
+    /// Smallest sum of the filtered bytes as signed magnitudes, as the
+    /// PNG spec recommends and libpng does. Never picks None.
+    MinSum,
+    /// Lowest Shannon entropy of the filtered bytes. Slower, but often
+    /// picks better on photographic content, and may pick None.
+    Entropy,
+}
+
 impl TryFrom<u8> for Filter {
     type Error = io::Error;
 
@@ -215,7 +228,7 @@ fn filter_complexity_delta(val: u8) -> u32 {
 // without overflow.
 //
 fn complexity_max() -> u32 {
-    u32::max_value() - 256
+    u32::MAX - 256
 }
 
 //
@@ -263,105 +276,183 @@ fn estimate_complexity(data: &[u8]) -> u32 {
     sum
 }
 
+//
+// Total Shannon entropy of the bytes, in bits: roughly how small
+// an ideal order-0 coder could make them. Unlike the sum heuristic,
+// this works on unfiltered bytes too.
+//
+fn estimate_entropy(data: &[u8]) -> u32 {
+    let mut counts = [0u32; 256];
+    for &b in data.iter() {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    let bits: f64 = counts.iter()
+                          .filter(|&&n| n > 0)
+                          .map(|&n| {
+                              let n = f64::from(n);
+                              n * (len / n).log2()
+                          })
+                          .sum();
+    (bits.ceil() as u64).min(u64::from(complexity_max())) as u32
+}
+
+// At most one buffer per filter type is worth keeping around.
+const MAX_SPARE_ROWS: usize = 5;
+
+thread_local! {
+    //
+    // Row buffers left by filters from earlier chunks on this thread,
+    // so each new chunk's filters can reuse them instead of allocating.
+    //
+    static SPARE_ROWS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn take_row(len: usize) -> Vec<u8> {
+    let mut row = SPARE_ROWS.with(|spare| spare.borrow_mut().pop()).unwrap_or_default();
+    // Filtering overwrites every byte, so old contents can stay.
+    row.resize(len, 0);
+    row
+}
+
+fn give_row(row: Vec<u8>) {
+    SPARE_ROWS.with(|spare| {
+        let mut spare = spare.borrow_mut();
+        if spare.len() < MAX_SPARE_ROWS {
+            spare.push(row);
+        }
+    });
+}
+
+//
+// Filter a row into dest, which has room for the filter type byte.
+// Returns the row's complexity by the given heuristic, if any, or 0.
+//
+#[inline(always)]
+fn do_filter_row(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+                 heuristic: Option<Heuristic>) -> u32 {
+    match filter {
+        Filter::None    => filter_none(bpp, prev, src, dest),
+        Filter::Sub     => filter_sub(bpp, prev, src, dest),
+        Filter::Up      => filter_up(bpp, prev, src, dest),
+        Filter::Average => filter_average(bpp, prev, src, dest),
+        Filter::Paeth   => filter_paeth(bpp, prev, src, dest),
+    }
+    match heuristic {
+        Some(Heuristic::MinSum) => estimate_complexity(&dest[1..]),
+        Some(Heuristic::Entropy) => estimate_entropy(&dest[1..]),
+        None => 0,
+    }
+}
+
+#[cfg(target_arch = "x86")]
+#[target_feature(enable = "sse2")]
+unsafe fn do_filter_row_sse2(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+                       heuristic: Option<Heuristic>) -> u32 {
+    do_filter_row(filter, bpp, prev, src, dest, heuristic)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.1")]
+unsafe fn do_filter_row_sse41(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+                       heuristic: Option<Heuristic>) -> u32 {
+    do_filter_row(filter, bpp, prev, src, dest, heuristic)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.2")]
+unsafe fn do_filter_row_sse42(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+                       heuristic: Option<Heuristic>) -> u32 {
+    do_filter_row(filter, bpp, prev, src, dest, heuristic)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn do_filter_row_avx(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+                       heuristic: Option<Heuristic>) -> u32 {
+    do_filter_row(filter, bpp, prev, src, dest, heuristic)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn do_filter_row_avx2(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+                       heuristic: Option<Heuristic>) -> u32 {
+    do_filter_row(filter, bpp, prev, src, dest, heuristic)
+}
+
+fn filter_row(filter: Filter, bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8],
+              heuristic: Option<Heuristic>) -> u32 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe {
+                do_filter_row_avx2(filter, bpp, prev, src, dest, heuristic)
+            };
+        }
+        if is_x86_feature_detected!("avx") {
+            return unsafe {
+                do_filter_row_avx(filter, bpp, prev, src, dest, heuristic)
+            };
+        }
+        if is_x86_feature_detected!("sse4.2") {
+            return unsafe {
+                do_filter_row_sse42(filter, bpp, prev, src, dest, heuristic)
+            };
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            return unsafe {
+                do_filter_row_sse41(filter, bpp, prev, src, dest, heuristic)
+            };
+        }
+    }
+    #[cfg(target_arch = "x86")]
+    {
+        // SSE2 is guaranteed on x86_64
+        // but may not be present on x86
+        if is_x86_feature_detected!("sse2") {
+            return unsafe {
+                do_filter_row_sse2(filter, bpp, prev, src, dest, heuristic)
+            };
+        }
+    }
+    do_filter_row(filter, bpp, prev, src, dest, heuristic)
+}
+
 //
 // Holds a target row that can be filtered
 // Can be reused.
 //
+// The row buffer is only set up on first use, so filters that are
+// never tried cost nothing. Complexity is only measured with a
+// heuristic to measure it by, for adaptive filtering.
+//
 struct Filterator {
     filter: Filter,
     bpp: usize,
+    stride: usize,
+    measure: Option<Heuristic>,
     data: Vec<u8>,
     complexity: u32,
 }
 
 impl Filterator {
-    fn new(filter: Filter, bpp: usize, stride: usize) -> Filterator {
+    fn new(filter: Filter, bpp: usize, stride: usize, measure: Option<Heuristic>) -> Filterator {
         Filterator {
             filter,
             bpp,
-            data: vec![0u8; stride + 1],
+            stride,
+            measure,
+            data: Vec::new(),
             complexity: 0,
         }
     }
 
-    #[inline(always)]
-    fn do_filter(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        match self.filter {
-            Filter::None    => filter_none(self.bpp, prev, src, &mut self.data),
-            Filter::Sub     => filter_sub(self.bpp, prev, src, &mut self.data),
-            Filter::Up      => filter_up(self.bpp, prev, src, &mut self.data),
-            Filter::Average => filter_average(self.bpp, prev, src, &mut self.data),
-            Filter::Paeth   => filter_paeth(self.bpp, prev, src, &mut self.data),
-        }
-        self.complexity = estimate_complexity(&self.data[1..]);
-        &self.data
-    }
-
-    #[cfg(target_arch = "x86")]
-    #[target_feature(enable = "sse2")]
-    unsafe fn do_filter_sse2(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        self.do_filter(prev, src)
-    }
-
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    #[target_feature(enable = "sse4.1")]
-    unsafe fn do_filter_sse41(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        self.do_filter(prev, src)
-    }
-
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    #[target_feature(enable = "sse4.2")]
-    unsafe fn do_filter_sse42(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        self.do_filter(prev, src)
-    }
-
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    #[target_feature(enable = "avx")]
-    unsafe fn do_filter_avx(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        self.do_filter(prev, src)
-    }
-
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    #[target_feature(enable = "avx2")]
-    unsafe fn do_filter_avx2(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        self.do_filter(prev, src)
-    }
-
     fn filter(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        {
-            if is_x86_feature_detected!("avx2") {
-                return unsafe {
-                    self.do_filter_avx2(prev, src)
-                };
-            }
-            if is_x86_feature_detected!("avx") {
-                return unsafe {
-                    self.do_filter_avx(prev, src)
-                };
-            }
-            if is_x86_feature_detected!("sse4.2") {
-                return unsafe {
-                    self.do_filter_sse42(prev, src)
-                };
-            }
-            if is_x86_feature_detected!("sse4.1") {
-                return unsafe {
-                    self.do_filter_sse41(prev, src)
-                };
-            }
-        }
-        #[cfg(target_arch = "x86")]
-        {
-            // SSE2 is guaranteed on x86_64
-            // but may not be present on x86
-            if is_x86_feature_detected!("sse2") {
-                return unsafe {
-                    self.do_filter_sse2(prev, src)
-                };
-            }
+        if self.data.is_empty() {
+            self.data = take_row(self.stride + 1);
         }
-        self.do_filter(prev, src)
+        self.complexity = filter_row(self.filter, self.bpp, prev, src, &mut self.data, self.measure);
+        &self.data
     }
 
     fn get_data(&self) -> &[u8] {
@@ -373,8 +464,23 @@ impl Filterator {
     }
 }
 
+impl Drop for Filterator {
+    fn drop(&mut self) {
+        if !self.data.is_empty() {
+            give_row(mem::take(&mut self.data));
+        }
+    }
+}
+
 pub struct AdaptiveFilter {
     mode: Mode<Filter>,
+    heuristic: Heuristic,
+    bpp: usize,
+    solid_rows: bool,
+    lock_in: usize,
+    streak: usize,
+    last: Option<Filter>,
+    locked: Option<Filter>,
     filter_none: Filterator,
     filter_up: Filterator,
     filter_sub: Filterator,
@@ -383,20 +489,56 @@ pub struct AdaptiveFilter {
 }
 
 impl AdaptiveFilter {
-    pub fn new(header: Header, mode: Mode<Filter>) -> AdaptiveFilter {
+    //
+    // With solid_rows, rows that repeat the one above or are a single
+    // color throughout skip the filter mode, and use whichever of Up
+    // or Sub turns them into all zeros.
+    //
+    // With a non-zero lock_in, once adaptive filtering has picked the
+    // same filter for that many rows in a row, it sticks with that
+    // filter for every later row instead of trying them all. Make a
+    // new AdaptiveFilter for each chunk to start over.
+    //
+    pub fn new(header: Header,
+               mode: Mode<Filter>,
+               heuristic: Heuristic,
+               solid_rows: bool,
+               lock_in: usize) -> AdaptiveFilter
+    {
         let stride = header.stride();
         let bpp = header.bytes_per_pixel();
+        let measure = match mode {
+            Adaptive => Some(heuristic),
+            Fixed(_) => None,
+        };
         AdaptiveFilter {
             mode,
-            filter_none:    Filterator::new(Filter::None,    bpp, stride),
-            filter_up:      Filterator::new(Filter::Up,      bpp, stride),
-            filter_sub:     Filterator::new(Filter::Sub,     bpp, stride),
-            filter_average: Filterator::new(Filter::Average, bpp, stride),
-            filter_paeth:   Filterator::new(Filter::Paeth,   bpp, stride),
+            heuristic,
+            bpp,
+            solid_rows,
+            lock_in,
+            streak: 0,
+            last: None,
+            locked: None,
+            filter_none:    Filterator::new(Filter::None,    bpp, stride, measure),
+            filter_up:      Filterator::new(Filter::Up,      bpp, stride, measure),
+            filter_sub:     Filterator::new(Filter::Sub,     bpp, stride, measure),
+            filter_average: Filterator::new(Filter::Average, bpp, stride, measure),
+            filter_paeth:   Filterator::new(Filter::Paeth,   bpp, stride, measure),
         }
     }
 
-    fn filter_adaptive(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
+    fn filterator(&mut self, filter: Filter) -> &mut Filterator {
+        match filter {
+            Filter::None    => &mut self.filter_none,
+            Filter::Sub     => &mut self.filter_sub,
+            Filter::Up      => &mut self.filter_up,
+            Filter::Average => &mut self.filter_average,
+            Filter::Paeth   => &mut self.filter_paeth,
+        }
+    }
+
+    fn choose_adaptive(&mut self, prev: &[u8], src: &[u8]) -> Filter {
         //
         // Note the "none" filter is often good for things like
         // line-art diagrams and screenshots that have lots of
@@ -408,8 +550,13 @@ impl AdaptiveFilter {
         //
         // Compression could be improved for some files if a heuristic
         // can be devised to check if the none filter will work well.
+        // The entropy heuristic is one, so it gets to try none too.
         //
 
+        if self.heuristic == Heuristic::Entropy {
+            self.filter_none.filter(prev, src);
+        }
+
         self.filter_sub.filter(prev, src);
         let mut min = self.filter_sub.get_complexity();
 
@@ -422,33 +569,137 @@ impl AdaptiveFilter {
         self.filter_paeth.filter(prev, src);
         min = cmp::min(min, self.filter_paeth.get_complexity());
 
-        if min == self.filter_sub.get_complexity()  {
-            self.filter_sub.get_data()
+        if self.heuristic == Heuristic::Entropy && self.filter_none.get_complexity() <= min {
+            Filter::None
+        } else if min == self.filter_sub.get_complexity()  {
+            Filter::Sub
         } else if min == self.filter_up.get_complexity() {
-            self.filter_up.get_data()
+            Filter::Up
         } else if min == self.filter_average.get_complexity() {
-            self.filter_average.get_data()
+            Filter::Average
         } else /* if min == self.filter_paeth.get_complexity() */ {
-            self.filter_paeth.get_data()
+            Filter::Paeth
+        }
+    }
+
+    fn filter_adaptive(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
+        if let Some(filter) = self.locked {
+            return self.filterator(filter).filter(prev, src);
+        }
+
+        let filter = self.choose_adaptive(prev, src);
+        if self.lock_in > 0 {
+            if self.last == Some(filter) {
+                self.streak += 1;
+            } else {
+                self.last = Some(filter);
+                self.streak = 1;
+            }
+            if self.streak >= self.lock_in {
+                self.locked = Some(filter);
+            }
+        }
+        self.filterator(filter).get_data()
+    }
+
+    fn is_solid_row(&self, prev: &[u8], src: &[u8]) -> bool {
+        self.solid_rows && (src == prev || is_solid(self.bpp, src))
+    }
+
+    //
+    // Filter a row straight into dest, which has room for the filter
+    // type byte. Fixed modes skip the row buffers and copy entirely.
+    //
+    pub fn filter_into(&mut self, prev: &[u8], src: &[u8], dest: &mut [u8]) {
+        match self.mode {
+            Fixed(filter) if !self.is_solid_row(prev, src) => {
+                filter_row(filter, self.bpp, prev, src, dest, None);
+            },
+            _ => dest.copy_from_slice(self.filter(prev, src)),
         }
     }
 
+    //
+    // Filter a row straight into dest with the given filter, ignoring
+    // the mode, as when replaying filter choices from an earlier run.
+    //
+    pub fn filter_with_into(&mut self, filter: Filter, prev: &[u8], src: &[u8], dest: &mut [u8]) {
+        filter_row(filter, self.bpp, prev, src, dest, None);
+    }
+
     pub fn filter(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
+        if self.solid_rows {
+            if src == prev {
+                return self.filter_up.filter(prev, src);
+            }
+            if is_solid(self.bpp, src) {
+                return self.filter_sub.filter(prev, src);
+            }
+        }
         match self.mode {
-            Fixed(Filter::None)    => self.filter_none.filter(prev, src),
-            Fixed(Filter::Sub)     => self.filter_sub.filter(prev, src),
-            Fixed(Filter::Up)      => self.filter_up.filter(prev, src),
-            Fixed(Filter::Average) => self.filter_average.filter(prev, src),
-            Fixed(Filter::Paeth)   => self.filter_paeth.filter(prev, src),
-            Adaptive               => self.filter_adaptive(prev, src),
+            Fixed(filter) => self.filterator(filter).filter(prev, src),
+            Adaptive      => self.filter_adaptive(prev, src),
         }
     }
 }
 
+//
+// Whether every pixel in a row is the same. Sub-byte pixels count
+// as solid when every byte is the same.
+//
+fn is_solid(bpp: usize, src: &[u8]) -> bool {
+    let first = &src[.. cmp::min(bpp, src.len())];
+    src.chunks(bpp).all(|pixel| pixel == first)
+}
+
+//
+// Reverse a filter in place, reconstructing a row of pixel data
+// from its filtered bytes and the reconstructed previous row.
+// The previous row should be all zeros for the first row.
+//
+// https://www.w3.org/TR/PNG/#9Filter-types
+//
+pub fn unfilter(filter: Filter, bpp: usize, prev: &[u8], row: &mut [u8]) {
+    let len = row.len();
+    match filter {
+        Filter::None => {},
+        Filter::Sub => {
+            for i in bpp .. len {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        },
+        Filter::Up => {
+            for (dest, up) in row.iter_mut().zip(prev) {
+                *dest = dest.wrapping_add(*up);
+            }
+        },
+        Filter::Average => {
+            for i in 0 .. len {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                let avg = ((u16::from(left) + u16::from(prev[i])) / 2) as u8;
+                row[i] = row[i].wrapping_add(avg);
+            }
+        },
+        Filter::Paeth => {
+            for i in 0 .. len {
+                let (left, upper_left) = if i >= bpp {
+                    (row[i - bpp], prev[i - bpp])
+                } else {
+                    (0, 0)
+                };
+                row[i] = row[i].wrapping_add(paeth_predictor(left, prev[i], upper_left));
+            }
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::AdaptiveFilter;
+    use super::Filter;
+    use super::Heuristic;
     use super::Mode;
+    use super::unfilter;
     use super::super::Header;
     use super::super::ColorType;
 
@@ -457,7 +708,7 @@ mod tests {
         let mut header = Header::new();
         header.set_size(1024, 768).unwrap();
         header.set_color(ColorType::Truecolor, 8).unwrap();
-        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive);
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 0);
 
         let prev = vec![0u8; header.stride()];
         let row = vec![0u8; header.stride()];
@@ -470,11 +721,170 @@ mod tests {
         let mut header = Header::new();
         header.set_size(1024, 768).unwrap();
         header.set_color(ColorType::Truecolor, 16).unwrap();
-        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive);
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 0);
 
         let prev = vec![0u8; header.stride()];
         let row = vec![0u8; header.stride()];
         let filtered_data = filter.filter(&prev, &row);
         assert_eq!(filtered_data.len(), header.stride() + 1);
     }
+
+    #[test]
+    fn unfilter_round_trip() {
+        let mut header = Header::new();
+        header.set_size(37, 2).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let stride = header.stride();
+        let bpp = header.bytes_per_pixel();
+
+        let prev: Vec<u8> = (0 .. stride).map(|i| (i * 7 % 256) as u8).collect();
+        let row: Vec<u8> = (0 .. stride).map(|i| (i * 13 % 251) as u8).collect();
+        for &filter in &[Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth] {
+            let mut filterator = AdaptiveFilter::new(header, Mode::Fixed(filter), Heuristic::MinSum, false, 0);
+            let filtered = filterator.filter(&prev, &row);
+            assert_eq!(filtered[0], filter as u8);
+
+            let mut out = filtered[1 ..].to_vec();
+            unfilter(filter, bpp, &prev, &mut out);
+            assert_eq!(out, row);
+        }
+    }
+
+    #[test]
+    fn solid_rows() {
+        let mut header = Header::new();
+        header.set_size(5, 2).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut filter = AdaptiveFilter::new(header, Mode::Fixed(Filter::None), Heuristic::MinSum, true, 0);
+
+        let prev = vec![9u8; header.stride()];
+        let solid: Vec<u8> = [1, 2, 3].iter().cycle().take(15).copied().collect();
+        assert_eq!(filter.filter(&prev, &solid)[0], Filter::Sub as u8);
+        assert!(filter.filter(&prev, &solid)[4 ..].iter().all(|&b| b == 0));
+        assert_eq!(filter.filter(&solid, &solid)[0], Filter::Up as u8);
+
+        // Other rows use the mode as usual.
+        let mut mixed = solid.clone();
+        mixed[14] = 0;
+        assert_eq!(filter.filter(&prev, &mixed)[0], Filter::None as u8);
+    }
+
+    #[test]
+    fn entropy_heuristic() {
+        let mut header = Header::new();
+        header.set_size(64, 2).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::Entropy, false, 0);
+
+        // A steady ramp is all ones after Sub.
+        let prev = vec![0u8; 64];
+        let ramp: Vec<u8> = (0 .. 64).collect();
+        assert_eq!(filter.filter(&prev, &ramp)[0], Filter::Sub as u8);
+
+        // Two alternating values have the same entropy either way,
+        // and ties go to None.
+        let stripes: Vec<u8> = (0 .. 64).map(|i| if i % 2 == 0 { 10 } else { 200 }).collect();
+        assert_eq!(filter.filter(&prev, &stripes)[0], Filter::None as u8);
+
+        // The sum heuristic never picks None.
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 0);
+        assert_ne!(filter.filter(&prev, &stripes)[0], Filter::None as u8);
+    }
+
+    #[test]
+    fn filter_into() {
+        let mut header = Header::new();
+        header.set_size(16, 2).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let prev: Vec<u8> = (0 .. 48).map(|i| i * 3).collect();
+        let row: Vec<u8> = (0 .. 48).map(|i| i * 5 + 1).collect();
+        let solid = vec![7u8; 48];
+
+        for &solid_rows in [false, true].iter() {
+            for &mode in [Mode::Adaptive, Mode::Fixed(Filter::Average), Mode::Fixed(Filter::Paeth)].iter() {
+                let mut filter = AdaptiveFilter::new(header, mode, Heuristic::MinSum, solid_rows, 0);
+                let mut copy = AdaptiveFilter::new(header, mode, Heuristic::MinSum, solid_rows, 0);
+                for src in [&row, &solid, &prev].iter() {
+                    let mut dest = vec![0u8; 49];
+                    filter.filter_into(&prev, src, &mut dest);
+                    assert_eq!(&dest[..], copy.filter(&prev, src));
+                }
+            }
+        }
+
+        // Fixed modes write straight out, with no row buffers.
+        super::SPARE_ROWS.with(|spare| spare.borrow_mut().clear());
+        let mut filter = AdaptiveFilter::new(header, Mode::Fixed(Filter::Up), Heuristic::MinSum, false, 0);
+        let mut dest = vec![0u8; 49];
+        filter.filter_into(&prev, &row, &mut dest);
+        drop(filter);
+        assert_eq!(dest[0], Filter::Up as u8);
+        assert_eq!(super::SPARE_ROWS.with(|spare| spare.borrow().len()), 0);
+    }
+
+    #[test]
+    fn reuses_rows() {
+        let spare = || super::SPARE_ROWS.with(|spare| spare.borrow().len());
+        let mut header = Header::new();
+        header.set_size(16, 2).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let prev = vec![0u8; 48];
+        let row: Vec<u8> = (0 .. 48).collect();
+
+        // A fixed filter only sets up its own buffer.
+        let expected = {
+            let mut filter = AdaptiveFilter::new(header, Mode::Fixed(Filter::Paeth), Heuristic::MinSum, false, 0);
+            filter.filter(&prev, &row).to_vec()
+        };
+        assert_eq!(spare(), 1);
+
+        // Which the next chunk's filters pick up again.
+        {
+            let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 0);
+            filter.filter(&prev, &row);
+            assert_eq!(spare(), 0);
+            let mut filter = AdaptiveFilter::new(header, Mode::Fixed(Filter::Paeth), Heuristic::MinSum, false, 0);
+            assert_eq!(filter.filter(&prev, &row), &expected[..]);
+        }
+        assert_eq!(spare(), 5);
+
+        // Buffers from a wider image get resized to fit.
+        header.set_size(4, 2).unwrap();
+        let mut filter = AdaptiveFilter::new(header, Mode::Fixed(Filter::Sub), Heuristic::MinSum, false, 0);
+        assert_eq!(filter.filter(&prev[.. 12], &row[.. 12]).len(), 13);
+    }
+
+    #[test]
+    fn lock_in() {
+        let mut header = Header::new();
+        header.set_size(64, 8).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let zero = vec![0u8; 64];
+        let ramp: Vec<u8> = (0 .. 64).collect();
+
+        // A row repeating the one above would pick Up...
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 0);
+        for _ in 0 .. 3 {
+            assert_eq!(filter.filter(&zero, &ramp)[0], Filter::Sub as u8);
+        }
+        assert_eq!(filter.filter(&ramp, &ramp)[0], Filter::Up as u8);
+
+        // ...unless three Sub rows in a row locked it in.
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 3);
+        for _ in 0 .. 3 {
+            assert_eq!(filter.filter(&zero, &ramp)[0], Filter::Sub as u8);
+        }
+        let output = filter.filter(&ramp, &ramp);
+        assert_eq!(output[0], Filter::Sub as u8);
+        assert_eq!(output[1 ..], [0u8].iter().chain([1u8; 63].iter()).copied().collect::<Vec<u8>>()[..]);
+
+        // A different pick starts the count over.
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, Heuristic::MinSum, false, 3);
+        filter.filter(&zero, &ramp);
+        filter.filter(&zero, &ramp);
+        assert_eq!(filter.filter(&ramp, &ramp)[0], Filter::Up as u8);
+        filter.filter(&zero, &ramp);
+        filter.filter(&zero, &ramp);
+        assert_eq!(filter.filter(&ramp, &ramp)[0], Filter::Up as u8);
+    }
 }
@@ -0,0 +1,253 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// fixed.rs - fast deflate encoder with a precomputed Huffman code and run matching
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//
+// Filtered PNG rows are mostly small values either side of zero,
+// and long runs of zeros. A Huffman code laid out for that ahead of
+// time, with matches only against the byte just before, does most of
+// the work of a real compressor in a fraction of the time: there are
+// no hash chains, and no Huffman trees to build for each block.
+//
+
+use std::mem;
+
+// Shortest and longest deflate matches.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// Literal/length symbols in use, through code 285 for 258-byte runs.
+const SYMBOLS: usize = 286;
+
+// Base lengths and extra bits for length codes 257 through 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Order the code length code lengths go out in the block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+//
+// Code length of each literal/length symbol. Bytes get shorter codes
+// the closer they are to zero, wrapping around, and short runs and
+// full-length runs the shortest of the lengths. The lengths add up
+// to a complete code, as inflaters require.
+//
+fn code_length(symbol: usize) -> u32 {
+    match symbol {
+        0 ..= 255 => match symbol.min(256 - symbol) {
+            0 => 2,
+            1 => 4,
+            2 => 5,
+            3 ..= 4 => 6,
+            5 ..= 8 => 7,
+            9 ..= 16 => 8,
+            17 ..= 32 => 9,
+            33 ..= 64 => 10,
+            _ => 12,
+        },
+        256 => 12,
+        257 ..= 264 => 6,
+        265 ..= 276 => 9,
+        277 ..= 284 => 10,
+        _ => 4,
+    }
+}
+
+// Lengths of the code for the code lengths themselves, by length.
+fn code_length_code_length(length: usize) -> u32 {
+    match length {
+        6 ..= 10 | 12 => 3,
+        1 | 2 | 4 | 5 => 4,
+        _ => 0,
+    }
+}
+
+//
+// Canonical Huffman codes for the given code lengths, with the bits
+// reversed ready to go out least significant first.
+//
+fn canonical_codes(lengths: &[u32]) -> Vec<(u32, u32)> {
+    let mut count = [0u32; 16];
+    for &len in lengths {
+        count[len as usize] += 1;
+    }
+    count[0] = 0;
+    let mut next = [0u32; 16];
+    let mut code = 0;
+    for bits in 1 .. 16 {
+        code = (code + count[bits - 1]) << 1;
+        next[bits] = code;
+    }
+    lengths.iter().map(|&len| {
+        if len == 0 {
+            return (0, 0);
+        }
+        let code = next[len as usize];
+        next[len as usize] += 1;
+        (code.reverse_bits() >> (32 - len), len)
+    }).collect()
+}
+
+pub struct FixedEncoder {
+    header: Vec<(u32, u32)>,
+    literals: Vec<(u32, u32)>,
+    lengths: Vec<(u32, u32)>,
+    end: (u32, u32),
+    bits: u64,
+    count: u32,
+    out: Vec<u8>,
+}
+
+impl FixedEncoder {
+    pub fn new() -> FixedEncoder {
+        let lengths: Vec<u32> = (0 .. SYMBOLS).map(code_length).collect();
+        let codes = canonical_codes(&lengths);
+
+        // Block header for the code: counts of literal/length codes,
+        // distance codes (just the one for distance 1), and code length
+        // codes, then the lengths of each, all written out in full.
+        let clc_lengths: Vec<u32> = (0 .. 19).map(code_length_code_length).collect();
+        let clc_codes = canonical_codes(&clc_lengths);
+        let clc_count = CODE_LENGTH_ORDER.iter().rposition(|&i| clc_lengths[i] != 0).unwrap() + 1;
+        let mut header = vec![
+            (0b10, 2),
+            ((SYMBOLS - 257) as u32, 5),
+            (0, 5),
+            ((clc_count - 4) as u32, 4),
+        ];
+        header.extend(CODE_LENGTH_ORDER[.. clc_count].iter().map(|&i| (clc_lengths[i], 3)));
+        header.extend(lengths.iter().chain(&[1]).map(|&len| clc_codes[len as usize]));
+
+        FixedEncoder {
+            header,
+            literals: codes[.. 256].to_vec(),
+            lengths: (0 ..= MAX_MATCH).map(|len| {
+                if len < MIN_MATCH {
+                    return (0, 0);
+                }
+                let index = LENGTH_BASE.iter().rposition(|&base| base as usize <= len).unwrap();
+                let (code, code_len) = codes[257 + index];
+                let value = (len - LENGTH_BASE[index] as usize) as u32;
+                (code | value << code_len, code_len + u32::from(LENGTH_EXTRA[index]))
+            }).collect(),
+            end: codes[256],
+            bits: 0,
+            count: 0,
+            out: Vec::new(),
+        }
+    }
+
+    fn put(&mut self, bits: u32, len: u32) {
+        self.bits |= u64::from(bits) << self.count;
+        self.count += len;
+        if self.count >= 32 {
+            self.out.extend_from_slice(&(self.bits as u32).to_le_bytes());
+            self.bits >>= 32;
+            self.count -= 32;
+        }
+    }
+
+    // Pad out to a byte boundary.
+    fn align(&mut self) {
+        while self.count > 0 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count = self.count.saturating_sub(8);
+        }
+        self.bits = 0;
+    }
+
+    //
+    // Compress data as a single block. Runs of a repeated byte
+    // become matches at distance 1, within this data only.
+    //
+    pub fn block(&mut self, data: &[u8], last: bool) {
+        self.put(u32::from(last), 1);
+        for i in 0 .. self.header.len() {
+            let (code, len) = self.header[i];
+            self.put(code, len);
+        }
+        let mut i = 0;
+        while i < data.len() {
+            let byte = data[i];
+            let (code, len) = self.literals[byte as usize];
+            self.put(code, len);
+            i += 1;
+
+            let run = data[i ..].iter().take(MAX_MATCH).take_while(|&&b| b == byte).count();
+            if run >= MIN_MATCH {
+                let (code, len) = self.lengths[run];
+                self.put(code, len);
+                // The only distance code, one bit long.
+                self.put(0, 1);
+                i += run;
+            }
+        }
+        let (code, len) = self.end;
+        self.put(code, len);
+        if last {
+            self.align();
+        }
+    }
+
+    //
+    // End on a byte boundary so more blocks can follow, with the
+    // empty stored block that zlib writes for a sync flush.
+    //
+    pub fn sync_flush(&mut self) {
+        self.put(0, 3);
+        self.align();
+        self.out.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+    }
+
+    // Take the output written so far.
+    pub fn take(&mut self) -> Vec<u8> {
+        mem::take(&mut self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_code() {
+        // Codes must fill the code space exactly, within 15 bits.
+        let kraft: u32 = (0 .. SYMBOLS).map(|symbol| 1 << (15 - code_length(symbol))).sum();
+        assert_eq!(kraft, 1 << 15);
+        let kraft: u32 = (0 .. 19).map(code_length_code_length)
+                                  .filter(|&len| len > 0)
+                                  .map(|len| 1 << (15 - len))
+                                  .sum();
+        assert_eq!(kraft, 1 << 15);
+    }
+}
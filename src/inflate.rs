@@ -0,0 +1,165 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// inflate.rs - wrapper for libz_sys decompression
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::mem;
+
+use std::os::raw::*;
+
+use ::libz_sys::*;
+
+use super::utils::*;
+
+//
+// Incremental zlib stream decompressor.
+// Feed compressed input in pieces as it arrives; output is appended.
+//
+pub struct Inflate {
+    initialized: bool,
+    finished: bool,
+    stream: Box<z_stream>,
+}
+
+impl Inflate {
+    pub fn new() -> Inflate {
+        Inflate {
+            initialized: false,
+            finished: false,
+            stream: Box::new(unsafe {
+                let maybe = mem::MaybeUninit::<z_stream>::zeroed();
+                maybe.assume_init()
+            }),
+        }
+    }
+
+    fn init(&mut self) -> IoResult {
+        if self.initialized {
+            return Ok(());
+        }
+        let ret = unsafe {
+            inflateInit2_(&mut *self.stream,
+                          15,
+                          zlibVersion(),
+                          mem::size_of::<z_stream>() as c_int)
+        };
+        match ret {
+            Z_OK => {
+                self.initialized = true;
+                Ok(())
+            },
+            _ => Err(zlib_error(ret)),
+        }
+    }
+
+    //
+    // Decompress the given input, appending to output.
+    // Input following the end of the stream is an error.
+    //
+    pub fn write(&mut self, data: &[u8], output: &mut Vec<u8>) -> IoResult {
+        self.init()?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.finished {
+            return Err(invalid_input("Extra data after end of compressed stream"));
+        }
+        let mut buffer = [0u8; 64 * 1024];
+        let stream = &mut *self.stream;
+        stream.next_in = data.as_ptr() as *mut u8;
+        stream.avail_in = data.len() as c_uint;
+        loop {
+            stream.next_out = buffer.as_mut_ptr();
+            stream.avail_out = buffer.len() as c_uint;
+            let ret = unsafe {
+                inflate(stream, Z_NO_FLUSH)
+            };
+            match ret {
+                Z_OK | Z_STREAM_END | Z_BUF_ERROR => {
+                    let end = buffer.len() - stream.avail_out as usize;
+                    output.extend_from_slice(&buffer[0 .. end]);
+                    if ret == Z_STREAM_END {
+                        self.finished = true;
+                        if stream.avail_in > 0 {
+                            return Err(invalid_input("Extra data after end of compressed stream"));
+                        }
+                        return Ok(());
+                    }
+                    if stream.avail_out != 0 {
+                        // All input consumed; wait for more.
+                        return Ok(());
+                    }
+                },
+                Z_DATA_ERROR => return Err(invalid_input("Corrupt compressed data")),
+                Z_NEED_DICT => return Err(invalid_input("Compressed data requires a dictionary")),
+                _ => return Err(zlib_error(ret)),
+            }
+        }
+    }
+
+    //
+    // Whether the end of the compressed stream has been seen.
+    //
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Drop for Inflate {
+    fn drop(&mut self) {
+        if self.initialized {
+            unsafe {
+                inflateEnd(&mut *self.stream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Inflate;
+    use super::super::deflate::{Deflate, Flush, Options};
+
+    #[test]
+    fn round_trip_in_pieces() {
+        let input: Vec<u8> = (0 .. 100_000).map(|i| (i % 251) as u8).collect();
+        let mut deflate = Deflate::new(Options::new(), Vec::new());
+        deflate.write(&input, Flush::Finish).unwrap();
+        let compressed = deflate.finish().unwrap();
+
+        let mut inflate = Inflate::new();
+        let mut output = Vec::new();
+        for piece in compressed.chunks(7) {
+            inflate.write(piece, &mut output).unwrap();
+        }
+        assert!(inflate.is_finished());
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut inflate = Inflate::new();
+        let mut output = Vec::new();
+        assert!(inflate.write(&[0x78, 0x9c, 0xff, 0xff, 0xff], &mut output).is_err());
+    }
+}
@@ -0,0 +1,486 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// layout.rs - row layout and sub-byte sample packing
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Row layout arithmetic for packed PNG pixel data.
+//!
+//! Samples of 1, 2, or 4 bits are packed most significant bits first,
+//! with any unused bits at the end of a row left as padding. Samples
+//! of 16 bits are stored big-endian.
+
+use std::io;
+
+use super::ColorType;
+use super::utils::invalid_input;
+
+/// Number of bits per pixel for the given color type and depth.
+pub fn bits_per_pixel(color_type: ColorType, depth: u8) -> usize {
+    color_type.channels() * depth as usize
+}
+
+/// Number of bytes per pixel, for PNG filtering purposes.
+///
+/// Pixels smaller than a byte count as 1.
+pub fn bytes_per_pixel(color_type: ColorType, depth: u8) -> usize {
+    bits_per_pixel(color_type, depth).div_ceil(8)
+}
+
+/// Number of bytes in a packed row of the given pixel width,
+/// rounding partial bytes up.
+///
+/// Returns None if the row length would overflow usize.
+pub fn stride(color_type: ColorType, depth: u8, width: u32) -> Option<usize> {
+    bits_per_pixel(color_type, depth)
+        .checked_mul(width as usize)
+        .map(|bits| bits.div_ceil(8))
+}
+
+/// Adam7 interlacing passes as (x offset, y offset, x step, y step).
+pub const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Pixel width and height of an Adam7 pass, numbered 0 through 6,
+/// for an image of the given size.
+///
+/// Small images leave some passes empty, with a width or height of 0.
+pub fn adam7_size(width: u32, height: u32, pass: usize) -> (u32, u32) {
+    let (x0, y0, dx, dy) = ADAM7[pass];
+    let span = |size: u32, start: usize, step: usize| {
+        (size as usize).saturating_sub(start).div_ceil(step) as u32
+    };
+    (span(width, x0, dx), span(height, y0, dy))
+}
+
+/// Pick out the pixels of a packed image row that belong to an Adam7
+/// pass, numbered 0 through 6, and pack them into a row of their own.
+///
+/// Will panic if the row is too short for the given pixel width.
+pub fn adam7_row(row: &[u8], bits_per_pixel: usize, width: u32, pass: usize) -> Vec<u8> {
+    let (x0, _, dx, _) = ADAM7[pass];
+    let xs = (x0 .. width as usize).step_by(dx);
+    if bits_per_pixel >= 8 {
+        let n = bits_per_pixel / 8;
+        xs.flat_map(|x| row[x * n .. (x + 1) * n].iter().cloned()).collect()
+    } else {
+        let depth = bits_per_pixel as u8;
+        let samples = unpack(row, depth, width as usize);
+        let picked: Vec<u8> = xs.map(|x| samples[x]).collect();
+        pack(&picked, depth)
+    }
+}
+
+/// Unpack the first `count` samples of a row at the given bit depth,
+/// one byte per sample. Depths of 8 and 16 bits are returned as-is.
+///
+/// Will panic if the row is too short to hold `count` samples.
+pub fn unpack(row: &[u8], depth: u8, count: usize) -> Vec<u8> {
+    match depth {
+        8 => row[.. count].to_vec(),
+        16 => row[.. count * 2].to_vec(),
+        _ => {
+            let depth = depth as usize;
+            let mask = (1u8 << depth) - 1;
+            (0 .. count).map(|i| {
+                let shift = 8 - depth - (i * depth) % 8;
+                (row[i * depth / 8] >> shift) & mask
+            }).collect()
+        }
+    }
+}
+
+/// Pack samples of one byte each into a row at the given bit depth,
+/// padding the final byte with zero bits. Depths of 8 and 16 bits
+/// are copied as-is.
+///
+/// Sample values are masked to the bit depth.
+pub fn pack(samples: &[u8], depth: u8) -> Vec<u8> {
+    match depth {
+        8 | 16 => samples.to_vec(),
+        _ => {
+            let mut row = vec![0u8; (samples.len() * depth as usize).div_ceil(8)];
+            pack_into(samples, depth, &mut row);
+            row
+        }
+    }
+}
+
+/// Pack samples of one byte each into the given row at a bit depth
+/// below 8, as for pack, overwriting what was there.
+///
+/// Will panic if the row is too short to hold the samples.
+pub fn pack_into(samples: &[u8], depth: u8, row: &mut [u8]) {
+    let depth = depth as usize;
+    let mask = (1u8 << depth) - 1;
+    row[.. (samples.len() * depth).div_ceil(8)].fill(0);
+    for (i, sample) in samples.iter().enumerate() {
+        let shift = 8 - depth - (i * depth) % 8;
+        row[i * depth / 8] |= (sample & mask) << shift;
+    }
+}
+
+/// Scale 8- or 16-bit samples that hold only `bits` significant bits
+/// up to the full range of the depth, mapping the largest `bits`-bit
+/// value to the largest sample value. This is the linear scaling the
+/// spec recommends for data described by an sBIT chunk.
+///
+/// Sample values too large for `bits` are clamped to full scale.
+pub fn rescale(row: &[u8], depth: u8, bits: u8) -> Vec<u8> {
+    rescale_channels(row, depth, &[bits])
+}
+
+/// Like rescale, but with the significant bits given per channel,
+/// as in an sBIT chunk. Samples take their bits from the list in turn.
+///
+/// Will panic if the list is empty.
+pub fn rescale_channels(row: &[u8], depth: u8, bits: &[u8]) -> Vec<u8> {
+    let max_out = (1u32 << depth) - 1;
+    let scale = |value: u32, bits: u8| {
+        let max_in = (1u32 << bits) - 1;
+        (value.min(max_in) * max_out + max_in / 2) / max_in
+    };
+    let bits = bits.iter().copied().cycle();
+    match depth {
+        16 => row.chunks(2).zip(bits).flat_map(|(pair, bits)| {
+            let value = scale(u32::from(pair[0]) << 8 | u32::from(pair[1]), bits);
+            (value as u16).to_be_bytes()
+        }).collect(),
+        _ => row.iter().zip(bits).map(|(&value, bits)| scale(u32::from(value), bits) as u8).collect(),
+    }
+}
+
+/// Largest sample value in a row of 8- or 16-bit samples.
+pub fn max_sample(row: &[u8], depth: u8) -> u16 {
+    match depth {
+        16 => row.chunks(2).map(|pair| u16::from(pair[0]) << 8 | u16::from(pair[1])).max(),
+        _ => row.iter().map(|&value| u16::from(value)).max(),
+    }.unwrap_or(0)
+}
+
+/// Largest value of each channel in a row of 8- or 16-bit samples
+/// with the given number of channels.
+pub fn max_channel_samples(row: &[u8], depth: u8, channels: usize) -> Vec<u16> {
+    let mut max = vec![0u16; channels];
+    for (i, sample) in row.chunks(usize::from(depth / 8)).enumerate() {
+        let value = sample.iter().fold(0u16, |value, &byte| value << 8 | u16::from(byte));
+        let max = &mut max[i % channels];
+        *max = (*max).max(value);
+    }
+    max
+}
+
+/// Composite a row of 8- or 16-bit pixels with an alpha channel over
+/// a matte color, dropping the alpha. The matte has one sample per
+/// color channel, at the same depth as the row.
+///
+/// Will panic if the matte is empty.
+pub fn flatten_alpha(row: &[u8], depth: u8, matte: &[u16]) -> Vec<u8> {
+    let channels = matte.len();
+    let max = (1u64 << depth) - 1;
+    let blend = |value: u64, alpha: u64, matte: u16| {
+        (value * alpha + u64::from(matte) * (max - alpha) + max / 2) / max
+    };
+    match depth {
+        16 => {
+            let samples: Vec<u64> = row.chunks(2).map(|pair| u64::from(pair[0]) << 8 | u64::from(pair[1])).collect();
+            samples.chunks(channels + 1).flat_map(|pixel| {
+                let alpha = pixel[channels];
+                pixel[.. channels].iter().zip(matte).flat_map(move |(&value, &matte)| {
+                    (blend(value, alpha, matte) as u16).to_be_bytes()
+                })
+            }).collect()
+        },
+        _ => row.chunks(channels + 1).flat_map(|pixel| {
+            let alpha = u64::from(pixel[channels]);
+            pixel[.. channels].iter().zip(matte).map(move |(&value, &matte)| {
+                blend(u64::from(value), alpha, matte) as u8
+            })
+        }).collect(),
+    }
+}
+
+/// How 8-bit greyscale samples are reduced to fewer bits.
+#[derive(Copy, Clone, Debug)]
+pub enum Reduction {
+    /// Round each sample to the nearest available level.
+    Nearest,
+    /// For 1-bit output, set pixels at or above this value to white.
+    Threshold(u8),
+    /// Floyd-Steinberg error diffusion between the available levels.
+    Dither,
+    /// Ordered dithering between the available levels, with an 8x8
+    /// Bayer pattern. Unlike error diffusion, each pixel depends only
+    /// on its own value and position.
+    Ordered,
+}
+
+// Bayer matrix for ordered dithering.
+const BAYER: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+//
+// Ordered dither offset for a pixel position, in 128ths of the step
+// between levels, spread evenly from -63 to 63.
+//
+pub(crate) fn ordered_offset(x: usize, y: usize) -> i32 {
+    i32::from(BAYER[y % 8][x % 8]) * 2 - 63
+}
+
+/// Packs rows of 8-bit greyscale samples, one byte per pixel, into
+/// 1, 2, or 4 bits per pixel.
+///
+/// Rows must be given in order, as dithering carries error from
+/// each row into the next.
+pub struct GreyscalePacker {
+    depth: u8,
+    reduction: Reduction,
+    // Error carried into the current and next rows, in sixteenths,
+    // with a pixel of padding on either side.
+    current: Vec<i32>,
+    next: Vec<i32>,
+    // Number of the next row, which places the ordered dither pattern.
+    row: usize,
+}
+
+impl GreyscalePacker {
+    /// Create a packer for the given output depth, which must be 1, 2, or 4.
+    /// Thresholding is only allowed for 1-bit output.
+    pub fn new(depth: u8, reduction: Reduction) -> io::Result<GreyscalePacker> {
+        if !matches!(depth, 1 | 2 | 4) {
+            return Err(invalid_input("Greyscale packing requires a depth of 1, 2, or 4 bits"));
+        }
+        if let (Reduction::Threshold(_), 2 | 4) = (reduction, depth) {
+            return Err(invalid_input("Thresholding requires 1-bit output"));
+        }
+        Ok(GreyscalePacker {
+            depth,
+            reduction,
+            current: Vec::new(),
+            next: Vec::new(),
+            row: 0,
+        })
+    }
+
+    /// Get the reduction in use.
+    pub fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    /// Set the number of the next row, for packing rows starting
+    /// partway through an image. Rows count from 0 otherwise.
+    pub fn set_row(&mut self, row: usize) {
+        self.row = row;
+    }
+
+    /// Reduce and pack a row of 8-bit samples.
+    pub fn pack_row(&mut self, row: &[u8]) -> Vec<u8> {
+        let max = (1i32 << self.depth) - 1;
+        let level = |value: i32| (value * max + 127) / 255;
+        let samples: Vec<u8> = match self.reduction {
+            Reduction::Nearest => row.iter().map(|&value| level(i32::from(value)) as u8).collect(),
+            Reduction::Threshold(threshold) => row.iter().map(|&value| (value >= threshold) as u8).collect(),
+            Reduction::Ordered => row.iter().enumerate().map(|(x, &value)| {
+                let offset = ordered_offset(x, self.row) * 255 / max / 128;
+                level((i32::from(value) + offset).clamp(0, 255)) as u8
+            }).collect(),
+            Reduction::Dither => {
+                if self.current.len() != row.len() + 2 {
+                    self.current = vec![0; row.len() + 2];
+                    self.next = vec![0; row.len() + 2];
+                }
+                let samples = row.iter().enumerate().map(|(x, &value)| {
+                    let value = (i32::from(value) + self.current[x + 1] / 16).clamp(0, 255);
+                    let chosen = level(value);
+                    let error = value - chosen * 255 / max;
+                    self.current[x + 2] += error * 7;
+                    self.next[x] += error * 3;
+                    self.next[x + 1] += error * 5;
+                    self.next[x + 2] += error;
+                    chosen as u8
+                }).collect();
+                std::mem::swap(&mut self.current, &mut self.next);
+                for error in self.next.iter_mut() {
+                    *error = 0;
+                }
+                samples
+            },
+        };
+        self.row += 1;
+        pack(&samples, self.depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ColorType::*;
+
+    const COLOR_TYPES: [ColorType; 5] = [Greyscale, Truecolor, IndexedColor,
+                                         GreyscaleAlpha, TruecolorAlpha];
+
+    #[test]
+    fn strides() {
+        for &color_type in COLOR_TYPES.iter() {
+            for &depth in [1u8, 2, 4, 8, 16].iter() {
+                if !color_type.is_depth_valid(depth) {
+                    continue;
+                }
+                for width in 1 .. 100u32 {
+                    let bits = color_type.channels() * depth as usize * width as usize;
+                    let mut bytes = bits / 8;
+                    if !bits.is_multiple_of(8) {
+                        bytes += 1;
+                    }
+                    assert_eq!(stride(color_type, depth, width), Some(bytes),
+                               "depth {} width {}", depth, width);
+                }
+            }
+        }
+        assert_eq!(stride(Greyscale, 1, 13), Some(2));
+        assert_eq!(stride(IndexedColor, 4, 13), Some(7));
+        assert_eq!(stride(IndexedColor, 2, 5), Some(2));
+    }
+
+    #[test]
+    fn pixel_bytes() {
+        assert_eq!(bytes_per_pixel(IndexedColor, 1), 1);
+        assert_eq!(bytes_per_pixel(Greyscale, 8), 1);
+        assert_eq!(bytes_per_pixel(Greyscale, 16), 2);
+        assert_eq!(bytes_per_pixel(Truecolor, 8), 3);
+        assert_eq!(bytes_per_pixel(TruecolorAlpha, 16), 8);
+    }
+
+    #[test]
+    fn packing() {
+        assert_eq!(pack(&[1, 0, 1, 1, 0, 0, 0, 1, 1], 1), vec![0b10110001, 0b10000000]);
+        assert_eq!(pack(&[3, 0, 2], 2), vec![0b11001000]);
+        assert_eq!(pack(&[0xa, 0x5, 0xf], 4), vec![0xa5, 0xf0]);
+        assert_eq!(unpack(&[0xa5, 0xf0], 4, 3), vec![0xa, 0x5, 0xf]);
+
+        for &depth in [1u8, 2, 4].iter() {
+            for count in 1 .. 40 {
+                let samples: Vec<u8> = (0 .. count).map(|i| (i * 7 % 16) as u8 & ((1 << depth) - 1))
+                                                   .collect();
+                let row = pack(&samples, depth);
+                assert_eq!(row.len(), stride(Greyscale, depth, count as u32).unwrap());
+                assert_eq!(unpack(&row, depth, count), samples);
+            }
+        }
+    }
+
+    #[test]
+    fn rescaling() {
+        // 10-bit data in 16-bit samples.
+        let row = [0x00, 0x00, 0x03, 0xff, 0x02, 0x00, 0xff, 0xff];
+        assert_eq!(rescale(&row, 16, 10), vec![0x00, 0x00, 0xff, 0xff, 0x80, 0x20, 0xff, 0xff]);
+        assert_eq!(max_sample(&row, 16), 0xffff);
+        assert_eq!(max_sample(&row[.. 6], 16), 0x3ff);
+
+        // 5-bit data in 8-bit samples.
+        assert_eq!(rescale(&[0, 31, 16, 1], 8, 5), vec![0, 255, 132, 8]);
+        assert_eq!(rescale(&[0, 7, 200], 8, 8), vec![0, 7, 200]);
+        assert_eq!(max_sample(&[3, 9, 2], 8), 9);
+
+        // 5-bit color with 1-bit alpha.
+        assert_eq!(rescale_channels(&[0, 31, 16, 1, 31, 0, 2, 0], 8, &[5, 5, 5, 1]),
+                   vec![0, 255, 132, 255, 255, 0, 16, 0]);
+        assert_eq!(max_channel_samples(&[0, 31, 16, 1, 31, 0, 2, 0], 8, 4), vec![31, 31, 16, 1]);
+        assert_eq!(max_channel_samples(&row, 16, 2), vec![0x200, 0xffff]);
+    }
+
+    #[test]
+    fn adam7() {
+        // Every pixel lands in exactly one pass.
+        for &(width, height) in [(1, 1), (3, 2), (8, 8), (13, 5), (33, 17)].iter() {
+            let total: u32 = (0 .. 7).map(|pass| {
+                let (w, h) = adam7_size(width, height, pass);
+                w * h
+            }).sum();
+            assert_eq!(total, width * height);
+        }
+        assert_eq!(adam7_size(1, 1, 1), (0, 1));
+        assert_eq!(adam7_size(1, 1, 6), (1, 0));
+
+        let row = [0b1010_0101, 0b1100_0000];
+        assert_eq!(adam7_row(&row, 1, 10, 0), vec![0b1100_0000]);
+        assert_eq!(adam7_row(&row, 1, 10, 5), vec![0b0011_1000]);
+        assert_eq!(adam7_row(&[1, 2, 3, 4, 5, 6], 16, 3, 3), vec![5, 6]);
+    }
+
+    #[test]
+    fn flattening() {
+        // Opaque, transparent, and half-covered pixels over a blue matte.
+        let row = [10, 20, 30, 255, 10, 20, 30, 0, 200, 100, 0, 128];
+        assert_eq!(flatten_alpha(&row, 8, &[0, 0, 255]),
+                   vec![10, 20, 30, 0, 0, 255, 100, 50, 127]);
+        assert_eq!(flatten_alpha(&[0xff, 0xff, 0x80, 0x00], 16, &[0]), vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn greyscale_packing() {
+        assert!(GreyscalePacker::new(8, Reduction::Nearest).is_err());
+        assert!(GreyscalePacker::new(2, Reduction::Threshold(128)).is_err());
+
+        let row = [0u8, 60, 100, 128, 200, 255, 90, 170, 30];
+        let mut packer = GreyscalePacker::new(1, Reduction::Threshold(100)).unwrap();
+        assert_eq!(packer.pack_row(&row), vec![0b00111101, 0b00000000]);
+        let mut packer = GreyscalePacker::new(2, Reduction::Nearest).unwrap();
+        assert_eq!(packer.pack_row(&row), pack(&[0, 1, 1, 2, 2, 3, 1, 2, 0], 2));
+        let mut packer = GreyscalePacker::new(4, Reduction::Nearest).unwrap();
+        assert_eq!(unpack(&packer.pack_row(&[0, 17, 255]), 4, 3), vec![0, 1, 15]);
+
+        // A flat mid-grey dithers to roughly half white pixels.
+        let mut packer = GreyscalePacker::new(1, Reduction::Dither).unwrap();
+        let mut white = 0;
+        for _ in 0 .. 16 {
+            let row = packer.pack_row(&[128; 64]);
+            white += unpack(&row, 1, 64).iter().filter(|&&bit| bit == 1).count();
+        }
+        assert!((480 .. 544).contains(&white), "{} white pixels", white);
+
+        // Ordered dithering gives exactly half, and depends on the row.
+        let mut packer = GreyscalePacker::new(1, Reduction::Ordered).unwrap();
+        let rows: Vec<Vec<u8>> = (0 .. 8).map(|_| packer.pack_row(&[128; 64])).collect();
+        let white: usize = rows.iter().map(|row| unpack(row, 1, 64).iter().filter(|&&bit| bit == 1).count()).sum();
+        assert_eq!(white, 256);
+        packer.set_row(3);
+        assert_eq!(packer.pack_row(&[128; 64]), rows[3]);
+        let mut packer = GreyscalePacker::new(2, Reduction::Ordered).unwrap();
+        assert_eq!(unpack(&packer.pack_row(&[0, 85, 170, 255]), 2, 4), vec![0, 1, 2, 3]);
+    }
+}
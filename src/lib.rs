@@ -26,23 +26,70 @@
 //! mtpng - a multithreaded parallel PNG encoder in Rust
 
 extern crate rayon;
-extern crate crc;
+extern crate crc32fast;
+extern crate simd_adler32;
 extern crate libz_sys;
 #[macro_use] extern crate itertools;
 
+#[cfg(feature="miniz")]
+extern crate miniz_oxide;
+#[cfg(feature="zlib-rs")]
+extern crate zlib_rs;
+#[cfg(feature="zopfli")]
+extern crate zopfli;
+
+#[cfg(feature="log")]
+extern crate log;
+
+#[cfg(feature="metrics")]
+extern crate metrics;
+
+#[cfg(feature="tokio")]
+extern crate tokio;
+
+#[cfg(feature="ndarray")]
+extern crate ndarray;
+
 #[cfg(feature="capi")]
 extern crate libc;
 #[cfg(feature="capi")]
 pub mod capi;
 
+#[macro_use]
+mod logging;
+#[cfg(feature="metrics")]
+mod telemetry;
+
+pub mod analyze;
+mod animation;
+#[cfg(feature = "tokio")]
+mod async_encoder;
+pub mod checksum;
+pub mod chunked;
 mod deflate;
+pub mod error;
 mod filter;
+mod fixed;
+mod inflate;
+pub mod decoder;
 pub mod encoder;
+pub mod layout;
+pub mod metadata;
+pub mod optimize;
+mod pull;
+pub mod quantize;
+pub mod reduce;
+pub mod tile;
 mod utils;
 mod writer;
 
+pub use error::Error;
+
+pub type Backend = deflate::Backend;
+pub type Flush = deflate::Flush;
 pub type Strategy = deflate::Strategy;
 pub type Filter = filter::Filter;
+pub type Heuristic = filter::Heuristic;
 
 use std::convert::TryFrom;
 use std::io;
@@ -127,6 +174,20 @@ pub enum CompressionMethod {
     Deflate = 0,
 }
 
+impl TryFrom<u8> for CompressionMethod {
+    type Error = io::Error;
+
+    /// Validate and produce a CompressionMethod from one of the PNG header constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(CompressionMethod::Deflate),
+            _ => Err(invalid_input("Invalid compression method")),
+        }
+    }
+}
+
 /// PNG header filter method representation.
 ///
 /// Currently only Standard is supported. This may be expanded to support APNG in future.
@@ -137,9 +198,21 @@ pub enum FilterMethod {
     Standard = 0,
 }
 
+impl TryFrom<u8> for FilterMethod {
+    type Error = io::Error;
+
+    /// Validate and produce a FilterMethod from one of the PNG header constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(FilterMethod::Standard),
+            _ => Err(invalid_input("Invalid filter method")),
+        }
+    }
+}
+
 /// PNG header interlace method representation.
-///
-/// Currently only Standard is supported; Adam7 interlacing will throw an error if used.
 #[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum InterlaceMethod {
@@ -149,10 +222,26 @@ pub enum InterlaceMethod {
     Standard = 0,
     /// Adam7 interlacing.
     ///
-    /// Not yet supported.
+    /// Pixels are split up into seven passes of increasing detail,
+    /// each made of its own shorter rows.
     Adam7 = 1,
 }
 
+impl TryFrom<u8> for InterlaceMethod {
+    type Error = io::Error;
+
+    /// Validate and produce an InterlaceMethod from one of the PNG header constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(InterlaceMethod::Standard),
+            1 => Ok(InterlaceMethod::Adam7),
+            _ => Err(invalid_input("Invalid interlace method")),
+        }
+    }
+}
+
 /// PNG header representation.
 ///
 /// You must create one of these with image metadata when encoding,
@@ -225,32 +314,50 @@ impl Header {
     ///
     /// If the bit depth is < 8, this will clamp at 1.
     pub fn bytes_per_pixel(&self) -> usize {
-        self.color_type.channels() * if self.depth > 8 {
-            2
-        } else {
-            1
-        }
+        layout::bytes_per_pixel(self.color_type, self.depth)
     }
 
     /// Calculate the stride in bytes for the encoded pixel rows.
     ///
     /// Will panic on arithmetic overflow if given pathologically long rows.
     pub fn stride(&self) -> usize {
-        let bits_per_pixel = self.color_type.channels() * self.depth as usize;
-
         // Very long line lengths can overflow usize on 32-bit.
         // If we got this far, let it panic in the unwrap().
-        let stride_bits = bits_per_pixel.checked_mul(self.width as usize)
-                                        .unwrap();
-
-        // And round up to nearest byte.
-        let stride_bytes = stride_bits >> 3;
-        let remainder = stride_bits & 3;
-        if remainder > 0 {
-            stride_bytes + 1
-        } else {
-            stride_bytes
+        layout::stride(self.color_type, self.depth, self.width).unwrap()
+    }
+
+    /// Pack a row of 1-, 2-, or 4-bit samples given one byte each into
+    /// the row layout the image data uses, which is stride() bytes.
+    /// Sample values are masked to the bit depth.
+    ///
+    /// Returns error for 8- and 16-bit images, which don't need
+    /// packing, or if either row is the wrong length.
+    pub fn pack_row(&self, unpacked: &[u8], packed: &mut [u8]) -> io::Result<()> {
+        self.check_packed_lengths(unpacked.len(), packed.len())?;
+        layout::pack_into(unpacked, self.depth, packed);
+        Ok(())
+    }
+
+    /// Unpack a row of 1-, 2-, or 4-bit samples into one byte each,
+    /// the reverse of pack_row.
+    pub fn unpack_row(&self, packed: &[u8], unpacked: &mut [u8]) -> io::Result<()> {
+        self.check_packed_lengths(unpacked.len(), packed.len())?;
+        unpacked.copy_from_slice(&layout::unpack(packed, self.depth, unpacked.len()));
+        Ok(())
+    }
+
+    fn check_packed_lengths(&self, unpacked: usize, packed: usize) -> io::Result<()> {
+        if self.depth >= 8 {
+            return Err(invalid_input("Only rows of 1-, 2-, and 4-bit samples are packed"));
+        }
+        let samples = self.width as usize * self.color_type.channels();
+        if unpacked != samples {
+            return Err(invalid_input(&format!("Unpacked row must hold {} samples", samples)));
+        }
+        if packed != self.stride() {
+            return Err(invalid_input(&format!("Packed row must be {} bytes", self.stride())));
         }
+        Ok(())
     }
 
     /// Set the pixel dimensions of the image.
@@ -307,12 +414,10 @@ impl Header {
 
     /// Set the interlace method.
     ///
-    /// Currently only Standard is supported; requesting Adam7 will return an error.
+    /// Adam7 output lets viewers show a coarse version of the image
+    /// early, but the encoder must hold the whole image before it can
+    /// start compressing, and files usually come out larger.
     pub fn set_interlace_method(&mut self, interlace_method: InterlaceMethod) -> io::Result<()> {
-        match interlace_method {
-            InterlaceMethod::Standard => {},
-            InterlaceMethod::Adam7 => return Err(invalid_input("Adam7 interlacing not yet")),
-        }
         self.interlace_method = interlace_method;
         Ok(())
     }
@@ -332,7 +437,41 @@ pub enum CompressionLevel {
     /// Good balance of speed and compression (zlib level 6).
     Default,
     /// Best compression but slow (zlib level 9).
-    High
+    High,
+    /// Smallest output, compressing each chunk with the Zopfli
+    /// algorithm in place of the deflate backend. Many times slower
+    /// than High; needs the "zopfli" feature.
+    Max,
+    /// Any zlib level from 1 (fastest) to 9 (smallest).
+    Specific(u8),
+}
+
+impl CompressionLevel {
+    /// The zlib level number, with 10 standing for Max.
+    pub fn level(self) -> u8 {
+        match self {
+            CompressionLevel::Fast => 1,
+            CompressionLevel::Default => 6,
+            CompressionLevel::High => 9,
+            CompressionLevel::Max => 10,
+            CompressionLevel::Specific(level) => level,
+        }
+    }
+
+    //
+    // Check the level is in range and can be used in this build.
+    //
+    pub(crate) fn validate(self) -> io::Result<()> {
+        match self {
+            CompressionLevel::Max if !cfg!(feature = "zopfli") => {
+                Err(invalid_input("Zopfli compression not compiled in"))
+            },
+            CompressionLevel::Specific(level) if !(1 ..= 9).contains(&level) => {
+                Err(invalid_input("Compression level not supported"))
+            },
+            _ => Ok(()),
+        }
+    }
 }
 
 impl TryFrom<u8> for CompressionLevel {
@@ -346,6 +485,8 @@ impl TryFrom<u8> for CompressionLevel {
             1 => Ok(CompressionLevel::Fast),
             6 => Ok(CompressionLevel::Default),
             9 => Ok(CompressionLevel::High),
+            10 => Ok(CompressionLevel::Max),
+            2 ..= 8 => Ok(CompressionLevel::Specific(val)),
             _ => Err(invalid_input("Compression level not supported")),
         }
     }
@@ -0,0 +1,83 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// logging.rs - optional debug and trace logging
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+
+//
+// Logging macros that forward to the log crate when the "log"
+// feature is enabled, and compile to nothing otherwise. The
+// arguments are still type-checked either way, so a message
+// can't break the build only when logging is turned on.
+//
+// All messages use the "mtpng" target, so they can be filtered
+// separately from the application's own logging.
+//
+
+#[cfg(feature="log")]
+macro_rules! warn_log {
+    ($($arg:tt)+) => {
+        ::log::warn!(target: "mtpng", $($arg)+)
+    }
+}
+
+#[cfg(feature="log")]
+macro_rules! debug_log {
+    ($($arg:tt)+) => {
+        ::log::debug!(target: "mtpng", $($arg)+)
+    }
+}
+
+#[cfg(feature="log")]
+macro_rules! trace_log {
+    ($($arg:tt)+) => {
+        ::log::trace!(target: "mtpng", $($arg)+)
+    }
+}
+
+#[cfg(not(feature="log"))]
+macro_rules! warn_log {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }
+}
+
+#[cfg(not(feature="log"))]
+macro_rules! debug_log {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }
+}
+
+#[cfg(not(feature="log"))]
+macro_rules! trace_log {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// metadata.rs - ancillary chunk metadata
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+
+//! Typed views of the ancillary chunks that describe an image,
+//! as collected by the decoder.
+
+use std::convert::TryFrom;
+use std::io;
+
+use super::inflate::Inflate;
+
+use super::utils::invalid_input;
+
+/// Rendering intent for an sRGB image, from an sRGB chunk.
+///
+/// https://www.w3.org/TR/PNG/#11sRGB
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum RenderingIntent {
+    /// For images such as photographs, keeping colors in proportion.
+    Perceptual = 0,
+    /// Match in-gamut colors to the white point, for logos and the like.
+    RelativeColorimetric = 1,
+    /// Keep colors saturated, for charts and graphs.
+    Saturation = 2,
+    /// Match colors absolutely, for proofs.
+    AbsoluteColorimetric = 3,
+}
+
+impl TryFrom<u8> for RenderingIntent {
+    type Error = io::Error;
+
+    /// Validate and produce a RenderingIntent from an sRGB chunk byte.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(RenderingIntent::Perceptual),
+            1 => Ok(RenderingIntent::RelativeColorimetric),
+            2 => Ok(RenderingIntent::Saturation),
+            3 => Ok(RenderingIntent::AbsoluteColorimetric),
+            _ => Err(invalid_input("Invalid rendering intent")),
+        }
+    }
+}
+
+/// CIE 1931 xy chromaticities of the white point and primaries,
+/// from a cHRM chunk.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Chromaticities {
+    white: (f64, f64),
+    red: (f64, f64),
+    green: (f64, f64),
+    blue: (f64, f64),
+}
+
+impl Chromaticities {
+    /// Create a set of chromaticities from (x, y) pairs.
+    pub fn new(white: (f64, f64), red: (f64, f64), green: (f64, f64), blue: (f64, f64)) -> Chromaticities {
+        Chromaticities {
+            white,
+            red,
+            green,
+            blue,
+        }
+    }
+
+    /// White point (x, y).
+    pub fn white(&self) -> (f64, f64) {
+        self.white
+    }
+
+    /// Red primary (x, y).
+    pub fn red(&self) -> (f64, f64) {
+        self.red
+    }
+
+    /// Green primary (x, y).
+    pub fn green(&self) -> (f64, f64) {
+        self.green
+    }
+
+    /// Blue primary (x, y).
+    pub fn blue(&self) -> (f64, f64) {
+        self.blue
+    }
+}
+
+/// An embedded ICC color profile, from an iCCP chunk.
+#[derive(Clone, Debug)]
+pub struct IccProfile {
+    name: String,
+    profile: Vec<u8>,
+}
+
+impl IccProfile {
+    /// Profile name, as given in the chunk.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Decompressed profile data.
+    pub fn profile(&self) -> &[u8] {
+        &self.profile
+    }
+}
+
+/// A text entry, from a tEXt, zTXt, or iTXt chunk.
+#[derive(Clone, Debug)]
+pub struct Text {
+    keyword: String,
+    text: String,
+    language: String,
+    translated_keyword: String,
+    compressed: bool,
+    international: bool,
+}
+
+impl Text {
+    /// Keyword, such as "Title" or "Comment".
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    /// Decompressed text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Language tag, from iTXt only; empty if not given.
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    /// Keyword translated into the language, from iTXt only; empty if not given.
+    pub fn translated_keyword(&self) -> &str {
+        &self.translated_keyword
+    }
+
+    /// Whether the text was stored compressed, in zTXt or compressed iTXt.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Whether the text came from an iTXt chunk.
+    pub fn is_international(&self) -> bool {
+        self.international
+    }
+}
+
+/// Physical pixel dimensions, from a pHYs chunk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    x: u32,
+    y: u32,
+    meters: bool,
+}
+
+impl PhysicalDimensions {
+    /// Pixels per unit horizontally.
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    /// Pixels per unit vertically.
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+
+    /// Whether the unit is the meter. If not, only the aspect ratio is known.
+    pub fn is_meters(&self) -> bool {
+        self.meters
+    }
+}
+
+/// Last modification time in UTC, from a tIME chunk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Time {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+impl Time {
+    /// Full year, such as 2024.
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    /// Month, 1-12.
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// Day of the month, 1-31.
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+
+    /// Hour, 0-23.
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    /// Minute, 0-59.
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    /// Second, 0-60 to allow for leap seconds.
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+}
+
+/// Where an ancillary chunk appeared relative to the critical chunks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChunkPosition {
+    /// After IHDR, before any PLTE or image data.
+    BeforePalette,
+    /// After PLTE, before the image data.
+    BeforeImage,
+    /// After the image data.
+    AfterImage,
+}
+
+/// An ancillary chunk kept as raw bytes.
+#[derive(Clone, Debug)]
+pub struct RawChunk {
+    tag: [u8; 4],
+    data: Vec<u8>,
+    position: ChunkPosition,
+}
+
+impl RawChunk {
+    /// Four-letter chunk tag.
+    pub fn tag(&self) -> &[u8; 4] {
+        &self.tag
+    }
+
+    /// Chunk contents.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Where the chunk appeared in the file.
+    pub fn position(&self) -> ChunkPosition {
+        self.position
+    }
+}
+
+/// Ancillary chunk metadata collected from a PNG file.
+///
+/// Gamma, chromaticities, sRGB rendering intent, ICC profile, text, physical dimensions, and
+/// modification time are parsed; tRNS is kept with the image data, and
+/// all other ancillary chunks are kept as raw bytes in file order.
+/// The raw bytes of the parsed chunks are kept as well, so they can
+/// be copied verbatim.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    gamma: Option<f64>,
+    chromaticities: Option<Chromaticities>,
+    rendering_intent: Option<RenderingIntent>,
+    icc_profile: Option<IccProfile>,
+    text: Vec<Text>,
+    physical_dimensions: Option<PhysicalDimensions>,
+    time: Option<Time>,
+    unknown: Vec<RawChunk>,
+    chunks: Vec<RawChunk>,
+}
+
+impl Metadata {
+    /// Image gamma from gAMA, such as 0.45455.
+    pub fn gamma(&self) -> Option<f64> {
+        self.gamma
+    }
+
+    /// Chromaticities from cHRM.
+    pub fn chromaticities(&self) -> Option<&Chromaticities> {
+        self.chromaticities.as_ref()
+    }
+
+    /// Rendering intent from sRGB, for images in the sRGB color space.
+    pub fn rendering_intent(&self) -> Option<RenderingIntent> {
+        self.rendering_intent
+    }
+
+    /// Embedded color profile from iCCP.
+    pub fn icc_profile(&self) -> Option<&IccProfile> {
+        self.icc_profile.as_ref()
+    }
+
+    /// Text entries, in file order.
+    pub fn text(&self) -> &[Text] {
+        &self.text
+    }
+
+    /// Physical pixel dimensions from pHYs.
+    pub fn physical_dimensions(&self) -> Option<&PhysicalDimensions> {
+        self.physical_dimensions.as_ref()
+    }
+
+    /// Modification time from tIME.
+    pub fn time(&self) -> Option<&Time> {
+        self.time.as_ref()
+    }
+
+    /// Other ancillary chunks, in file order.
+    pub fn unknown(&self) -> &[RawChunk] {
+        &self.unknown
+    }
+
+    /// Every ancillary chunk except tRNS, parsed or not, exactly as
+    /// it appeared and in file order, for copying into another file.
+    pub fn chunks(&self) -> &[RawChunk] {
+        &self.chunks
+    }
+
+    //
+    // Parse an ancillary chunk into the matching field.
+    // Duplicates of single-instance chunks are an error.
+    //
+    pub(crate) fn add_chunk(&mut self, tag: &[u8], data: &[u8], position: ChunkPosition) -> io::Result<()> {
+        let raw = RawChunk {
+            tag: [tag[0], tag[1], tag[2], tag[3]],
+            data: data.to_vec(),
+            position,
+        };
+        self.chunks.push(raw.clone());
+        match tag {
+            b"gAMA" => {
+                if self.gamma.is_some() || data.len() != 4 {
+                    return Err(invalid_input("Invalid gAMA chunk"));
+                }
+                self.gamma = Some(fixed_point(data));
+            },
+            b"cHRM" => {
+                if self.chromaticities.is_some() || data.len() != 32 {
+                    return Err(invalid_input("Invalid cHRM chunk"));
+                }
+                let pair = |i: usize| (fixed_point(&data[i * 8 ..]), fixed_point(&data[i * 8 + 4 ..]));
+                self.chromaticities = Some(Chromaticities::new(pair(0), pair(1), pair(2), pair(3)));
+            },
+            b"sRGB" => {
+                if self.rendering_intent.is_some() || data.len() != 1 {
+                    return Err(invalid_input("Invalid sRGB chunk"));
+                }
+                self.rendering_intent = Some(RenderingIntent::try_from(data[0])?);
+            },
+            b"iCCP" => {
+                if self.icc_profile.is_some() {
+                    return Err(invalid_input("Duplicate iCCP chunk"));
+                }
+                let (name, rest) = split_keyword(data)?;
+                match rest.split_first() {
+                    Some((0, compressed)) => {
+                        self.icc_profile = Some(IccProfile {
+                            name,
+                            profile: inflate(compressed)?,
+                        });
+                    },
+                    _ => return Err(invalid_input("Invalid iCCP compression method")),
+                }
+            },
+            b"tEXt" => {
+                let (keyword, rest) = split_keyword(data)?;
+                self.text.push(Text {
+                    keyword,
+                    text: from_latin1(rest),
+                    language: String::new(),
+                    translated_keyword: String::new(),
+                    compressed: false,
+                    international: false,
+                });
+            },
+            b"zTXt" => {
+                let (keyword, rest) = split_keyword(data)?;
+                match rest.split_first() {
+                    Some((0, compressed)) => {
+                        self.text.push(Text {
+                            keyword,
+                            text: from_latin1(&inflate(compressed)?),
+                            language: String::new(),
+                            translated_keyword: String::new(),
+                            compressed: true,
+                            international: false,
+                        });
+                    },
+                    _ => return Err(invalid_input("Invalid zTXt compression method")),
+                }
+            },
+            b"iTXt" => {
+                let (keyword, rest) = split_keyword(data)?;
+                if rest.len() < 2 || rest[1] != 0 || rest[0] > 1 {
+                    return Err(invalid_input("Invalid iTXt compression"));
+                }
+                let compressed = rest[0] == 1;
+                let (language, rest) = split_utf8(&rest[2 ..])?;
+                let (translated_keyword, rest) = split_utf8(rest)?;
+                let text = if compressed {
+                    inflate(rest)?
+                } else {
+                    rest.to_vec()
+                };
+                self.text.push(Text {
+                    keyword,
+                    text: String::from_utf8(text).map_err(|_e| invalid_input::<io::Error>("Invalid UTF-8 in iTXt"))?,
+                    language,
+                    translated_keyword,
+                    compressed,
+                    international: true,
+                });
+            },
+            b"pHYs" => {
+                if self.physical_dimensions.is_some() || data.len() != 9 || data[8] > 1 {
+                    return Err(invalid_input("Invalid pHYs chunk"));
+                }
+                self.physical_dimensions = Some(PhysicalDimensions {
+                    x: be32(data),
+                    y: be32(&data[4 ..]),
+                    meters: data[8] == 1,
+                });
+            },
+            b"tIME" => {
+                if self.time.is_some() || data.len() != 7 {
+                    return Err(invalid_input("Invalid tIME chunk"));
+                }
+                self.time = Some(Time {
+                    year: u16::from_be_bytes([data[0], data[1]]),
+                    month: data[2],
+                    day: data[3],
+                    hour: data[4],
+                    minute: data[5],
+                    second: data[6],
+                });
+            },
+            _ => {
+                self.unknown.push(raw);
+            },
+        }
+        Ok(())
+    }
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// PNG stores gamma and chromaticities times 100000.
+fn fixed_point(bytes: &[u8]) -> f64 {
+    f64::from(be32(bytes)) / 100000.0
+}
+
+fn from_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+//
+// Split a null-terminated Latin-1 keyword off the front of a chunk.
+//
+fn split_keyword(data: &[u8]) -> io::Result<(String, &[u8])> {
+    match data.iter().position(|&b| b == 0) {
+        Some(end) if (1 ..= 79).contains(&end) => Ok((from_latin1(&data[.. end]), &data[end + 1 ..])),
+        _ => Err(invalid_input("Invalid chunk keyword")),
+    }
+}
+
+//
+// Split a null-terminated UTF-8 string off the front of an iTXt chunk.
+//
+fn split_utf8(data: &[u8]) -> io::Result<(String, &[u8])> {
+    let end = data.iter().position(|&b| b == 0).ok_or_else(|| invalid_input::<io::Error>("Unterminated iTXt field"))?;
+    let s = String::from_utf8(data[.. end].to_vec()).map_err(|_e| invalid_input::<io::Error>("Invalid UTF-8 in iTXt"))?;
+    Ok((s, &data[end + 1 ..]))
+}
+
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut inflate = Inflate::new();
+    let mut out = Vec::new();
+    inflate.write(data, &mut out)?;
+    if !inflate.is_finished() {
+        return Err(invalid_input("Compressed chunk data is incomplete"));
+    }
+    Ok(out)
+}
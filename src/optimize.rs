@@ -0,0 +1,544 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// optimize.rs - brute-force search for the smallest encoding
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Brute-force search over encoder settings for the smallest output.
+
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use super::CompressionLevel;
+use super::Filter;
+use super::Mode;
+use super::Mode::{Adaptive, Fixed};
+use super::Strategy;
+
+use super::decoder::{decode, Decoded};
+use super::encoder::{EncodeStats, Encoder, Options};
+use super::metadata::ChunkPosition;
+use super::reduce::{Reduced, Reduction};
+
+use super::utils::*;
+
+/// One combination of encoder settings to try.
+#[derive(Copy, Clone)]
+pub struct Trial {
+    filter_mode: Mode<Filter>,
+    strategy_mode: Mode<Strategy>,
+    compression_level: CompressionLevel,
+}
+
+impl Trial {
+    /// Create a trial with the given filter, strategy, and level.
+    pub fn new(filter_mode: Mode<Filter>,
+               strategy_mode: Mode<Strategy>,
+               compression_level: CompressionLevel) -> Trial {
+        Trial {
+            filter_mode,
+            strategy_mode,
+            compression_level,
+        }
+    }
+
+    /// Get the filter mode.
+    pub fn filter_mode(&self) -> Mode<Filter> {
+        self.filter_mode
+    }
+
+    /// Get the deflate strategy mode.
+    pub fn strategy_mode(&self) -> Mode<Strategy> {
+        self.strategy_mode
+    }
+
+    /// Get the compression level.
+    pub fn compression_level(&self) -> CompressionLevel {
+        self.compression_level
+    }
+
+    fn apply(&self, options: &mut Options) -> IoResult {
+        options.set_filter_mode(self.filter_mode)?;
+        options.set_strategy_mode(self.strategy_mode)?;
+        options.set_compression_level(self.compression_level)?;
+        Ok(())
+    }
+}
+
+/// The full set of trials: every filter mode against every fixed
+/// strategy except Fixed, at the default and high compression levels.
+pub fn all_trials() -> Vec<Trial> {
+    trials(&[Adaptive,
+             Fixed(Filter::None),
+             Fixed(Filter::Sub),
+             Fixed(Filter::Up),
+             Fixed(Filter::Average),
+             Fixed(Filter::Paeth)],
+           &[Fixed(Strategy::Default),
+             Fixed(Strategy::Filtered),
+             Fixed(Strategy::Rle),
+             Fixed(Strategy::HuffmanOnly)],
+           &[CompressionLevel::Default,
+             CompressionLevel::High])
+}
+
+/// Build the cross product of the given filters, strategies, and levels.
+pub fn trials(filters: &[Mode<Filter>],
+              strategies: &[Mode<Strategy>],
+              levels: &[CompressionLevel]) -> Vec<Trial> {
+    let mut out = Vec::with_capacity(filters.len() * strategies.len() * levels.len());
+    for &filter_mode in filters {
+        for &strategy_mode in strategies {
+            for &level in levels {
+                out.push(Trial::new(filter_mode, strategy_mode, level));
+            }
+        }
+    }
+    out
+}
+
+/// The winning encoding from an optimization run.
+pub struct Optimized {
+    trial: Trial,
+    reductions: Vec<Reduction>,
+    stats: EncodeStats,
+    data: Vec<u8>,
+    trials_run: usize,
+}
+
+impl Optimized {
+    /// Settings that produced the smallest output.
+    pub fn trial(&self) -> Trial {
+        self.trial
+    }
+
+    /// Reductions applied to the winning candidate image, when trying
+    /// several with optimize_reductions. Empty if the image as given won.
+    pub fn reductions(&self) -> &[Reduction] {
+        &self.reductions
+    }
+
+    /// Encoder statistics for the winning run.
+    pub fn stats(&self) -> EncodeStats {
+        self.stats.clone()
+    }
+
+    /// The encoded PNG file.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Take the encoded PNG file.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Number of trials that were encoded.
+    pub fn trials_run(&self) -> usize {
+        self.trials_run
+    }
+}
+
+//
+// Encode a single trial into memory.
+//
+fn run<'a, F>(options: &Options<'a>, trial: &Trial, encode: F)
+    -> io::Result<(EncodeStats, Vec<u8>)>
+    where F: FnOnce(&mut Encoder<'a, Vec<u8>>) -> IoResult
+{
+    let mut options = *options;
+    trial.apply(&mut options)?;
+
+    let mut encoder = Encoder::new(Vec::new(), &options);
+    encode(&mut encoder)?;
+    encoder.flush()?;
+    let stats = encoder.stats();
+    let data = encoder.finish()?;
+    Ok((stats, data))
+}
+
+/// Encode the image once per trial and keep the smallest file.
+///
+/// The `encode` callback must write the header, any other chunks, and
+/// all image rows; it is called once per trial, possibly concurrently.
+/// Other settings such as chunk size and thread pool come from `options`.
+///
+/// Trials run side by side on their own threads, as many as the thread
+/// pool has, each spreading its chunks over the pool. Ties go to the
+/// earlier trial in the list.
+pub fn optimize<'a, F>(options: &Options<'a>, trials: &[Trial], encode: F)
+    -> io::Result<Optimized>
+    where F: Fn(&mut Encoder<'a, Vec<u8>>) -> IoResult + Sync
+{
+    if trials.is_empty() {
+        return Err(invalid_input("No trials to run"));
+    }
+    let (index, stats, data) = search(options, trials.len(), |index| {
+        run(options, &trials[index], &encode)
+    })?;
+    Ok(Optimized {
+        trial: trials[index],
+        reductions: Vec::new(),
+        stats,
+        data,
+        trials_run: trials.len(),
+    })
+}
+
+/// Encode each candidate image once per trial, and keep the smallest
+/// file of them all.
+///
+/// Candidates usually come from reduce::candidates, and all must hold
+/// the same pixels. The `encode` callback must write the header, the
+/// palette and transparency of the candidate it's given, any other
+/// chunks, and all image rows. Every candidate and trial pair runs
+/// side by side as with optimize(); ties go to the earlier candidate,
+/// then the earlier trial.
+pub fn optimize_reductions<'a, F>(options: &Options<'a>,
+                                  trials: &[Trial],
+                                  candidates: &[Reduced],
+                                  encode: F) -> io::Result<Optimized>
+    where F: Fn(&mut Encoder<'a, Vec<u8>>, &Reduced) -> IoResult + Sync
+{
+    if trials.is_empty() || candidates.is_empty() {
+        return Err(invalid_input("No trials to run"));
+    }
+    let count = trials.len() * candidates.len();
+    let (index, stats, data) = search(options, count, |index| {
+        let candidate = &candidates[index / trials.len()];
+        run(options, &trials[index % trials.len()], |encoder| encode(encoder, candidate))
+    })?;
+    Ok(Optimized {
+        trial: trials[index % trials.len()],
+        reductions: candidates[index / trials.len()].reductions().to_vec(),
+        stats,
+        data,
+        trials_run: count,
+    })
+}
+
+//
+// Run the numbered jobs side by side on as many threads as the
+// pool has, returning the index and output of the smallest.
+//
+fn search<F>(options: &Options, count: usize, job: F)
+    -> io::Result<(usize, EncodeStats, Vec<u8>)>
+    where F: Fn(usize) -> io::Result<(EncodeStats, Vec<u8>)> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let workers = options.thread_count().clamp(1, count);
+
+    // Each worker keeps its own best result, plus the first error it hit.
+    type Best = Option<(usize, EncodeStats, Vec<u8>)>;
+    let worker = || -> (Best, Option<(usize, io::Error)>) {
+        let mut best: Best = None;
+        loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            if index >= count {
+                return (best, None);
+            }
+            match job(index) {
+                Ok((stats, data)) => {
+                    let smaller = match &best {
+                        Some((_, _, best_data)) => data.len() < best_data.len(),
+                        None => true,
+                    };
+                    if smaller {
+                        best = Some((index, stats, data));
+                    }
+                },
+                Err(e) => {
+                    // Stop handing out further jobs.
+                    next.store(count, Ordering::Relaxed);
+                    return (best, Some((index, e)));
+                },
+            }
+        }
+    };
+
+    let results: Vec<(Best, Option<(usize, io::Error)>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0 .. workers).map(|_| scope.spawn(worker)).collect();
+        handles.into_iter()
+               .map(|handle| handle.join().map_err(|_| other("Optimizer thread panicked")))
+               .collect::<io::Result<_>>()
+    })?;
+
+    let mut winner: Best = None;
+    let mut failure: Option<(usize, io::Error)> = None;
+    for (best, error) in results {
+        if let Some((index, e)) = error {
+            if failure.as_ref().is_none_or(|(i, _)| index < *i) {
+                failure = Some((index, e));
+            }
+        }
+        if let Some((index, stats, data)) = best {
+            let better = match &winner {
+                Some((i, _, d)) => data.len() < d.len() || (data.len() == d.len() && index < *i),
+                None => true,
+            };
+            if better {
+                winner = Some((index, stats, data));
+            }
+        }
+    }
+
+    if let Some((_, e)) = failure {
+        return Err(e);
+    }
+    winner.ok_or_else(|| other("No trial produced output"))
+}
+
+// APNG chunks, which describe frames the decoder doesn't keep.
+const ANIMATION_TAGS: [&[u8; 4]; 3] = [b"acTL", b"fcTL", b"fdAT"];
+
+//
+// Write a decoded image back out with its ancillary chunks, less any
+// animation. Those that followed the image data go just before it
+// instead, as the encoder takes no chunks after image rows.
+//
+fn write_decoded<W: Write>(encoder: &mut Encoder<W>, decoded: &Decoded) -> IoResult {
+    let chunks: Vec<_> = decoded.metadata().chunks().iter()
+                                .filter(|chunk| !ANIMATION_TAGS.contains(&chunk.tag()))
+                                .collect();
+    let write_chunks = |encoder: &mut Encoder<W>, position: ChunkPosition| -> IoResult {
+        for chunk in chunks.iter().filter(|chunk| chunk.position() == position) {
+            encoder.write_chunk(chunk.tag(), chunk.data())?;
+        }
+        Ok(())
+    };
+
+    encoder.write_header(decoded.header())?;
+    write_chunks(encoder, ChunkPosition::BeforePalette)?;
+    if let Some(palette) = decoded.palette() {
+        encoder.write_palette(palette)?;
+    }
+    if let Some(transparency) = decoded.transparency() {
+        encoder.write_transparency(transparency)?;
+    }
+    write_chunks(encoder, ChunkPosition::BeforeImage)?;
+    write_chunks(encoder, ChunkPosition::AfterImage)?;
+    encoder.write_image_rows(decoded.data())?;
+    Ok(())
+}
+
+/// Decode a PNG file and re-encode it once per trial, keeping the
+/// smallest result, as with optimize().
+///
+/// Every ancillary chunk in the source is copied byte for byte, in
+/// its original order, including ICC profiles, text, and private
+/// chunks, except that chunks after the image data are moved to just
+/// before it. That suits text and the other chunks PNG lets come on
+/// either side of the image data, but not APNG frames, so acTL, fcTL,
+/// and fdAT chunks are dropped and animated files come out as just
+/// their default image. Interlaced files come out non-interlaced.
+pub fn recompress(options: &Options, trials: &[Trial], png: &[u8]) -> io::Result<Optimized> {
+    let decoded = decode(png)?;
+    optimize(options, trials, |encoder| write_decoded(encoder, &decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ColorType;
+    use super::super::CompressionLevel;
+    use super::super::Filter;
+    use super::super::Header;
+    use super::super::Mode::Fixed;
+    use super::super::Strategy;
+    use super::super::encoder::{AnimationEncoder, Encoder, FrameControl, Options};
+    use super::super::utils::IoResult;
+    use super::super::checksum;
+    use super::super::decoder::decode;
+    use super::super::metadata::ChunkPosition;
+    use super::super::reduce::{candidates, Reduced};
+    use super::{all_trials, optimize, optimize_reductions, recompress, trials};
+
+    fn encode(encoder: &mut Encoder<Vec<u8>>) -> IoResult {
+        let mut header = Header::new();
+        header.set_size(64, 64)?;
+        header.set_color(ColorType::Truecolor, 8)?;
+        encoder.write_header(&header)?;
+
+        let mut data = Vec::with_capacity(64 * 64 * 3);
+        for y in 0 .. 64 {
+            for x in 0 .. 64 * 3 {
+                data.push(((x * y) % 251) as u8);
+            }
+        }
+        encoder.write_image_rows(&data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn picks_smallest() {
+        let options = Options::new();
+        let trials = all_trials();
+        let result = optimize(&options, &trials, encode).unwrap();
+        assert_eq!(result.trials_run(), trials.len());
+
+        // No single trial may beat the winner.
+        for trial in trials.iter() {
+            let single = optimize(&options, &[*trial], encode).unwrap();
+            assert!(result.data().len() <= single.data().len());
+        }
+    }
+
+    #[test]
+    fn single_trial_matches_encoder() {
+        let mut options = Options::new();
+        options.set_filter_mode(Fixed(Filter::Paeth)).unwrap();
+        options.set_strategy_mode(Fixed(Strategy::Rle)).unwrap();
+        options.set_compression_level(CompressionLevel::High).unwrap();
+
+        let mut encoder = Encoder::new(Vec::new(), &options);
+        encode(&mut encoder).unwrap();
+        let direct = encoder.finish().unwrap();
+
+        let trial = trials(&[Fixed(Filter::Paeth)],
+                           &[Fixed(Strategy::Rle)],
+                           &[CompressionLevel::High]);
+        let result = optimize(&Options::new(), &trial, encode).unwrap();
+        assert_eq!(result.data(), &direct[..]);
+        // Stats are taken before the 12-byte IEND chunk is written.
+        assert_eq!(result.stats().output_bytes() + 12, direct.len() as u64);
+    }
+
+    fn write_reduced(encoder: &mut Encoder<Vec<u8>>, image: &Reduced) -> IoResult {
+        encoder.write_header(image.header())?;
+        if let Some(palette) = image.palette() {
+            encoder.write_palette(palette)?;
+        }
+        if let Some(transparency) = image.transparency() {
+            encoder.write_transparency(transparency)?;
+        }
+        encoder.write_image_rows(image.data())?;
+        Ok(())
+    }
+
+    #[test]
+    fn picks_smallest_reduction() {
+        // Opaque stripes in two colors reduce several ways.
+        let mut header = Header::new();
+        header.set_size(64, 64).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let data: Vec<u8> = (0 .. 64 * 64).flat_map(|i| if i % 3 == 0 {
+            [200, 10, 10, 255]
+        } else {
+            [10, 10, 200, 255]
+        }).collect();
+        let list = candidates(&header, &data, None, None).unwrap();
+        assert!(list.len() > 2);
+
+        let trials = trials(&[Fixed(Filter::Sub)],
+                            &[Fixed(Strategy::Default)],
+                            &[CompressionLevel::Default]);
+        let result = optimize_reductions(&Options::new(), &trials, &list, write_reduced).unwrap();
+        assert_eq!(result.trials_run(), list.len());
+        assert!(!result.reductions().is_empty());
+
+        for candidate in list.iter() {
+            let single = optimize_reductions(&Options::new(), &trials, std::slice::from_ref(candidate),
+                                             write_reduced).unwrap();
+            assert!(result.data().len() <= single.data().len());
+        }
+        assert!(optimize_reductions(&Options::new(), &trials, &[], write_reduced).is_err());
+    }
+
+    #[test]
+    fn recompress_keeps_chunks() {
+        let mut header = Header::new();
+        header.set_size(16, 16).unwrap();
+        header.set_color(ColorType::IndexedColor, 4).unwrap();
+        let data: Vec<u8> = (0 .. 16 * 8).map(|i| (i * 7 % 256) as u8).collect();
+
+        let mut encoder = Encoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_chunk(b"gAMA", &[0, 0, 0xb1, 0x8f]).unwrap();
+        encoder.write_chunk(b"prIV", b"before palette").unwrap();
+        let palette: Vec<u8> = (0 .. 16 * 3).map(|i| i as u8 * 5).collect();
+        encoder.write_palette(&palette).unwrap();
+        encoder.write_transparency(&[0, 128]).unwrap();
+        encoder.write_chunk(b"bKGD", &[3]).unwrap();
+        encoder.write_text("Title", "Fish").unwrap();
+        encoder.write_image_rows(&data).unwrap();
+        let mut png = encoder.finish().unwrap();
+
+        // Splice in a chunk after the image data, before IEND.
+        let mut late = b"tEXt".to_vec();
+        late.extend_from_slice(b"Comment\0Late");
+        let crc = checksum::crc32(&late);
+        let mut chunk = (late.len() as u32 - 4).to_be_bytes().to_vec();
+        chunk.extend_from_slice(&late);
+        chunk.extend_from_slice(&crc.to_be_bytes());
+        let end = png.len() - 12;
+        png.splice(end .. end, chunk);
+
+        let source = decode(&png[..]).unwrap();
+        let result = recompress(&Options::new(), &all_trials(), &png).unwrap();
+        let output = decode(result.data()).unwrap();
+        assert_eq!(output.data(), source.data());
+        assert_eq!(output.palette(), source.palette());
+        assert_eq!(output.transparency(), source.transparency());
+
+        let before = source.metadata().chunks();
+        let after = output.metadata().chunks();
+        assert_eq!(before.len(), 5);
+        assert_eq!(before.len(), after.len());
+        for (a, b) in before.iter().zip(after.iter()) {
+            assert_eq!((a.tag(), a.data()), (b.tag(), b.data()));
+        }
+        assert_eq!(before[4].position(), ChunkPosition::AfterImage);
+        assert_eq!(after[4].position(), ChunkPosition::BeforeImage);
+    }
+
+    #[test]
+    fn recompress_drops_animation() {
+        let mut header = Header::new();
+        header.set_size(16, 16).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let first: Vec<u8> = (0 .. 16 * 16 * 3).map(|i| (i % 251) as u8).collect();
+        let second = vec![255u8; 16 * 16 * 3];
+
+        let mut encoder = AnimationEncoder::new(Vec::new(), &Options::new());
+        encoder.write_header(&header, 2, 0).unwrap();
+        encoder.encoder().write_chunk(b"tEXt", b"Title\0Animated").unwrap();
+        encoder.write_frame(&FrameControl::new(), &first).unwrap();
+        encoder.write_frame(&FrameControl::new(), &second).unwrap();
+        let png = encoder.finish().unwrap();
+
+        let result = recompress(&Options::new(), &all_trials(), &png).unwrap();
+        let output = decode(result.data()).unwrap();
+        assert_eq!(output.data(), &first[..]);
+        let tags: Vec<_> = output.metadata().chunks().iter().map(|chunk| chunk.tag()).collect();
+        assert_eq!(tags, [b"tEXt"]);
+
+        let reader = png::Decoder::new(result.data()).read_info().unwrap();
+        assert!(reader.info().animation_control.is_none());
+    }
+
+    #[test]
+    fn no_trials() {
+        let result = optimize(&Options::new(), &[], encode);
+        assert!(result.is_err());
+    }
+}
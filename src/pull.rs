@@ -0,0 +1,171 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// pull.rs - encoding with output taken by the caller
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Encoding with output pulled by the caller.
+//!
+//! A PullEncoder takes no Write output. Compressed data builds up in
+//! memory instead, and the caller takes it as it's ready, at whatever
+//! pace suits its transport, such as HTTP chunked transfer.
+
+use std::mem;
+
+use super::Header;
+use super::encoder::{Encoder, Options};
+use super::error;
+use super::utils::*;
+
+/// Encodes a PNG, handing out the output as it's produced instead of
+/// writing it anywhere.
+///
+/// next_output, or iterating, returns the bytes written since the last
+/// call. With streaming mode on (Options::set_streaming) each image data
+/// chunk comes out as soon as it's compressed; otherwise image data is
+/// held until the end of the image.
+///
+/// Once the output so far has all been taken, iteration ends until
+/// more rows are written or the encoder is finished, so loop over it
+/// again after each.
+pub struct PullEncoder<'a> {
+    encoder: Option<Encoder<'a, Vec<u8>>>,
+    // Output left when the encoder finished.
+    rest: Vec<u8>,
+}
+
+impl<'a> PullEncoder<'a> {
+    /// Creates a new PullEncoder.
+    pub fn new(options: &Options<'a>) -> PullEncoder<'a> {
+        PullEncoder {
+            encoder: Some(Encoder::new(Vec::new(), options)),
+            rest: Vec::new(),
+        }
+    }
+
+    /// The underlying encoder, for writing the palette, metadata,
+    /// and other chunks, or using its other input methods.
+    ///
+    /// Returns an error once finished.
+    pub fn encoder(&mut self) -> error::Result<&mut Encoder<'a, Vec<u8>>> {
+        self.encoder.as_mut().ok_or_else(|| invalid_input("Encoder already finished"))
+    }
+
+    /// Write the PNG signature and header chunk.
+    pub fn write_header(&mut self, header: &Header) -> error::Result<()> {
+        self.encoder()?.write_header(header)
+    }
+
+    /// Encode and compress the given image data, as for
+    /// Encoder::write_image_rows.
+    pub fn write_image_rows(&mut self, buf: &[u8]) -> error::Result<()> {
+        self.encoder()?.write_image_rows(buf)
+    }
+
+    /// Finish the image, leaving the rest of the output to be taken.
+    ///
+    /// Returns an error if the image data isn't complete.
+    pub fn finish(&mut self) -> error::Result<()> {
+        match self.encoder.take() {
+            Some(encoder) => {
+                self.rest = encoder.finish()?;
+                Ok(())
+            },
+            None => Err(invalid_input("Encoder already finished")),
+        }
+    }
+
+    /// Take the output written since the last call, waiting for chunks
+    /// being compressed to be written out if there's none yet. Returns
+    /// None when there's nothing more to come until more rows are
+    /// written or the encoder is finished.
+    pub fn next_output(&mut self) -> error::Result<Option<Vec<u8>>> {
+        let encoder = match self.encoder {
+            Some(ref mut encoder) => encoder,
+            None if self.rest.is_empty() => return Ok(None),
+            None => return Ok(Some(mem::take(&mut self.rest))),
+        };
+        encoder.poll()?;
+        while encoder.output_mut().is_empty() && encoder.has_pending_output() {
+            encoder.wait()?;
+        }
+        let output = mem::take(encoder.output_mut());
+        Ok(if output.is_empty() { None } else { Some(output) })
+    }
+}
+
+impl Iterator for PullEncoder<'_> {
+    type Item = error::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_output().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PullEncoder;
+    use super::super::{ColorType, Header};
+    use super::super::decoder::decode;
+    use super::super::encoder::Options;
+
+    #[test]
+    fn pull_output() {
+        let width = 640;
+        let height = 480;
+        let data: Vec<u8> = (0 .. width * height * 3).map(|i| (i * 7 / 5 % 256) as u8).collect();
+        let mut header = Header::new();
+        header.set_size(width as u32, height as u32).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        for &streaming in [false, true].iter() {
+            let mut options = Options::new();
+            options.set_chunk_size(32768).unwrap();
+            options.set_streaming(streaming).unwrap();
+
+            let mut encoder = PullEncoder::new(&options);
+            let mut pieces = Vec::new();
+            encoder.write_header(&header).unwrap();
+            for rows in data.chunks(width * 3 * 100) {
+                encoder.write_image_rows(rows).unwrap();
+                for output in &mut encoder {
+                    pieces.push(output.unwrap());
+                }
+            }
+            encoder.finish().unwrap();
+            pieces.extend(encoder.map(|output| output.unwrap()));
+
+            // Streaming output comes out as the image goes in.
+            assert!(pieces.len() > if streaming { 4 } else { 1 });
+            assert!(pieces.iter().all(|piece| !piece.is_empty()));
+            let png = pieces.concat();
+            assert_eq!(decode(&png[..]).unwrap().data(), &data[..]);
+        }
+
+        let options = Options::new();
+        let mut encoder = PullEncoder::new(&options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&data[.. width * 3]).unwrap();
+        assert!(encoder.finish().is_err());
+        assert!(encoder.write_image_rows(&data[.. width * 3]).is_err());
+    }
+}
@@ -0,0 +1,633 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// quantize.rs - palette quantization for truecolor input
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Palette quantization for reducing truecolor images to indexed color.
+
+use rayon::prelude::*;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+
+use super::ColorType;
+use super::Header;
+use super::layout;
+
+use super::utils::invalid_input;
+
+type Rgba = [u8; 4];
+
+// Rows dithered in order by each job; the first takes its errors
+// from the row above, as if that row had none coming in.
+const DITHER_BAND_ROWS: usize = 64;
+
+/// How pixels are mapped to palette entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dither {
+    /// Each pixel goes to its closest entry.
+    None,
+    /// Ordered dithering with an 8x8 Bayer pattern on the color
+    /// channels, leaving alpha alone. This keeps a regular texture
+    /// that compresses better than error diffusion.
+    Ordered,
+    /// Floyd-Steinberg error diffusion on all channels.
+    FloydSteinberg,
+}
+
+/// Reduces 8-bit truecolor images to an indexed-color palette
+/// using median cut.
+#[derive(Copy, Clone)]
+pub struct Quantizer {
+    colors: usize,
+    dither: Dither,
+}
+
+/// Output of a quantization run, ready to pass to the encoder.
+pub struct Quantized {
+    header: Header,
+    data: Vec<u8>,
+    palette: Vec<u8>,
+    transparency: Option<Vec<u8>>,
+}
+
+impl Quantized {
+    /// Get the updated header, which will be 8-bit indexed color.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Get the palette index data, one byte per pixel.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the PLTE chunk data.
+    pub fn palette(&self) -> &[u8] {
+        &self.palette
+    }
+
+    /// Get the tRNS chunk data, if any palette entries are not opaque.
+    pub fn transparency(&self) -> Option<&[u8]> {
+        self.transparency.as_deref()
+    }
+}
+
+// Range of color entries being considered for a single palette entry.
+struct ColorBox {
+    start: usize,
+    end: usize,
+}
+
+impl Quantizer {
+    /// Create a new quantizer producing at most the given number of
+    /// palette entries, which must be in the range 2-256.
+    pub fn new(colors: usize) -> io::Result<Quantizer> {
+        if !(2 ..= 256).contains(&colors) {
+            return Err(invalid_input("Palette size must be between 2 and 256"));
+        }
+        Ok(Quantizer {
+            colors,
+            dither: Dither::None,
+        })
+    }
+
+    /// Enable or disable Floyd-Steinberg dithering of the palette mapping.
+    ///
+    /// Dithering hides banding on gradients and photos at the cost of
+    /// noisier output, which usually compresses a little worse.
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = if dither {
+            Dither::FloydSteinberg
+        } else {
+            Dither::None
+        };
+    }
+
+    /// Set how pixels are mapped to palette entries. Defaults to None.
+    pub fn set_dithering(&mut self, dither: Dither) {
+        self.dither = dither;
+    }
+
+    /// Quantize packed 8-bit Truecolor or TruecolorAlpha image data.
+    pub fn quantize(&self, header: &Header, data: &[u8]) -> io::Result<Quantized> {
+        let channels = check_input(header, data)?;
+        let palette = self.build_palette(&histogram(data, channels));
+
+        let mut indexes = vec![0u8; data.len() / channels];
+        let width = header.width as usize;
+        match self.dither {
+            Dither::None => map_nearest(&palette, channels, width, data, &mut indexes),
+            Dither::Ordered => map_ordered(&palette, channels, width, data, &mut indexes),
+            Dither::FloydSteinberg => map_dithered(&palette, channels, width, data, &mut indexes),
+        }
+
+        let mut out_header = *header;
+        out_header.set_color(ColorType::IndexedColor, 8)?;
+
+        let mut plte = Vec::with_capacity(palette.len() * 3);
+        for color in palette.iter() {
+            plte.extend_from_slice(&color[0 .. 3]);
+        }
+        // Transparent entries are sorted first, so tRNS can stop early.
+        let alphas: Vec<u8> = palette.iter()
+                                     .map(|color| color[3])
+                                     .take_while(|&alpha| alpha < 255)
+                                     .collect();
+
+        Ok(Quantized {
+            header: out_header,
+            data: indexes,
+            palette: plte,
+            transparency: if alphas.is_empty() {
+                None
+            } else {
+                Some(alphas)
+            },
+        })
+    }
+
+    /// Build a suggested palette for packed 8-bit Truecolor or
+    /// TruecolorAlpha image data, returned as sPLT chunk data ready
+    /// for Encoder::write_chunk. The pixel data is left unchanged.
+    ///
+    /// Each entry's frequency is proportional to the number of pixels
+    /// closest to it, scaled so the most common is 65535, and entries
+    /// are listed most frequent first. The name must be 1-79 printable
+    /// ASCII characters without leading, trailing, or consecutive spaces,
+    /// and unique among the file's sPLT chunks.
+    ///
+    /// https://www.w3.org/TR/PNG/#11sPLT
+    pub fn suggest_palette(&self, header: &Header, data: &[u8], name: &str) -> io::Result<Vec<u8>> {
+        if name.is_empty() || name.len() > 79 || !name.bytes().all(|c| (32 ..= 126).contains(&c)) {
+            return Err(invalid_input("Palette name must be 1-79 printable ASCII characters"));
+        }
+        if name.starts_with(' ') || name.ends_with(' ') || name.contains("  ") {
+            return Err(invalid_input("Palette name must not have leading, trailing, or consecutive spaces"));
+        }
+        let channels = check_input(header, data)?;
+        let palette = self.build_palette(&histogram(data, channels));
+
+        let mut indexes = vec![0u8; data.len() / channels];
+        map_nearest(&palette, channels, header.width as usize, data, &mut indexes);
+        let mut counts = vec![0u64; palette.len()];
+        for &index in indexes.iter() {
+            counts[index as usize] += 1;
+        }
+        let max = counts.iter().copied().max().unwrap_or(0).max(1);
+
+        let mut entries: Vec<(Rgba, u16)> = palette.iter().zip(counts.iter()).map(|(&color, &count)| {
+            (color, (count * 65535 / max) as u16)
+        }).collect();
+        entries.sort_by_key(|&(_, frequency)| cmp::Reverse(frequency));
+
+        // Name, null separator, sample depth, then RGBA and frequency.
+        let mut chunk = Vec::with_capacity(name.len() + 2 + entries.len() * 6);
+        chunk.extend_from_slice(name.as_bytes());
+        chunk.push(0);
+        chunk.push(8);
+        for (color, frequency) in entries {
+            chunk.extend_from_slice(&color);
+            chunk.extend_from_slice(&frequency.to_be_bytes());
+        }
+        Ok(chunk)
+    }
+
+    //
+    // Pick palette entries by median cut over the color histogram.
+    // Returns non-opaque entries first.
+    //
+    fn build_palette(&self, histogram: &[(Rgba, u32)]) -> Vec<Rgba> {
+        let mut entries = histogram.to_vec();
+
+        let mut palette = if entries.len() <= self.colors {
+            // Few enough colors to keep them all exactly.
+            entries.sort_by_key(|&(_, count)| cmp::Reverse(count));
+            entries.iter().map(|&(color, _)| color).collect::<Vec<Rgba>>()
+        } else {
+            let mut boxes = vec![ColorBox {
+                start: 0,
+                end: entries.len()
+            }];
+            while boxes.len() < self.colors {
+                match split_candidate(&entries, &boxes) {
+                    Some(i) => {
+                        let (channel, _) = widest_channel(&entries[boxes[i].start .. boxes[i].end]);
+                        let upper = split_box(&mut entries, &mut boxes[i], channel);
+                        boxes.push(upper);
+                    },
+                    None => break,
+                }
+            }
+            boxes.iter().map(|b| average(&entries[b.start .. b.end])).collect()
+        };
+
+        palette.sort_by_key(|color| color[3] == 255);
+        palette
+    }
+}
+
+//
+// Check for 8-bit truecolor input of the right size,
+// returning the number of channels.
+//
+fn check_input(header: &Header, data: &[u8]) -> io::Result<usize> {
+    let channels = match (header.color_type, header.depth) {
+        (ColorType::Truecolor, 8) => 3,
+        (ColorType::TruecolorAlpha, 8) => 4,
+        _ => return Err(invalid_input("Quantization requires 8-bit truecolor input")),
+    };
+    if data.len() != header.stride() * header.height as usize {
+        return Err(invalid_input("Image data does not match the header size"));
+    }
+    Ok(channels)
+}
+
+//
+// Count the unique colors in the image, treating RGB as opaque RGBA.
+// Sorted so the palette doesn't depend on hash order.
+//
+fn histogram(data: &[u8], channels: usize) -> Vec<(Rgba, u32)> {
+    let mut counts = HashMap::<Rgba, u32>::new();
+    for pixel in data.chunks(channels) {
+        *counts.entry(rgba(pixel)).or_insert(0) += 1;
+    }
+    let mut entries: Vec<(Rgba, u32)> = counts.into_iter().collect();
+    entries.sort_unstable();
+    entries
+}
+
+fn rgba(pixel: &[u8]) -> Rgba {
+    if pixel.len() == 4 {
+        [pixel[0], pixel[1], pixel[2], pixel[3]]
+    } else {
+        [pixel[0], pixel[1], pixel[2], 255]
+    }
+}
+
+// Returns the channel with the widest value range, and that range.
+fn widest_channel(entries: &[(Rgba, u32)]) -> (usize, u32) {
+    let mut best = (0, 0);
+    for channel in 0 .. 4 {
+        let min = entries.iter().map(|(color, _)| color[channel]).min().unwrap_or(0);
+        let max = entries.iter().map(|(color, _)| color[channel]).max().unwrap_or(0);
+        let range = u32::from(max - min);
+        if range > best.1 {
+            best = (channel, range);
+        }
+    }
+    best
+}
+
+//
+// Pick the box to split next: the one with the largest range
+// weighted by the number of pixels it covers, so frequent colors
+// get more precise palette entries.
+//
+fn split_candidate(entries: &[(Rgba, u32)], boxes: &[ColorBox]) -> Option<usize> {
+    let mut best = None;
+    let mut best_score = 0u64;
+    for (i, b) in boxes.iter().enumerate() {
+        if b.end - b.start < 2 {
+            continue;
+        }
+        let slice = &entries[b.start .. b.end];
+        let (_, range) = widest_channel(slice);
+        let count: u64 = slice.iter().map(|&(_, count)| u64::from(count)).sum();
+        let score = u64::from(range) * count;
+        if score > best_score {
+            best_score = score;
+            best = Some(i);
+        }
+    }
+    best
+}
+
+//
+// Split a box at the weighted median of the given channel,
+// shrinking it to the lower half and returning the upper half.
+//
+fn split_box(entries: &mut [(Rgba, u32)], b: &mut ColorBox, channel: usize) -> ColorBox {
+    let slice = &mut entries[b.start .. b.end];
+    slice.sort_by_key(|&(color, _)| color[channel]);
+
+    let total: u64 = slice.iter().map(|&(_, count)| u64::from(count)).sum();
+    let mut sum = 0u64;
+    let mut split = 1;
+    for (i, &(_, count)) in slice.iter().enumerate() {
+        sum += u64::from(count);
+        if sum * 2 >= total {
+            split = i + 1;
+            break;
+        }
+    }
+    // Both halves must keep at least one color.
+    let split = split.clamp(1, slice.len() - 1);
+
+    let upper = ColorBox {
+        start: b.start + split,
+        end: b.end,
+    };
+    b.end = b.start + split;
+    upper
+}
+
+fn average(entries: &[(Rgba, u32)]) -> Rgba {
+    let mut sums = [0u64; 4];
+    let mut total = 0u64;
+    for &(color, count) in entries {
+        for channel in 0 .. 4 {
+            sums[channel] += u64::from(color[channel]) * u64::from(count);
+        }
+        total += u64::from(count);
+    }
+    let mut out = [0u8; 4];
+    for channel in 0 .. 4 {
+        out[channel] = ((sums[channel] + total / 2) / total) as u8;
+    }
+    out
+}
+
+fn nearest(palette: &[Rgba], color: Rgba) -> u8 {
+    let mut best = 0;
+    let mut best_distance = u32::MAX;
+    for (i, entry) in palette.iter().enumerate() {
+        let mut distance = 0u32;
+        for channel in 0 .. 4 {
+            let delta = i32::from(entry[channel]) - i32::from(color[channel]);
+            distance += (delta * delta) as u32;
+        }
+        if distance < best_distance {
+            best_distance = distance;
+            best = i;
+        }
+    }
+    best as u8
+}
+
+//
+// Map each pixel to its closest palette entry.
+// Rows are independent, so run them on the thread pool.
+//
+fn map_nearest(palette: &[Rgba], channels: usize, width: usize, data: &[u8], out: &mut [u8]) {
+    out.par_chunks_mut(width)
+       .zip(data.par_chunks(width * channels))
+       .for_each_init(HashMap::<Rgba, u8>::new, |cache, (dest, src)| {
+           for (index, pixel) in dest.iter_mut().zip(src.chunks(channels)) {
+               let color = rgba(pixel);
+               *index = *cache.entry(color).or_insert_with(|| nearest(palette, color));
+           }
+       });
+}
+
+//
+// Map pixels with an ordered dither, spread over the typical distance
+// between palette entries. Rows are independent, as for map_nearest.
+//
+fn map_ordered(palette: &[Rgba], channels: usize, width: usize, data: &[u8], out: &mut [u8]) {
+    let spread = entry_spacing(palette);
+    out.par_chunks_mut(width)
+       .zip(data.par_chunks(width * channels))
+       .enumerate()
+       .for_each_init(HashMap::<Rgba, u8>::new, |cache, (y, (dest, src))| {
+           for (x, (index, pixel)) in dest.iter_mut().zip(src.chunks(channels)).enumerate() {
+               let offset = layout::ordered_offset(x, y) * spread / 128;
+               let mut color = rgba(pixel);
+               for value in color[.. 3].iter_mut() {
+                   *value = (i32::from(*value) + offset).clamp(0, 255) as u8;
+               }
+               *index = *cache.entry(color).or_insert_with(|| nearest(palette, color));
+           }
+       });
+}
+
+//
+// Average distance from each palette entry to its closest neighbor,
+// going by the color channel that differs most.
+//
+fn entry_spacing(palette: &[Rgba]) -> i32 {
+    if palette.len() < 2 {
+        return 0;
+    }
+    let total: i32 = palette.iter().enumerate().map(|(i, entry)| {
+        palette.iter().enumerate().filter(|&(j, _)| j != i).map(|(_, other)| {
+            (0 .. 3).map(|channel| (i32::from(entry[channel]) - i32::from(other[channel])).abs()).max().unwrap()
+        }).min().unwrap()
+    }).sum();
+    total / palette.len() as i32
+}
+
+//
+// Map pixels with Floyd-Steinberg error diffusion. Each row depends
+// on the errors of the one above, so bands of rows run in parallel,
+// each starting from the errors of the row above it alone.
+//
+fn map_dithered(palette: &[Rgba], channels: usize, width: usize, data: &[u8], out: &mut [u8]) {
+    let stride = width * channels;
+    out.par_chunks_mut(width * DITHER_BAND_ROWS)
+       .enumerate()
+       .for_each(|(band, dest)| {
+           let start = band * DITHER_BAND_ROWS * stride;
+           let src = &data[start .. start + dest.len() * channels];
+           let above = start.checked_sub(stride).map(|above| &data[above .. start]);
+           map_dithered_band(palette, channels, width, above, src, dest);
+       });
+}
+
+// Dither a band of rows, starting from the errors of the row above.
+fn map_dithered_band(palette: &[Rgba], channels: usize, width: usize,
+                     above: Option<&[u8]>, data: &[u8], out: &mut [u8]) {
+    let mut cache = HashMap::<Rgba, u8>::new();
+
+    // One pixel of padding on either side saves edge checks.
+    let mut current = vec![[0i32; 4]; width + 2];
+    let mut next = vec![[0i32; 4]; width + 2];
+
+    let mut discard = vec![0u8; width];
+    let above = above.map(|src| (&mut discard[..], src));
+    let rows = above.into_iter()
+                    .chain(out.chunks_mut(width).zip(data.chunks(width * channels)));
+    for (dest, src) in rows {
+        for (x, (index, pixel)) in dest.iter_mut().zip(src.chunks(channels)).enumerate() {
+            let source = rgba(pixel);
+            let mut color = [0u8; 4];
+            for channel in 0 .. 4 {
+                let value = i32::from(source[channel]) + current[x + 1][channel] / 16;
+                color[channel] = value.clamp(0, 255) as u8;
+            }
+
+            *index = *cache.entry(color).or_insert_with(|| nearest(palette, color));
+
+            let chosen = palette[*index as usize];
+            for channel in 0 .. 4 {
+                let error = i32::from(color[channel]) - i32::from(chosen[channel]);
+                current[x + 2][channel] += error * 7;
+                next[x][channel] += error * 3;
+                next[x + 1][channel] += error * 5;
+                next[x + 2][channel] += error;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        for error in next.iter_mut() {
+            *error = [0; 4];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dither;
+    use super::Quantizer;
+    use super::super::Header;
+    use super::super::ColorType;
+
+    fn gradient(width: u32, height: u32, alpha: bool) -> (Header, Vec<u8>) {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(if alpha {
+            ColorType::TruecolorAlpha
+        } else {
+            ColorType::Truecolor
+        }, 8).unwrap();
+
+        let mut data = Vec::new();
+        for y in 0 .. height {
+            for x in 0 .. width {
+                data.push(x as u8);
+                data.push(y as u8);
+                data.push((x + y) as u8);
+                if alpha {
+                    data.push(if x < 4 { 0 } else { 255 });
+                }
+            }
+        }
+        (header, data)
+    }
+
+    #[test]
+    fn exact_palette() {
+        let (header, data) = gradient(4, 2, false);
+        let result = Quantizer::new(256).unwrap().quantize(&header, &data).unwrap();
+        assert_eq!(result.palette().len(), 8 * 3);
+        assert_eq!(result.data().len(), 8);
+        assert!(result.transparency().is_none());
+
+        // Every pixel maps back to its exact color.
+        for (i, &index) in result.data().iter().enumerate() {
+            let entry = &result.palette()[index as usize * 3 .. index as usize * 3 + 3];
+            assert_eq!(entry, &data[i * 3 .. i * 3 + 3]);
+        }
+    }
+
+    #[test]
+    fn reduces_colors() {
+        let (header, data) = gradient(64, 200, false);
+        for &dither in &[Dither::None, Dither::Ordered, Dither::FloydSteinberg] {
+            let mut quantizer = Quantizer::new(16).unwrap();
+            quantizer.set_dithering(dither);
+            let result = quantizer.quantize(&header, &data).unwrap();
+            assert_eq!(result.palette().len(), 16 * 3);
+            assert_eq!(result.data().len(), 64 * 200);
+            assert!(result.data().iter().all(|&index| index < 16));
+            assert_eq!(result.header().color_type() as u8, ColorType::IndexedColor as u8);
+        }
+    }
+
+    #[test]
+    fn dithering_keeps_averages() {
+        // A horizontal grey ramp down to four levels.
+        let mut header = Header::new();
+        header.set_size(256, 256).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data: Vec<u8> = (0 .. 256 * 256).flat_map(|i| [(i % 256) as u8; 3]).collect();
+
+        let band_error = |dither: Dither| {
+            let mut quantizer = Quantizer::new(4).unwrap();
+            quantizer.set_dithering(dither);
+            let result = quantizer.quantize(&header, &data).unwrap();
+            // Compare bands of 8 columns, the width of the ordered
+            // pattern. Columns past the darkest and lightest entries
+            // can't average out, so leave out the ends.
+            (4 .. 28).map(|band| {
+                let sum: i32 = (0 .. 256 * 8).map(|i| {
+                    let (x, y) = (band * 8 + i % 8, i / 8);
+                    i32::from(result.palette()[result.data()[y * 256 + x] as usize * 3])
+                }).sum();
+                (sum / (256 * 8) - (band * 8) as i32 - 4).abs()
+            }).sum::<i32>() / 24
+        };
+        let nearest = band_error(Dither::None);
+        for &dither in &[Dither::Ordered, Dither::FloydSteinberg] {
+            let error = band_error(dither);
+            assert!(error < 4 && error < nearest / 3, "{:?}: {} vs {}", dither, error, nearest);
+        }
+    }
+
+    #[test]
+    fn transparent_entries_first() {
+        let (header, data) = gradient(16, 16, true);
+        let result = Quantizer::new(32).unwrap().quantize(&header, &data).unwrap();
+        let trns = result.transparency().unwrap();
+        assert!(!trns.is_empty());
+        assert!(trns.iter().all(|&alpha| alpha < 255));
+        for (i, &index) in result.data().iter().enumerate() {
+            let transparent = (i % 16) < 4;
+            assert_eq!((index as usize) < trns.len(), transparent);
+        }
+    }
+
+    #[test]
+    fn suggested_palette() {
+        let (header, mut data) = gradient(16, 16, false);
+        // Make one color dominate.
+        for pixel in data[.. 16 * 8 * 3].chunks_mut(3) {
+            pixel.copy_from_slice(&[1, 2, 3]);
+        }
+        let quantizer = Quantizer::new(8).unwrap();
+        let chunk = quantizer.suggest_palette(&header, &data, "mtpng").unwrap();
+        assert_eq!(&chunk[.. 7], b"mtpng\0\x08");
+
+        let entries: Vec<&[u8]> = chunk[7 ..].chunks(6).collect();
+        assert_eq!(entries.len(), 8);
+        assert!(entries.iter().all(|entry| entry.len() == 6 && entry[3] == 255));
+        assert_eq!(&entries[0][4 ..], &[0xff, 0xff]);
+        let frequencies: Vec<u16> = entries.iter().map(|entry| u16::from_be_bytes([entry[4], entry[5]])).collect();
+        assert!(frequencies.windows(2).all(|pair| pair[0] >= pair[1]));
+
+        assert!(quantizer.suggest_palette(&header, &data, "").is_err());
+        assert!(quantizer.suggest_palette(&header, &data, " lead").is_err());
+        assert!(quantizer.suggest_palette(&header, &data, "two  spaces").is_err());
+        assert!(quantizer.suggest_palette(&header, &data, &"x".repeat(80)).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(Quantizer::new(1).is_err());
+        assert!(Quantizer::new(257).is_err());
+
+        let mut header = Header::new();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        assert!(Quantizer::new(16).unwrap().quantize(&header, &[0]).is_err());
+    }
+}
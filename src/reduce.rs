@@ -0,0 +1,650 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// reduce.rs - lossless color type reduction
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Lossless color type reduction.
+//!
+//! Checks whether an image can be stored in a smaller color type
+//! without changing any pixel values, and converts it if so.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::io;
+
+use super::ColorType;
+use super::Header;
+use super::layout;
+
+use super::utils::invalid_input;
+
+/// A reduction that was applied to an image.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Reduction {
+    /// Every pixel was opaque, so the alpha channel was dropped.
+    StripAlpha,
+    /// Every pixel was opaque or fully transparent, with a single color
+    /// for the transparent ones, so alpha was replaced by a color key.
+    ColorKey,
+    /// Every pixel had equal red, green, and blue, so they were merged.
+    Greyscale,
+    /// There were few enough colors to use an indexed palette
+    /// with the given number of entries.
+    Palette(usize),
+    /// Every sample fit exactly in the given lower bit depth.
+    Depth(u8),
+}
+
+impl fmt::Display for Reduction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reduction::StripAlpha => write!(f, "stripped unused alpha channel"),
+            Reduction::ColorKey => write!(f, "replaced alpha channel with a transparent color key"),
+            Reduction::Greyscale => write!(f, "converted to greyscale"),
+            Reduction::Palette(n) => write!(f, "converted to indexed color with {} entries", n),
+            Reduction::Depth(depth) => write!(f, "reduced bit depth to {}", depth),
+        }
+    }
+}
+
+/// Image data after reduction, ready to pass to the encoder.
+pub struct Reduced {
+    header: Header,
+    data: Vec<u8>,
+    palette: Option<Vec<u8>>,
+    transparency: Option<Vec<u8>>,
+    reductions: Vec<Reduction>,
+}
+
+impl Reduced {
+    /// Get the updated header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Get the packed image data, in the updated color type.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the PLTE chunk data, if the image is indexed.
+    pub fn palette(&self) -> Option<&[u8]> {
+        self.palette.as_deref()
+    }
+
+    /// Get the tRNS chunk data, if any.
+    pub fn transparency(&self) -> Option<&[u8]> {
+        self.transparency.as_deref()
+    }
+
+    /// List the reductions that were applied, in order.
+    /// Empty if the image was already as small as it can go.
+    pub fn reductions(&self) -> &[Reduction] {
+        &self.reductions
+    }
+}
+
+/// Analyze an image and convert it to the smallest color type that
+/// can represent it exactly.
+///
+/// Images of bit depths below 8 and indexed images are returned unchanged.
+///
+/// Transparency data for the output is derived from the pixels' alpha
+/// values: alpha per palette entry, with transparent entries first so
+/// tRNS stays short, or failing that a single color key when alpha is
+/// all-or-nothing.
+pub fn reduce(header: &Header,
+              data: &[u8],
+              palette: Option<&[u8]>,
+              transparency: Option<&[u8]>) -> io::Result<Reduced>
+{
+    apply(header, data, palette, transparency, Steps {
+        alpha: true,
+        greyscale: true,
+        palette: true,
+        depth: false,
+    })
+}
+
+/// List every distinct exact encoding of an image reachable by some
+/// combination of alpha stripping, greyscale conversion, palette
+/// conversion, and bit-depth reduction, starting with the image as
+/// given. Which of these compresses best depends on the image, so
+/// they're meant to be tried against each other, as by
+/// optimize::optimize_reductions.
+///
+/// Bit-depth reduction takes 16-bit samples whose high and low bytes
+/// match down to 8 bits, and greyscale or indexed images below 8 bits
+/// when every value fits.
+pub fn candidates(header: &Header,
+                  data: &[u8],
+                  palette: Option<&[u8]>,
+                  transparency: Option<&[u8]>) -> io::Result<Vec<Reduced>>
+{
+    let mut out: Vec<Reduced> = Vec::new();
+    for bits in 0 .. 16 {
+        let steps = Steps {
+            alpha: bits & 1 != 0,
+            greyscale: bits & 2 != 0,
+            palette: bits & 4 != 0,
+            depth: bits & 8 != 0,
+        };
+        let reduced = apply(header, data, palette, transparency, steps)?;
+        // The same reductions from the same image give the same result.
+        if !out.iter().any(|other| other.reductions == reduced.reductions) {
+            out.push(reduced);
+        }
+    }
+    Ok(out)
+}
+
+// Which reductions to attempt.
+#[derive(Copy, Clone)]
+struct Steps {
+    alpha: bool,
+    greyscale: bool,
+    palette: bool,
+    depth: bool,
+}
+
+fn apply(header: &Header,
+         data: &[u8],
+         palette: Option<&[u8]>,
+         transparency: Option<&[u8]>,
+         steps: Steps) -> io::Result<Reduced>
+{
+    if data.len() != header.stride() * header.height as usize {
+        return Err(invalid_input("Image data does not match the header size"));
+    }
+
+    let mut reduced = Reduced {
+        header: *header,
+        data: data.to_vec(),
+        palette: palette.map(|p| p.to_vec()),
+        transparency: transparency.map(|t| t.to_vec()),
+        reductions: Vec::new(),
+    };
+
+    if header.depth < 8 {
+        return Ok(reduced);
+    }
+    if steps.depth {
+        to_eight_bits(&mut reduced)?;
+    }
+    if !matches!(header.color_type, ColorType::IndexedColor) {
+        if steps.alpha {
+            strip_alpha(&mut reduced)?;
+        }
+        if steps.greyscale {
+            greyscale(&mut reduced)?;
+        }
+        if steps.palette {
+            to_palette(&mut reduced)?;
+        }
+        if steps.alpha {
+            color_key(&mut reduced)?;
+        }
+    }
+    if steps.depth {
+        below_eight_bits(&mut reduced)?;
+    }
+
+    Ok(reduced)
+}
+
+fn has_alpha(color_type: ColorType) -> bool {
+    matches!(color_type, ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha)
+}
+
+fn sample_bytes(header: &Header) -> usize {
+    header.depth as usize / 8
+}
+
+fn strip_alpha(image: &mut Reduced) -> io::Result<()> {
+    if !has_alpha(image.header.color_type) {
+        return Ok(());
+    }
+    let bytes = sample_bytes(&image.header);
+    let pixel = image.header.bytes_per_pixel();
+    let color = pixel - bytes;
+
+    let opaque = image.data.chunks(pixel)
+                           .all(|p| p[color ..].iter().all(|&b| b == 0xff));
+    if !opaque {
+        return Ok(());
+    }
+
+    image.data = image.data.chunks(pixel)
+                           .flat_map(|p| p[0 .. color].iter().copied())
+                           .collect();
+    let color_type = match image.header.color_type {
+        ColorType::TruecolorAlpha => ColorType::Truecolor,
+        _ => ColorType::Greyscale,
+    };
+    image.header.set_color(color_type, image.header.depth)?;
+    image.reductions.push(Reduction::StripAlpha);
+    Ok(())
+}
+
+//
+// Replace an alpha channel holding only fully opaque and fully
+// transparent pixels with a tRNS color key, if the transparent
+// pixels all share one color that no opaque pixel uses.
+//
+fn color_key(image: &mut Reduced) -> io::Result<()> {
+    if !has_alpha(image.header.color_type) {
+        return Ok(());
+    }
+    let bytes = sample_bytes(&image.header);
+    let pixel = image.header.bytes_per_pixel();
+    let color = pixel - bytes;
+
+    let mut key: Option<&[u8]> = None;
+    for p in image.data.chunks(pixel) {
+        if p[color ..].iter().all(|&b| b == 0) {
+            match key {
+                Some(k) if k != &p[.. color] => return Ok(()),
+                _ => key = Some(&p[.. color]),
+            }
+        } else if !p[color ..].iter().all(|&b| b == 0xff) {
+            return Ok(());
+        }
+    }
+    let key = match key {
+        Some(key) => key.to_vec(),
+        None => return Ok(()),
+    };
+    let clash = image.data.chunks(pixel)
+                          .any(|p| p[color ..].iter().all(|&b| b == 0xff) && p[.. color] == key[..]);
+    if clash {
+        return Ok(());
+    }
+
+    image.data = image.data.chunks(pixel)
+                           .flat_map(|p| p[0 .. color].iter().copied())
+                           .collect();
+    // Keys are always 16-bit samples.
+    image.transparency = Some(if bytes == 1 {
+        key.iter().flat_map(|&sample| [0, sample]).collect()
+    } else {
+        key
+    });
+    let color_type = match image.header.color_type {
+        ColorType::TruecolorAlpha => ColorType::Truecolor,
+        _ => ColorType::Greyscale,
+    };
+    image.header.set_color(color_type, image.header.depth)?;
+    image.reductions.push(Reduction::ColorKey);
+    Ok(())
+}
+
+fn greyscale(image: &mut Reduced) -> io::Result<()> {
+    let alpha = match image.header.color_type {
+        ColorType::Truecolor => false,
+        ColorType::TruecolorAlpha => true,
+        _ => return Ok(()),
+    };
+    let bytes = sample_bytes(&image.header);
+    let pixel = image.header.bytes_per_pixel();
+
+    let grey = image.data.chunks(pixel).all(|p| {
+        p[0 .. bytes] == p[bytes .. bytes * 2] && p[0 .. bytes] == p[bytes * 2 .. bytes * 3]
+    });
+    if !grey {
+        return Ok(());
+    }
+
+    image.data = image.data.chunks(pixel)
+                           .flat_map(|p| {
+                               p[0 .. bytes].iter().chain(if alpha {
+                                   p[bytes * 3 ..].iter()
+                               } else {
+                                   [].iter()
+                               }).copied()
+                           })
+                           .collect();
+
+    // A truecolor key stays valid only if it is itself grey;
+    // otherwise no pixel can match it, and it can be dropped.
+    if let Some(trns) = image.transparency.take() {
+        if trns.len() == 6 && trns[0 .. 2] == trns[2 .. 4] && trns[0 .. 2] == trns[4 .. 6] {
+            image.transparency = Some(trns[0 .. 2].to_vec());
+        }
+    }
+
+    image.header.set_color(if alpha {
+        ColorType::GreyscaleAlpha
+    } else {
+        ColorType::Greyscale
+    }, image.header.depth)?;
+    image.reductions.push(Reduction::Greyscale);
+    Ok(())
+}
+
+// Expand a pixel of any 8-bit color type to RGBA, applying a color key.
+fn rgba(color_type: ColorType, pixel: &[u8], key: Option<&[u8]>) -> [u8; 4] {
+    let keyed = match key {
+        // Keys are 16-bit samples; at 8-bit depth the high byte is zero.
+        Some(key) => key.iter().skip(1).step_by(2).eq(pixel.iter()),
+        None => false,
+    };
+    let out = match color_type {
+        ColorType::Greyscale => [pixel[0], pixel[0], pixel[0], 255],
+        ColorType::GreyscaleAlpha => [pixel[0], pixel[0], pixel[0], pixel[1]],
+        ColorType::Truecolor => [pixel[0], pixel[1], pixel[2], 255],
+        _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+    };
+    if keyed {
+        [out[0], out[1], out[2], 0]
+    } else {
+        out
+    }
+}
+
+fn to_palette(image: &mut Reduced) -> io::Result<()> {
+    if image.header.depth != 8 {
+        return Ok(());
+    }
+    // Single-channel greyscale is already as small as a palette.
+    if let ColorType::Greyscale = image.header.color_type {
+        return Ok(());
+    }
+    let color_type = image.header.color_type;
+    let pixel = image.header.bytes_per_pixel();
+    let key = image.transparency.as_deref();
+
+    // Collect distinct colors in order of first appearance.
+    let mut colors = Vec::<[u8; 4]>::new();
+    let mut lookup = HashMap::<[u8; 4], u8>::new();
+    for p in image.data.chunks(pixel) {
+        let color = rgba(color_type, p, key);
+        if let Entry::Vacant(slot) = lookup.entry(color) {
+            if colors.len() == 256 {
+                return Ok(());
+            }
+            slot.insert(0);
+            colors.push(color);
+        }
+    }
+
+    // Transparent entries go first, so tRNS can stop early.
+    colors.sort_by_key(|color| color[3] == 255);
+    for (i, color) in colors.iter().enumerate() {
+        lookup.insert(*color, i as u8);
+    }
+
+    image.data = image.data.chunks(pixel)
+                           .map(|p| lookup[&rgba(color_type, p, key)])
+                           .collect();
+
+    let mut plte = Vec::with_capacity(colors.len() * 3);
+    for color in colors.iter() {
+        plte.extend_from_slice(&color[0 .. 3]);
+    }
+    let alphas: Vec<u8> = colors.iter()
+                                .map(|color| color[3])
+                                .take_while(|&alpha| alpha < 255)
+                                .collect();
+
+    image.palette = Some(plte);
+    image.transparency = if alphas.is_empty() {
+        None
+    } else {
+        Some(alphas)
+    };
+    image.header.set_color(ColorType::IndexedColor, 8)?;
+    image.reductions.push(Reduction::Palette(colors.len()));
+    Ok(())
+}
+
+//
+// Drop 16-bit samples to 8 bits if every sample's bytes match.
+//
+fn to_eight_bits(image: &mut Reduced) -> io::Result<()> {
+    if image.header.depth != 16 {
+        return Ok(());
+    }
+    // Only color keys go with 16-bit images, and they're 16 bits too.
+    let exact = |bytes: &[u8]| bytes.chunks(2).all(|s| s[0] == s[1]);
+    if !exact(&image.data) || !image.transparency.as_deref().is_none_or(exact) {
+        return Ok(());
+    }
+    image.data = image.data.iter().step_by(2).copied().collect();
+    if let Some(trns) = image.transparency.as_mut() {
+        // At 8 bits the key's high bytes are zero.
+        for sample in trns.chunks_mut(2) {
+            sample[0] = 0;
+        }
+    }
+    image.header.set_color(image.header.color_type, 8)?;
+    image.reductions.push(Reduction::Depth(8));
+    Ok(())
+}
+
+//
+// Pack 8-bit greyscale values that are all multiples of a lower
+// depth's step, or palette indices that all fit in fewer bits.
+//
+fn below_eight_bits(image: &mut Reduced) -> io::Result<()> {
+    if image.header.depth != 8 {
+        return Ok(());
+    }
+    let color_type = image.header.color_type;
+    let step = |depth: u8| 255 / ((1u8 << depth) - 1);
+    let depth = match color_type {
+        ColorType::Greyscale => {
+            let key = image.transparency.as_deref().map(|t| t[1]);
+            [1u8, 2, 4].iter().copied().find(|&depth| {
+                image.data.iter().chain(key.iter()).all(|&v| v % step(depth) == 0)
+            })
+        },
+        ColorType::IndexedColor => {
+            let used = image.data.iter().max().map_or(1, |&max| max as usize + 1);
+            [1u8, 2, 4].iter().copied().find(|&depth| used <= 1 << depth)
+        },
+        _ => None,
+    };
+    let depth = match depth {
+        Some(depth) => depth,
+        None => return Ok(()),
+    };
+
+    let step = match color_type {
+        ColorType::Greyscale => step(depth),
+        _ => 1,
+    };
+    let width = image.header.width as usize;
+    image.data = image.data.chunks(width)
+                           .flat_map(|row| {
+                               let samples: Vec<u8> = row.iter().map(|&v| v / step).collect();
+                               layout::pack(&samples, depth)
+                           })
+                           .collect();
+    match color_type {
+        ColorType::Greyscale => {
+            if let Some(trns) = image.transparency.as_mut() {
+                trns[1] /= step;
+            }
+        },
+        _ => {
+            // The palette may not have more entries than the depth allows.
+            let entries = 1usize << depth;
+            if let Some(plte) = image.palette.as_mut() {
+                plte.truncate(entries * 3);
+            }
+            if let Some(trns) = image.transparency.as_mut() {
+                trns.truncate(entries);
+            }
+        },
+    }
+    image.header.set_color(color_type, depth)?;
+    image.reductions.push(Reduction::Depth(depth));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{candidates, reduce, Reduction};
+    use super::super::Header;
+    use super::super::ColorType;
+
+    fn header(color_type: ColorType, depth: u8, width: u32, height: u32) -> Header {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(color_type, depth).unwrap();
+        header
+    }
+
+    #[test]
+    fn opaque_grey_rgba() {
+        // Opaque greys in RGBA collapse to plain greyscale.
+        let header = header(ColorType::TruecolorAlpha, 8, 2, 1);
+        let data = [10, 10, 10, 255, 20, 20, 20, 255];
+        let result = reduce(&header, &data, None, None).unwrap();
+        assert_eq!(result.reductions(), &[Reduction::StripAlpha, Reduction::Greyscale]);
+        assert_eq!(result.data(), &[10, 20]);
+        assert_eq!(result.header().color_type() as u8, ColorType::Greyscale as u8);
+    }
+
+    #[test]
+    fn few_colors_to_palette() {
+        let header = header(ColorType::TruecolorAlpha, 8, 3, 1);
+        let data = [255, 0, 0, 255, 0, 0, 255, 0, 255, 0, 0, 255];
+        let result = reduce(&header, &data, None, None).unwrap();
+        assert_eq!(result.reductions(), &[Reduction::Palette(2)]);
+        assert_eq!(result.transparency().unwrap(), &[0]);
+        assert_eq!(result.palette().unwrap(), &[0, 0, 255, 255, 0, 0]);
+        assert_eq!(result.data(), &[1, 0, 1]);
+    }
+
+    #[test]
+    fn color_key_to_palette() {
+        let header = header(ColorType::Truecolor, 8, 2, 1);
+        let data = [1, 2, 3, 4, 5, 6];
+        let key = [0, 4, 0, 5, 0, 6];
+        let result = reduce(&header, &data, None, Some(&key)).unwrap();
+        assert_eq!(result.reductions(), &[Reduction::Palette(2)]);
+        assert_eq!(result.palette().unwrap(), &[4, 5, 6, 1, 2, 3]);
+        assert_eq!(result.transparency().unwrap(), &[0]);
+        assert_eq!(result.data(), &[1, 0]);
+    }
+
+    #[test]
+    fn alpha_to_color_key() {
+        // Too many colors for a palette, but alpha is all-or-nothing.
+        let header = header(ColorType::TruecolorAlpha, 8, 300, 1);
+        let data: Vec<u8> = (0 .. 300u32).flat_map(|i| if i % 7 == 0 {
+            vec![0, 0, 0, 0]
+        } else {
+            vec![i as u8, (i >> 8) as u8, 7, 255]
+        }).collect();
+        let result = reduce(&header, &data, None, None).unwrap();
+        assert_eq!(result.reductions(), &[Reduction::ColorKey]);
+        assert_eq!(result.transparency().unwrap(), &[0, 0, 0, 0, 0, 0]);
+        assert_eq!(result.data().len(), 300 * 3);
+        assert_eq!(&result.data()[3 .. 6], &[1, 0, 7]);
+
+        // 16-bit greys can't use a palette, but can use a key.
+        let header = self::header(ColorType::TruecolorAlpha, 16, 3, 1);
+        let data = [0, 9, 0, 9, 0, 9, 0, 0,
+                    1, 0, 1, 0, 1, 0, 0xff, 0xff,
+                    2, 0, 2, 0, 2, 0, 0xff, 0xff];
+        let result = reduce(&header, &data, None, None).unwrap();
+        assert_eq!(result.reductions(), &[Reduction::Greyscale, Reduction::ColorKey]);
+        assert_eq!(result.transparency().unwrap(), &[0, 9]);
+        assert_eq!(result.data(), &[0, 9, 1, 0, 2, 0]);
+
+        // Transparent pixels of differing colors, opaque pixels matching
+        // the key, or partial alpha all keep the alpha channel.
+        for data in [[1, 1, 1, 0, 2, 2, 2, 0, 3, 3, 3, 255],
+                     [1, 1, 1, 0, 1, 1, 1, 255, 3, 3, 3, 255],
+                     [1, 1, 1, 0, 2, 2, 2, 128, 3, 3, 3, 255]].iter() {
+            let data: Vec<u8> = data.iter().cycle().take(12 * 100).copied().collect();
+            let wide = self::header(ColorType::TruecolorAlpha, 8, 300, 1);
+            let result = reduce(&wide, &data, None, None).unwrap();
+            assert!(!result.reductions().contains(&Reduction::ColorKey));
+        }
+    }
+
+    #[test]
+    fn many_colors_unchanged() {
+        let header = header(ColorType::Truecolor, 8, 300, 1);
+        let data: Vec<u8> = (0 .. 300u32).flat_map(|i| vec![i as u8, (i >> 8) as u8, 7]).collect();
+        let result = reduce(&header, &data, None, None).unwrap();
+        assert!(result.reductions().is_empty());
+        assert_eq!(result.data(), &data[..]);
+    }
+
+    #[test]
+    fn depth_candidates() {
+        // Two colors with 16-bit samples that fit in 8 bits.
+        let header = header(ColorType::Truecolor, 16, 3, 2);
+        let data: Vec<u8> = [[7u8; 6], [200u8; 6], [7u8; 6]].iter()
+                                                             .cycle()
+                                                             .take(6)
+                                                             .flatten()
+                                                             .copied()
+                                                             .collect();
+        let list = candidates(&header, &data, None, None).unwrap();
+        assert!(list[0].reductions().is_empty());
+        assert_eq!(list[0].data(), &data[..]);
+        for (i, a) in list.iter().enumerate() {
+            for b in list[i + 1 ..].iter() {
+                assert_ne!(a.reductions(), b.reductions());
+            }
+        }
+
+        // Without depth reduction, greys stay 16-bit.
+        let grey = list.iter().find(|r| r.reductions() == [Reduction::Greyscale]).unwrap();
+        assert_eq!(grey.header().depth(), 16);
+
+        // 7 and 200 aren't multiples of a low-depth grey step.
+        let grey8 = list.iter().find(|r| r.reductions() == [Reduction::Depth(8), Reduction::Greyscale]).unwrap();
+        assert_eq!(grey8.data(), &[7, 200, 7, 7, 200, 7]);
+
+        // Two palette entries pack into one bit per pixel.
+        let packed = list.iter()
+                         .find(|r| r.reductions() == [Reduction::Depth(8), Reduction::Palette(2), Reduction::Depth(1)])
+                         .unwrap();
+        assert_eq!(packed.header().depth(), 1);
+        assert_eq!(packed.data(), &[0b0100_0000, 0b0100_0000]);
+
+        // Greyscale values on the 2-bit step go down to 2 bits, key included.
+        let header = self::header(ColorType::Greyscale, 8, 4, 1);
+        let key = [0, 170];
+        let list = candidates(&header, &[0, 85, 170, 255], None, Some(&key)).unwrap();
+        let packed = list.iter().find(|r| r.reductions() == [Reduction::Depth(2)]).unwrap();
+        assert_eq!(packed.data(), &[0b0001_1011]);
+        assert_eq!(packed.transparency().unwrap(), &[0, 2]);
+    }
+
+    #[test]
+    fn sixteen_bit_alpha() {
+        let header = header(ColorType::GreyscaleAlpha, 16, 1, 2);
+        let data = [1, 2, 0xff, 0xff, 3, 4, 0xff, 0xff];
+        let result = reduce(&header, &data, None, None).unwrap();
+        assert_eq!(result.reductions(), &[Reduction::StripAlpha]);
+        assert_eq!(result.data(), &[1, 2, 3, 4]);
+    }
+}
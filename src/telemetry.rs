@@ -0,0 +1,99 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// telemetry.rs - optional metrics crate export
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+
+//
+// Encoder metrics reported through the metrics crate when the
+// "metrics" feature is enabled, for whatever recorder the
+// application has installed:
+//
+// * gauges mtpng_chunks_in_flight, mtpng_queue_depth, and
+//   mtpng_bytes_buffered, summed across all live encoders
+// * counters mtpng_chunks_written_total, mtpng_compressed_bytes_total,
+//   and mtpng_images_total
+// * histogram mtpng_chunk_stage_seconds, labeled by stage, with the
+//   time each chunk spent in each stage
+// * histograms mtpng_image_seconds and mtpng_worker_utilization, for
+//   each finished image
+//
+
+use metrics::{counter, gauge, histogram};
+
+use super::encoder::{EncoderMetrics, StageTimes};
+
+//
+// The amounts one encoder has added to the gauges, so several
+// encoders can share them, and so they're taken back out when the
+// encoder is dropped, finished or not.
+//
+#[derive(Default)]
+pub(crate) struct Gauges {
+    chunks_in_flight: usize,
+    queue_depth: usize,
+    bytes_buffered: usize,
+}
+
+impl Gauges {
+    pub(crate) fn update(&mut self, metrics: &EncoderMetrics) {
+        adjust("mtpng_chunks_in_flight", &mut self.chunks_in_flight, metrics.chunks_in_flight());
+        adjust("mtpng_queue_depth", &mut self.queue_depth, metrics.queue_depth());
+        adjust("mtpng_bytes_buffered", &mut self.bytes_buffered, metrics.bytes_buffered());
+    }
+}
+
+impl Drop for Gauges {
+    fn drop(&mut self) {
+        adjust("mtpng_chunks_in_flight", &mut self.chunks_in_flight, 0);
+        adjust("mtpng_queue_depth", &mut self.queue_depth, 0);
+        adjust("mtpng_bytes_buffered", &mut self.bytes_buffered, 0);
+    }
+}
+
+fn adjust(name: &'static str, reported: &mut usize, value: usize) {
+    if value > *reported {
+        gauge!(name).increment((value - *reported) as f64);
+    } else if value < *reported {
+        gauge!(name).decrement((*reported - value) as f64);
+    }
+    *reported = value;
+}
+
+pub(crate) fn chunk_written(times: &StageTimes, compressed_bytes: usize) {
+    counter!("mtpng_chunks_written_total").increment(1);
+    counter!("mtpng_compressed_bytes_total").increment(compressed_bytes as u64);
+    for (stage, time) in [("pixel_copy", times.pixel_copy()),
+                          ("filter", times.filter()),
+                          ("deflate", times.deflate()),
+                          ("checksum", times.checksum()),
+                          ("write", times.write())] {
+        histogram!("mtpng_chunk_stage_seconds", "stage" => stage).record(time.as_secs_f64());
+    }
+}
+
+pub(crate) fn image_finished(metrics: &EncoderMetrics) {
+    counter!("mtpng_images_total").increment(1);
+    histogram!("mtpng_image_seconds").record(metrics.elapsed().as_secs_f64());
+    histogram!("mtpng_worker_utilization").record(metrics.worker_utilization());
+}
@@ -0,0 +1,240 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// tile.rs - assembles out-of-order tiles into rows for the encoder
+//
+// Copyright (c) 2018-2024 Brooke Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Assembly of image rows from rectangular tiles.
+//!
+//! Parallel renderers and map stitchers often finish tiles of an image
+//! in no particular order. A TileSink takes those tiles as they come,
+//! holds only the rows that aren't complete yet, and passes each run of
+//! finished rows from the top down to the encoder, so the whole frame
+//! never has to be staged in memory.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+
+use super::Header;
+use super::encoder::Encoder;
+use super::layout;
+use super::utils::*;
+
+// A row waiting for the rest of its tiles.
+struct PendingRow {
+    data: Vec<u8>,
+    // Pixel spans filled in so far, as start and end columns.
+    spans: Vec<(u32, u32)>,
+    filled: u32,
+}
+
+impl PendingRow {
+    fn new(stride: usize) -> PendingRow {
+        PendingRow {
+            data: vec![0u8; stride],
+            spans: Vec::new(),
+            filled: 0,
+        }
+    }
+
+    fn overlaps(&self, start: u32, end: u32) -> bool {
+        self.spans.iter().any(|&(s, e)| start < e && s < end)
+    }
+}
+
+/// Takes tiles of an image in any order and feeds complete rows to
+/// an encoder in order.
+///
+/// The encoder's header must already be written. Tiles must not
+/// overlap each other or rows already passed to the encoder.
+pub struct TileSink<'e, 'a: 'e, W: Write + 'e> {
+    encoder: &'e mut Encoder<'a, W>,
+    header: Header,
+    bits_per_pixel: usize,
+    stride: usize,
+    // The first row not yet passed to the encoder.
+    next_row: u32,
+    // Rows from next_row down, as far as tiles have reached.
+    rows: VecDeque<PendingRow>,
+}
+
+impl<'e, 'a: 'e, W: Write + 'e> TileSink<'e, 'a, W> {
+    /// Create a sink feeding the given encoder, starting from the
+    /// next row it expects. Returns an error if the header hasn't
+    /// been written yet.
+    pub fn new(encoder: &'e mut Encoder<'a, W>) -> io::Result<TileSink<'e, 'a, W>> {
+        let (header, next_row) = encoder.input_position()?;
+        Ok(TileSink {
+            encoder,
+            header,
+            bits_per_pixel: layout::bits_per_pixel(header.color_type(), header.depth()),
+            stride: header.stride(),
+            next_row,
+            rows: VecDeque::new(),
+        })
+    }
+
+    /// Add a tile of the given position and size. Pixels are packed as
+    /// for Encoder::write_image_rows, in rows of the tile's width.
+    ///
+    /// Below 8 bits per pixel, tiles must start and end on byte
+    /// boundaries of the image rows, apart from at the right edge.
+    ///
+    /// Any rows at the top that the tile completes go straight to the
+    /// encoder, and errors from encoding them are returned here.
+    pub fn write_tile(&mut self, x: u32, y: u32, width: u32, height: u32, pixels: &[u8]) -> IoResult {
+        if width == 0 || height == 0 {
+            return Err(invalid_input("Tile width and height cannot be 0"));
+        }
+        let right = x.checked_add(width).filter(|&right| right <= self.header.width());
+        let bottom = y.checked_add(height).filter(|&bottom| bottom <= self.header.height());
+        let (right, bottom) = match (right, bottom) {
+            (Some(right), Some(bottom)) => (right, bottom),
+            _ => return Err(invalid_input("Tile extends past the edge of the image")),
+        };
+        if y < self.next_row {
+            return Err(invalid_input("Tile overlaps rows already encoded"));
+        }
+
+        let start_bit = x as usize * self.bits_per_pixel;
+        let end_bit = right as usize * self.bits_per_pixel;
+        if !start_bit.is_multiple_of(8) || (!end_bit.is_multiple_of(8) && right != self.header.width()) {
+            return Err(invalid_input("Tile edges must fall on byte boundaries"));
+        }
+        let start = start_bit / 8;
+        let tile_stride = end_bit.div_ceil(8) - start;
+        if pixels.len() != tile_stride * height as usize {
+            return Err(invalid_input(&format!("Tile data must be {} bytes", tile_stride * height as usize)));
+        }
+
+        // Check the whole tile before taking any of it.
+        let first = (y - self.next_row) as usize;
+        let last = (bottom - self.next_row) as usize;
+        let clash = self.rows.iter()
+                             .skip(first)
+                             .take(last - first)
+                             .any(|row| row.overlaps(x, right));
+        if clash {
+            return Err(invalid_input("Tile overlaps another tile"));
+        }
+
+        while self.rows.len() < last {
+            self.rows.push_back(PendingRow::new(self.stride));
+        }
+        for (row, src) in self.rows.range_mut(first .. last).zip(pixels.chunks(tile_stride)) {
+            row.data[start .. start + tile_stride].copy_from_slice(src);
+            row.spans.push((x, right));
+            row.filled += width;
+        }
+
+        self.flush_rows()
+    }
+
+    // Pass any complete rows at the top on to the encoder.
+    fn flush_rows(&mut self) -> IoResult {
+        let complete = self.rows.iter()
+                                .take_while(|row| row.filled == self.header.width())
+                                .count();
+        if complete == 0 {
+            return Ok(());
+        }
+        let mut buf = Vec::with_capacity(complete * self.stride);
+        for row in self.rows.drain(.. complete) {
+            buf.extend_from_slice(&row.data);
+        }
+        self.next_row += complete as u32;
+        self.encoder.write_image_rows(&buf)?;
+        Ok(())
+    }
+
+    /// Number of rows held waiting for tiles, starting from the first
+    /// row not yet encoded.
+    pub fn pending_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Whether every row of the image has been passed to the encoder.
+    pub fn is_complete(&self) -> bool {
+        self.next_row == self.header.height()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TileSink;
+    use super::super::ColorType;
+    use super::super::Header;
+    use super::super::decoder::decode;
+    use super::super::encoder::{Encoder, Options};
+
+    #[test]
+    fn out_of_order_tiles() {
+        let mut header = Header::new();
+        header.set_size(10, 7).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let image: Vec<u8> = (0 .. 10 * 7 * 3).map(|i| (i * 5 % 256) as u8).collect();
+        let tile = |x: u32, y: u32, w: u32, h: u32| -> Vec<u8> {
+            (y .. y + h).flat_map(|row| {
+                let start = (row * 10 + x) as usize * 3;
+                image[start .. start + w as usize * 3].to_vec()
+            }).collect()
+        };
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        assert!(TileSink::new(&mut encoder).is_err());
+        encoder.write_header(&header).unwrap();
+        {
+            let mut sink = TileSink::new(&mut encoder).unwrap();
+            // Bottom half first, then the top in two pieces.
+            sink.write_tile(0, 4, 10, 3, &tile(0, 4, 10, 3)).unwrap();
+            assert_eq!(sink.pending_rows(), 7);
+            sink.write_tile(6, 0, 4, 4, &tile(6, 0, 4, 4)).unwrap();
+            assert!(sink.write_tile(5, 1, 2, 1, &tile(5, 1, 2, 1)).is_err());
+            assert!(sink.write_tile(9, 6, 2, 1, &[0; 6]).is_err());
+            assert!(sink.write_tile(0, 0, 6, 4, &[0; 5]).is_err());
+            sink.write_tile(0, 0, 6, 4, &tile(0, 0, 6, 4)).unwrap();
+            assert_eq!(sink.pending_rows(), 0);
+            assert!(sink.is_complete());
+            assert!(sink.write_tile(0, 0, 1, 1, &[0; 3]).is_err());
+        }
+        let png = encoder.finish().unwrap();
+        assert_eq!(decode(&png[..]).unwrap().data(), &image[..]);
+    }
+
+    #[test]
+    fn packed_tiles() {
+        let mut header = Header::new();
+        header.set_size(12, 2).unwrap();
+        header.set_color(ColorType::Greyscale, 1).unwrap();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &Options::new());
+        encoder.write_header(&header).unwrap();
+        {
+            let mut sink = TileSink::new(&mut encoder).unwrap();
+            assert!(sink.write_tile(4, 0, 8, 2, &[0; 2]).is_err());
+            sink.write_tile(8, 0, 4, 2, &[0xa0, 0x50]).unwrap();
+            sink.write_tile(0, 0, 8, 2, &[0xff, 0x00]).unwrap();
+        }
+        let png = encoder.finish().unwrap();
+        assert_eq!(decode(&png[..]).unwrap().data(), &[0xff, 0xa0, 0x00, 0x50]);
+    }
+}
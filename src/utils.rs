@@ -24,18 +24,34 @@
 //
 
 use ::std::io;
-use ::std::io::{Error, ErrorKind, Write};
+use ::std::io::Write;
+
+use super::error::Error;
 
 pub type IoResult = io::Result<()>;
 
-pub fn invalid_input(payload: &str) -> Error
+//
+// These build an Error, or an io::Error carrying one, depending on
+// what the caller returns.
+//
+pub fn invalid_input<E: From<Error>>(payload: &str) -> E
+{
+    E::from(Error::InvalidInput(payload.to_string()))
+}
+
+pub fn other<E: From<Error>>(payload: &str) -> E
+{
+    E::from(Error::Other(payload.to_string()))
+}
+
+pub fn zlib_error<E: From<Error>>(code: i32) -> E
 {
-    Error::new(ErrorKind::InvalidInput, payload)
+    E::from(Error::ZlibError(code))
 }
 
-pub fn other(payload: &str) -> Error
+pub fn worker_panic<E: From<Error>>() -> E
 {
-    Error::new(ErrorKind::Other, payload)
+    E::from(Error::WorkerPanic)
 }
 
 pub fn write_be32<W: Write>(w: &mut W, val: u32) -> IoResult {
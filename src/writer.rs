@@ -23,18 +23,153 @@
 // THE SOFTWARE.
 //
 
-use crc::crc32;
-use crc::Hasher32;
-
 use std::io;
-use std::io::Write;
+use std::io::{SeekFrom, Write};
+
+use std::convert::TryFrom;
 
+use super::ColorType;
 use super::Header;
+use super::checksum::Crc32;
 
 use super::utils::*;
 
+// Seeks the output, for outputs that can.
+pub type SeekFunc<W> = fn(&mut W, SeekFrom) -> io::Result<u64>;
+
+//
+// Chunk ordering rules.
+// https://www.w3.org/TR/PNG/#5ChunkOrdering
+//
+
+// Ancillary chunks that must precede PLTE and IDAT.
+pub const BEFORE_PALETTE: [&[u8]; 8] = [b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB",
+                                        b"cICP", b"mDCv", b"cLLi"];
+
+// Ancillary chunks that must follow PLTE if present, and precede IDAT.
+pub const AFTER_PALETTE: [&[u8]; 3] = [b"tRNS", b"bKGD", b"hIST"];
+
+// Other ancillary chunks that must precede IDAT.
+pub const BEFORE_DATA: [&[u8]; 5] = [b"pHYs", b"sPLT", b"eXIf", b"oFFs", b"sTER"];
+
+// Ancillary chunks that may appear only once.
+pub const SINGLE_CHUNKS: [&[u8]; 16] = [b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB",
+                                        b"cICP", b"mDCv", b"cLLi",
+                                        b"tRNS", b"bKGD", b"hIST", b"pHYs", b"tIME", b"eXIf",
+                                        b"oFFs", b"sTER"];
+
+//
+// Tracks the chunks written so far, and rejects any chunk that
+// would break the ordering rules. Chunks not listed above may go
+// anywhere between IHDR and IEND.
+//
+#[derive(Default)]
+struct ChunkOrder {
+    color_type: Option<ColorType>,
+    palette: bool,
+    started_data: bool,
+    ended_data: bool,
+    ended: bool,
+    singles: Vec<[u8; 4]>,
+}
+
+impl ChunkOrder {
+    fn check(&mut self, tag: &[u8]) -> IoResult {
+        let name = String::from_utf8_lossy(tag);
+        if self.ended {
+            return Err(invalid_input("Cannot write chunks after IEND"));
+        }
+        if tag == b"IHDR" {
+            if self.color_type.is_some() {
+                return Err(invalid_input("IHDR must appear only once"));
+            }
+            return Ok(());
+        }
+        let color_type = self.color_type.ok_or_else(|| invalid_input::<io::Error>("IHDR must be the first chunk"))?;
+        let indexed = matches!(color_type, ColorType::IndexedColor);
+
+        if tag == b"IDAT" {
+            if self.ended_data {
+                return Err(invalid_input("IDAT chunks must be consecutive"));
+            }
+            if indexed && !self.palette {
+                return Err(invalid_input("Indexed-color images need PLTE before IDAT"));
+            }
+            self.started_data = true;
+            return Ok(());
+        }
+        if self.started_data {
+            self.ended_data = true;
+        }
+        match tag {
+            b"PLTE" => {
+                if self.palette {
+                    return Err(invalid_input("PLTE must appear only once"));
+                }
+                if matches!(color_type, ColorType::Greyscale | ColorType::GreyscaleAlpha) {
+                    return Err(invalid_input("Greyscale images cannot have PLTE"));
+                }
+                if self.started_data {
+                    return Err(invalid_input("PLTE must come before IDAT"));
+                }
+                if self.singles.iter().any(|seen| AFTER_PALETTE.contains(&&seen[..])) {
+                    return Err(invalid_input("PLTE must come before tRNS, bKGD, and hIST"));
+                }
+                self.palette = true;
+            },
+            b"IEND" => {
+                if !self.started_data {
+                    return Err(invalid_input("IEND must follow IDAT"));
+                }
+                self.ended = true;
+            },
+            _ if tag[0].is_ascii_uppercase() => {
+                return Err(invalid_input(&format!("Unknown critical chunk {}", name)));
+            },
+            _ if BEFORE_PALETTE.contains(&tag) && (self.palette || self.started_data) => {
+                return Err(invalid_input(&format!("{} must come before PLTE and IDAT", name)));
+            },
+            _ if AFTER_PALETTE.contains(&tag) => {
+                if self.started_data {
+                    return Err(invalid_input(&format!("{} must come before IDAT", name)));
+                }
+                if (indexed || tag == b"hIST") && !self.palette {
+                    return Err(invalid_input(&format!("{} must come after PLTE", name)));
+                }
+            },
+            _ if BEFORE_DATA.contains(&tag) && self.started_data => {
+                return Err(invalid_input(&format!("{} must come before IDAT", name)));
+            },
+            _ => {},
+        }
+        if SINGLE_CHUNKS.contains(&tag) {
+            let conflicts = |seen: &[u8; 4]| {
+                &seen[..] == tag ||
+                (tag == b"sRGB" && seen == b"iCCP") ||
+                (tag == b"iCCP" && seen == b"sRGB")
+            };
+            if self.singles.iter().any(conflicts) {
+                return Err(invalid_input(&format!("{} must appear only once, and not with both sRGB and iCCP", name)));
+            }
+            self.singles.push([tag[0], tag[1], tag[2], tag[3]]);
+        }
+        Ok(())
+    }
+}
+
+// A chunk being written in pieces, whose length is filled in at the end.
+struct OpenChunk {
+    position: u64,
+    len: u32,
+    digest: Crc32,
+}
+
 pub struct Writer<W: Write> {
     output: W,
+    written: u64,
+    seek: Option<SeekFunc<W>>,
+    open_chunk: Option<OpenChunk>,
+    order: ChunkOrder,
 }
 
 impl<W: Write> Writer<W> {
@@ -46,9 +181,25 @@ impl<W: Write> Writer<W> {
     pub fn new(output: W) -> Writer<W> {
         Writer {
             output,
+            written: 0,
+            seek: None,
+            open_chunk: None,
+            order: ChunkOrder::default(),
         }
     }
 
+    //
+    // Let the writer seek its output, allowing chunks to be written
+    // in pieces with begin_chunk, append_chunk, and end_chunk.
+    //
+    pub fn set_seek(&mut self, seek: SeekFunc<W>) {
+        self.seek = Some(seek);
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.seek.is_some()
+    }
+
     //
     // Close out the writer and return the Write
     // passed in originally so it can be used for
@@ -61,6 +212,23 @@ impl<W: Write> Writer<W> {
         Ok(self.output)
     }
 
+    //
+    // Return the output stream as it is, without flushing,
+    // for giving up partway through a file.
+    //
+    // Consumes the writer.
+    //
+    pub fn into_inner(self: Writer<W>) -> W {
+        self.output
+    }
+
+    //
+    // The output stream, for taking what's been written so far.
+    //
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
     //
     // Write the PNG file signature to output stream.
     // https://www.w3.org/TR/PNG/#5PNG-file-signature
@@ -80,15 +248,25 @@ impl<W: Write> Writer<W> {
     }
 
     fn write_be32(&mut self, val: u32) -> IoResult {
-        write_be32(&mut self.output, val)
+        self.write_bytes(&val.to_be_bytes())
     }
 
     fn write_bytes(&mut self, data: &[u8]) -> IoResult {
-        self.output.write_all(data)
+        self.output.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
     }
 
     //
-    // Write a chunk to the output stream.
+    // Total number of bytes written to the output so far.
+    //
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    //
+    // Write a chunk to the output stream. Chunks out of order,
+    // such as gAMA after PLTE or a second PLTE, are rejected.
     //
     // https://www.w3.org/TR/PNG/#5DataRep
     // https://www.w3.org/TR/PNG/#5CRC-algorithm
@@ -97,15 +275,16 @@ impl<W: Write> Writer<W> {
         if tag.len() != 4 {
             return Err(invalid_input("Chunk tags must be 4 bytes"));
         }
-        if data.len() > u32::max_value() as usize {
+        if data.len() > u32::MAX as usize {
             return Err(invalid_input("Data chunks cannot exceed 4 GiB - 1 byte"));
         }
+        self.order.check(tag)?;
 
         // CRC covers both tag and data.
-        let mut digest = crc32::Digest::new(crc32::IEEE);
-        digest.write(tag);
-        digest.write(data);
-        let checksum = digest.sum32();
+        let mut digest = Crc32::new();
+        digest.update(tag);
+        digest.update(data);
+        let checksum = digest.sum();
 
         // Write data...
         self.write_be32(data.len() as u32)?;
@@ -114,6 +293,66 @@ impl<W: Write> Writer<W> {
         self.write_be32(checksum)
     }
 
+    //
+    // Start a chunk whose data will come in pieces, leaving its length
+    // to be filled in by end_chunk. Needs a seekable output.
+    //
+    pub fn begin_chunk(&mut self, tag: &[u8]) -> IoResult {
+        let seek = self.seek.ok_or_else(|| invalid_input::<io::Error>("Output is not seekable"))?;
+        if tag.len() != 4 {
+            return Err(invalid_input("Chunk tags must be 4 bytes"));
+        }
+        if self.open_chunk.is_some() {
+            return Err(invalid_input("A chunk is already open"));
+        }
+        self.order.check(tag)?;
+
+        let position = seek(&mut self.output, SeekFrom::Current(0))?;
+        let mut digest = Crc32::new();
+        digest.update(tag);
+
+        self.write_be32(0)?;
+        self.write_bytes(tag)?;
+        self.open_chunk = Some(OpenChunk {
+            position,
+            len: 0,
+            digest,
+        });
+        Ok(())
+    }
+
+    //
+    // Add data to the chunk started with begin_chunk.
+    //
+    pub fn append_chunk(&mut self, data: &[u8]) -> IoResult {
+        let len = match self.open_chunk {
+            Some(ref chunk) => u32::try_from(chunk.len as usize + data.len())
+                .map_err(|_e| invalid_input::<io::Error>("Data chunks cannot exceed 4 GiB - 1 byte"))?,
+            None => return Err(invalid_input("No chunk is open")),
+        };
+        self.write_bytes(data)?;
+        if let Some(ref mut chunk) = self.open_chunk {
+            chunk.len = len;
+            chunk.digest.update(data);
+        }
+        Ok(())
+    }
+
+    //
+    // Finish the chunk started with begin_chunk, going back to fill
+    // in its length.
+    //
+    pub fn end_chunk(&mut self) -> IoResult {
+        let chunk = self.open_chunk.take().ok_or_else(|| invalid_input::<io::Error>("No chunk is open"))?;
+        let seek = self.seek.ok_or_else(|| invalid_input::<io::Error>("Output is not seekable"))?;
+
+        seek(&mut self.output, SeekFrom::Start(chunk.position))?;
+        self.output.write_all(&chunk.len.to_be_bytes())?;
+        seek(&mut self.output, SeekFrom::Start(chunk.position + 8 + u64::from(chunk.len)))?;
+
+        self.write_be32(chunk.digest.sum())
+    }
+
     //
     // IHDR - first chunk in the file.
     // https://www.w3.org/TR/PNG/#11IHDR
@@ -128,7 +367,9 @@ impl<W: Write> Writer<W> {
         write_byte(&mut data, header.filter_method as u8)?;
         write_byte(&mut data, header.interlace_method as u8)?;
 
-        self.write_chunk(b"IHDR", &data)
+        self.write_chunk(b"IHDR", &data)?;
+        self.order.color_type = Some(header.color_type);
+        Ok(())
     }
 
     //
@@ -150,10 +391,21 @@ impl<W: Write> Writer<W> {
 #[cfg(test)]
 mod tests {
     use std::io;
+    use std::io::{Seek, Write};
 
+    use super::super::{ColorType, Header};
     use super::Writer;
     use super::IoResult;
 
+    // Length of the signature and IHDR chunk.
+    const START: usize = 8 + 25;
+
+    fn start<W: Write>(writer: &mut Writer<W>) -> IoResult {
+        writer.write_signature()?;
+        writer.write_header(Header::new())
+    }
+
+    #[allow(clippy::assertions_on_constants)]
     fn test_writer<F, G>(test_func: F, assert_func: G)
         where F: Fn(&mut Writer<Vec<u8>>) -> IoResult,
               G: Fn(&[u8])
@@ -191,39 +443,115 @@ mod tests {
     #[test]
     fn empty_chunk_works() {
         test_writer(|writer| {
+            start(writer)?;
             writer.write_chunk(b"IDAT", b"")
         }, |output| {
             // 4 bytes len
             // 4 bytes tag
             // 4 bytes crc
-            assert_eq!(output.len(), 12);
+            assert_eq!(output.len(), START + 12);
         })
     }
 
     #[test]
     fn full_chunk_works() {
         test_writer(|writer| {
+            start(writer)?;
             writer.write_chunk(b"IDAT", b"01234567890123456789")
         }, |output| {
             // 4 bytes len
             // 4 bytes tag
             // 20 bytes data
             // 4 bytes crc
-            assert_eq!(output.len(), 32);
+            assert_eq!(output.len(), START + 32);
         })
     }
 
+    #[test]
+    fn counts_bytes() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        start(&mut writer).unwrap();
+        writer.write_chunk(b"IDAT", b"0123").unwrap();
+        assert_eq!(writer.bytes_written(), (START + 12 + 4) as u64);
+    }
+
+    #[test]
+    fn pieces_match_whole_chunk() {
+        let mut whole = Writer::new(Vec::<u8>::new());
+        start(&mut whole).unwrap();
+        whole.write_chunk(b"IDAT", b"01234567890123456789").unwrap();
+        whole.write_end().unwrap();
+
+        let mut pieces = Writer::new(io::Cursor::new(Vec::<u8>::new()));
+        assert!(pieces.begin_chunk(b"IDAT").is_err());
+        pieces.set_seek(io::Cursor::seek);
+        start(&mut pieces).unwrap();
+        pieces.begin_chunk(b"IDAT").unwrap();
+        assert!(pieces.begin_chunk(b"IDAT").is_err());
+        pieces.append_chunk(b"0123456789").unwrap();
+        pieces.append_chunk(b"").unwrap();
+        pieces.append_chunk(b"0123456789").unwrap();
+        pieces.end_chunk().unwrap();
+        assert!(pieces.end_chunk().is_err());
+        pieces.write_end().unwrap();
+
+        assert_eq!(pieces.bytes_written(), whole.bytes_written());
+        assert_eq!(pieces.finish().unwrap().into_inner(), whole.finish().unwrap());
+    }
+
     #[test]
     fn crc_works() {
         // From a 1x1 truecolor black pixel made with gd
         let one_pixel = b"\x08\x99\x63\x60\x60\x60\x00\x00\x00\x04\x00\x01";
         test_writer(|writer| {
+            start(writer)?;
             writer.write_chunk(b"IDAT", one_pixel)
         }, |output| {
+            let output = &output[START ..];
             assert_eq!(output[0..4], b"\x00\x00\x00\x0c"[..], "expected length 12");
             assert_eq!(output[4..8], b"IDAT"[..], "expected IDAT");
             assert_eq!(output[8..20], one_pixel[..], "expected data payload");
             assert_eq!(output[20..24], b"\xa3\x0a\x15\xe3"[..], "expected crc32");
         })
     }
+
+    #[test]
+    fn chunk_order() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_signature().unwrap();
+        assert!(writer.write_chunk(b"gAMA", &[0; 4]).is_err());
+        let mut header = Header::new();
+        header.set_color(ColorType::IndexedColor, 8).unwrap();
+        writer.write_header(header).unwrap();
+        assert!(writer.write_header(header).is_err());
+        writer.write_chunk(b"gAMA", &[0; 4]).unwrap();
+        assert!(writer.write_chunk(b"gAMA", &[0; 4]).is_err());
+        writer.write_chunk(b"iCCP", b"p\0\0").unwrap();
+        assert!(writer.write_chunk(b"sRGB", &[0]).is_err());
+        assert!(writer.write_chunk(b"tRNS", &[0]).is_err());
+        assert!(writer.write_chunk(b"IDAT", b"").is_err());
+        assert!(writer.write_chunk(b"CRIT", b"").is_err());
+        writer.write_chunk(b"PLTE", &[0; 3]).unwrap();
+        assert!(writer.write_chunk(b"PLTE", &[0; 3]).is_err());
+        assert!(writer.write_chunk(b"cHRM", &[0; 32]).is_err());
+        writer.write_chunk(b"tRNS", &[0]).unwrap();
+        writer.write_chunk(b"IDAT", b"").unwrap();
+        writer.write_chunk(b"IDAT", b"").unwrap();
+        assert!(writer.write_chunk(b"pHYs", &[0; 9]).is_err());
+        writer.write_chunk(b"tEXt", b"a\0b").unwrap();
+        assert!(writer.write_chunk(b"IDAT", b"").is_err());
+        writer.write_end().unwrap();
+        assert!(writer.write_chunk(b"tEXt", b"a\0b").is_err());
+
+        // No palette for greyscale, and IEND needs image data.
+        let mut writer = Writer::new(Vec::<u8>::new());
+        start(&mut writer).unwrap();
+        assert!(writer.write_end().is_err());
+        let mut header = Header::new();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_header(header).unwrap();
+        assert!(writer.write_chunk(b"PLTE", &[0; 3]).is_err());
+        assert!(writer.write_chunk(b"hIST", &[0; 2]).is_err());
+    }
 }